@@ -0,0 +1,151 @@
+//! Builds bootable disk images of a yarhos kernel executable and runs one of them in QEMU.
+//!
+//! Used as the cargo runner of the kernel target in place of `bootimage runner`, see
+//! `.cargo/config.toml`. Both a BIOS and a UEFI image are created next to the executable, named
+//! after it with `.bios.img` and `.uefi.img` appended. Which one is booted is selected with the
+//! `YARHOS_FIRMWARE` environment variable, `uefi` (the default, using OVMF) or `bios`.
+//!
+//! Like with `bootimage`, executables in a `deps` directory are tests. They run without a display
+//! and with the `isa-debug-exit` device, and pass if the kernel exits QEMU with
+//! `QemuExitCode::Success` within `TEST_TIMEOUT`.
+//!
+//! Arguments after the executable are passed on to QEMU, e.g. `cargo run -- -s -S` for debugging
+//! with GDB.
+
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bootloader::{BiosBoot, UefiBoot};
+use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
+
+/// QEMU arguments for running tests.
+const TEST_ARGS: &[&str] = &[
+    "-device",
+    "isa-debug-exit,iobase=0xf4,iosize=0x04",
+    "-serial",
+    "stdio",
+    "-display",
+    "none",
+];
+/// QEMU arguments for running the kernel.
+const RUN_ARGS: &[&str] = &["-serial", "stdio"];
+/// The exit status of QEMU for `QemuExitCode::Success`, which `isa-debug-exit` turns into
+/// `(0x10 << 1) | 1`.
+const TEST_SUCCESS_EXIT_CODE: i32 = 33;
+/// The time after which a test executable is killed and fails.
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The firmware to boot with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Firmware {
+    Bios,
+    Uefi,
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let kernel = match args.next() {
+        Some(kernel) => PathBuf::from(kernel),
+        None => fail("usage: yarhos-builder <kernel executable> [QEMU arguments...]"),
+    };
+    let qemu_args: Vec<OsString> = args.collect();
+    let firmware = match env::var("YARHOS_FIRMWARE").as_deref() {
+        Err(_) | Ok("uefi") => Firmware::Uefi,
+        Ok("bios") => Firmware::Bios,
+        Ok(other) => fail(&format!(
+            "unknown YARHOS_FIRMWARE `{}`, expected `uefi` or `bios`",
+            other
+        )),
+    };
+
+    let bios_image = image_path(&kernel, "bios");
+    let uefi_image = image_path(&kernel, "uefi");
+    if let Err(error) = BiosBoot::new(&kernel).create_disk_image(&bios_image) {
+        fail(&format!("creating the BIOS image failed: {:?}", error));
+    }
+    if let Err(error) = UefiBoot::new(&kernel).create_disk_image(&uefi_image) {
+        fail(&format!("creating the UEFI image failed: {:?}", error));
+    }
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    match firmware {
+        Firmware::Bios => {
+            qemu.arg("-drive").arg(drive("format=raw", &bios_image));
+        }
+        Firmware::Uefi => {
+            let ovmf_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/ovmf");
+            let ovmf = match Prebuilt::fetch(Source::LATEST, ovmf_dir) {
+                Ok(ovmf) => ovmf,
+                Err(error) => fail(&format!("fetching OVMF failed: {}", error)),
+            };
+            qemu.arg("-drive").arg(drive(
+                "if=pflash,format=raw,readonly=on",
+                &ovmf.get_file(Arch::X64, FileType::Code),
+            ));
+            // NOTE: the variable store is writable, but changes are discarded on exit
+            qemu.arg("-drive").arg(drive(
+                "if=pflash,format=raw,snapshot=on",
+                &ovmf.get_file(Arch::X64, FileType::Vars),
+            ));
+            qemu.arg("-drive").arg(drive("format=raw", &uefi_image));
+        }
+    }
+    let is_test = kernel
+        .parent()
+        .is_some_and(|directory| directory.ends_with("deps"));
+    qemu.args(if is_test { TEST_ARGS } else { RUN_ARGS });
+    qemu.args(qemu_args);
+
+    let mut child = match qemu.spawn() {
+        Ok(child) => child,
+        Err(error) => fail(&format!("starting QEMU failed: {}", error)),
+    };
+    if !is_test {
+        let status = child.wait().expect("waiting for QEMU failed");
+        process::exit(status.code().unwrap_or(1));
+    }
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("waiting for QEMU failed") {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            fail(&format!("test timed out after {:?}", TEST_TIMEOUT));
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    match status.code() {
+        Some(TEST_SUCCESS_EXIT_CODE) => {}
+        Some(code) => fail(&format!("test failed with QEMU exit status {}", code)),
+        None => fail("QEMU was terminated by a signal"),
+    }
+}
+
+/// Returns the path of the disk image of `kernel` for `firmware`.
+fn image_path(kernel: &Path, firmware: &str) -> PathBuf {
+    let mut path = kernel.as_os_str().to_owned();
+    path.push(format!(".{}.img", firmware));
+    PathBuf::from(path)
+}
+
+/// Returns a QEMU `-drive` option with `options` for the image at `path`.
+fn drive(options: &str, path: &Path) -> OsString {
+    let mut drive = OsString::from(options);
+    drive.push(",file=");
+    drive.push(path);
+    drive
+}
+
+/// Prints `message` and exits with a failure status.
+fn fail(message: &str) -> ! {
+    eprintln!("yarhos-builder: {}", message);
+    process::exit(1);
+}
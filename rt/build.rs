@@ -0,0 +1,8 @@
+// Links the example programs like `scripts/build_test_programs.sh` links the assembly ones
+fn main() {
+    // The lowest user space address, see `memory::USER_SPACE_START` of the kernel
+    println!("cargo:rustc-link-arg-examples=--image-base=0x8000000000");
+    println!("cargo:rustc-link-arg-examples=-zmax-page-size=0x1000");
+    println!("cargo:rustc-link-arg-examples=-znoexecstack");
+    println!("cargo:rerun-if-changed=build.rs");
+}
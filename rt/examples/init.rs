@@ -0,0 +1,52 @@
+//! The first process started by the kernel, with process ID 1.
+//!
+//! Starts the embedded programs, and reaps them as well as any orphaned processes handed over to
+//! it until none are left. The kernel shuts down the machine once init exits.
+
+#![no_std]
+#![no_main]
+
+use yarhos_rt::{
+    entry_point, println,
+    syscall::{self, Error},
+    Arguments,
+};
+
+entry_point!(main);
+
+// NOTE: embedded until there is a file system to load programs from
+static PROGRAMS: &[(&str, &[u8])] = &[
+    ("data", include_bytes!("../../tests/programs/data.elf")),
+    ("exit", include_bytes!("../../tests/programs/exit.elf")),
+];
+
+fn main(_: Arguments) -> u64 {
+    println!("Hello, World! ö");
+    println!("Ohai :3");
+
+    for &(name, image) in PROGRAMS {
+        match syscall::fork() {
+            Ok(0) => {
+                let error = syscall::exec(image, &[name]);
+                println!("Executing {} failed: {:?}", name, error);
+                syscall::exit(127);
+            }
+            Ok(pid) => println!("Spawned {} as process {}", name, pid),
+            Err(error) => println!("Spawning {} failed: {:?}", name, error),
+        }
+    }
+
+    loop {
+        match syscall::wait(None) {
+            Ok((pid, status)) => println!("Process {} exited with status {}", pid, status),
+            Err(Error::NoChildren) => break,
+            Err(error) => {
+                println!("Waiting failed: {:?}", error);
+                return 1;
+            }
+        }
+    }
+
+    println!("It did not crash :o");
+    0
+}
@@ -0,0 +1,51 @@
+//! Prints its arguments, allocates from the heap and with `mmap`, and forks a child which exits
+//! with the number of arguments. Exits with 42, or with the number of the failed step.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use yarhos_rt::{entry_point, println, syscall, Arguments};
+
+entry_point!(main);
+
+fn main(arguments: Arguments) -> u64 {
+    // 1: arguments
+    let mut joined = String::new();
+    for argument in arguments.iter() {
+        joined.push_str(argument);
+        joined.push(' ');
+    }
+    println!("{} arguments: {}", arguments.len(), joined.trim_end());
+    if arguments.get(arguments.len()).is_some() {
+        return 1;
+    }
+
+    // 2: growing the heap
+    let numbers: Vec<u64> = (0..100_000).collect();
+    if numbers.iter().sum::<u64>() != 99_999 * 100_000 / 2 {
+        return 2;
+    }
+    drop(numbers);
+
+    // 3: large allocations of their own
+    let large = Box::new([7u8; 1 << 20]);
+    if large.iter().any(|&byte| byte != 7) {
+        return 3;
+    }
+    drop(large);
+
+    // 4: fork and wait
+    match syscall::fork() {
+        Ok(0) => syscall::exit(arguments.len() as u64),
+        Ok(child) => match syscall::wait(Some(child)) {
+            Ok((pid, status)) if pid == child && status == arguments.len() as u64 => {}
+            _ => return 4,
+        },
+        Err(_) => return 4,
+    }
+    42
+}
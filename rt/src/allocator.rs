@@ -0,0 +1,88 @@
+//! The global allocator of user space programs.
+//!
+//! Small allocations come from a heap right after the program image, which grows by moving the
+//! program break with `brk`. Large allocations get memory areas of their own with `mmap`, so that
+//! freeing them returns the memory to the kernel right away.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+use crate::syscall::{self, PROT_READ, PROT_WRITE};
+
+/// The page size of the kernel.
+const PAGE_SIZE: usize = 4096;
+/// Allocations of at least this size are mapped with `mmap`.
+const MMAP_THRESHOLD: usize = 128 * 1024;
+/// The minimum amount the heap grows by.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: UserAllocator = UserAllocator;
+
+// NOTE: programs only have a single thread, but the allocator must still be `Sync`
+static HEAP: Mutex<Heap> = Mutex::new(Heap::empty());
+
+/// The global allocator, allocating from `HEAP` or with `mmap`.
+struct UserAllocator;
+
+unsafe impl GlobalAlloc for UserAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_mapped(layout) {
+            return syscall::mmap(None, layout.size() as u64, PROT_READ | PROT_WRITE)
+                .unwrap_or_default();
+        }
+        let mut heap = HEAP.lock();
+        if let Ok(allocation) = heap.allocate_first_fit(layout) {
+            return allocation.as_ptr();
+        }
+        if !grow(&mut heap, layout.size() + layout.align()) {
+            return core::ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_mapped(layout) {
+            // SAFETY: guaranteed by the caller
+            // NOTE: there is nothing to do about memory that cannot be released
+            let _ = unsafe { syscall::munmap(ptr, layout.size() as u64) };
+            return;
+        }
+        // SAFETY: guaranteed by the caller
+        unsafe { HEAP.lock().deallocate(NonNull::new_unchecked(ptr), layout) };
+    }
+}
+
+/// Returns whether an allocation with `layout` gets its own memory area.
+fn is_mapped(layout: Layout) -> bool {
+    // NOTE: `mmap` returns page aligned memory
+    layout.size() >= MMAP_THRESHOLD && layout.align() <= PAGE_SIZE
+}
+
+/// Grows `heap` by at least `size` bytes by moving the program break. Returns whether it grew.
+fn grow(heap: &mut Heap, size: usize) -> bool {
+    let size = size.max(HEAP_GROWTH);
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    // SAFETY: querying the break changes nothing
+    let start = unsafe { syscall::brk(0) };
+    // SAFETY: raising the break releases nothing
+    let end = unsafe { syscall::brk(start + size as u64) };
+    if end != start + size as u64 {
+        return false;
+    }
+    // NOTE: nothing else moves the break, so the heap always ends at it
+    if heap.size() == 0 {
+        // SAFETY: the memory was just added to the program and is used for nothing else
+        unsafe { heap.init(start as *mut u8, size) };
+    } else {
+        // SAFETY: the memory right after the heap was just added to the program
+        unsafe { heap.extend(size) };
+    }
+    true
+}
@@ -0,0 +1,34 @@
+//! Printing to the console with the `write` system call.
+
+use core::fmt::{self, Write};
+
+use crate::syscall;
+
+/// Like the `print!` macro in `std`, but prints to the console.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+/// Like the `println!` macro in `std`, but prints to the console.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// The console, written to with a system call per string.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        // NOTE: the kernel writes whole strings
+        syscall::write(string).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // NOTE: there is nowhere to report a failed write to the console
+    let _ = Console.write_fmt(args);
+}
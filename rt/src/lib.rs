@@ -0,0 +1,153 @@
+//! A minimal runtime for yarhos user space programs.
+//!
+//! Provides the `_start` entry point, which collects the arguments from the initial stack, calls
+//! the function given to `entry_point!` and exits with the value it returns. On top of that there
+//! are wrappers for the system calls of the kernel, `print!` and `println!` writing to the
+//! console, a global allocator growing the heap with `brk`, and a panic handler exiting with 101.
+//!
+//! A program looks like this:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use yarhos_rt::{entry_point, println, Arguments};
+//!
+//! entry_point!(main);
+//!
+//! fn main(arguments: Arguments) -> u64 {
+//!     println!("Hello from {}", arguments.get(0).unwrap_or("nowhere"));
+//!     0
+//! }
+//! ```
+//!
+//! See the `examples` directory, which `scripts/build_test_programs.sh` builds for the kernel
+//! tests.
+
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod io;
+pub mod syscall;
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+/// The exit code of a program that panicked.
+pub const PANIC_EXIT_CODE: u64 = 101;
+
+/// Defines the function called by `_start` with the program arguments. Its return value is the
+/// exit code of the program.
+///
+/// The function must have the signature `fn(Arguments) -> u64`.
+#[macro_export]
+macro_rules! entry_point {
+    ($path:path) => {
+        #[export_name = "__yarhos_rt_main"]
+        pub fn __impl_main(arguments: $crate::Arguments) -> u64 {
+            // validate the signature of the program entry point
+            let f: fn($crate::Arguments) -> u64 = $path;
+            f(arguments)
+        }
+    };
+}
+
+extern "Rust" {
+    fn __yarhos_rt_main(arguments: Arguments) -> u64;
+}
+
+// The kernel starts programs with the stack pointer pointing at `argc`, followed by the `argv`
+// array, see `loader` of the kernel
+global_asm!(
+    ".global _start",
+    "_start:",
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+extern "C" fn start(stack: *const u64) -> ! {
+    // SAFETY: the initial stack holds `argc` and the `argv` array
+    let arguments = unsafe {
+        Arguments {
+            count: *stack as usize,
+            pointers: stack.add(1).cast(),
+        }
+    };
+    // SAFETY: defined by `entry_point!` with the right signature
+    let code = unsafe { __yarhos_rt_main(arguments) };
+    syscall::exit(code)
+}
+
+/// The arguments of the program, i.e. its `argv` array.
+#[derive(Debug, Clone, Copy)]
+pub struct Arguments {
+    count: usize,
+    pointers: *const *const u8,
+}
+
+impl Arguments {
+    /// Returns the number of arguments.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns whether there are no arguments.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the argument at `index`, or `None` if there is no such argument or it is not valid
+    /// UTF-8.
+    pub fn get(&self, index: usize) -> Option<&'static str> {
+        if index >= self.count {
+            return None;
+        }
+        // SAFETY: the kernel passes `count` pointers to NUL terminated strings, which are never
+        // freed
+        unsafe {
+            let string = *self.pointers.add(index);
+            let mut length = 0;
+            while *string.add(length) != 0 {
+                length += 1;
+            }
+            core::str::from_utf8(core::slice::from_raw_parts(string, length)).ok()
+        }
+    }
+
+    /// Returns an iterator over the arguments, skipping those that are not valid UTF-8.
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        let arguments = *self;
+        (0..self.count).filter_map(move |index| arguments.get(index))
+    }
+}
+
+/// Runs `tests`, for programs using the custom test frameworks feature.
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+}
+
+#[cfg(test)]
+entry_point!(test_entry_point);
+
+#[cfg(test)]
+fn test_entry_point(_: Arguments) -> u64 {
+    test_main();
+    0
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    syscall::exit(PANIC_EXIT_CODE)
+}
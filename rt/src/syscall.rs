@@ -0,0 +1,274 @@
+//! Wrappers for the system calls of the kernel.
+//!
+//! The numbers, errors and constants mirror the `syscall` module of the kernel, see there for the
+//! ABI and the details of each system call.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+pub const EXIT: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const YIELD: u64 = 2;
+pub const SLEEP: u64 = 3;
+pub const TIME: u64 = 4;
+pub const GETPID: u64 = 5;
+pub const GETPPID: u64 = 6;
+pub const WAIT: u64 = 7;
+pub const FORK: u64 = 8;
+pub const EXEC: u64 = 9;
+pub const MMAP: u64 = 10;
+pub const MUNMAP: u64 = 11;
+pub const BRK: u64 = 12;
+pub const SIGACTION: u64 = 13;
+pub const SIGPROCMASK: u64 = 14;
+pub const KILL: u64 = 15;
+pub const SIGRETURN: u64 = 16;
+
+/// `mmap` protection bits.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// `sigaction` handlers for the default action and ignoring the signal.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// `sigprocmask` operations.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// The reasons a system call can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoSuchSyscall,
+    InvalidArgument,
+    BadAddress,
+    NoSuchProcess,
+    NoChildren,
+    OutOfMemory,
+    NotExecutable,
+    Interrupted,
+    /// An error unknown to the runtime, with the negative value returned for it.
+    Other(i64),
+}
+
+impl Error {
+    fn from_return_value(value: i64) -> Error {
+        match value {
+            -1 => Error::NoSuchSyscall,
+            -2 => Error::InvalidArgument,
+            -3 => Error::BadAddress,
+            -4 => Error::NoSuchProcess,
+            -5 => Error::NoChildren,
+            -6 => Error::OutOfMemory,
+            -7 => Error::NotExecutable,
+            -8 => Error::Interrupted,
+            value => Error::Other(value),
+        }
+    }
+}
+
+/// Returns the error if `value` is an error, i.e. in `-4095..0` as `i64`, and the value otherwise.
+fn result(value: u64) -> Result<u64, Error> {
+    match value as i64 {
+        -4095..=-1 => Err(Error::from_return_value(value as i64)),
+        _ => Ok(value),
+    }
+}
+
+/// Makes the system call `number` with up to three arguments, returning the raw return value.
+///
+/// # Safety
+///
+/// The arguments must be valid for the system call, e.g. pointers must point to memory that the
+/// kernel may access as the system call requires.
+pub unsafe fn syscall(number: u64, arguments: [u64; 3]) -> u64 {
+    let value;
+    // SAFETY: guaranteed by the caller; `SYSCALL` clobbers `rcx` and `r11` only
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number => value,
+            in("rdi") arguments[0],
+            in("rsi") arguments[1],
+            in("rdx") arguments[2],
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    value
+}
+
+pub fn exit(code: u64) -> ! {
+    // SAFETY: exiting does not touch memory
+    unsafe { syscall(EXIT, [code, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+/// Writes `string` to the console, and returns the number of bytes written.
+pub fn write(string: &str) -> Result<usize, Error> {
+    // SAFETY: the string is readable
+    let value = unsafe { syscall(WRITE, [string.as_ptr() as u64, string.len() as u64, 0]) };
+    result(value).map(|length| length as usize)
+}
+
+pub fn yield_now() {
+    // SAFETY: yielding does not touch memory
+    unsafe { syscall(YIELD, [0; 3]) };
+}
+
+pub fn sleep(milliseconds: u64) {
+    // SAFETY: sleeping does not touch memory
+    unsafe { syscall(SLEEP, [milliseconds, 0, 0]) };
+}
+
+/// Returns the time since boot in milliseconds.
+pub fn time() -> u64 {
+    // SAFETY: getting the time does not touch memory
+    unsafe { syscall(TIME, [0; 3]) }
+}
+
+pub fn getpid() -> u64 {
+    // SAFETY: getting the process ID does not touch memory
+    unsafe { syscall(GETPID, [0; 3]) }
+}
+
+pub fn getppid() -> u64 {
+    // SAFETY: getting the process ID does not touch memory
+    unsafe { syscall(GETPPID, [0; 3]) }
+}
+
+/// Waits for the child process `pid`, or any child if `None`, to exit, and returns its process ID
+/// and exit status.
+pub fn wait(pid: Option<u64>) -> Result<(u64, u64), Error> {
+    let mut status = 0u64;
+    // SAFETY: the status is writable
+    let value = unsafe {
+        syscall(
+            WAIT,
+            [pid.unwrap_or(u64::MAX), &mut status as *mut u64 as u64, 0],
+        )
+    };
+    result(value).map(|pid| (pid, status))
+}
+
+/// Creates a child process as a copy of the calling one. Returns the process ID of the child in
+/// the parent, and 0 in the child.
+pub fn fork() -> Result<u64, Error> {
+    // SAFETY: forking does not touch memory of the calling process
+    result(unsafe { syscall(FORK, [0; 3]) })
+}
+
+/// Replaces the program of the calling process with the ELF executable `image`, passing it
+/// `arguments`. Only returns on failure.
+pub fn exec(image: &[u8], arguments: &[&str]) -> Error {
+    // The kernel expects NUL terminated strings in a null terminated array
+    let strings: Vec<Vec<u8>> = arguments
+        .iter()
+        .map(|argument| {
+            let mut string = Vec::with_capacity(argument.len() + 1);
+            string.extend_from_slice(argument.as_bytes());
+            string.push(0);
+            string
+        })
+        .collect();
+    let mut pointers: Vec<u64> = strings
+        .iter()
+        .map(|string| string.as_ptr() as u64)
+        .collect();
+    pointers.push(0);
+    // SAFETY: the image and the arguments are readable
+    let value = unsafe {
+        syscall(
+            EXEC,
+            [
+                image.as_ptr() as u64,
+                image.len() as u64,
+                pointers.as_ptr() as u64,
+            ],
+        )
+    };
+    match result(value) {
+        Err(error) => error,
+        Ok(_) => unreachable!("exec returned"),
+    }
+}
+
+/// Reserves `length` bytes of zero-filled memory, at `address` if given, and returns its start.
+pub fn mmap(address: Option<u64>, length: u64, protection: u64) -> Result<*mut u8, Error> {
+    // SAFETY: new memory does not overlap existing memory
+    let value = unsafe { syscall(MMAP, [address.unwrap_or(0), length, protection]) };
+    result(value).map(|address| address as *mut u8)
+}
+
+/// Releases the memory reserved with `mmap` in `[address, address + length)`.
+///
+/// # Safety
+///
+/// The memory must not be used anymore.
+pub unsafe fn munmap(address: *mut u8, length: u64) -> Result<(), Error> {
+    // SAFETY: guaranteed by the caller
+    result(unsafe { syscall(MUNMAP, [address as u64, length, 0]) }).map(|_| ())
+}
+
+/// Moves the program break to `address` unless it is null, and returns the new break, which is
+/// unchanged on failure.
+///
+/// # Safety
+///
+/// Memory released by lowering the break must not be used anymore.
+pub unsafe fn brk(address: u64) -> u64 {
+    // SAFETY: guaranteed by the caller
+    unsafe { syscall(BRK, [address, 0, 0]) }
+}
+
+/// A signal handler, called with the signal number.
+pub type SignalHandler = extern "C" fn(u64);
+
+/// Sets the handler of `signal` to `SIG_DFL`, `SIG_IGN` or the address of a `SignalHandler`, and
+/// returns the previous one.
+///
+/// Handlers return through a restorer provided by the runtime.
+pub fn sigaction(signal: u64, handler: u64) -> Result<u64, Error> {
+    // SAFETY: the kernel only checks the addresses, and the restorer makes the `sigreturn` system
+    // call
+    let value = unsafe { syscall(SIGACTION, [signal, handler, restorer_address()]) };
+    result(value)
+}
+
+/// Changes the blocked signals by applying `set` with the operation `how`, and returns the
+/// previous mask.
+pub fn sigprocmask(how: u64, set: u64) -> Result<u64, Error> {
+    // SAFETY: changing the mask does not touch memory
+    result(unsafe { syscall(SIGPROCMASK, [how, set, 0]) })
+}
+
+/// Sends `signal` to the process `pid`.
+pub fn kill(pid: u64, signal: u64) -> Result<(), Error> {
+    // SAFETY: sending a signal does not touch memory
+    result(unsafe { syscall(KILL, [pid, signal, 0]) }).map(|_| ())
+}
+
+// Signal handlers return here, with the stack pointer right above the signal frame of the kernel
+core::arch::global_asm!(
+    ".global __yarhos_rt_restorer",
+    "__yarhos_rt_restorer:",
+    "mov eax, 16", // SIGRETURN
+    "syscall",
+    "ud2",
+);
+
+extern "C" {
+    fn __yarhos_rt_restorer();
+}
+
+fn restorer_address() -> u64 {
+    __yarhos_rt_restorer as *const () as u64
+}
+
+/// Returns a `SIGACTION` handler argument for `handler`.
+pub fn handler_address(handler: SignalHandler) -> u64 {
+    handler as *const () as u64
+}
@@ -0,0 +1,29 @@
+#!/usr/bin/sh
+# Assembles and links the user space test programs embedded into the tests with `include_bytes!`,
+# and builds the examples of the `yarhos-rt` runtime crate into test programs of the same names
+# NOTE: assumes GNU binutils targeting x86_64
+# NOTE: programs are built in alphabetical order, so programs embedding others with `.incbin` must
+# sort after them
+
+PROGRAMS_DIR="$(dirname "$0")/../tests/programs"
+# The lowest user space address, see `memory::USER_SPACE_START`
+BASE_ADDRESS=0x8000000000
+
+for source in "$PROGRAMS_DIR"/*.S; do
+    program="${source%.S}"
+    as --64 -I "$PROGRAMS_DIR" -o "$program.o" "$source" \
+        && ld -static -nostdlib -z max-page-size=0x1000 -z noexecstack \
+            -Ttext-segment="$BASE_ADDRESS" -o "$program.elf" "$program.o" \
+        && rm "$program.o" \
+        || exit 1
+done
+
+# NOTE: built from the root directory to use its Cargo configuration
+ROOT_DIR="$(dirname "$0")/.."
+EXAMPLES_DIR="$ROOT_DIR/target/yarhos-x86_64-nosse-softfloat/release/examples"
+
+(cd "$ROOT_DIR" && cargo build --release -p yarhos-rt --examples) || exit 1
+for source in "$ROOT_DIR"/rt/examples/*.rs; do
+    program="$(basename "${source%.rs}")"
+    cp "$EXAMPLES_DIR/$program" "$PROGRAMS_DIR/$program.elf" || exit 1
+done
@@ -0,0 +1,9 @@
+#!/usr/bin/sh
+# The cargo runner of the kernel target: builds disk images of the kernel executable given as the
+# first argument and boots one in QEMU, see `builder/src/main.rs`
+# NOTE: the builder is built from outside the repository, as it runs on the host and the kernel
+# cargo configuration (`build-std` in particular) must not apply to it
+
+BUILDER_DIR="$(cd "$(dirname "$0")/../builder" && pwd)"
+
+cd / && exec cargo run --quiet --manifest-path "$BUILDER_DIR/Cargo.toml" -- "$@"
@@ -0,0 +1,538 @@
+//! Address spaces for user code.
+//!
+//! Every address space has its own level 4 page table. The user space part of it starts out empty,
+//! while all other entries are copied from the active page table, so that the kernel stays mapped
+//! at the same addresses in all address spaces.
+//!
+//! Forking an address space shares its frames with the copy. Pages writable before are mapped
+//! read-only and marked `memory::COPY_ON_WRITE` in both, so that the first write to such a page
+//! faults and `copy_on_write()` gives the writer its own copy of the frame.
+//!
+//! Besides the eagerly mapped program image and stack, an address space has memory areas (see
+//! `vma`) whose pages are mapped on demand by `handle_page_fault()`. Anonymous areas are placed
+//! between `MMAP_START` and `MMAP_END`, and the area right above the program image grows and
+//! shrinks with the program break.
+//!
+//! Dropping an address space frees its user page tables, and all frames mapped in user space that
+//! are not shared with other address spaces.
+
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::TranslateResult, page_table::PageTableEntry, FrameAllocator, FrameDeallocator,
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    loader,
+    memory::{
+        self, GlobalFrameAllocator, COPY_ON_WRITE, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START,
+    },
+    vma::{Protection, Vma, Vmas},
+};
+
+/// The lowest address of anonymous memory areas. The program image and break lie below it.
+pub const MMAP_START: u64 = 0x0000_4000_0000_0000;
+/// The address right above the highest address of anonymous memory areas, leaving a guard page
+/// below the user stack.
+pub const MMAP_END: u64 = loader::USER_STACK_TOP - loader::USER_STACK_SIZE - PAGE_SIZE;
+
+/// The number of bytes mapped by a level 4 page table entry.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+/// The index of the first level 4 page table entry belonging to user space.
+const FIRST_USER_ENTRY: usize = (USER_SPACE_START / LEVEL_4_ENTRY_SIZE) as usize;
+/// The index of the level 4 page table entry right after user space.
+const END_USER_ENTRY: usize = (USER_SPACE_END / LEVEL_4_ENTRY_SIZE) as usize;
+
+static_assertions::const_assert_eq!(USER_SPACE_START % LEVEL_4_ENTRY_SIZE, 0);
+static_assertions::const_assert_eq!(USER_SPACE_END % LEVEL_4_ENTRY_SIZE, 0);
+
+/// The flags of user page tables.
+///
+/// NOTE: the page tables are writable and user accessible, so that the permissions of each page
+/// only depend on its own entry.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// An error accessing memory of an address space through its page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedAddress(pub VirtAddr);
+
+/// The reasons changing the memory areas of an address space can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaError {
+    /// The range is empty, not page aligned, or outside of the permitted range.
+    BadRange,
+    /// The range overlaps an existing memory area.
+    Overlap,
+    /// There is no free range large enough.
+    NoSpace,
+}
+
+/// A user address space, i.e. a page table hierarchy sharing the kernel mappings.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    areas: Vmas,
+    /// The lowest possible program break, right above the program image.
+    break_start: VirtAddr,
+    program_break: VirtAddr,
+}
+
+impl AddressSpace {
+    /// Creates an address space with no user mappings, or returns `None` if out of memory.
+    ///
+    /// NOTE: kernel mappings are shared on the level of the level 4 page table entries. Kernel
+    /// memory mapped later on in a previously unused level 4 entry is not visible in existing
+    /// address spaces.
+    pub fn new() -> Option<AddressSpace> {
+        let level_4_frame = memory::allocate_zeroed_frame()?;
+        let (active_frame, _) = Cr3::read();
+        let active: *const PageTable = memory::phys_to_virt(active_frame.start_address()).as_ptr();
+        let table: *mut PageTable =
+            memory::phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+        // SAFETY: the active table is valid, and the new one was just allocated so nothing else
+        // refers to it
+        let (active, table) = unsafe { (&*active, &mut *table) };
+        for (index, entry) in active.iter().enumerate() {
+            if !(FIRST_USER_ENTRY..END_USER_ENTRY).contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Some(AddressSpace {
+            level_4_frame,
+            areas: Vmas::new(),
+            break_start: VirtAddr::new(USER_SPACE_START),
+            program_break: VirtAddr::new(USER_SPACE_START),
+        })
+    }
+
+    /// Creates a copy of this address space sharing all user frames copy-on-write, or returns
+    /// `None` if out of memory.
+    ///
+    /// The writable pages of this address space become copy-on-write as well.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        child.areas = self.areas.clone();
+        child.break_start = self.break_start;
+        child.program_break = self.program_break;
+        // SAFETY: both tables are owned by their address spaces, and `&mut self` prevents other
+        // changes to this one
+        let (parent_table, child_table) = unsafe {
+            (
+                page_table(self.level_4_frame),
+                page_table(child.level_4_frame),
+            )
+        };
+        let mut result = Some(());
+        for index in FIRST_USER_ENTRY..END_USER_ENTRY {
+            // SAFETY: see above
+            result = unsafe { fork_entry(&mut parent_table[index], &mut child_table[index], 3) };
+            if result.is_none() {
+                break;
+            }
+        }
+        if self.is_active() {
+            // Writable pages just became read-only
+            tlb::flush_all();
+        }
+        // NOTE: on failure, dropping the partial copy releases the frames it shares
+        result.map(|_| child)
+    }
+
+    /// Returns the frame of the level 4 page table.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this is the active address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    ///
+    /// NOTE: while the address space is active, the caller must not use `memory::active_mapper()`
+    /// at the same time.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        // SAFETY: the frame contains a valid level 4 table, and `&mut self` prevents creating
+        // another mapper through it
+        unsafe { memory::mapper_for(self.level_4_frame) }
+    }
+
+    /// Switches to this address space, returning the previously active level 4 page table.
+    ///
+    /// # Safety
+    ///
+    /// The caller must not rely on user mappings of the previous address space until switching
+    /// back to it.
+    pub unsafe fn activate(&self) -> PhysFrame {
+        // SAFETY: guaranteed by the caller
+        unsafe { switch_to(self.level_4_frame) }
+    }
+
+    /// Returns the memory areas of this address space.
+    pub fn areas(&self) -> &Vmas {
+        &self.areas
+    }
+
+    /// Reserves a memory area of `size` bytes for zero-filled memory with `protection` and the
+    /// permissions it implies, and returns its start address. The area starts at `address` if
+    /// given, and otherwise wherever it fits between `MMAP_START` and `MMAP_END`.
+    ///
+    /// Frames are only allocated once the pages are accessed.
+    pub fn map_anonymous(
+        &mut self,
+        address: Option<VirtAddr>,
+        size: u64,
+        protection: Protection,
+    ) -> Result<VirtAddr, AreaError> {
+        let size = page_align_up(size).ok_or(AreaError::BadRange)?;
+        let start = match address {
+            Some(start) => start,
+            None => self
+                .areas
+                .find_free(size, VirtAddr::new(MMAP_START), VirtAddr::new(MMAP_END))
+                .ok_or(AreaError::NoSpace)?,
+        };
+        let end = anonymous_range_end(start, size)?;
+        self.areas
+            .insert(Vma {
+                start,
+                end,
+                protection: protection.with_implied(),
+            })
+            .map_err(|_| AreaError::Overlap)?;
+        Ok(start)
+    }
+
+    /// Removes `[address, address + size)` between `MMAP_START` and `MMAP_END` from the memory
+    /// areas, and frees the frames mapped there.
+    pub fn unmap(&mut self, address: VirtAddr, size: u64) -> Result<(), AreaError> {
+        let size = page_align_up(size).ok_or(AreaError::BadRange)?;
+        let end = anonymous_range_end(address, size)?;
+        for area in self.areas.remove(address, end) {
+            self.free_pages(area.start, area.end);
+        }
+        Ok(())
+    }
+
+    /// Returns the program break, i.e. the end of the memory area above the program image.
+    pub fn program_break(&self) -> VirtAddr {
+        self.program_break
+    }
+
+    /// Sets the lowest program break, right after the program image.
+    pub(crate) fn init_program_break(&mut self, address: VirtAddr) {
+        assert_eq!(self.break_start, self.program_break, "Program break in use");
+        self.break_start = address;
+        self.program_break = address;
+    }
+
+    /// Moves the program break to `address`, growing or shrinking the readable and writable memory
+    /// area above the program image, which may extend up to `MMAP_START`.
+    pub fn set_program_break(&mut self, address: VirtAddr) -> Result<(), AreaError> {
+        if address < self.break_start || address.as_u64() > MMAP_START {
+            return Err(AreaError::BadRange);
+        }
+        let old_end = self.program_break.align_up(PAGE_SIZE);
+        let new_end = address.align_up(PAGE_SIZE);
+        if new_end > old_end {
+            let area = Vma {
+                start: old_end,
+                end: new_end,
+                protection: Protection::READ_WRITE,
+            };
+            self.areas.insert(area).map_err(|_| AreaError::Overlap)?;
+        } else {
+            for area in self.areas.remove(new_end, old_end) {
+                self.free_pages(area.start, area.end);
+            }
+        }
+        self.program_break = address;
+        Ok(())
+    }
+
+    /// Maps a zeroed frame for the unmapped page containing `address` if it lies in a memory area
+    /// permitting `access`. Returns whether the page was mapped.
+    pub fn handle_page_fault(&mut self, address: VirtAddr, access: Protection) -> bool {
+        let flags = match self.areas.find(address) {
+            Some(area) if area.protection.contains(access) => area.protection.page_flags(),
+            _ => None,
+        };
+        let flags = match flags {
+            Some(flags) => flags | PageTableFlags::USER_ACCESSIBLE,
+            None => return false,
+        };
+        let page = Page::<Size4KiB>::containing_address(address);
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        if let TranslateResult::Mapped { .. } = mapper.translate(page.start_address()) {
+            return false;
+        }
+        let frame = match memory::allocate_zeroed_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        // SAFETY: the frame is unused and the page lies in user space
+        match unsafe {
+            mapper.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                USER_TABLE_FLAGS,
+                &mut GlobalFrameAllocator,
+            )
+        } {
+            Ok(flush) if active => flush.flush(),
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                // SAFETY: the frame was never mapped
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Maps the pages of `[start, start + size)` in memory areas which were not accessed yet, so
+    /// that the kernel can check and access them with `access` on behalf of user code.
+    pub fn populate(&mut self, start: VirtAddr, size: u64, access: Protection) {
+        if size == 0 || !memory::is_user_range(start, size) {
+            return;
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+        for page in Page::range_inclusive(first, last) {
+            // NOTE: mapped pages are left alone
+            self.handle_page_fault(page.start_address(), access);
+        }
+    }
+
+    /// Unmaps the pages in `[start, end)` and releases their frames.
+    fn free_pages(&mut self, start: VirtAddr, end: VirtAddr) {
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let range = Page::<Size4KiB>::range(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        );
+        for page in range {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                if active {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+                // SAFETY: the page no longer refers to the frame
+                unsafe { memory::release_frame(frame) };
+            }
+        }
+    }
+
+    /// Copies `bytes` to `address` in this address space through the physical memory mapping,
+    /// regardless of the page permissions. Copies shared frames on write first.
+    pub fn write(&mut self, address: VirtAddr, bytes: &[u8]) -> Result<(), UnmappedAddress> {
+        let mut mapper = self.mapper();
+        let mut address = address;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let page = Page::<Size4KiB>::containing_address(address);
+            let offset = address - page.start_address();
+            let length = bytes.len().min((PAGE_SIZE - offset) as usize);
+            if let TranslateResult::Mapped { flags, .. } = mapper.translate(address) {
+                if flags.contains(COPY_ON_WRITE) && !copy_on_write(&mut mapper, page) {
+                    return Err(UnmappedAddress(address));
+                }
+            }
+            let frame_address = match mapper.translate(address) {
+                TranslateResult::Mapped { frame, offset, .. } => frame.start_address() + offset,
+                _ => return Err(UnmappedAddress(address)),
+            };
+            let target: *mut u8 = memory::phys_to_virt(frame_address).as_mut_ptr();
+            // SAFETY: the target lies within a mapped frame, which holds user memory only
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), target, length) };
+            address += length;
+            bytes = &bytes[length..];
+        }
+        Ok(())
+    }
+}
+
+/// Switches to the page table hierarchy rooted at `level_4_frame`, returning the previously active
+/// level 4 page table.
+///
+/// # Safety
+///
+/// `level_4_frame` must be the level 4 page table of the kernel or of an `AddressSpace` which
+/// outlives its use. The caller must not rely on user mappings of the previous address space until
+/// switching back to it.
+pub unsafe fn switch_to(level_4_frame: PhysFrame) -> PhysFrame {
+    let (previous, flags) = Cr3::read();
+    // SAFETY: the kernel is mapped identically in all address spaces
+    unsafe { Cr3::write(level_4_frame, flags) };
+    previous
+}
+
+/// Returns `size` rounded up to whole pages, or `None` if it is zero or overflows.
+fn page_align_up(size: u64) -> Option<u64> {
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    (size != 0).then_some(size)
+}
+
+/// Returns the end of the page aligned range of `size` bytes at `start`, if it lies between
+/// `MMAP_START` and `MMAP_END`.
+fn anonymous_range_end(start: VirtAddr, size: u64) -> Result<VirtAddr, AreaError> {
+    let end = start.as_u64().checked_add(size);
+    match end {
+        Some(end)
+            if start.is_aligned(PAGE_SIZE) && start.as_u64() >= MMAP_START && end <= MMAP_END =>
+        {
+            Ok(VirtAddr::new(end))
+        }
+        _ => Err(AreaError::BadRange),
+    }
+}
+
+/// Gives the copy-on-write `page` mapped by `mapper` a frame of its own and makes it writable.
+///
+/// The frame is copied only if it is still shared. Returns `false` if `page` is not a
+/// copy-on-write page, or if the copy cannot be allocated.
+pub fn copy_on_write(mapper: &mut OffsetPageTable, page: Page<Size4KiB>) -> bool {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame, flags, .. } if flags.contains(COPY_ON_WRITE) => (
+            PhysFrame::<Size4KiB>::containing_address(frame.start_address()),
+            flags,
+        ),
+        _ => return false,
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if !memory::is_frame_shared(frame) {
+        // SAFETY: the frame is no longer shared, so only this page refers to it
+        return match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+    let copy = match GlobalFrameAllocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    let source: *const u8 = memory::phys_to_virt(frame.start_address()).as_ptr();
+    let target: *mut u8 = memory::phys_to_virt(copy.start_address()).as_mut_ptr();
+    // SAFETY: the copy was just allocated, and the source frame is mapped so it stays allocated
+    unsafe { core::ptr::copy_nonoverlapping(source, target, PAGE_SIZE as usize) };
+    // SAFETY: the page is remapped to an identical copy of its frame right away
+    let (_, flush) = mapper.unmap(page).expect("Translated page not mapped");
+    flush.flush();
+    // SAFETY: the copy is unused, and the page tables exist so that nothing is allocated
+    unsafe { mapper.map_to(page, copy, flags, &mut GlobalFrameAllocator) }
+        .expect("Remapping a page failed")
+        .flush();
+    // SAFETY: the page no longer refers to the frame
+    unsafe { memory::release_frame(frame) };
+    true
+}
+
+/// Returns the page table in `frame`.
+///
+/// # Safety
+///
+/// `frame` must contain a page table, to which no other references exist while the returned one
+/// is in use.
+unsafe fn page_table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    let table: *mut PageTable = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    // SAFETY: guaranteed by the caller
+    unsafe { &mut *table }
+}
+
+/// Copies the unused `child` entry from `parent` entry at `level` (see `free_entry()`), copying
+/// page tables and sharing mapped frames copy-on-write.
+///
+/// Returns `None` if out of memory, leaving the copy partial.
+///
+/// # Safety
+///
+/// `parent` and `child` must belong to different address spaces which are not modified otherwise.
+unsafe fn fork_entry(
+    parent: &mut PageTableEntry,
+    child: &mut PageTableEntry,
+    level: u8,
+) -> Option<()> {
+    if parent.is_unused() {
+        return Some(());
+    }
+    let frame = PhysFrame::<Size4KiB>::containing_address(parent.addr());
+    if level == 0 {
+        let mut flags = parent.flags();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            parent.set_flags(flags);
+        }
+        memory::share_frame(frame);
+        child.set_frame(frame, flags);
+        return Some(());
+    }
+    let table_frame = memory::allocate_zeroed_frame()?;
+    child.set_frame(table_frame, parent.flags());
+    // SAFETY: guaranteed by the caller, and the child table was just allocated
+    let (parent_table, child_table) = unsafe { (page_table(frame), page_table(table_frame)) };
+    for (parent, child) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+        // SAFETY: guaranteed by the caller
+        unsafe { fork_entry(parent, child, level - 1) }?;
+    }
+    Some(())
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Dropping the active address space");
+        // SAFETY: the table is owned by this address space, which is not in use
+        let table = unsafe { page_table(self.level_4_frame) };
+        for entry in table.iter_mut().take(END_USER_ENTRY).skip(FIRST_USER_ENTRY) {
+            // SAFETY: user entries refer to page tables and frames owned by this address space
+            unsafe { free_entry(entry, 3) };
+        }
+        // SAFETY: the table is no longer referred to
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Frees the frame `entry` refers to, and if it is a page table at `level` (as opposed to a
+/// mapped frame at level 0), everything it refers to. Mapped frames drop a reference and are freed
+/// once unshared.
+///
+/// # Safety
+///
+/// The frames must be owned by the page table hierarchy and not be in use.
+unsafe fn free_entry(entry: &mut PageTableEntry, level: u8) {
+    if entry.is_unused() {
+        return;
+    }
+    // NOTE: user space is only ever mapped with 4 KiB pages
+    assert!(
+        level == 0 || !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+        "Huge page in user space"
+    );
+    let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    if level > 0 {
+        // SAFETY: guaranteed by the caller
+        for next in unsafe { page_table(frame) }.iter_mut() {
+            // SAFETY: guaranteed by the caller
+            unsafe { free_entry(next, level - 1) };
+        }
+        // SAFETY: guaranteed by the caller
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    } else {
+        // SAFETY: guaranteed by the caller
+        unsafe { memory::release_frame(frame) };
+    }
+    entry.set_unused();
+}
@@ -0,0 +1,84 @@
+//! The kernel heap, backing the `alloc` crate.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+
+use linked_list_allocator::Heap;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Mapper, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{memory, sync::IrqSpinlock};
+
+/// The start of the kernel heap, in the last but one level 4 page table entry.
+///
+/// NOTE: the heap must be mapped before any user address space is created, as kernel mappings
+/// are shared on the level of level 4 entries.
+pub const HEAP_START: u64 = 0xFFFF_FF00_0000_0000;
+/// The size of the kernel heap.
+pub const HEAP_SIZE: u64 = 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+// NOTE: interrupt handlers may not allocate, but the heap is still locked with interrupts
+// disabled so that allocating never has to wait for an interrupted allocation
+static HEAP: IrqSpinlock<Heap> = IrqSpinlock::with_name("HEAP", Heap::empty());
+
+/// Maps the kernel heap and hands it to the allocator.
+pub(crate) fn init(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::map_zeroed(mapper, VirtAddr::new(HEAP_START), HEAP_SIZE, flags)?;
+    // SAFETY: the heap memory was just mapped and is used for nothing else
+    unsafe { HEAP.lock().init(HEAP_START as *mut u8, HEAP_SIZE as usize) };
+    Ok(())
+}
+
+/// Returns the number of bytes currently allocated from the kernel heap.
+pub fn used() -> usize {
+    HEAP.lock().used()
+}
+
+/// The global allocator, allocating from `HEAP`.
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        HEAP.lock()
+            .allocate_first_fit(layout)
+            .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: guaranteed by the caller
+        unsafe { HEAP.lock().deallocate(NonNull::new_unchecked(ptr), layout) };
+    }
+}
+
+#[test_case]
+fn test_heap_allocations_are_freed() {
+    use alloc::{boxed::Box, vec::Vec};
+
+    let used = self::used();
+    let boxed = Box::new(41);
+    let vector: Vec<u64> = (0..1000).collect();
+    assert_eq!(*boxed + 1, 42);
+    assert_eq!(vector.iter().sum::<u64>(), 999 * 1000 / 2);
+    drop(boxed);
+    drop(vector);
+    assert_eq!(self::used(), used);
+}
+
+#[test_case]
+fn test_heap_reuses_memory() {
+    use alloc::boxed::Box;
+
+    // More than fits on the heap without reuse
+    for i in 0..HEAP_SIZE / 1024 {
+        let block = Box::new([i; 256]);
+        assert_eq!(block[255], i);
+    }
+}
@@ -0,0 +1,316 @@
+//! A parser for ANSI escape sequences[^ansi], as understood by VT100 compatible terminals.
+//!
+//! Output meant for both the VGA screen and a terminal on the serial interface can use escape
+//! sequences for colors and cursor control: the terminal interprets them itself, and the VGA
+//! `Writer` through this parser.
+//!
+//! The supported control sequences (`ESC [` followed by parameters and a final character) are
+//! listed in `Sequence`, along with `ESC 7` and `ESC 8` for saving and restoring the cursor. Other
+//! escape sequences are consumed and ignored.
+//!
+//! [^ansi]: https://en.wikipedia.org/wiki/ANSI_escape_code
+
+/// The maximum number of parameters of a control sequence, further ones are ignored.
+const MAX_PARAMS: usize = 8;
+
+const ESC: char = '\x1B';
+/// Cancels an escape sequence, as does SUB.
+const CAN: char = '\x18';
+const SUB: char = '\x1A';
+
+/// The numeric parameters of a control sequence, with omitted ones as 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Params {
+        Params {
+            values: [0; MAX_PARAMS],
+            len: 1,
+        }
+    }
+
+    /// Returns an iterator over the parameters.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+
+    /// Returns the parameter at `index`, or `default` if it is omitted or 0.
+    fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    /// Returns the 0 based position given by the 1 based parameter at `index`.
+    fn position(&self, index: usize) -> usize {
+        self.get_or(index, 1) as usize - 1
+    }
+
+    fn count(&self, index: usize) -> usize {
+        self.get_or(index, 1) as usize
+    }
+}
+
+/// Which part of the line or the display to erase, relative to the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// From the cursor to the end, inclusive.
+    ToEnd,
+    /// From the start to the cursor, inclusive.
+    ToStart,
+    All,
+}
+
+impl Erase {
+    fn new(param: u16) -> Option<Erase> {
+        match param {
+            0 => Some(Erase::ToEnd),
+            1 => Some(Erase::ToStart),
+            // NOTE: 3 also erases the scrollback of xterm
+            2 | 3 => Some(Erase::All),
+            _ => None,
+        }
+    }
+}
+
+/// A supported escape sequence. Rows and columns are 0 based, unlike in the sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sequence {
+    /// Select Graphic Rendition, `CSI n;... m`, e.g. colors.
+    SelectGraphicRendition(Params),
+    /// `CSI n A`
+    CursorUp(usize),
+    /// `CSI n B`
+    CursorDown(usize),
+    /// `CSI n C`
+    CursorForward(usize),
+    /// `CSI n D`
+    CursorBack(usize),
+    /// Moves the cursor down to the first column, `CSI n E`.
+    CursorNextLine(usize),
+    /// Moves the cursor up to the first column, `CSI n F`.
+    CursorPreviousLine(usize),
+    /// `CSI column G`
+    CursorColumn(usize),
+    /// `CSI row;column H` or `CSI row;column f`
+    CursorPosition { row: usize, column: usize },
+    /// `CSI n J`
+    EraseInDisplay(Erase),
+    /// `CSI n K`
+    EraseInLine(Erase),
+    /// `CSI s` or `ESC 7`
+    SaveCursor,
+    /// `CSI u` or `ESC 8`
+    RestoreCursor,
+    /// Restricts scrolling to the rows from `top` to `bottom` inclusive, or to the last row if
+    /// `None`, `CSI top;bottom r`.
+    SetScrollRegion { top: usize, bottom: Option<usize> },
+}
+
+/// What the parser makes of a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// A character to write, including control characters other than ESC.
+    Char(char),
+    /// A completed escape sequence.
+    Sequence(Sequence),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// In an escape sequence with intermediate characters, none of which are supported.
+    EscapeIntermediate,
+    /// In a control sequence, which is ignored if `ignore` is set.
+    ControlSequence {
+        ignore: bool,
+    },
+}
+
+/// A state machine splitting a stream of characters into characters to write and escape
+/// sequences.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    params: Params,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: Params::new(),
+        }
+    }
+
+    /// Abandons a partially parsed escape sequence.
+    pub fn reset(&mut self) {
+        self.state = State::Ground;
+    }
+
+    /// Feeds `c` to the parser, returning what to do if anything.
+    pub fn advance(&mut self, c: char) -> Option<Output> {
+        match (self.state, c) {
+            (_, ESC) => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, c) => Some(Output::Char(c)),
+            (_, CAN) | (_, SUB) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Escape, '[') => {
+                self.params = Params::new();
+                self.state = State::ControlSequence { ignore: false };
+                None
+            }
+            (State::Escape, '7') => self.finish(Some(Sequence::SaveCursor)),
+            (State::Escape, '8') => self.finish(Some(Sequence::RestoreCursor)),
+            (State::Escape, '\x20'..='\x2F') => {
+                self.state = State::EscapeIntermediate;
+                None
+            }
+            (State::EscapeIntermediate, '\x20'..='\x2F') => None,
+            (State::Escape, _) | (State::EscapeIntermediate, _) => self.finish(None),
+            (State::ControlSequence { ignore }, c) => self.control_sequence(ignore, c),
+        }
+    }
+
+    fn control_sequence(&mut self, ignore: bool, c: char) -> Option<Output> {
+        match c {
+            '0'..='9' => {
+                let value = &mut self.params.values[self.params.len - 1];
+                *value = value
+                    .saturating_mul(10)
+                    .saturating_add(c as u16 - '0' as u16);
+                None
+            }
+            ';' => {
+                if self.params.len < MAX_PARAMS {
+                    self.params.len += 1;
+                } else {
+                    self.state = State::ControlSequence { ignore: true };
+                }
+                None
+            }
+            // Private parameters and intermediate characters, as in `CSI ?25l`
+            '\x20'..='\x2F' | '\x3A'..='\x3F' => {
+                self.state = State::ControlSequence { ignore: true };
+                None
+            }
+            '\x40'..='\x7E' if ignore => self.finish(None),
+            '\x40'..='\x7E' => {
+                let params = self.params;
+                let sequence = match c {
+                    'm' => Some(Sequence::SelectGraphicRendition(params)),
+                    'A' => Some(Sequence::CursorUp(params.count(0))),
+                    'B' => Some(Sequence::CursorDown(params.count(0))),
+                    'C' => Some(Sequence::CursorForward(params.count(0))),
+                    'D' => Some(Sequence::CursorBack(params.count(0))),
+                    'E' => Some(Sequence::CursorNextLine(params.count(0))),
+                    'F' => Some(Sequence::CursorPreviousLine(params.count(0))),
+                    'G' => Some(Sequence::CursorColumn(params.position(0))),
+                    'H' | 'f' => Some(Sequence::CursorPosition {
+                        row: params.position(0),
+                        column: params.position(1),
+                    }),
+                    'J' => Erase::new(params.get_or(0, 0)).map(Sequence::EraseInDisplay),
+                    'K' => Erase::new(params.get_or(0, 0)).map(Sequence::EraseInLine),
+                    's' => Some(Sequence::SaveCursor),
+                    'u' => Some(Sequence::RestoreCursor),
+                    'r' => Some(Sequence::SetScrollRegion {
+                        top: params.position(0),
+                        bottom: params.get_or(1, 0).checked_sub(1).map(usize::from),
+                    }),
+                    _ => None,
+                };
+                self.finish(sequence)
+            }
+            // NOTE: terminals execute control characters within control sequences
+            _ => Some(Output::Char(c)),
+        }
+    }
+
+    /// Returns to the ground state with the result of an escape sequence.
+    fn finish(&mut self, sequence: Option<Sequence>) -> Option<Output> {
+        self.state = State::Ground;
+        sequence.map(Output::Sequence)
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+#[cfg(test)]
+fn test_parse(s: &str) -> alloc::vec::Vec<Output> {
+    let mut parser = Parser::new();
+    s.chars().filter_map(|c| parser.advance(c)).collect()
+}
+
+#[test_case]
+fn test_ansi_plain() {
+    assert_eq!(test_parse("a\n"), [Output::Char('a'), Output::Char('\n')]);
+}
+
+#[test_case]
+fn test_ansi_control_sequences() {
+    assert_eq!(
+        test_parse("\x1B[H\x1B[5;10f\x1B[3A\x1B[C\x1B[0K\x1B[2J\x1B[s\x1B8"),
+        [
+            Output::Sequence(Sequence::CursorPosition { row: 0, column: 0 }),
+            Output::Sequence(Sequence::CursorPosition { row: 4, column: 9 }),
+            Output::Sequence(Sequence::CursorUp(3)),
+            Output::Sequence(Sequence::CursorForward(1)),
+            Output::Sequence(Sequence::EraseInLine(Erase::ToEnd)),
+            Output::Sequence(Sequence::EraseInDisplay(Erase::All)),
+            Output::Sequence(Sequence::SaveCursor),
+            Output::Sequence(Sequence::RestoreCursor),
+        ]
+    );
+    assert_eq!(
+        test_parse("\x1B[2;20r\x1B[r"),
+        [
+            Output::Sequence(Sequence::SetScrollRegion {
+                top: 1,
+                bottom: Some(19)
+            }),
+            Output::Sequence(Sequence::SetScrollRegion {
+                top: 0,
+                bottom: None
+            }),
+        ]
+    );
+}
+
+#[test_case]
+fn test_ansi_select_graphic_rendition() {
+    let output = test_parse("\x1B[1;31;;44m\x1B[m");
+    let params = |output: &Output| match output {
+        Output::Sequence(Sequence::SelectGraphicRendition(params)) => {
+            params.iter().collect::<alloc::vec::Vec<_>>()
+        }
+        other => panic!("Unexpected {:?}", other),
+    };
+    assert_eq!(output.len(), 2);
+    assert_eq!(params(&output[0]), [1, 31, 0, 44]);
+    assert_eq!(params(&output[1]), [0]);
+}
+
+#[test_case]
+fn test_ansi_ignored() {
+    // Private, unknown and cancelled sequences, and a character set designation
+    assert_eq!(
+        test_parse("\x1B[?25l\x1B[5n\x1B[3\x18x\x1B(Bz"),
+        [Output::Char('x'), Output::Char('z')]
+    );
+}
@@ -0,0 +1,296 @@
+//! The kernel command line, configuring the kernel at boot.
+//!
+//! The command line consists of whitespace separated options, which are either `name=value` pairs
+//! or flags given by `name` alone. Values containing whitespace can be enclosed in double quotes.
+//!
+//! Modules declare their options as typed `Param` statics, which are registered in `PARAMS`. Each
+//! option can be given at most once, and its value is fixed after `init()`; unset options keep
+//! their default. Unknown options and invalid values are reported as errors and otherwise ignored.
+//!
+//! NOTE: the bootloader does not pass a command line, so it is embedded at build time from the
+//! `YARHOS_CMDLINE` environment variable, e.g. `YARHOS_CMDLINE="loglevel=debug" cargo run`.
+
+use core::fmt;
+
+use spin::Once;
+
+use crate::{console, error, framebuffer, log, splash, time, vga_buffer};
+
+/// The command line embedded at build time.
+pub const DEFAULT: &str = match option_env!("YARHOS_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+/// The options of all modules.
+static PARAMS: &[&dyn BootParam] = &[
+    &console::CONSOLE,
+    &framebuffer::DISPLAY,
+    &log::LOG_LEVEL,
+    &splash::SPLASH,
+    &time::TIMER_HZ,
+    &vga_buffer::SCROLLBACK,
+    &crate::TEST_FILTER,
+];
+
+/// A type of option values.
+pub trait ParamValue: Copy + fmt::Display + Send + Sync + 'static {
+    /// Parses the value of an option, which is `None` for flags. Returns `None` if the value is
+    /// invalid.
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<bool> {
+        match value {
+            None | Some("1") | Some("on") | Some("yes") | Some("true") => Some(true),
+            Some("0") | Some("off") | Some("no") | Some("false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+impl ParamValue for u64 {
+    fn parse(value: Option<&'static str>) -> Option<u64> {
+        value?.parse().ok()
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<&'static str> {
+        value
+    }
+}
+
+/// An option of type `T`.
+pub struct Param<T> {
+    name: &'static str,
+    description: &'static str,
+    default: T,
+    /// Whether a parsed value is acceptable.
+    check: fn(T) -> bool,
+    value: Once<T>,
+}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(name: &'static str, description: &'static str, default: T) -> Param<T> {
+        Param::with_check(name, description, default, |_| true)
+    }
+
+    /// Creates an option restricted to values for which `check` returns `true`.
+    pub const fn with_check(
+        name: &'static str,
+        description: &'static str,
+        default: T,
+        check: fn(T) -> bool,
+    ) -> Param<T> {
+        Param {
+            name,
+            description,
+            default,
+            check,
+            value: Once::new(),
+        }
+    }
+
+    /// Returns the value of the option, or its default if it was not given.
+    pub fn get(&self) -> T {
+        self.value.get().copied().unwrap_or(self.default)
+    }
+}
+
+/// The reasons an option is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    /// No module has an option with the name.
+    Unknown(&'static str),
+    /// The value is not valid for the option, or missing.
+    InvalidValue {
+        name: &'static str,
+        value: Option<&'static str>,
+    },
+    /// The option was already given.
+    Duplicate(&'static str),
+    /// A quoted value is missing its closing quote.
+    UnterminatedQuote(&'static str),
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Unknown(name) => write!(f, "unknown option `{}`", name),
+            ParamError::InvalidValue { name, value: None } => {
+                write!(f, "option `{}` needs a value", name)
+            }
+            ParamError::InvalidValue {
+                name,
+                value: Some(value),
+            } => write!(f, "invalid value `{}` for option `{}`", value, name),
+            ParamError::Duplicate(name) => write!(f, "option `{}` given more than once", name),
+            ParamError::UnterminatedQuote(name) => {
+                write!(f, "unterminated quote in option `{}`", name)
+            }
+        }
+    }
+}
+
+/// A type erased `Param`, for registration in `PARAMS`.
+trait BootParam: Sync {
+    fn name(&self) -> &'static str;
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError>;
+    /// Writes `name=value (description)` of the effective value.
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl<T: ParamValue> BootParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError> {
+        let parsed = T::parse(value)
+            .filter(|&parsed| (self.check)(parsed))
+            .ok_or(ParamError::InvalidValue {
+                name: self.name,
+                value,
+            })?;
+        if self.value.get().is_some() {
+            return Err(ParamError::Duplicate(self.name));
+        }
+        self.value.call_once(|| parsed);
+        Ok(())
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={} ({})", self.name, self.get(), self.description)
+    }
+}
+
+/// Returns the options of `cmdline` as names and values, which are `None` for flags.
+fn options(
+    cmdline: &'static str,
+) -> impl Iterator<Item = Result<(&'static str, Option<&'static str>), ParamError>> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(|c: char| c.is_whitespace() || c == '=');
+        let name = &rest[..end.unwrap_or(rest.len())];
+        rest = &rest[name.len()..];
+        if !rest.starts_with('=') {
+            return Some(Ok((name, None)));
+        }
+        rest = &rest[1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => {
+                    rest = &quoted[end + 1..];
+                    &quoted[..end]
+                }
+                None => {
+                    rest = "";
+                    return Some(Err(ParamError::UnterminatedQuote(name)));
+                }
+            }
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        Some(Ok((name, Some(value))))
+    })
+}
+
+/// Sets the options given in `cmdline`, calling `report` for each rejected one.
+pub fn parse(cmdline: &'static str, mut report: impl FnMut(ParamError)) {
+    for option in options(cmdline) {
+        let result = option.and_then(|(name, value)| {
+            PARAMS
+                .iter()
+                .find(|param| param.name() == name)
+                .ok_or(ParamError::Unknown(name))?
+                .set(value)
+        });
+        if let Err(error) = result {
+            report(error);
+        }
+    }
+}
+
+/// Sets the options given in `cmdline` and logs rejected ones. Must be called once at boot, before
+/// the options are used.
+pub fn init(cmdline: &'static str) {
+    parse(cmdline, |error| error!("Kernel command line: {}", error));
+    for param in PARAMS {
+        crate::debug!("Boot option {}", Description(*param));
+    }
+}
+
+/// Formats the description of an option.
+struct Description(&'static dyn BootParam);
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.describe(f)
+    }
+}
+
+#[cfg(test)]
+fn test_options(cmdline: &'static str) -> alloc::vec::Vec<(&'static str, Option<&'static str>)> {
+    options(cmdline).map(Result::unwrap).collect()
+}
+
+#[test_case]
+fn test_cmdline_options() {
+    assert_eq!(test_options(""), []);
+    assert_eq!(
+        test_options("  quiet loglevel=debug\tfilter=\"a b\" empty= "),
+        [
+            ("quiet", None),
+            ("loglevel", Some("debug")),
+            ("filter", Some("a b")),
+            ("empty", Some("")),
+        ]
+    );
+    let mut unterminated = options("a=\"b c");
+    assert_eq!(
+        unterminated.next(),
+        Some(Err(ParamError::UnterminatedQuote("a")))
+    );
+    assert_eq!(unterminated.next(), None);
+}
+
+#[test_case]
+fn test_cmdline_params() {
+    static NUMBER: Param<u64> =
+        Param::with_check("number", "A number below 10", 3, |number| number < 10);
+    static FLAG: Param<bool> = Param::new("flag", "A flag", false);
+
+    assert_eq!(NUMBER.get(), 3);
+    assert_eq!(
+        NUMBER.set(Some("10")),
+        Err(ParamError::InvalidValue {
+            name: "number",
+            value: Some("10")
+        })
+    );
+    assert_eq!(
+        NUMBER.set(None),
+        Err(ParamError::InvalidValue {
+            name: "number",
+            value: None
+        })
+    );
+    assert_eq!(NUMBER.set(Some("7")), Ok(()));
+    assert_eq!(NUMBER.set(Some("8")), Err(ParamError::Duplicate("number")));
+    assert_eq!(NUMBER.get(), 7);
+    assert_eq!(FLAG.set(None), Ok(()));
+    assert!(FLAG.get());
+
+    let mut errors = alloc::vec::Vec::new();
+    parse("no_such_option=1", |error| errors.push(error));
+    assert_eq!(errors, [ParamError::Unknown("no_such_option")]);
+}
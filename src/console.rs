@@ -0,0 +1,130 @@
+//! Console output, and emergency console output for panic and fault handlers.
+//!
+//! The `print!` and `println!` macros write to the VGA text buffer, the serial interface, or both,
+//! as set with the `console` boot option.
+//!
+//! The regular `print!` and `serial_print!` macros spin on `WRITER` and `SERIAL0`, which hangs
+//! forever if the panicking code was holding either lock. The emergency path instead forcibly takes
+//! over both locks and prints to both the VGA text buffer and the serial interface, switching the
+//! screen to the first virtual console if needed.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cmdline::{Param, ParamValue},
+    serial::{self, SERIAL0},
+    sync::IrqGuard,
+    vga_buffer::{self, ControlCharMode, WRITER},
+};
+
+/// The devices `print!` writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Vga,
+    Serial,
+    Both,
+}
+
+impl fmt::Display for Console {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Console::Vga => "vga",
+            Console::Serial => "serial",
+            Console::Both => "both",
+        })
+    }
+}
+
+impl ParamValue for Console {
+    fn parse(value: Option<&'static str>) -> Option<Console> {
+        match value? {
+            "vga" => Some(Console::Vga),
+            "serial" => Some(Console::Serial),
+            "both" => Some(Console::Both),
+            _ => None,
+        }
+    }
+}
+
+/// The devices `print!` writes to.
+pub static CONSOLE: Param<Console> = Param::new(
+    "console",
+    "where the console is: vga, serial or both",
+    Console::Vga,
+);
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let console = CONSOLE.get();
+    if console != Console::Serial {
+        vga_buffer::_print(args);
+    }
+    if console != Console::Vga {
+        serial::_print(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _println(args: fmt::Arguments) {
+    let console = CONSOLE.get();
+    if console != Console::Serial {
+        vga_buffer::_println(args);
+    }
+    if console != Console::Vga {
+        serial::_print(format_args!("{}\n", args));
+    }
+}
+
+/// The nesting depth of emergency output, i.e. how many times printing has itself panicked.
+static EMERGENCY_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Prints to both the VGA text buffer and the serial interface, without ever deadlocking.
+///
+/// Any holder of `WRITER` or `SERIAL0` is assumed to never resume, which holds when the caller is
+/// about to halt the machine.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::console::_emergency_print(format_args!($($arg)*)));
+}
+
+/// Prints to both the VGA text buffer and the serial interface, appending a newline, without ever
+/// deadlocking.
+///
+/// See `emergency_print!`.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($fmt:expr) => ($crate::emergency_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::emergency_print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Returns whether emergency output is being printed, see `emergency_print!`.
+pub(crate) fn in_emergency() -> bool {
+    EMERGENCY_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: core::fmt::Arguments) {
+    let _irq = IrqGuard::new();
+    let depth = EMERGENCY_DEPTH.fetch_add(1, Ordering::Relaxed);
+
+    // A panic while printing to the screen skips the screen the next time around, and a panic
+    // while printing to serial gives up on output altogether.
+    if depth == 0 {
+        // SAFETY: the caller guarantees the holders never resume
+        unsafe { vga_buffer::force_switch_to_writer() };
+        // SAFETY: the caller guarantees the holder never resumes
+        let mut writer = unsafe { WRITER.force_lock() };
+        writer.set_control_mode(ControlCharMode::Control);
+        let _ = writer.write_fmt(args);
+    }
+    if depth <= 1 {
+        // SAFETY: the caller guarantees the holder never resumes
+        let _ = unsafe { SERIAL0.force_lock() }.write_fmt(args);
+    }
+
+    EMERGENCY_DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
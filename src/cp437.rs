@@ -0,0 +1,100 @@
+//! Translation between Unicode and code page 437[^cp437], the character set of the VGA text mode.
+//!
+//! The lower half of code page 437 is ASCII, except that the control characters and DEL have
+//! glyphs of their own. The upper half holds accented letters, box drawing characters, Greek
+//! letters and mathematical symbols.
+//!
+//! [^cp437]: https://en.wikipedia.org/wiki/Code_page_437
+
+/// The glyphs of the control characters `0x00..0x20`.
+const CONTROL_GLYPHS: [char; 0x20] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyph of DEL, `0x7F`.
+const DELETE_GLYPH: char = '⌂';
+
+/// The characters of the upper half, `0x80..=0xFF`.
+const UPPER_HALF: [char; 0x80] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Characters shown with the glyph of a similar looking one, e.g. Greek small letter beta with
+/// sharp s.
+pub const ALIASES: [(char, u8); 9] = [
+    ('β', 0xE1),
+    ('∏', 0xE3),
+    ('∑', 0xE4),
+    ('μ', 0xE6),
+    ('Ω', 0xEA),
+    ('ϕ', 0xED),
+    ('∅', 0xED),
+    ('⌀', 0xED),
+    ('∈', 0xEE),
+];
+
+/// Returns the character shown for `byte`.
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x00..=0x1F => CONTROL_GLYPHS[byte as usize],
+        0x7F => DELETE_GLYPH,
+        0x80..=0xFF => UPPER_HALF[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+/// Returns the byte showing `c`, or `None` if code page 437 lacks it.
+///
+/// ASCII characters, including the control characters, map to themselves. Some characters map to
+/// a glyph of a similar looking one, see `ALIASES`.
+pub fn from_char(c: char) -> Option<u8> {
+    match c {
+        c if c.is_ascii() => Some(c as u8),
+        DELETE_GLYPH => Some(0x7F),
+        _ => position(&CONTROL_GLYPHS, c)
+            .or_else(|| position(&UPPER_HALF, c).map(|index| index + 0x80))
+            .or_else(|| {
+                ALIASES
+                    .iter()
+                    .find(|&&(alias, _)| alias == c)
+                    .map(|&(_, byte)| byte)
+            }),
+    }
+}
+
+/// Returns the index of `c` in `characters` as a byte.
+fn position(characters: &[char], c: char) -> Option<u8> {
+    characters
+        .iter()
+        .position(|&character| character == c)
+        .map(|index| index as u8)
+}
+
+#[test_case]
+fn test_cp437_round_trip() {
+    for byte in 0..=0xFF {
+        assert_eq!(from_char(to_char(byte)), Some(byte));
+    }
+}
+
+#[test_case]
+fn test_cp437_from_char() {
+    assert_eq!(from_char('A'), Some(b'A'));
+    assert_eq!(from_char('\n'), Some(b'\n'));
+    assert_eq!(from_char('☺'), Some(0x01));
+    assert_eq!(from_char('ö'), Some(0x94));
+    assert_eq!(from_char('╬'), Some(0xCE));
+    assert_eq!(from_char('π'), Some(0xE3));
+    assert_eq!(from_char('β'), from_char('ß'));
+    assert_eq!(from_char('μ'), from_char('µ'));
+    assert_eq!(from_char('€'), None);
+    assert_eq!(from_char('Д'), None);
+}
@@ -0,0 +1,248 @@
+//! Parsing of ELF64 executables.
+//!
+//! Only what is needed to load statically linked x86_64 executables is supported: the file header
+//! and the program headers. All fields are read with bounds checks, so arbitrary bytes can be
+//! parsed safely.
+
+use core::convert::TryInto;
+
+/// The size of the ELF64 file header.
+const FILE_HEADER_SIZE: usize = 64;
+/// The size of an ELF64 program header.
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+const MAGIC: [u8; 4] = *b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+/// The type of a loadable segment.
+pub const PT_LOAD: u32 = 1;
+
+/// Segment permission: executable.
+pub const PF_X: u32 = 1;
+/// Segment permission: writable.
+pub const PF_W: u32 = 2;
+/// Segment permission: readable.
+pub const PF_R: u32 = 4;
+
+/// The reasons an ELF file can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file is too small to contain the structure being read.
+    Truncated,
+    /// The file does not start with the ELF magic number.
+    BadMagic,
+    /// The file is not a 64-bit, little endian, current version ELF file.
+    UnsupportedFormat,
+    /// The file is not an executable.
+    NotExecutable,
+    /// The file is not for x86_64.
+    WrongMachine,
+    /// The program header table is malformed.
+    BadProgramHeaders,
+}
+
+/// A parsed ELF64 executable.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_header_offset: usize,
+    program_header_count: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Parses and validates the file header of `data`, and checks that the program header table
+    /// lies within it.
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if data.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN || data[6] != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16)? != TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18)? != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let entry = read_u64(data, 24)?;
+        let program_header_offset = to_usize(read_u64(data, 32)?)?;
+        let program_header_size = usize::from(read_u16(data, 54)?);
+        let program_header_count = usize::from(read_u16(data, 56)?);
+        if program_header_count > 0 && program_header_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_end = program_header_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(program_header_offset))
+            .ok_or(ElfError::BadProgramHeaders)?;
+        if table_end > data.len() {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        Ok(ElfFile {
+            data,
+            entry,
+            program_header_offset,
+            program_header_count,
+        })
+    }
+
+    /// Returns the raw bytes of the file.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the virtual address of the entry point.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns the file offset of the program header table.
+    pub fn program_header_offset(&self) -> usize {
+        self.program_header_offset
+    }
+
+    /// Returns the number of program headers.
+    pub fn program_header_count(&self) -> usize {
+        self.program_header_count
+    }
+
+    /// Returns an iterator over the program headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.program_header_offset;
+        (0..self.program_header_count).map(move |index| {
+            let start = offset + index * PROGRAM_HEADER_SIZE;
+            // NOTE: the table was checked to be within the file by `parse()`
+            ProgramHeader::parse(&data[start..start + PROGRAM_HEADER_SIZE])
+        })
+    }
+
+    /// Returns the file contents of the segment described by `header`.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = to_usize(header.offset)?;
+        let end = to_usize(header.file_size)?
+            .checked_add(start)
+            .ok_or(ElfError::BadProgramHeaders)?;
+        self.data.get(start..end).ok_or(ElfError::BadProgramHeaders)
+    }
+}
+
+/// An ELF64 program header, describing a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Parses a program header from exactly `PROGRAM_HEADER_SIZE` bytes.
+    fn parse(data: &[u8]) -> ProgramHeader {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        ProgramHeader {
+            kind: u32_at(0),
+            flags: u32_at(4),
+            offset: u64_at(8),
+            virtual_address: u64_at(16),
+            file_size: u64_at(32),
+            memory_size: u64_at(40),
+            align: u64_at(48),
+        }
+    }
+
+    /// Returns whether the segment is to be loaded into memory.
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ElfError::Truncated)
+}
+
+fn to_usize(value: u64) -> Result<usize, ElfError> {
+    value.try_into().map_err(|_| ElfError::BadProgramHeaders)
+}
+
+#[cfg(test)]
+static TEST_PROGRAM: &[u8] = include_bytes!("../tests/programs/data.elf");
+
+#[cfg(test)]
+fn patched_test_program(offset: usize, bytes: &[u8]) -> [u8; FILE_HEADER_SIZE] {
+    let mut header = [0; FILE_HEADER_SIZE];
+    header.copy_from_slice(&TEST_PROGRAM[..FILE_HEADER_SIZE]);
+    header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    header
+}
+
+#[test_case]
+fn test_parse_executable() {
+    let file = ElfFile::parse(TEST_PROGRAM).expect("Parsing test program failed");
+    assert!(file.entry() >= crate::memory::USER_SPACE_START);
+    let mut loadable = 0;
+    for header in file.program_headers().filter(ProgramHeader::is_load) {
+        loadable += 1;
+        assert!(header.file_size <= header.memory_size);
+        assert!(file.segment_data(&header).is_ok());
+    }
+    assert!(loadable >= 2);
+    assert!(file
+        .program_headers()
+        .any(|header| header.is_load() && header.flags == PF_R | PF_X));
+}
+
+#[test_case]
+fn test_parse_rejects_invalid_headers() {
+    assert_eq!(
+        ElfFile::parse(&TEST_PROGRAM[..FILE_HEADER_SIZE - 1]).unwrap_err(),
+        ElfError::Truncated
+    );
+    let cases: [(usize, &[u8], ElfError); 6] = [
+        (0, b"\x7FELV", ElfError::BadMagic),
+        (4, &[1], ElfError::UnsupportedFormat),
+        (5, &[2], ElfError::UnsupportedFormat),
+        (16, &[3, 0], ElfError::NotExecutable),
+        (18, &[0x28, 0], ElfError::WrongMachine),
+        (54, &[32, 0], ElfError::BadProgramHeaders),
+    ];
+    for (offset, bytes, error) in cases.iter() {
+        let header = patched_test_program(*offset, bytes);
+        assert_eq!(ElfFile::parse(&header).unwrap_err(), *error);
+    }
+}
+
+#[test_case]
+fn test_parse_rejects_truncated_program_headers() {
+    // The program header table lies outside of a file consisting of just the file header
+    let header = patched_test_program(0, &[]);
+    assert_eq!(
+        ElfFile::parse(&header).unwrap_err(),
+        ElfError::BadProgramHeaders
+    );
+}
@@ -0,0 +1,305 @@
+//! The framebuffer console: the character cells of a virtual console drawn on a framebuffer, see
+//! `vga_buffer::enable_framebuffer_console()`.
+//!
+//! The glyphs are those of the Noto Sans Mono font rasterized by `noto_sans_mono_bitmap`, blended
+//! between the foreground and background colors. The box drawing characters and block elements
+//! of code page 437, which the font lacks, are drawn as lines and rectangles.
+
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
+
+use crate::{
+    framebuffer::Rgb,
+    graphics::{blend, Canvas},
+};
+
+const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+const RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
+
+/// The width of a character cell in pixels.
+pub const CELL_WIDTH: usize = get_raster_width(FONT_WEIGHT, RASTER_HEIGHT);
+/// The height of a character cell in pixels.
+pub const CELL_HEIGHT: usize = RASTER_HEIGHT.val();
+
+/// The glyph shown for characters the font lacks.
+const BACKUP_CHAR: char = '\u{FFFD}';
+
+/// The height of the underline cursor in pixels.
+const UNDERLINE_HEIGHT: usize = 2;
+
+/// The colors of the VGA text mode, in the order of `vga_buffer::Color`.
+pub const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xAA),
+    Rgb::new(0x00, 0xAA, 0x00),
+    Rgb::new(0x00, 0xAA, 0xAA),
+    Rgb::new(0xAA, 0x00, 0x00),
+    Rgb::new(0xAA, 0x00, 0xAA),
+    Rgb::new(0xAA, 0x55, 0x00),
+    Rgb::new(0xAA, 0xAA, 0xAA),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xFF),
+    Rgb::new(0x55, 0xFF, 0x55),
+    Rgb::new(0x55, 0xFF, 0xFF),
+    Rgb::new(0xFF, 0x55, 0x55),
+    Rgb::new(0xFF, 0x55, 0xFF),
+    Rgb::new(0xFF, 0xFF, 0x55),
+    Rgb::new(0xFF, 0xFF, 0xFF),
+];
+
+/// Returns the number of columns and rows of character cells fitting on `canvas`.
+pub fn size(canvas: &impl Canvas) -> (usize, usize) {
+    (canvas.width() / CELL_WIDTH, canvas.height() / CELL_HEIGHT)
+}
+
+/// The line of an arm of a box drawing character, from the center to an edge of the cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    None,
+    Single,
+    Double,
+}
+
+/// Returns the lines of the up, right, down and left arms of box drawing character `c`, or
+/// `None` if it is no box drawing character of code page 437.
+fn box_lines(c: char) -> Option<[Line; 4]> {
+    use Line::{Double as D, None as N, Single as S};
+
+    Some(match c {
+        '─' => [N, S, N, S],
+        '│' => [S, N, S, N],
+        '┌' => [N, S, S, N],
+        '┐' => [N, N, S, S],
+        '└' => [S, S, N, N],
+        '┘' => [S, N, N, S],
+        '├' => [S, S, S, N],
+        '┤' => [S, N, S, S],
+        '┬' => [N, S, S, S],
+        '┴' => [S, S, N, S],
+        '┼' => [S, S, S, S],
+        '═' => [N, D, N, D],
+        '║' => [D, N, D, N],
+        '╒' => [N, D, S, N],
+        '╓' => [N, S, D, N],
+        '╔' => [N, D, D, N],
+        '╕' => [N, N, S, D],
+        '╖' => [N, N, D, S],
+        '╗' => [N, N, D, D],
+        '╘' => [S, D, N, N],
+        '╙' => [D, S, N, N],
+        '╚' => [D, D, N, N],
+        '╛' => [S, N, N, D],
+        '╜' => [D, N, N, S],
+        '╝' => [D, N, N, D],
+        '╞' => [S, D, S, N],
+        '╟' => [D, S, D, N],
+        '╠' => [D, D, D, N],
+        '╡' => [S, N, S, D],
+        '╢' => [D, N, D, S],
+        '╣' => [D, N, D, D],
+        '╤' => [N, D, S, D],
+        '╥' => [N, S, D, S],
+        '╦' => [N, D, D, D],
+        '╧' => [S, D, N, D],
+        '╨' => [D, S, N, S],
+        '╩' => [D, D, N, D],
+        '╪' => [S, D, S, D],
+        '╫' => [D, S, D, S],
+        '╬' => [D, D, D, D],
+        _ => return None,
+    })
+}
+
+/// Returns the rectangle of block element `c` within a cell, as its left, top, right and bottom
+/// edges in eighths of the cell, and the intensity of its foreground out of 255, or `None` if `c`
+/// is no block element of code page 437.
+fn block(c: char) -> Option<([usize; 4], u8)> {
+    Some(match c {
+        '█' => ([0, 0, 8, 8], 255),
+        '▀' => ([0, 0, 8, 4], 255),
+        '▄' => ([0, 4, 8, 8], 255),
+        '▌' => ([0, 0, 4, 8], 255),
+        '▐' => ([4, 0, 8, 8], 255),
+        '■' => ([2, 3, 6, 6], 255),
+        '░' => ([0, 0, 8, 8], 64),
+        '▒' => ([0, 0, 8, 8], 128),
+        '▓' => ([0, 0, 8, 8], 192),
+        _ => return None,
+    })
+}
+
+/// Draws the arms of a box drawing character in the cell at `x` and `y`. Double lines are two
+/// single lines on either side of the center.
+fn draw_box(canvas: &mut impl Canvas, (x, y): (usize, usize), lines: [Line; 4], color: Rgb) {
+    let (center_x, center_y) = (CELL_WIDTH / 2, CELL_HEIGHT / 2);
+    let [up, right, down, left] = lines;
+    // The offsets of the lines from the center, and how far past it they reach
+    let offsets = |line: Line| match line {
+        Line::None => &[][..],
+        Line::Single => &[0][..],
+        Line::Double => &[-2, 2][..],
+    };
+    let reach = |across: Line| if across == Line::Double { 2 } else { 0 };
+    let vertical_reach = reach(left).max(reach(right));
+    let horizontal_reach = reach(up).max(reach(down));
+    for &offset in offsets(up) {
+        let column = (center_x as isize + offset) as usize;
+        canvas.fill_rect(x + column, y, 1, center_y + vertical_reach + 1, color);
+    }
+    for &offset in offsets(down) {
+        let column = (center_x as isize + offset) as usize;
+        let top = center_y - vertical_reach;
+        canvas.fill_rect(x + column, y + top, 1, CELL_HEIGHT - top, color);
+    }
+    for &offset in offsets(left) {
+        let row = (center_y as isize + offset) as usize;
+        canvas.fill_rect(x, y + row, center_x + horizontal_reach + 1, 1, color);
+    }
+    for &offset in offsets(right) {
+        let row = (center_y as isize + offset) as usize;
+        let left_edge = center_x - horizontal_reach;
+        canvas.fill_rect(x + left_edge, y + row, CELL_WIDTH - left_edge, 1, color);
+    }
+}
+
+/// Draws character `c` in a cell with the top left corner at `x` and `y` of `canvas`.
+pub fn draw_char(
+    canvas: &mut impl Canvas,
+    x: usize,
+    y: usize,
+    c: char,
+    foreground: Rgb,
+    background: Rgb,
+) {
+    canvas.fill_rect(x, y, CELL_WIDTH, CELL_HEIGHT, background);
+    if let Some(lines) = box_lines(c) {
+        draw_box(canvas, (x, y), lines, foreground);
+    } else if let Some(([left, top, right, bottom], intensity)) = block(c) {
+        let color = blend(foreground, background, intensity);
+        let (left, right) = (left * CELL_WIDTH / 8, right * CELL_WIDTH / 8);
+        let (top, bottom) = (top * CELL_HEIGHT / 8, bottom * CELL_HEIGHT / 8);
+        canvas.fill_rect(x + left, y + top, right - left, bottom - top, color);
+    } else {
+        let raster = get_raster(c, FONT_WEIGHT, RASTER_HEIGHT)
+            .or_else(|| get_raster(BACKUP_CHAR, FONT_WEIGHT, RASTER_HEIGHT))
+            .expect("Backup character missing from the font");
+        for (dy, raster_row) in raster.raster().iter().enumerate() {
+            for (dx, &intensity) in raster_row.iter().enumerate() {
+                if intensity != 0 {
+                    let color = blend(foreground, background, intensity);
+                    canvas.set_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+    }
+}
+
+/// Draws `text` on a single line of cells with the top left corner at `x` and `y` of `canvas`.
+pub fn draw_text(
+    canvas: &mut impl Canvas,
+    x: usize,
+    y: usize,
+    text: &str,
+    foreground: Rgb,
+    background: Rgb,
+) {
+    for (index, c) in text.chars().enumerate() {
+        draw_char(canvas, x + index * CELL_WIDTH, y, c, foreground, background);
+    }
+}
+
+/// Draws character `c` in the cell at `row` and `column` of `canvas`.
+pub fn draw_cell(
+    canvas: &mut impl Canvas,
+    row: usize,
+    column: usize,
+    c: char,
+    foreground: Rgb,
+    background: Rgb,
+) {
+    let (x, y) = (column * CELL_WIDTH, row * CELL_HEIGHT);
+    draw_char(canvas, x, y, c, foreground, background);
+}
+
+/// Draws an underline cursor over the cell at `row` and `column` of `canvas`.
+pub fn draw_underline(canvas: &mut impl Canvas, row: usize, column: usize, color: Rgb) {
+    let (x, y) = (
+        column * CELL_WIDTH,
+        (row + 1) * CELL_HEIGHT - UNDERLINE_HEIGHT,
+    );
+    canvas.fill_rect(x, y, CELL_WIDTH, UNDERLINE_HEIGHT, color);
+}
+
+#[cfg(test)]
+use crate::framebuffer::{test_framebuffer, PixelFormat};
+
+/// Returns the number of pixels of `color` in the cell at `row` and `column` of `canvas`.
+#[cfg(test)]
+pub(crate) fn count_pixels(canvas: &impl Canvas, row: usize, column: usize, color: Rgb) -> usize {
+    let (x, y) = (column * CELL_WIDTH, row * CELL_HEIGHT);
+    (y..y + CELL_HEIGHT)
+        .flat_map(|y| (x..x + CELL_WIDTH).map(move |x| (x, y)))
+        .filter(|&(x, y)| canvas.pixel(x, y) == Some(color))
+        .count()
+}
+
+#[test_case]
+fn test_draw_glyph() {
+    let (foreground, background) = (PALETTE[15], PALETTE[1]);
+    let mut framebuffer = test_framebuffer(3 * CELL_WIDTH, 2 * CELL_HEIGHT, PixelFormat::Bgr);
+    assert_eq!(size(&framebuffer), (3, 2));
+    draw_cell(&mut framebuffer, 1, 2, 'A', foreground, background);
+    assert!(count_pixels(&framebuffer, 1, 2, foreground) > 0);
+    assert!(count_pixels(&framebuffer, 1, 2, background) > CELL_WIDTH * CELL_HEIGHT / 2);
+    assert_eq!(
+        count_pixels(&framebuffer, 0, 2, Rgb::BLACK),
+        CELL_WIDTH * CELL_HEIGHT
+    );
+    draw_cell(&mut framebuffer, 0, 0, ' ', foreground, background);
+    assert_eq!(
+        count_pixels(&framebuffer, 0, 0, background),
+        CELL_WIDTH * CELL_HEIGHT
+    );
+    // Missing from the font
+    draw_cell(&mut framebuffer, 0, 1, 'Д', foreground, background);
+    assert!(count_pixels(&framebuffer, 0, 1, foreground) > 0);
+    draw_text(
+        &mut framebuffer,
+        0,
+        CELL_HEIGHT,
+        "  ",
+        foreground,
+        background,
+    );
+    assert_eq!(
+        count_pixels(&framebuffer, 1, 1, background),
+        CELL_WIDTH * CELL_HEIGHT
+    );
+}
+
+#[test_case]
+fn test_draw_box_and_blocks() {
+    let (foreground, background) = (PALETTE[14], PALETTE[0]);
+    let mut framebuffer = test_framebuffer(4 * CELL_WIDTH, CELL_HEIGHT, PixelFormat::Rgb);
+    draw_cell(&mut framebuffer, 0, 0, '─', foreground, background);
+    assert_eq!(count_pixels(&framebuffer, 0, 0, foreground), CELL_WIDTH);
+    assert_eq!(framebuffer.pixel(0, CELL_HEIGHT / 2), Some(foreground));
+    draw_cell(&mut framebuffer, 0, 1, '╬', foreground, background);
+    assert_eq!(
+        framebuffer.pixel(CELL_WIDTH + CELL_WIDTH / 2, 0),
+        Some(background)
+    );
+    assert_eq!(
+        framebuffer.pixel(CELL_WIDTH + CELL_WIDTH / 2 - 2, 0),
+        Some(foreground)
+    );
+    draw_cell(&mut framebuffer, 0, 2, '▄', foreground, background);
+    assert_eq!(
+        count_pixels(&framebuffer, 0, 2, foreground),
+        CELL_WIDTH * CELL_HEIGHT / 2
+    );
+    draw_underline(&mut framebuffer, 0, 3, foreground);
+    assert_eq!(
+        count_pixels(&framebuffer, 0, 3, foreground),
+        CELL_WIDTH * UNDERLINE_HEIGHT
+    );
+}
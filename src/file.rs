@@ -0,0 +1,97 @@
+//! Open files of processes.
+
+use alloc::vec::Vec;
+
+/// A file descriptor, i.e. an index into a `FileTable`.
+pub type FileDescriptor = usize;
+
+/// The standard input file descriptor.
+pub const STDIN: FileDescriptor = 0;
+/// The standard output file descriptor.
+pub const STDOUT: FileDescriptor = 1;
+/// The standard error file descriptor.
+pub const STDERR: FileDescriptor = 2;
+
+/// An open file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// The kernel console.
+    Console,
+}
+
+/// The open files of a process, indexed by file descriptor.
+#[derive(Debug, Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    /// Creates a table without open files.
+    pub fn new() -> FileTable {
+        FileTable::default()
+    }
+
+    /// Creates a table with the console open as standard input, output and error.
+    pub fn with_console() -> FileTable {
+        FileTable {
+            files: alloc::vec![Some(File::Console); 3],
+        }
+    }
+
+    /// Returns the file open as `descriptor`.
+    pub fn get(&self, descriptor: FileDescriptor) -> Option<File> {
+        self.files.get(descriptor).copied().flatten()
+    }
+
+    /// Stores `file` at the lowest free file descriptor, and returns it.
+    pub fn open(&mut self, file: File) -> FileDescriptor {
+        match self.files.iter().position(Option::is_none) {
+            Some(descriptor) => {
+                self.files[descriptor] = Some(file);
+                descriptor
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Removes the file open as `descriptor`, and returns it.
+    pub fn close(&mut self, descriptor: FileDescriptor) -> Option<File> {
+        let file = self.files.get_mut(descriptor)?.take();
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        file
+    }
+
+    /// Closes all files.
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+
+    /// Returns the number of open files.
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    /// Returns whether there are no open files.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test_case]
+fn test_file_table_reuses_lowest_descriptor() {
+    let mut table = FileTable::with_console();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.get(STDOUT), Some(File::Console));
+    assert_eq!(table.close(STDIN), Some(File::Console));
+    assert_eq!(table.get(STDIN), None);
+    assert_eq!(table.open(File::Console), STDIN);
+    assert_eq!(table.open(File::Console), 3);
+    assert_eq!(table.close(7), None);
+    table.close_all();
+    assert!(table.is_empty());
+}
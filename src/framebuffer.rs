@@ -0,0 +1,355 @@
+//! Linear framebuffers: the one the bootloader sets up, or one of the Bochs VBE extensions of the
+//! QEMU and Bochs display adapters (QEMU `-vga std`).
+//!
+//! Framebuffer only boots, e.g. UEFI, lack the VGA text mode, so the virtual consoles are drawn on
+//! the framebuffer instead, see `fb_console`. Which framebuffer to use is chosen with the `display`
+//! boot option.
+//!
+//! Framebuffers are drawn on through the `graphics::Canvas` trait.
+
+use core::fmt;
+
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    cmdline::{Param, ParamValue},
+    graphics::Canvas,
+    memory, pci,
+    sync::IrqSpinlock,
+    vga_buffer,
+};
+
+/// The Bochs VBE extensions, which override the VGA registers while enabled.
+const BOCHS_VBE_INDEX_PORT: u16 = 0x1CE;
+const BOCHS_VBE_DATA_PORT: u16 = 0x1CF;
+const BOCHS_VBE_ID: u16 = 0x0;
+const BOCHS_VBE_XRES: u16 = 0x1;
+const BOCHS_VBE_YRES: u16 = 0x2;
+const BOCHS_VBE_BPP: u16 = 0x3;
+const BOCHS_VBE_ENABLE: u16 = 0x4;
+const BOCHS_VBE_VIRT_WIDTH: u16 = 0x6;
+/// The flags of the enable register enabling the extensions, with the linear framebuffer.
+const BOCHS_VBE_ENABLED: u16 = 0x01;
+const BOCHS_VBE_LFB_ENABLED: u16 = 0x40;
+
+/// The PCI vendor and device IDs of the QEMU and Bochs display adapter, whose first base address
+/// register holds the linear framebuffer.
+const BOCHS_VGA_VENDOR_ID: u16 = 0x1234;
+const BOCHS_VGA_DEVICE_ID: u16 = 0x1111;
+
+/// The resolution set with `display=vbe`, fitting 80 by 30 characters of the framebuffer console.
+const VBE_WIDTH: u16 = 720;
+const VBE_HEIGHT: u16 = 480;
+
+/// A 24 bit color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xFF, 0xFF, 0xFF);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+/// The byte order of the color components of a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Red, green, then blue.
+    Rgb,
+    /// Blue, green, then red.
+    Bgr,
+    /// A single gray level.
+    Gray,
+}
+
+/// A linear framebuffer: rows of pixels of `bytes_per_pixel` bytes each, starting every `stride`
+/// pixels.
+#[derive(Debug)]
+pub struct FrameBuffer {
+    buffer: &'static mut [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    bytes_per_pixel: usize,
+    format: PixelFormat,
+}
+
+impl FrameBuffer {
+    /// Returns the framebuffer of `width` by `height` pixels in `buffer`.
+    ///
+    /// Panics if `buffer` is too small, or the pixels too small for `format`.
+    pub fn new(
+        buffer: &'static mut [u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        bytes_per_pixel: usize,
+        format: PixelFormat,
+    ) -> FrameBuffer {
+        let components = if format == PixelFormat::Gray { 1 } else { 3 };
+        assert!(stride >= width && (components..=4).contains(&bytes_per_pixel));
+        assert!(buffer.len() >= stride * height * bytes_per_pixel);
+        FrameBuffer {
+            buffer,
+            width,
+            height,
+            stride,
+            bytes_per_pixel,
+            format,
+        }
+    }
+
+    /// Returns the framebuffer set up by the bootloader, or `None` if its pixel format is
+    /// unsupported.
+    pub fn from_boot_info(framebuffer: bootloader_api::info::FrameBuffer) -> Option<FrameBuffer> {
+        use bootloader_api::info::PixelFormat as BootPixelFormat;
+
+        let info = framebuffer.info();
+        let format = match info.pixel_format {
+            BootPixelFormat::Rgb => PixelFormat::Rgb,
+            BootPixelFormat::Bgr => PixelFormat::Bgr,
+            BootPixelFormat::U8 => PixelFormat::Gray,
+            _ => return None,
+        };
+        Some(FrameBuffer::new(
+            framebuffer.into_buffer(),
+            info.width,
+            info.height,
+            info.stride,
+            info.bytes_per_pixel,
+            format,
+        ))
+    }
+
+    /// Returns the offset of the first byte of the pixel at `x` and `y` in the buffer, or `None`
+    /// outside of the framebuffer.
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then(|| (y * self.stride + x) * self.bytes_per_pixel)
+    }
+
+    /// Returns the bytes of a pixel of `color`.
+    fn encode(&self, color: Rgb) -> [u8; 4] {
+        match self.format {
+            PixelFormat::Rgb => [color.r, color.g, color.b, 0],
+            PixelFormat::Bgr => [color.b, color.g, color.r, 0],
+            PixelFormat::Gray => {
+                // NOTE: the ITU-R BT.601 luma weights, in 1/256ths
+                let luma =
+                    (77 * u32::from(color.r) + 150 * u32::from(color.g) + 29 * u32::from(color.b))
+                        >> 8;
+                [luma as u8, 0, 0, 0]
+            }
+        }
+    }
+}
+
+impl Canvas for FrameBuffer {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if let Some(offset) = self.offset(x, y) {
+            let bytes = self.encode(color);
+            self.buffer[offset..offset + self.bytes_per_pixel]
+                .copy_from_slice(&bytes[..self.bytes_per_pixel]);
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        let offset = self.offset(x, y)?;
+        let bytes = &self.buffer[offset..offset + self.bytes_per_pixel];
+        Some(match self.format {
+            PixelFormat::Rgb => Rgb::new(bytes[0], bytes[1], bytes[2]),
+            PixelFormat::Bgr => Rgb::new(bytes[2], bytes[1], bytes[0]),
+            PixelFormat::Gray => Rgb::new(bytes[0], bytes[0], bytes[0]),
+        })
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let bytes = self.encode(color);
+        let bytes = &bytes[..self.bytes_per_pixel];
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        for y in y.min(y_end)..y_end {
+            let start = (y * self.stride + x.min(x_end)) * self.bytes_per_pixel;
+            let end = (y * self.stride + x_end) * self.bytes_per_pixel;
+            for pixel in self.buffer[start..end].chunks_exact_mut(self.bytes_per_pixel) {
+                pixel.copy_from_slice(bytes);
+            }
+        }
+    }
+}
+
+/// Where the framebuffer console gets its framebuffer from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Display {
+    /// The framebuffer set up by the bootloader.
+    Framebuffer,
+    /// A Bochs VBE mode of `VBE_WIDTH` by `VBE_HEIGHT` pixels.
+    Vbe,
+    /// No framebuffer console, leaving the screen as the bootloader left it.
+    Off,
+}
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Display::Framebuffer => "framebuffer",
+            Display::Vbe => "vbe",
+            Display::Off => "off",
+        })
+    }
+}
+
+impl ParamValue for Display {
+    fn parse(value: Option<&'static str>) -> Option<Display> {
+        match value? {
+            "framebuffer" => Some(Display::Framebuffer),
+            "vbe" => Some(Display::Vbe),
+            "off" => Some(Display::Off),
+            _ => None,
+        }
+    }
+}
+
+/// The framebuffer the virtual consoles are drawn on.
+pub static DISPLAY: Param<Display> = Param::new(
+    "display",
+    "framebuffer console: framebuffer (of the bootloader), vbe or off",
+    Display::Framebuffer,
+);
+
+/// The framebuffer of the display, if any.
+pub static FRAMEBUFFER: IrqSpinlock<Option<FrameBuffer>> =
+    IrqSpinlock::with_name("FRAMEBUFFER", None);
+
+/// Sets up the framebuffer chosen with the `display` boot option, given the one of the bootloader,
+/// and shows the virtual consoles on it. Must be called after `memory::init()`.
+pub fn init(boot_framebuffer: Option<bootloader_api::info::FrameBuffer>) {
+    let framebuffer = match DISPLAY.get() {
+        Display::Framebuffer => boot_framebuffer.and_then(FrameBuffer::from_boot_info),
+        Display::Vbe => set_bochs_vbe_mode(VBE_WIDTH, VBE_HEIGHT),
+        Display::Off => None,
+    };
+    if framebuffer.is_some() {
+        *FRAMEBUFFER.lock() = framebuffer;
+        vga_buffer::enable_framebuffer_console();
+    }
+}
+
+fn bochs_vbe_read(index: u16) -> u16 {
+    // SAFETY: reading the Bochs VBE registers has no side effects, and without them the read
+    // returns all ones
+    unsafe {
+        Port::<u16>::new(BOCHS_VBE_INDEX_PORT).write(index);
+        Port::<u16>::new(BOCHS_VBE_DATA_PORT).read()
+    }
+}
+
+/// Writes a Bochs VBE register. Must only be called if `bochs_vbe_present()`.
+fn bochs_vbe_write(index: u16, value: u16) {
+    // SAFETY: the Bochs VBE registers only affect the display
+    unsafe {
+        Port::<u16>::new(BOCHS_VBE_INDEX_PORT).write(index);
+        Port::<u16>::new(BOCHS_VBE_DATA_PORT).write(value);
+    }
+}
+
+/// Returns whether the Bochs VBE extensions are present.
+fn bochs_vbe_present() -> bool {
+    (0xB0C0..=0xB0C5).contains(&bochs_vbe_read(BOCHS_VBE_ID))
+}
+
+/// Disables the Bochs VBE graphics mode, if present and enabled, as set by the bootloader.
+pub(crate) fn disable_bochs_vbe() {
+    if bochs_vbe_present() {
+        bochs_vbe_write(BOCHS_VBE_ENABLE, 0);
+    }
+}
+
+/// Sets a 32 bit per pixel Bochs VBE mode of `width` by `height` pixels, returning its
+/// framebuffer, or `None` without the Bochs VBE extensions.
+///
+/// Takes the framebuffer away from any previous `FrameBuffer` of the adapter, so it must only be
+/// called once, when there is no other.
+fn set_bochs_vbe_mode(width: u16, height: u16) -> Option<FrameBuffer> {
+    if !bochs_vbe_present() {
+        return None;
+    }
+    let adapter = pci::find(BOCHS_VGA_VENDOR_ID, BOCHS_VGA_DEVICE_ID)?;
+    let address = PhysAddr::new(u64::from(adapter.config_read(pci::BAR0) & !0xF));
+
+    bochs_vbe_write(BOCHS_VBE_ENABLE, 0);
+    bochs_vbe_write(BOCHS_VBE_XRES, width);
+    bochs_vbe_write(BOCHS_VBE_YRES, height);
+    bochs_vbe_write(BOCHS_VBE_BPP, 32);
+    bochs_vbe_write(BOCHS_VBE_VIRT_WIDTH, width);
+    bochs_vbe_write(BOCHS_VBE_ENABLE, BOCHS_VBE_ENABLED | BOCHS_VBE_LFB_ENABLED);
+
+    let (width, height) = (usize::from(width), usize::from(height));
+    let start = memory::phys_to_virt(address).as_mut_ptr();
+    // SAFETY: the bootloader maps the first 4 GiB of physical memory, which the PCI memory lies in,
+    // and the caller guarantees no other reference to the framebuffer
+    let buffer = unsafe { core::slice::from_raw_parts_mut(start, width * height * 4) };
+    Some(FrameBuffer::new(
+        buffer,
+        width,
+        height,
+        width,
+        4,
+        PixelFormat::Bgr,
+    ))
+}
+
+/// Returns a framebuffer of `width` by `height` pixels in leaked heap memory.
+#[cfg(test)]
+pub(crate) fn test_framebuffer(width: usize, height: usize, format: PixelFormat) -> FrameBuffer {
+    let bytes_per_pixel = if format == PixelFormat::Gray { 1 } else { 4 };
+    let buffer = alloc::vec![0; width * height * bytes_per_pixel].leak();
+    FrameBuffer::new(buffer, width, height, width, bytes_per_pixel, format)
+}
+
+#[test_case]
+fn test_pixel_formats() {
+    let color = Rgb::new(0x12, 0x34, 0x56);
+    for format in [PixelFormat::Rgb, PixelFormat::Bgr] {
+        let mut framebuffer = test_framebuffer(4, 3, format);
+        framebuffer.set_pixel(3, 2, color);
+        assert_eq!(framebuffer.pixel(3, 2), Some(color));
+        assert_eq!(framebuffer.pixel(2, 2), Some(Rgb::BLACK));
+        assert_eq!(framebuffer.pixel(4, 2), None);
+    }
+    let bgr = test_framebuffer(1, 1, PixelFormat::Bgr);
+    assert_eq!(bgr.encode(color), [0x56, 0x34, 0x12, 0]);
+    let mut gray = test_framebuffer(2, 2, PixelFormat::Gray);
+    gray.set_pixel(1, 1, Rgb::WHITE);
+    assert_eq!(gray.pixel(1, 1), Some(Rgb::new(0xFF, 0xFF, 0xFF)));
+}
+
+#[test_case]
+fn test_fill_rect() {
+    let color = Rgb::new(0xAA, 0xBB, 0xCC);
+    let mut framebuffer = test_framebuffer(8, 6, PixelFormat::Rgb);
+    framebuffer.fill_rect(6, 4, 10, 10, color);
+    for y in 0..6 {
+        for x in 0..8 {
+            let expected = if x >= 6 && y >= 4 { color } else { Rgb::BLACK };
+            assert_eq!(framebuffer.pixel(x, y), Some(expected));
+        }
+    }
+    framebuffer.fill_rect(9, 0, 1, 1, color);
+    framebuffer.clear(Rgb::WHITE);
+    assert_eq!(framebuffer.pixel(7, 5), Some(Rgb::WHITE));
+}
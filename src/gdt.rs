@@ -10,18 +10,32 @@ use x86_64::{
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// The size of the stack used when entering the kernel from user mode.
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        // NOTE: tss.privilege_stack_table required for user mode
+        // Stack for interrupts and traps from user mode (ring 3 to ring 0 transitions)
+        tss.privilege_stack_table[0] = {
+            // NOTE: this stack has no guard page below it
+            // TODO: memory allocation
+            // NOTE: `mut` needed in order for the bootloader to map the stack to a writable page
+            static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+            // NOTE: only the address is taken, the stack is never accessed through a reference
+            let stack_start = VirtAddr::from_ptr(core::ptr::addr_of_mut!(STACK));
+            #[allow(clippy::let_and_return)]
+            let stack_end = stack_start + PRIVILEGE_STACK_SIZE;
+            stack_end
+        };
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             // NOTE: this stack has no guard page below it
             const STACK_SIZE: usize = 4096 * 5;
             // TODO: memory allocation
             // NOTE: `mut` needed in order for the bootloader to map the stach to a writable page
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            // SAFETY: compiler can't guarantee race freedom for `static mut` accesses
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            // NOTE: only the address is taken, the stack is never accessed through a reference
+            let stack_start = VirtAddr::from_ptr(core::ptr::addr_of_mut!(STACK));
             #[allow(clippy::let_and_return)]
             let stack_end = stack_start + STACK_SIZE;
             stack_end
@@ -30,40 +44,66 @@ lazy_static! {
     };
 }
 
+/// The segment selectors of the `GDT`.
+///
+/// NOTE: the order of the kernel and user segments is dictated by the `SYSCALL`/`SYSRET`
+/// instructions: kernel data must follow kernel code, and user code must follow user data.
 #[derive(Debug, Clone, Copy)]
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
         (
             gdt,
             Selectors {
-                code_selector,
-                tss_selector,
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
             },
         )
     };
 }
 
+/// Returns the segment selectors of the `GDT`.
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Returns the top of the stack the CPU switches to when entering the kernel from user mode.
+pub fn privilege_stack_top() -> VirtAddr {
+    TSS.privilege_stack_table[0]
+}
+
 pub fn init() {
     let (
         gdt,
         Selectors {
-            code_selector,
-            tss_selector,
+            kernel_code,
+            kernel_data,
+            tss,
+            ..
         },
     ) = (&GDT.0, GDT.1);
 
     gdt.load();
     // SAFETY: selectors created using the x86_64 crate are valid
     unsafe {
-        x86_64::instructions::segmentation::CS::set_reg(code_selector);
-        x86_64::instructions::tables::load_tss(tss_selector);
+        x86_64::instructions::segmentation::CS::set_reg(kernel_code);
+        x86_64::instructions::segmentation::SS::set_reg(kernel_data);
+        x86_64::instructions::tables::load_tss(tss);
     }
 }
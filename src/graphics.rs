@@ -0,0 +1,611 @@
+//! Drawing on framebuffers and other canvases: pixels, lines, rectangles, circles and images,
+//! clipped and optionally blended, see `Painter`, and back buffers presenting only the areas
+//! changed since the last time, see `BackBuffer`.
+//!
+//! Coordinates are in pixels from the top left corner, and may lie outside of the canvas, which
+//! clips the parts drawn there.
+
+use alloc::vec::Vec;
+
+use crate::framebuffer::Rgb;
+
+/// The number of separate changed areas a back buffer keeps track of, beyond which they are merged
+/// into one.
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// A rectangle of pixels, from `left` and `top` up to but excluding `right` and `bottom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub left: isize,
+    pub top: isize,
+    pub right: isize,
+    pub bottom: isize,
+}
+
+impl Rect {
+    /// Returns the rectangle of `width` by `height` pixels with the top left corner at `x` and `y`.
+    pub const fn new(x: isize, y: isize, width: usize, height: usize) -> Rect {
+        Rect {
+            left: x,
+            top: y,
+            right: x + width as isize,
+            bottom: y + height as isize,
+        }
+    }
+
+    pub fn width(self) -> usize {
+        (self.right - self.left).max(0) as usize
+    }
+
+    pub fn height(self) -> usize {
+        (self.bottom - self.top).max(0) as usize
+    }
+
+    /// Returns whether the rectangle holds no pixels.
+    pub fn is_empty(self) -> bool {
+        self.left >= self.right || self.top >= self.bottom
+    }
+
+    /// Returns whether the pixel at `x` and `y` lies within the rectangle.
+    pub fn contains_point(self, x: isize, y: isize) -> bool {
+        (self.left..self.right).contains(&x) && (self.top..self.bottom).contains(&y)
+    }
+
+    /// Returns whether all pixels of `other` lie within the rectangle.
+    pub fn contains(self, other: Rect) -> bool {
+        other.is_empty()
+            || (self.left <= other.left
+                && self.top <= other.top
+                && other.right <= self.right
+                && other.bottom <= self.bottom)
+    }
+
+    /// Returns the pixels within both rectangles, which may be empty.
+    pub fn intersection(self, other: Rect) -> Rect {
+        Rect {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        }
+    }
+
+    /// Returns the smallest rectangle containing both rectangles.
+    pub fn union(self, other: Rect) -> Rect {
+        if self.is_empty() {
+            return other;
+        }
+        if other.is_empty() {
+            return self;
+        }
+        Rect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    /// Returns whether the rectangles overlap or share an edge or a corner.
+    pub fn touches(self, other: Rect) -> bool {
+        self.left <= other.right
+            && other.left <= self.right
+            && self.top <= other.bottom
+            && other.top <= self.bottom
+    }
+}
+
+/// Returns `background` blended towards `foreground` by `alpha` out of 255.
+pub fn blend(foreground: Rgb, background: Rgb, alpha: u8) -> Rgb {
+    let mix = |foreground: u8, background: u8| {
+        let (foreground, background) = (u32::from(foreground), u32::from(background));
+        let alpha = u32::from(alpha);
+        ((foreground * alpha + background * (255 - alpha)) / 255) as u8
+    };
+    Rgb::new(
+        mix(foreground.r, background.r),
+        mix(foreground.g, background.g),
+        mix(foreground.b, background.b),
+    )
+}
+
+/// Returns the product of two alpha values out of 255.
+fn multiply_alpha(a: u8, b: u8) -> u8 {
+    ((u32::from(a) * u32::from(b) + 127) / 255) as u8
+}
+
+/// Pixels to draw on, e.g. a `FrameBuffer` or a `BackBuffer`.
+pub trait Canvas {
+    /// Returns the width in pixels.
+    fn width(&self) -> usize;
+
+    /// Returns the height in pixels.
+    fn height(&self) -> usize;
+
+    /// Sets the pixel at `x` and `y` to `color`. Does nothing outside of the canvas.
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb);
+
+    /// Returns the color of the pixel at `x` and `y`, or `None` outside of the canvas.
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb>;
+
+    /// Fills the rectangle of `width` by `height` pixels at `x` and `y` with `color`, clipped to
+    /// the canvas.
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        for y in y..y_end {
+            for x in x..x_end {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Fills the whole canvas with `color`.
+    fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.width(), self.height(), color);
+    }
+
+    /// Returns the rectangle of the whole canvas.
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width(), self.height())
+    }
+}
+
+/// An image of `width` by `height` pixels, row by row, optionally with an alpha value per pixel.
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    width: usize,
+    height: usize,
+    pixels: &'a [Rgb],
+    alpha: Option<&'a [u8]>,
+}
+
+impl<'a> Image<'a> {
+    /// Returns the opaque image of `width` by `height` `pixels`.
+    ///
+    /// Panics if `pixels` is not `width` times `height` long.
+    pub fn new(width: usize, height: usize, pixels: &'a [Rgb]) -> Image<'a> {
+        assert_eq!(pixels.len(), width * height);
+        Image {
+            width,
+            height,
+            pixels,
+            alpha: None,
+        }
+    }
+
+    /// Returns the image with an `alpha` value out of 255 for each pixel.
+    ///
+    /// Panics if `alpha` is not as long as the pixels.
+    pub fn with_alpha(self, alpha: &'a [u8]) -> Image<'a> {
+        assert_eq!(alpha.len(), self.pixels.len());
+        Image {
+            alpha: Some(alpha),
+            ..self
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the color and the alpha value of the pixel at `x` and `y`, which must lie within the
+    /// image.
+    fn pixel(&self, x: usize, y: usize) -> (Rgb, u8) {
+        let index = y * self.width + x;
+        let alpha = self.alpha.map_or(u8::MAX, |alpha| alpha[index]);
+        (self.pixels[index], alpha)
+    }
+}
+
+/// Draws on a canvas, clipped to a rectangle of it, and blended with what is there by a constant
+/// alpha value.
+pub struct Painter<'a, C: Canvas + ?Sized> {
+    canvas: &'a mut C,
+    clip: Rect,
+    alpha: u8,
+}
+
+impl<'a, C: Canvas + ?Sized> Painter<'a, C> {
+    /// Returns a painter drawing opaquely on all of `canvas`.
+    pub fn new(canvas: &'a mut C) -> Painter<'a, C> {
+        let clip = canvas.bounds();
+        Painter {
+            canvas,
+            clip,
+            alpha: u8::MAX,
+        }
+    }
+
+    /// Restricts drawing to the part of `clip` within the canvas.
+    pub fn set_clip(&mut self, clip: Rect) {
+        self.clip = clip.intersection(self.canvas.bounds());
+    }
+
+    /// Returns the rectangle drawing is restricted to.
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Sets how opaque everything drawn is, out of 255.
+    pub fn set_alpha(&mut self, alpha: u8) {
+        self.alpha = alpha;
+    }
+
+    /// Draws the pixel at `x` and `y` with `color` at `alpha`, combined with the alpha of the
+    /// painter, if within the clip rectangle.
+    fn plot(&mut self, x: isize, y: isize, color: Rgb, alpha: u8) {
+        if !self.clip.contains_point(x, y) {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        match multiply_alpha(self.alpha, alpha) {
+            0 => {}
+            u8::MAX => self.canvas.set_pixel(x, y, color),
+            alpha => {
+                if let Some(background) = self.canvas.pixel(x, y) {
+                    self.canvas.set_pixel(x, y, blend(color, background, alpha));
+                }
+            }
+        }
+    }
+
+    /// Draws the pixel at `x` and `y` with `color`.
+    pub fn pixel(&mut self, x: isize, y: isize, color: Rgb) {
+        self.plot(x, y, color, u8::MAX);
+    }
+
+    /// Draws a line from `x0` and `y0` to `x1` and `y1`, both included, with `color`.
+    pub fn line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: Rgb) {
+        // NOTE: Bresenham's line algorithm, for all octants
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        loop {
+            self.plot(x, y, color, u8::MAX);
+            if (x, y) == (x1, y1) {
+                break;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Fills `rect` with `color`.
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let rect = rect.intersection(self.clip);
+        if rect.is_empty() {
+            return;
+        }
+        if self.alpha == u8::MAX {
+            let (x, y) = (rect.left as usize, rect.top as usize);
+            self.canvas
+                .fill_rect(x, y, rect.width(), rect.height(), color);
+            return;
+        }
+        for y in rect.top..rect.bottom {
+            for x in rect.left..rect.right {
+                self.plot(x, y, color, u8::MAX);
+            }
+        }
+    }
+
+    /// Draws the one pixel wide outline of `rect` with `color`.
+    pub fn rect(&mut self, rect: Rect, color: Rgb) {
+        if rect.is_empty() {
+            return;
+        }
+        let (width, height) = (rect.width(), rect.height());
+        self.fill_rect(Rect::new(rect.left, rect.top, width, 1), color);
+        if height > 1 {
+            self.fill_rect(Rect::new(rect.left, rect.bottom - 1, width, 1), color);
+        }
+        let sides = height.saturating_sub(2);
+        self.fill_rect(Rect::new(rect.left, rect.top + 1, 1, sides), color);
+        if width > 1 {
+            self.fill_rect(Rect::new(rect.right - 1, rect.top + 1, 1, sides), color);
+        }
+    }
+
+    /// Draws the one pixel wide outline of the circle of `radius` around `x` and `y` with `color`.
+    pub fn circle(&mut self, x: isize, y: isize, radius: usize, color: Rgb) {
+        // NOTE: the midpoint circle algorithm, mirroring an eighth of the circle
+        let (mut dx, mut dy) = (0, radius as isize);
+        let mut error = 1 - dy;
+        while dx <= dy {
+            let points = [
+                (dx, dy),
+                (dy, dx),
+                (-dx, dy),
+                (-dy, dx),
+                (dx, -dy),
+                (dy, -dx),
+                (-dx, -dy),
+                (-dy, -dx),
+            ];
+            // Each pixel is drawn once, which matters when blending
+            for (index, &(px, py)) in points.iter().enumerate() {
+                if !points[..index].contains(&(px, py)) {
+                    self.plot(x + px, y + py, color, u8::MAX);
+                }
+            }
+            dx += 1;
+            if error < 0 {
+                error += 2 * dx + 1;
+            } else {
+                dy -= 1;
+                error += 2 * (dx - dy) + 1;
+            }
+        }
+    }
+
+    /// Fills the circle of `radius` around `x` and `y` with `color`.
+    pub fn fill_circle(&mut self, x: isize, y: isize, radius: usize, color: Rgb) {
+        let radius = radius as isize;
+        // NOTE: the half widths of the rows are rounded like the outline of `circle()`
+        let mut half_width = radius;
+        for dy in 0..=radius {
+            while half_width * half_width + dy * dy > radius * radius + radius {
+                half_width -= 1;
+            }
+            let width = (2 * half_width + 1) as usize;
+            self.fill_rect(Rect::new(x - half_width, y + dy, width, 1), color);
+            if dy != 0 {
+                self.fill_rect(Rect::new(x - half_width, y - dy, width, 1), color);
+            }
+        }
+    }
+
+    /// Draws `image` with the top left corner at `x` and `y`, blended by its alpha values.
+    pub fn blit(&mut self, x: isize, y: isize, image: &Image) {
+        let area = Rect::new(x, y, image.width, image.height).intersection(self.clip);
+        for py in area.top..area.bottom {
+            for px in area.left..area.right {
+                let (color, alpha) = image.pixel((px - x) as usize, (py - y) as usize);
+                self.plot(px, py, color, alpha);
+            }
+        }
+    }
+}
+
+/// An off-screen canvas, which keeps track of the areas changed since it was last presented.
+///
+/// Drawing a scene on a back buffer and then presenting it avoids showing it half drawn, and
+/// copies only the pixels that changed: pixels set to the color they already have do not count
+/// as changed.
+#[derive(Debug)]
+pub struct BackBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+    /// The disjoint areas changed since the last `present()`.
+    dirty: Vec<Rect>,
+}
+
+impl BackBuffer {
+    /// Returns a back buffer of `width` by `height` black pixels, all of which are to be presented.
+    pub fn new(width: usize, height: usize) -> BackBuffer {
+        let mut dirty = Vec::with_capacity(MAX_DIRTY_RECTS);
+        dirty.push(Rect::new(0, 0, width, height));
+        BackBuffer {
+            width,
+            height,
+            pixels: alloc::vec![Rgb::BLACK; width * height],
+            dirty,
+        }
+    }
+
+    /// Returns the areas changed since the last `present()`.
+    pub fn dirty_rects(&self) -> &[Rect] {
+        &self.dirty
+    }
+
+    /// Marks `area` as changed, merging it with the changed areas it touches.
+    pub fn invalidate(&mut self, area: Rect) {
+        let mut area = area.intersection(self.bounds());
+        if area.is_empty() || self.dirty.iter().any(|dirty| dirty.contains(area)) {
+            return;
+        }
+        // NOTE: merging touching areas keeps e.g. the pixels of a line in one rectangle
+        while let Some(index) = self.dirty.iter().position(|dirty| dirty.touches(area)) {
+            area = area.union(self.dirty.swap_remove(index));
+        }
+        if self.dirty.len() == MAX_DIRTY_RECTS {
+            area = self.dirty.drain(..).fold(area, Rect::union);
+        }
+        self.dirty.push(area);
+    }
+
+    /// Copies the areas changed since the last time to `target`, with the top left corner of the
+    /// back buffer at `x` and `y`, clipped to `target`.
+    pub fn present(&mut self, target: &mut (impl Canvas + ?Sized), x: usize, y: usize) {
+        for area in self.dirty.drain(..) {
+            for row in area.top as usize..area.bottom as usize {
+                for column in area.left as usize..area.right as usize {
+                    let color = self.pixels[row * self.width + column];
+                    target.set_pixel(x + column, y + row, color);
+                }
+            }
+        }
+    }
+
+    /// Returns the pixels as an image, e.g. to draw on another canvas.
+    pub fn image(&self) -> Image<'_> {
+        Image::new(self.width, self.height, &self.pixels)
+    }
+}
+
+impl Canvas for BackBuffer {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height && self.pixels[y * self.width + x] != color {
+            self.pixels[y * self.width + x] = color;
+            self.invalidate(Rect::new(x as isize, y as isize, 1, 1));
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        (x < self.width && y < self.height).then(|| self.pixels[y * self.width + x])
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        let mut changed = Rect::new(0, 0, 0, 0);
+        for y in y..y_end {
+            for x in x..x_end {
+                let pixel = &mut self.pixels[y * self.width + x];
+                if *pixel != color {
+                    *pixel = color;
+                    changed = changed.union(Rect::new(x as isize, y as isize, 1, 1));
+                }
+            }
+        }
+        self.invalidate(changed);
+    }
+}
+
+#[cfg(test)]
+use crate::framebuffer::{test_framebuffer, PixelFormat};
+
+/// Returns the number of pixels of `color` on `canvas`.
+#[cfg(test)]
+fn count_color(canvas: &impl Canvas, color: Rgb) -> usize {
+    (0..canvas.height())
+        .flat_map(|y| (0..canvas.width()).map(move |x| (x, y)))
+        .filter(|&(x, y)| canvas.pixel(x, y) == Some(color))
+        .count()
+}
+
+#[test_case]
+fn test_rect() {
+    let a = Rect::new(-2, 1, 4, 3);
+    assert_eq!((a.width(), a.height()), (4, 3));
+    assert!(a.contains_point(-2, 3) && !a.contains_point(2, 3));
+    let b = Rect::new(2, 0, 2, 2);
+    assert!(a.intersection(b).is_empty());
+    assert!(a.touches(b));
+    assert_eq!(a.union(b), Rect::new(-2, 0, 6, 4));
+    assert!(a.union(b).contains(a) && !a.contains(b));
+    assert_eq!(a.union(Rect::new(9, 9, 0, 5)), a);
+    assert!(!Rect::new(0, 0, 1, 1).touches(Rect::new(2, 0, 1, 1)));
+}
+
+#[test_case]
+fn test_lines_and_rects() {
+    let (red, green) = (Rgb::new(0xFF, 0, 0), Rgb::new(0, 0xFF, 0));
+    let mut framebuffer = test_framebuffer(8, 6, PixelFormat::Rgb);
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.line(-3, -3, 10, 10, red);
+    painter.line(7, 0, 0, 0, green);
+    painter.line(2, 5, 2, 5, green);
+    assert_eq!(framebuffer.pixel(5, 5), Some(red));
+    assert_eq!(framebuffer.pixel(4, 0), Some(green));
+    assert_eq!(framebuffer.pixel(2, 5), Some(green));
+    assert_eq!(count_color(&framebuffer, red), 5);
+
+    let mut framebuffer = test_framebuffer(8, 6, PixelFormat::Bgr);
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.rect(Rect::new(1, 1, 4, 3), red);
+    painter.set_clip(Rect::new(-5, 4, 20, 20));
+    painter.fill_rect(Rect::new(0, 0, 100, 100), green);
+    assert_eq!(count_color(&framebuffer, red), 10);
+    assert_eq!(framebuffer.pixel(2, 2), Some(Rgb::BLACK));
+    assert_eq!(count_color(&framebuffer, green), 16);
+    assert_eq!(framebuffer.pixel(0, 3), Some(Rgb::BLACK));
+}
+
+#[test_case]
+fn test_circles() {
+    let color = Rgb::WHITE;
+    let mut framebuffer = test_framebuffer(11, 11, PixelFormat::Rgb);
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.circle(5, 5, 4, color);
+    for &(x, y) in [(5, 1), (9, 5), (5, 9), (1, 5)].iter() {
+        assert_eq!(framebuffer.pixel(x, y), Some(color));
+    }
+    assert_eq!(framebuffer.pixel(5, 5), Some(Rgb::BLACK));
+    assert_eq!(framebuffer.pixel(3, 3), Some(Rgb::BLACK));
+    let outline = count_color(&framebuffer, color);
+
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.fill_circle(5, 5, 4, color);
+    assert_eq!(framebuffer.pixel(5, 5), Some(color));
+    assert_eq!(framebuffer.pixel(1, 1), Some(Rgb::BLACK));
+    assert!(count_color(&framebuffer, color) > outline);
+    // Blending draws each pixel of the outline once
+    let mut framebuffer = test_framebuffer(11, 11, PixelFormat::Rgb);
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.set_alpha(128);
+    painter.circle(5, 5, 4, color);
+    assert_eq!(count_color(&framebuffer, Rgb::new(128, 128, 128)), outline);
+}
+
+#[test_case]
+fn test_blit_and_alpha() {
+    let (red, blue) = (Rgb::new(0xFF, 0, 0), Rgb::new(0, 0, 0xFF));
+    let pixels = [red, red, blue, blue];
+    let alpha = [255, 0, 255, 51];
+    let image = Image::new(2, 2, &pixels).with_alpha(&alpha);
+    let mut framebuffer = test_framebuffer(3, 3, PixelFormat::Rgb);
+    framebuffer.clear(Rgb::WHITE);
+    let mut painter = Painter::new(&mut framebuffer);
+    painter.blit(1, 1, &image);
+    painter.blit(-1, -1, &image);
+    assert_eq!(framebuffer.pixel(1, 1), Some(red));
+    assert_eq!(framebuffer.pixel(2, 1), Some(Rgb::WHITE));
+    assert_eq!(framebuffer.pixel(1, 2), Some(blue));
+    assert_eq!(framebuffer.pixel(2, 2), Some(Rgb::new(204, 204, 0xFF)));
+    assert_eq!(framebuffer.pixel(0, 0), Some(blend(blue, Rgb::WHITE, 51)));
+    assert_eq!(blend(red, blue, 0), blue);
+}
+
+#[test_case]
+fn test_back_buffer() {
+    let color = Rgb::new(0x12, 0x34, 0x56);
+    let mut back = BackBuffer::new(16, 8);
+    let mut framebuffer = test_framebuffer(20, 10, PixelFormat::Bgr);
+    assert_eq!(back.dirty_rects(), [Rect::new(0, 0, 16, 8)]);
+    back.present(&mut framebuffer, 0, 0);
+    assert!(back.dirty_rects().is_empty());
+
+    let mut painter = Painter::new(&mut back);
+    painter.line(1, 1, 4, 4, color);
+    painter.fill_rect(Rect::new(10, 2, 3, 3), color);
+    painter.fill_rect(Rect::new(10, 2, 3, 3), color);
+    painter.pixel(0, 7, Rgb::BLACK);
+    assert_eq!(
+        back.dirty_rects(),
+        [Rect::new(1, 1, 4, 4), Rect::new(10, 2, 3, 3)]
+    );
+    framebuffer.set_pixel(14, 6, Rgb::WHITE);
+    back.present(&mut framebuffer, 2, 1);
+    assert_eq!(framebuffer.pixel(6, 5), Some(color));
+    assert_eq!(framebuffer.pixel(14, 5), Some(color));
+    assert_eq!(framebuffer.pixel(14, 6), Some(Rgb::WHITE));
+    assert_eq!(count_color(&framebuffer, color), 4 + 9);
+
+    for index in 0..MAX_DIRTY_RECTS + 1 {
+        back.set_pixel(index % 8 * 2, index / 8 * 2, Rgb::WHITE);
+    }
+    assert_eq!(back.dirty_rects(), [Rect::new(0, 0, 15, 5)]);
+    let image = back.image();
+    assert_eq!(image.pixel(3, 3), (color, u8::MAX));
+}
@@ -1,9 +1,22 @@
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::Page,
+    },
+    PrivilegeLevel, VirtAddr,
+};
 
-use crate::{gdt, print, println};
+use crate::{
+    address_space, gdt, memory, pc_speaker, print, println, process, sync::Spinlock, time,
+    usermode, vga_buffer, vma::Protection, workqueue,
+};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -30,8 +43,38 @@ impl InterruptIndex {
 }
 
 // SAFETY: PIC offsets are valid
-static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+static PICS: Spinlock<ChainedPics> = Spinlock::with_name("PICS", unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
+
+/// The nesting depth of hardware interrupt handlers currently executing.
+static IRQ_NESTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the caller is running inside a hardware interrupt handler.
+pub fn in_interrupt() -> bool {
+    IRQ_NESTING.load(Ordering::Relaxed) > 0
+}
+
+/// A RAII guard marking the execution of a hardware interrupt handler.
+///
+/// Every hardware interrupt handler should hold one for its whole duration.
+pub struct IrqContext {
+    // Prevent construction outside of `IrqContext::enter()`
+    _private: (),
+}
+
+impl IrqContext {
+    pub fn enter() -> IrqContext {
+        IRQ_NESTING.fetch_add(1, Ordering::Relaxed);
+        IrqContext { _private: () }
+    }
+}
+
+impl Drop for IrqContext {
+    fn drop(&mut self) {
+        IRQ_NESTING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 pub fn init_pics() {
     // SAFETY: PIC configuration is sound
@@ -49,8 +92,30 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        // Exceptions user code can cause go through entry stubs, which hand faults of user code
+        // to `usermode`
+        // SAFETY: the stubs jump to the handler of the exception or to the user fault entry point
+        unsafe {
+            idt.divide_error.set_handler_addr(fault_entry_address(__yarhos_divide_error));
+            idt.invalid_opcode.set_handler_addr(fault_entry_address(__yarhos_invalid_opcode));
+            idt.general_protection_fault
+                .set_handler_addr(fault_entry_address(__yarhos_general_protection));
+            idt.page_fault.set_handler_addr(fault_entry_address(__yarhos_page_fault));
+            idt.x87_floating_point
+                .set_handler_addr(fault_entry_address(__yarhos_x87_floating_point));
+            idt.simd_floating_point
+                .set_handler_addr(fault_entry_address(__yarhos_simd_floating_point));
+        }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // The fallback gate for system calls
+        // SAFETY: the user trap entry point expects to be entered from ring 3 through an interrupt
+        // gate
+        unsafe {
+            idt[usize::from(usermode::TRAP_VECTOR)]
+                .set_handler_addr(usermode::trap_entry_address())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
@@ -59,6 +124,72 @@ pub fn init_idt() {
     IDT.load();
 }
 
+// Entry stubs of the exceptions user code can cause. Each pushes the exception vector on top of
+// the error code, pushing a zero error code first if the CPU does not push one. Exceptions from
+// user mode, identified by the privilege level of the saved code segment, continue at
+// `__yarhos_user_fault` with this layout. Kernel mode exceptions drop what the stub pushed and
+// jump to the handler as if it had been invoked by the CPU.
+macro_rules! fault_entry {
+    ($name:literal, $vector:literal, $handler:ident) => {
+        global_asm!(
+            concat!(".global ", $name),
+            concat!($name, ":"),
+            "push 0",
+            concat!("push ", $vector),
+            "test byte ptr [rsp + 24], 3",
+            "jnz __yarhos_user_fault",
+            "add rsp, 16",
+            "jmp {handler}",
+            handler = sym $handler,
+        );
+    };
+    ($name:literal, $vector:literal, $handler:ident, error_code) => {
+        global_asm!(
+            concat!(".global ", $name),
+            concat!($name, ":"),
+            concat!("push ", $vector),
+            "test byte ptr [rsp + 24], 3",
+            "jnz __yarhos_user_fault",
+            "add rsp, 8",
+            "jmp {handler}",
+            handler = sym $handler,
+        );
+    };
+}
+
+fault_entry!("__yarhos_divide_error", 0, divide_error_handler);
+fault_entry!("__yarhos_invalid_opcode", 6, invalid_opcode_handler);
+fault_entry!(
+    "__yarhos_general_protection",
+    13,
+    general_protection_fault_handler,
+    error_code
+);
+fault_entry!("__yarhos_page_fault", 14, page_fault_handler, error_code);
+fault_entry!(
+    "__yarhos_x87_floating_point",
+    16,
+    x87_floating_point_handler
+);
+fault_entry!(
+    "__yarhos_simd_floating_point",
+    19,
+    simd_floating_point_handler
+);
+
+extern "C" {
+    fn __yarhos_divide_error();
+    fn __yarhos_invalid_opcode();
+    fn __yarhos_general_protection();
+    fn __yarhos_page_fault();
+    fn __yarhos_x87_floating_point();
+    fn __yarhos_simd_floating_point();
+}
+
+fn fault_entry_address(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::from_ptr(entry as *const ())
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -71,8 +202,86 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    // Faults of the kernel accessing user memory on behalf of user code
+    let address = Cr2::read();
+    if resolve_page_fault(address, error_code) {
+        return;
+    }
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed address: {:?}\nError code: {:?}\n{:#?}",
+        address, error_code, stack_frame
+    );
+}
+
+/// Resolves a page fault at `address` which does not mean an error: a write to a copy-on-write
+/// page, or the first access to a page mapped on demand. Returns whether the access can be retried.
+pub(crate) fn resolve_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Writes to copy-on-write pages, by user code or by the kernel on its behalf
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::is_user_range(address, 1)
+    {
+        // SAFETY: mappers are not used across anything that could write to user memory
+        let mut mapper = unsafe { memory::active_mapper() };
+        if address_space::copy_on_write(&mut mapper, Page::containing_address(address)) {
+            return true;
+        }
+    }
+    // First accesses to memory areas whose pages are mapped on demand
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Protection::WRITE
+        } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Protection::EXECUTE
+        } else {
+            Protection::READ
+        };
+        return if memory::is_user_range(address, 1) {
+            process::handle_page_fault(address, access)
+        } else {
+            memory::handle_lazy_fault(address)
+        };
+    }
+    false
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\nError code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+    let _irq = IrqContext::enter();
+    time::tick();
+    // NOTE: a full queue just means a missed tick mark; the drop is counted in its statistics
+    let _ = workqueue::IRQ_WORK.enqueue(timer_tick_work, 0);
     // SAFETY: correct interrupt index
     unsafe {
         PICS.lock()
@@ -80,35 +289,85 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+/// Deferred processing of a timer interrupt.
+fn timer_tick_work(_: usize) {
+    pc_speaker::tick();
+    vga_buffer::tick();
+    print!(".");
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
 
-    static KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        pc_keyboard::HandleControl::Ignore,
-    ));
-
-    let mut keyboard = KEYBOARD.lock();
+    let _irq = IrqContext::enter();
     let mut port = Port::new(0x60);
 
     // SAFETY: reading a byte from the PS/2 data port in keyboard interrupt has no ill side effects
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+    // NOTE: a full queue loses the scancode; the drop is counted in its statistics
+    let _ = workqueue::IRQ_WORK.enqueue(keyboard_scancode_work, usize::from(scancode));
+
+    // SAFETY: correct interrupt index
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+}
+
+/// Deferred processing of a scancode read in the keyboard interrupt handler.
+///
+/// Shift+PageUp and Shift+PageDown scroll the VGA scrollback history by half a screen, and Alt+F1
+/// to Alt+F6 switch between the virtual consoles.
+fn keyboard_scancode_work(scancode: usize) {
+    use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
+
+    // Whether the shift and alt keys are down, which `Keyboard` does not tell
+    static LEFT_SHIFT: AtomicBool = AtomicBool::new(false);
+    static RIGHT_SHIFT: AtomicBool = AtomicBool::new(false);
+    static LEFT_ALT: AtomicBool = AtomicBool::new(false);
+    static RIGHT_ALT: AtomicBool = AtomicBool::new(false);
+
+    static KEYBOARD: Spinlock<Keyboard<layouts::Us104Key, ScancodeSet1>> = Spinlock::with_name(
+        "KEYBOARD",
+        Keyboard::new(
+            ScancodeSet1::new(),
+            layouts::Us104Key,
+            pc_keyboard::HandleControl::Ignore,
+        ),
+    );
+
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode as u8) {
+        let down = key_event.state == KeyState::Down;
+        match key_event.code {
+            KeyCode::LShift => LEFT_SHIFT.store(down, Ordering::Relaxed),
+            KeyCode::RShift => RIGHT_SHIFT.store(down, Ordering::Relaxed),
+            KeyCode::LAlt => LEFT_ALT.store(down, Ordering::Relaxed),
+            KeyCode::RAltGr => RIGHT_ALT.store(down, Ordering::Relaxed),
+            _ => {}
+        }
+        let shifted = LEFT_SHIFT.load(Ordering::Relaxed) || RIGHT_SHIFT.load(Ordering::Relaxed);
+        let alt = LEFT_ALT.load(Ordering::Relaxed) || RIGHT_ALT.load(Ordering::Relaxed);
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
+                // Half of the screen at a time
+                DecodedKey::RawKey(KeyCode::PageUp) if shifted => {
+                    vga_buffer::scroll_back(vga_buffer::active_height() / 2)
+                }
+                DecodedKey::RawKey(KeyCode::PageDown) if shifted => {
+                    vga_buffer::scroll_forward(vga_buffer::active_height() / 2)
+                }
+                DecodedKey::RawKey(KeyCode::F1) if alt => vga_buffer::switch_console(0),
+                DecodedKey::RawKey(KeyCode::F2) if alt => vga_buffer::switch_console(1),
+                DecodedKey::RawKey(KeyCode::F3) if alt => vga_buffer::switch_console(2),
+                DecodedKey::RawKey(KeyCode::F4) if alt => vga_buffer::switch_console(3),
+                DecodedKey::RawKey(KeyCode::F5) if alt => vga_buffer::switch_console(4),
+                DecodedKey::RawKey(KeyCode::F6) if alt => vga_buffer::switch_console(5),
                 DecodedKey::Unicode(character) => print!("{}", character),
                 DecodedKey::RawKey(raw_key) => print!("{:?}", raw_key),
             }
         }
     }
-
-    // SAFETY: correct interrupt index
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
 
 #[test_case]
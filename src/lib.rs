@@ -5,17 +5,67 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+pub mod address_space;
+pub mod allocator;
+pub mod ansi;
+pub mod cmdline;
+pub mod console;
+pub mod cp437;
+pub mod elf;
+pub mod fb_console;
+pub mod file;
+pub mod framebuffer;
 pub mod gdt;
+pub mod graphics;
 pub mod interrupts;
+pub mod loader;
+pub mod lockdep;
+pub mod log;
+pub mod memory;
+pub mod pc_speaker;
+pub mod pci;
+pub mod process;
+pub mod psf;
 pub mod serial;
+pub mod signal;
+pub mod splash;
+pub mod sync;
+pub mod syscall;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
+pub mod vga_font;
+pub mod vga_mode;
+pub mod vma;
+pub mod workqueue;
 
 use core::panic::PanicInfo;
 
+use bootloader_api::{config::Mapping, BootloaderConfig};
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader_api::{entry_point, BootInfo};
+
+/// The bootloader configuration of the kernel, which all kernel executables including the tests
+/// must pass to `entry_point!`.
+///
+/// Everything the bootloader maps lies in the higher half, below the fixed kernel regions starting
+/// at `memory::LAZY_START`, keeping the lower half free for user space.
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(memory::PHYSICAL_MEMORY_OFFSET));
+    config.mappings.kernel_stack = Mapping::FixedAddress(0xFFFF_FF80_0000_0000);
+    config.mappings.boot_info = Mapping::FixedAddress(0xFFFF_FFFF_8000_0000);
+    // The frame buffer and anything else the bootloader places itself
+    config.mappings.dynamic_range_start = Some(0xFFFF_C000_0000_0000);
+    config.mappings.dynamic_range_end = Some(memory::LAZY_START - 1);
+    config.kernel_stack_size = 512 * memory::PAGE_SIZE;
+    config
+};
 
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self);
 }
 
@@ -23,8 +73,12 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        serial_print!("{}...\t", self.name());
         self();
         serial_println!("[ok]");
     }
@@ -49,11 +103,47 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
-/// Conserve energy by halting the CPU.
+/// Runs the kernel worker if there is deferred work, and otherwise halts the CPU until the next
+/// interrupt.
+pub fn idle() {
+    use x86_64::instructions::interrupts;
+
+    workqueue::run_worker();
+    // NOTE: checking for work with interrupts disabled and atomically re-enabling them with HLT
+    // makes sure that work enqueued in between wakes us up
+    interrupts::disable();
+    if workqueue::has_pending() {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Run the kernel worker whenever there is deferred work, and otherwise conserve energy by halting
+/// the CPU.
 pub fn halt_loop() -> ! {
     loop {
-        x86_64::instructions::interrupts::enable_and_hlt();
+        idle();
+    }
+}
+
+/// Powers off the machine, or hangs if that fails.
+///
+/// NOTE: uses the ACPI power management ports of QEMU and Bochs until there is ACPI support
+pub fn shutdown() -> ! {
+    use x86_64::instructions::port::Port;
+
+    /// `SLP_TYP` soft off with `SLP_EN` in the PM1a control register.
+    const SOFT_OFF: u16 = 0x2000;
+    // SAFETY: writing the PM1a control register only powers off the machine, and the ports are
+    // unused otherwise
+    unsafe {
+        // QEMU with the PIIX4 or ICH9 power management controller
+        Port::new(0x604).write(SOFT_OFF);
+        // Bochs and older QEMU
+        Port::new(0xB004).write(SOFT_OFF);
     }
+    hang();
 }
 
 /// Hang by disabling interrupts and invoking the HLT instruction.
@@ -62,27 +152,48 @@ pub fn hang() -> ! {
     unreachable!("Unexpected wakeup with interrupts disabled");
 }
 
+/// Only run the tests whose names contain this.
+pub static TEST_FILTER: cmdline::Param<&'static str> =
+    cmdline::Param::new("test_filter", "only run tests whose names contain this", "");
+
 pub fn init() {
+    cmdline::init(cmdline::DEFAULT);
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
     interrupts::init_pics();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
 #[cfg(test)]
-entry_point!(_test_start);
+entry_point!(_test_start, config = &BOOTLOADER_CONFIG);
 
 #[cfg(test)]
 #[no_mangle]
-pub fn _test_start(_boot_info: &'static BootInfo) -> ! {
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
     init();
+    let boot_framebuffer = boot_info.framebuffer.take();
+    memory::init(boot_info);
+    framebuffer::init(boot_framebuffer);
     test_main();
     halt_loop();
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    let filter = TEST_FILTER.get();
+    let selected = tests.iter().filter(|test| test.name().contains(filter));
+    let count = selected.clone().count();
+    if count == tests.len() {
+        serial_println!("Running {} tests", count);
+    } else {
+        serial_println!(
+            "Running {} tests, {} filtered out",
+            count,
+            tests.len() - count
+        );
+    }
+    for test in selected {
         test.run();
     }
     exit_qemu(QemuExitCode::Success);
@@ -99,14 +210,14 @@ pub fn test_runner_should_panic(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    emergency_println!("[failed]\n");
+    emergency_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failure);
     hang();
 }
 
 pub fn test_panic_handler_should_panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
+    emergency_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
     hang();
 }
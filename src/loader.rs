@@ -0,0 +1,330 @@
+//! Loading of ELF64 executables into user address spaces.
+//!
+//! The `PT_LOAD` segments of an executable are mapped into a fresh address space with the
+//! permissions given in their program headers. A user stack is mapped below `USER_STACK_TOP` and
+//! set up as described by the System V x86_64 ABI, with the stack pointer pointing at:
+//!
+//! - `argc`
+//! - `argv[0]`, ..., `argv[argc - 1]`, `NULL`
+//! - `envp[0]`, ..., `NULL`
+//! - auxiliary vector entries (type, value pairs) terminated by an `AT_NULL` entry
+//!
+//! The strings pointed to are stored above these at the top of the stack.
+//!
+//! The segments must lie below `address_space::MMAP_START`, and the program break starts out right
+//! after the highest one.
+
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    address_space::{self, AddressSpace},
+    elf::{self, ElfError, ElfFile, ProgramHeader},
+    memory::{self, GlobalFrameAllocator, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START},
+    usermode::{self, UserContext},
+};
+
+/// The initial user stack pointer is below this address.
+///
+/// The page right below the end of user space is left unmapped as a guard.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+/// The size of the user stack.
+pub const USER_STACK_SIZE: u64 = 16 * PAGE_SIZE;
+/// The maximum size of the arguments, environment, and auxiliary vector on the initial stack.
+const MAX_INITIAL_STACK_SIZE: u64 = USER_STACK_SIZE / 4;
+
+/// Auxiliary vector entry types.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// The reasons loading a program can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file is not a supported ELF executable.
+    Elf(ElfError),
+    /// A loadable segment lies outside of user space or is otherwise malformed.
+    BadSegment,
+    /// The entry point is not in an executable segment.
+    BadEntryPoint,
+    /// The arguments and environment do not fit on the initial stack.
+    ArgumentsTooLarge,
+    /// There is not enough memory for the program.
+    OutOfMemory,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> LoadError {
+        LoadError::Elf(error)
+    }
+}
+
+/// A program loaded into its own address space, ready to be run.
+#[derive(Debug)]
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub context: UserContext,
+}
+
+impl Program {
+    /// Runs the program in its address space until it exits, returning its exit code.
+    ///
+    /// Switches back to the previous address space afterwards.
+    pub fn run(&mut self) -> u64 {
+        // SAFETY: the previous address space is restored before returning
+        let previous = unsafe { self.address_space.activate() };
+        // SAFETY: the loader mapped the code and stack of the context user accessible
+        let exit_code = unsafe { usermode::run_until_exit(&mut self.context) };
+        // SAFETY: switching back to the address space active on entry
+        unsafe { address_space::switch_to(previous) };
+        exit_code
+    }
+}
+
+/// Loads the ELF executable `image` into a new address space, passing it `arguments` and
+/// `environment` on its stack.
+pub fn load(image: &[u8], arguments: &[&str], environment: &[&str]) -> Result<Program, LoadError> {
+    let file = ElfFile::parse(image)?;
+    for header in file.program_headers().filter(ProgramHeader::is_load) {
+        validate_segment(&file, &header)?;
+    }
+    let entry_is_executable = file.program_headers().any(|header| {
+        header.is_load()
+            && header.flags & elf::PF_X != 0
+            && (header.virtual_address..header.virtual_address + header.memory_size)
+                .contains(&file.entry())
+    });
+    if !entry_is_executable {
+        return Err(LoadError::BadEntryPoint);
+    }
+
+    let mut address_space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+    for header in file.program_headers().filter(ProgramHeader::is_load) {
+        load_segment(&mut address_space, &file, &header)?;
+    }
+    let image_end = file
+        .program_headers()
+        .filter(ProgramHeader::is_load)
+        .map(|header| header.virtual_address + header.memory_size)
+        .max()
+        .unwrap_or(USER_SPACE_START);
+    address_space.init_program_break(VirtAddr::new(image_end).align_up(PAGE_SIZE));
+    let stack_pointer = set_up_stack(&mut address_space, &file, arguments, environment)?;
+    let context = UserContext::new(VirtAddr::new(file.entry()), VirtAddr::new(stack_pointer));
+    Ok(Program {
+        address_space,
+        context,
+    })
+}
+
+fn validate_segment(file: &ElfFile, header: &ProgramHeader) -> Result<(), LoadError> {
+    file.segment_data(header)?;
+    let start = VirtAddr::try_new(header.virtual_address).map_err(|_| LoadError::BadSegment)?;
+    if header.file_size > header.memory_size
+        || !memory::is_user_range(start, header.memory_size)
+        || start.as_u64() + header.memory_size > address_space::MMAP_START
+    {
+        return Err(LoadError::BadSegment);
+    }
+    // The file offset and the address must be congruent modulo the alignment
+    if header.align > 1
+        && (!header.align.is_power_of_two()
+            || header.offset % header.align != header.virtual_address % header.align)
+    {
+        return Err(LoadError::BadSegment);
+    }
+    Ok(())
+}
+
+/// Returns the page table flags for a segment with the ELF permission `flags`.
+fn segment_page_flags(flags: u32) -> PageTableFlags {
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags & elf::PF_W != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if flags & elf::PF_X == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    page_flags
+}
+
+fn load_segment(
+    address_space: &mut AddressSpace,
+    file: &ElfFile,
+    header: &ProgramHeader,
+) -> Result<(), LoadError> {
+    if header.memory_size == 0 {
+        return Ok(());
+    }
+    let start = VirtAddr::new(header.virtual_address);
+    let flags = segment_page_flags(header.flags);
+    let mut mapper = address_space.mapper();
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (header.memory_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        match mapper.translate(page.start_address()) {
+            // Segments not aligned to pages may share a page, which gets the union of their
+            // permissions
+            TranslateResult::Mapped {
+                flags: old_flags, ..
+            } => {
+                let merged = (old_flags | flags) & !PageTableFlags::NO_EXECUTE
+                    | (old_flags & flags & PageTableFlags::NO_EXECUTE);
+                // SAFETY: only user space permissions of the new address space change
+                unsafe { mapper.update_flags(page, merged) }
+                    .map_err(|_| LoadError::BadSegment)?
+                    .ignore();
+            }
+            _ => {
+                let frame = memory::allocate_zeroed_frame().ok_or(LoadError::OutOfMemory)?;
+                // NOTE: the page tables are writable so that merging permissions of a shared page
+                // only needs to touch its own entry
+                let table_flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE;
+                // SAFETY: the frame is unused and the address space is not active
+                match unsafe {
+                    mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        table_flags,
+                        &mut GlobalFrameAllocator,
+                    )
+                } {
+                    Ok(flush) => flush.ignore(),
+                    Err(_) => {
+                        // SAFETY: the frame was never mapped
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        return Err(LoadError::OutOfMemory);
+                    }
+                }
+            }
+        }
+    }
+    // NOTE: the rest of the segment is already zeroed as the frames are
+    address_space
+        .write(start, file.segment_data(header)?)
+        .map_err(|_| LoadError::BadSegment)
+}
+
+/// Maps the user stack and fills in the arguments, environment and auxiliary vector, returning
+/// the initial stack pointer.
+fn set_up_stack(
+    address_space: &mut AddressSpace,
+    file: &ElfFile,
+    arguments: &[&str],
+    environment: &[&str],
+) -> Result<u64, LoadError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    memory::map_zeroed(
+        &mut address_space.mapper(),
+        VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+        USER_STACK_SIZE,
+        flags,
+    )
+    .map_err(|_| LoadError::OutOfMemory)?;
+
+    // The program headers are only accessible to the program if they are part of a segment
+    let auxiliary = [
+        (AT_PHDR, program_header_address(file)),
+        (AT_PHENT, Some(elf::PROGRAM_HEADER_SIZE as u64)),
+        (AT_PHNUM, Some(file.program_header_count() as u64)),
+        (AT_PAGESZ, Some(PAGE_SIZE)),
+        (AT_ENTRY, Some(file.entry())),
+    ];
+    let auxiliary_count = auxiliary
+        .iter()
+        .filter(|(_, value)| value.is_some())
+        .count();
+
+    let strings_size: u64 = arguments
+        .iter()
+        .chain(environment.iter())
+        .map(|string| string.len() as u64 + 1)
+        .sum();
+    let words =
+        (1 + arguments.len() + 1 + environment.len() + 1 + 2 * (auxiliary_count + 1)) as u64;
+    if strings_size + words * 8 + 16 > MAX_INITIAL_STACK_SIZE {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+    let strings_start = USER_STACK_TOP - strings_size;
+    // The ABI requires the stack pointer to be 16 byte aligned at the entry point
+    let stack_pointer = (strings_start - words * 8) & !0xF;
+
+    let mut stack = StackWriter {
+        address_space,
+        words: stack_pointer,
+        strings: strings_start,
+    };
+    stack.push_word(arguments.len() as u64);
+    for argument in arguments {
+        stack.push_string(argument);
+    }
+    stack.push_word(0);
+    for variable in environment {
+        stack.push_string(variable);
+    }
+    stack.push_word(0);
+    for &(kind, value) in auxiliary.iter() {
+        if let Some(value) = value {
+            stack.push_word(kind);
+            stack.push_word(value);
+        }
+    }
+    stack.push_word(AT_NULL);
+    stack.push_word(0);
+    Ok(stack_pointer)
+}
+
+/// Returns the address at which the program header table is loaded, if it is.
+fn program_header_address(file: &ElfFile) -> Option<u64> {
+    let offset = file.program_header_offset() as u64;
+    let size = (file.program_header_count() * elf::PROGRAM_HEADER_SIZE) as u64;
+    file.program_headers()
+        .find(|header| {
+            header.is_load()
+                && header.offset <= offset
+                && offset + size <= header.offset + header.file_size
+        })
+        .map(|header| header.virtual_address + (offset - header.offset))
+}
+
+/// Writes the initial stack contents: words upwards from the stack pointer, and the strings they
+/// point to upwards from the start of the string area.
+struct StackWriter<'a> {
+    address_space: &'a mut AddressSpace,
+    words: u64,
+    strings: u64,
+}
+
+impl StackWriter<'_> {
+    fn push_word(&mut self, word: u64) {
+        self.address_space
+            .write(VirtAddr::new(self.words), &word.to_le_bytes())
+            .expect("User stack not mapped");
+        self.words += 8;
+    }
+
+    /// Stores `string` NUL terminated in the string area and pushes a pointer to it.
+    fn push_string(&mut self, string: &str) {
+        let address = self.strings;
+        self.address_space
+            .write(VirtAddr::new(address), string.as_bytes())
+            .expect("User stack not mapped");
+        // NOTE: the terminating NUL is already there as the stack is zeroed
+        self.strings += string.len() as u64 + 1;
+        self.push_word(address);
+    }
+}
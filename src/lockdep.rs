@@ -0,0 +1,389 @@
+//! Lock dependency validator.
+//!
+//! When the `lockdep` feature is enabled, every acquisition of a `Spinlock` or `IrqSpinlock` is
+//! recorded by lock *class*. Each lock instance embeds its own `LockClass`, so every lock is a
+//! class of its own, identified by the id assigned to it on its first acquisition; the name only
+//! labels the class in reports, and locks sharing a name are still separate classes. The
+//! validator keeps track of
+//!
+//! - the order in which classes are acquired while other classes are held, and reports acquisitions
+//!   that close a cycle in that order (e.g. an ABBA inversion) before they get a chance to
+//!   deadlock,
+//! - recursive acquisition of an already held class, which always deadlocks a spinlock,
+//! - classes that are taken both from interrupt context and with interrupts enabled, which
+//!   deadlocks as soon as the interrupt arrives while the lock is held.
+//!
+//! Reports are written directly to the serial port, bypassing `SERIAL0`. Every distinct problem is
+//! reported only once. Without the feature all hooks compile down to nothing.
+
+use core::sync::atomic::AtomicU8;
+
+/// Identifies a class of locks for the purpose of dependency tracking.
+///
+/// Class ids are assigned lazily on first acquisition.
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+    #[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+    id: AtomicU8,
+}
+
+impl LockClass {
+    /// Sentinel for a class which has not been assigned an id yet.
+    const UNREGISTERED: u8 = u8::MAX;
+
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass {
+            name,
+            id: AtomicU8::new(Self::UNREGISTERED),
+        }
+    }
+
+    /// Returns the name of this class.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Records that a lock of `class` is about to be acquired with a blocking lock operation.
+///
+/// `irqs_enabled` tells whether interrupts will be enabled while the lock is held. Must be called
+/// *before* spinning on the lock so that a report can be made before a deadlock occurs.
+#[inline]
+pub fn acquire(class: &LockClass, irqs_enabled: bool) {
+    #[cfg(feature = "lockdep")]
+    imp::acquire(class, irqs_enabled, true);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = (class, irqs_enabled);
+}
+
+/// Records that a lock of `class` was acquired with a non-blocking lock operation.
+///
+/// A successful try-lock can't deadlock, so no ordering is checked, but the lock is tracked as
+/// held.
+#[inline]
+pub fn acquire_try(class: &LockClass, irqs_enabled: bool) {
+    #[cfg(feature = "lockdep")]
+    imp::acquire(class, irqs_enabled, false);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = (class, irqs_enabled);
+}
+
+/// Records that a lock of `class` has been released.
+#[inline]
+pub fn release(class: &LockClass) {
+    #[cfg(feature = "lockdep")]
+    imp::release(class);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = class;
+}
+
+/// Returns the number of problems reported so far.
+pub fn violations() -> usize {
+    #[cfg(feature = "lockdep")]
+    return imp::violations();
+    #[cfg(not(feature = "lockdep"))]
+    0
+}
+
+#[cfg(feature = "lockdep")]
+mod imp {
+    use core::{
+        fmt::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use spin::Mutex;
+    use uart_16550::SerialPort;
+
+    use super::LockClass;
+    use crate::{interrupts, sync::IrqGuard};
+
+    /// The maximum number of distinct lock classes.
+    const MAX_CLASSES: usize = 32;
+    /// The maximum number of simultaneously held locks.
+    const MAX_HELD: usize = 16;
+
+    /// A set of lock classes.
+    type ClassSet = u32;
+    static_assertions::const_assert!(ClassSet::BITS as usize >= MAX_CLASSES);
+
+    /// The I/O port of the serial interface reports are written to.
+    const REPORT_PORT: u16 = 0x3F8;
+
+    /// Per class usage flags.
+    #[derive(Debug, Clone, Copy)]
+    struct Usage {
+        in_irq: bool,
+        irqs_enabled: bool,
+        reported_irq: bool,
+        reported_recursion: bool,
+    }
+
+    struct State {
+        names: [&'static str; MAX_CLASSES],
+        usage: [Usage; MAX_CLASSES],
+        /// `after[a]` contains `b` if `b` has been acquired while `a` was held.
+        after: [ClassSet; MAX_CLASSES],
+        /// `reported[a]` contains `b` if an inversion involving the edge `a -> b` was reported.
+        reported: [ClassSet; MAX_CLASSES],
+        held: [u8; MAX_HELD],
+        depth: usize,
+        classes: usize,
+        /// Set once the validator has run out of space and stopped tracking.
+        disabled: bool,
+    }
+
+    static STATE: Mutex<State> = Mutex::new(State {
+        names: [""; MAX_CLASSES],
+        usage: [Usage {
+            in_irq: false,
+            irqs_enabled: false,
+            reported_irq: false,
+            reported_recursion: false,
+        }; MAX_CLASSES],
+        after: [0; MAX_CLASSES],
+        reported: [0; MAX_CLASSES],
+        held: [0; MAX_HELD],
+        depth: 0,
+        classes: 0,
+        disabled: false,
+    });
+
+    static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn violations() -> usize {
+        VIOLATIONS.load(Ordering::Relaxed)
+    }
+
+    pub fn acquire(class: &LockClass, irqs_enabled: bool, check_order: bool) {
+        let _irq = IrqGuard::new();
+        let mut state = STATE.lock();
+        if state.disabled {
+            return;
+        }
+        let id = match state.class_id(class) {
+            Some(id) => id,
+            None => return,
+        };
+
+        if check_order {
+            state.check_recursion(id);
+            state.check_order(id);
+        }
+        state.check_usage(id, interrupts::in_interrupt(), irqs_enabled);
+
+        for i in 0..state.depth {
+            let held = usize::from(state.held[i]);
+            state.after[held] |= 1 << id;
+        }
+        if state.depth == MAX_HELD {
+            state.disable("too many locks held");
+            return;
+        }
+        let depth = state.depth;
+        state.held[depth] = id as u8;
+        state.depth += 1;
+    }
+
+    pub fn release(class: &LockClass) {
+        let _irq = IrqGuard::new();
+        let mut state = STATE.lock();
+        if state.disabled {
+            return;
+        }
+        let id = class.id.load(Ordering::Relaxed);
+        // Locks are usually but not necessarily released in reverse order of acquisition
+        match state.held[..state.depth]
+            .iter()
+            .rposition(|&held| held == id)
+        {
+            Some(index) => {
+                let depth = state.depth;
+                state.held.copy_within(index + 1..depth, index);
+                state.depth -= 1;
+            }
+            None => state.disable("released a lock which is not held"),
+        }
+    }
+
+    impl State {
+        /// Returns the id of `class`, registering it first if necessary.
+        fn class_id(&mut self, class: &LockClass) -> Option<usize> {
+            let id = class.id.load(Ordering::Relaxed);
+            if id != LockClass::UNREGISTERED {
+                return Some(usize::from(id));
+            }
+            if self.classes == MAX_CLASSES {
+                self.disable("too many lock classes");
+                return None;
+            }
+            let id = self.classes;
+            self.classes += 1;
+            self.names[id] = class.name;
+            class.id.store(id as u8, Ordering::Relaxed);
+            Some(id)
+        }
+
+        fn check_recursion(&mut self, id: usize) {
+            if self.held[..self.depth].contains(&(id as u8)) && !self.usage[id].reported_recursion {
+                self.usage[id].reported_recursion = true;
+                let mut report = Report::begin("recursive locking detected");
+                let _ = writeln!(report, "acquiring {} which is already held", self.names[id]);
+                self.write_held(&mut report);
+            }
+        }
+
+        fn check_order(&mut self, id: usize) {
+            for i in 0..self.depth {
+                let held = usize::from(self.held[i]);
+                if held == id || self.after[held] & (1 << id) != 0 {
+                    continue;
+                }
+                // The new edge `held -> id` closes a cycle if `held` is reachable from `id`
+                if self.reachable(id) & (1 << held) != 0 && self.reported[held] & (1 << id) == 0 {
+                    self.reported[held] |= 1 << id;
+                    let mut report = Report::begin("possible circular locking dependency");
+                    let _ = writeln!(
+                        report,
+                        "acquiring {} while holding {}, but {} has been acquired before {}",
+                        self.names[id], self.names[held], self.names[id], self.names[held],
+                    );
+                    self.write_held(&mut report);
+                }
+            }
+        }
+
+        fn check_usage(&mut self, id: usize, in_irq: bool, irqs_enabled: bool) {
+            let usage = &mut self.usage[id];
+            usage.in_irq |= in_irq;
+            usage.irqs_enabled |= irqs_enabled && !in_irq;
+            if usage.in_irq && usage.irqs_enabled && !usage.reported_irq {
+                usage.reported_irq = true;
+                let mut report = Report::begin("inconsistent interrupt state");
+                let _ = writeln!(
+                    report,
+                    "{} is taken in interrupt context and with interrupts enabled",
+                    self.names[id]
+                );
+                self.write_held(&mut report);
+            }
+        }
+
+        /// Returns the set of classes which have been acquired after `id`, directly or indirectly.
+        fn reachable(&self, id: usize) -> ClassSet {
+            let mut reached: ClassSet = 0;
+            let mut frontier = self.after[id];
+            while frontier & !reached != 0 {
+                reached |= frontier;
+                let mut next = 0;
+                for class in 0..self.classes {
+                    if frontier & (1 << class) != 0 {
+                        next |= self.after[class];
+                    }
+                }
+                frontier = next;
+            }
+            reached
+        }
+
+        fn write_held(&self, report: &mut Report) {
+            let _ = writeln!(report, "locks held by the current context:");
+            for (i, &held) in self.held[..self.depth].iter().enumerate() {
+                let _ = writeln!(report, "  #{}: {}", i, self.names[usize::from(held)]);
+            }
+            let _ = writeln!(
+                report,
+                "in interrupt context: {}",
+                interrupts::in_interrupt()
+            );
+        }
+
+        fn disable(&mut self, reason: &str) {
+            self.disabled = true;
+            let mut report = Report::begin("lock validation turned off");
+            let _ = writeln!(report, "{}", reason);
+        }
+    }
+
+    /// A report written to the serial port without going through `SERIAL0`, which may be the very
+    /// lock being validated.
+    struct Report(SerialPort);
+
+    impl Report {
+        fn begin(title: &str) -> Report {
+            VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+            // SAFETY: the port has been initialized by `SERIAL0` or the firmware, and interleaving
+            // output with it is harmless
+            let mut report = Report(unsafe { SerialPort::new(REPORT_PORT) });
+            let _ = writeln!(report, "\n===== LOCKDEP: {} =====", title);
+            report
+        }
+    }
+
+    impl Write for Report {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0.write_str(s)
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+#[test_case]
+fn test_lockdep_detects_inversion() {
+    use crate::sync::Spinlock;
+
+    static A: Spinlock<()> = Spinlock::with_name("test_inversion::A", ());
+    static B: Spinlock<()> = Spinlock::with_name("test_inversion::B", ());
+
+    let before = violations();
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    assert_eq!(violations(), before);
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+    assert_eq!(violations(), before + 1);
+    // Already reported once
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+    assert_eq!(violations(), before + 1);
+}
+
+#[cfg(feature = "lockdep")]
+#[test_case]
+fn test_lockdep_detects_irq_inconsistency() {
+    use crate::{interrupts::IrqContext, sync::Spinlock};
+
+    static LOCK: Spinlock<()> = Spinlock::with_name("test_irq_inconsistency::LOCK", ());
+
+    let before = violations();
+    drop(LOCK.lock());
+    assert_eq!(violations(), before);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _irq = IrqContext::enter();
+        drop(LOCK.lock());
+    });
+    assert_eq!(violations(), before + 1);
+}
+
+#[cfg(feature = "lockdep")]
+#[test_case]
+fn test_lockdep_irq_spinlock_is_consistent() {
+    use crate::{interrupts::IrqContext, sync::IrqSpinlock};
+
+    static LOCK: IrqSpinlock<()> = IrqSpinlock::with_name("test_irq_spinlock::LOCK", ());
+
+    let before = violations();
+    drop(LOCK.lock());
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _irq = IrqContext::enter();
+        drop(LOCK.lock());
+    });
+    assert_eq!(violations(), before);
+}
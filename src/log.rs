@@ -0,0 +1,90 @@
+//! Kernel log messages with a severity level, printed to the console unless below the level set
+//! with the `loglevel` boot option.
+
+use core::fmt;
+
+use crate::{
+    cmdline::{Param, ParamValue},
+    println,
+};
+
+/// The severity of a log message, from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+impl ParamValue for LogLevel {
+    fn parse(value: Option<&'static str>) -> Option<LogLevel> {
+        match value? {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// The least severe level of messages that are printed.
+pub static LOG_LEVEL: Param<LogLevel> = Param::new(
+    "loglevel",
+    "least severe messages printed: error, warn, info or debug",
+    LogLevel::Info,
+);
+
+/// Logs a message with the given `LogLevel`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, format_args!($($arg)*)));
+}
+
+/// Logs an error message.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Error, $($arg)*));
+}
+
+/// Logs a warning message.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Warn, $($arg)*));
+}
+
+/// Logs an informational message.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Info, $($arg)*));
+}
+
+/// Logs a debug message.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Debug, $($arg)*));
+}
+
+/// Returns whether messages of `level` are printed.
+pub fn enabled(level: LogLevel) -> bool {
+    level <= LOG_LEVEL.get()
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    if enabled(level) {
+        println!("[{}] {}", level, args);
+    }
+}
@@ -6,63 +6,47 @@
 
 use core::panic::PanicInfo;
 
-use bootloader::{entry_point, BootInfo};
-use yarhos::{println, vga_buffer};
+use bootloader_api::{entry_point, BootInfo};
+use yarhos::{
+    println,
+    process::{self, Pid},
+};
 
-entry_point!(_kernel_entry_point);
+entry_point!(_kernel_entry_point, config = &yarhos::BOOTLOADER_CONFIG);
 
-#[no_mangle]
-#[allow(unreachable_code)]
-pub fn _kernel_entry_point(_boot_info: &'static BootInfo) -> ! {
-    use vga_buffer::{Color, ControlCharMode};
-
-    vga_buffer::set_color(Color::LightCyan, Color::Black);
-    // TODO: code page 437 conversion from Unicode
-    println!("Hello, World{}", "! ö");
+// NOTE: embedded until there is a file system to load programs from, see
+// `scripts/build_test_programs.sh`
+static INIT_PROGRAM: &[u8] = include_bytes!("../tests/programs/init.elf");
 
+#[no_mangle]
+pub fn _kernel_entry_point(boot_info: &'static mut BootInfo) -> ! {
     // Set up IDT
     yarhos::init();
-
-    #[allow(dead_code)]
-    #[allow(unconditional_recursion)]
-    fn stack_overflow() {
-        stack_overflow();
-    }
-
-    // Uncomment for a stack overflow
-    //stack_overflow();
-
-    vga_buffer::set_color(Color::LightGreen, Color::DarkGray);
-    vga_buffer::print_character_set();
-
-    vga_buffer::set_fg_color(Color::Red);
-    vga_buffer::set_control_mode(ControlCharMode::Glyph);
-    println!("A\tB\rC\nD");
-
-    vga_buffer::set_bg_color(Color::White);
-    vga_buffer::set_control_mode(ControlCharMode::Control);
-    println!("A\tB\rC\nD");
-
-    // HACK: busy wait for some time
-    for _ in 0..5_000_000 {
-        x86_64::instructions::nop();
-    }
-
-    vga_buffer::set_color(Color::Pink, Color::Black);
-    vga_buffer::clear();
-    println!("Ohai :3");
+    let boot_framebuffer = boot_info.framebuffer.take();
+    yarhos::memory::init(boot_info);
+    yarhos::framebuffer::init(boot_framebuffer);
 
     #[cfg(test)]
     test_main();
 
-    println!("It did not crash :o");
-    yarhos::halt_loop();
+    yarhos::splash::show_boot_splash();
+
+    if let Err(error) = process::spawn_init(INIT_PROGRAM, &["init"]) {
+        panic!("Starting init failed: {:?}", error);
+    }
+    // NOTE: init reaps all other processes
+    match process::wait(Some(Pid::INIT)) {
+        Ok((_, status)) => println!("Init exited with status {}", status),
+        Err(error) => panic!("Waiting for init failed: {:?}", error),
+    }
+    yarhos::shutdown();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    yarhos::emergency_println!("{}", info);
+    yarhos::splash::show_panic_screen(info);
     yarhos::hang();
 }
 
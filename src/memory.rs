@@ -0,0 +1,422 @@
+use alloc::collections::BTreeMap;
+
+use bootloader_api::{
+    info::{MemoryRegionKind, MemoryRegions},
+    BootInfo,
+};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    sync::IrqSpinlock,
+    vma::{Protection, Vma, Vmas},
+};
+
+/// The size of a (small) page and a physical frame.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// The lowest user space address.
+///
+/// User space starts at the second level 4 page table entry, so that the first one can stay shared
+/// with the kernel image and the bootloader's identity mapped trampoline.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+/// The address right above the highest user space address, i.e. the end of the lower half.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// The start of the kernel region for lazily allocated buffers, in the level 4 page table entry
+/// below the kernel heap. See `reserve_lazy()`.
+pub const LAZY_START: u64 = 0xFFFF_FE80_0000_0000;
+/// The size of the region for lazily allocated buffers, i.e. one level 4 page table entry.
+pub const LAZY_SIZE: u64 = 1 << 39;
+
+/// Marks a read-only user page whose frame is to be copied on the first write, as it may be shared
+/// with other address spaces. See `address_space::copy_on_write()`.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The virtual address at which the bootloader maps all of physical memory, see
+/// `crate::BOOTLOADER_CONFIG`.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+/// The end of low memory, which holds firmware data structures and is never allocated.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// The frame allocator backing all physical memory allocations after `init()`.
+static FRAME_ALLOCATOR: IrqSpinlock<Option<BootInfoFrameAllocator>> =
+    IrqSpinlock::with_name("FRAME_ALLOCATOR", None);
+
+/// The number of additional references to frames mapped more than once, e.g. shared copy-on-write.
+static SHARED_FRAMES: IrqSpinlock<BTreeMap<PhysFrame, usize>> =
+    IrqSpinlock::with_name("SHARED_FRAMES", BTreeMap::new());
+
+/// The reserved lazily allocated kernel buffers.
+static LAZY_AREAS: IrqSpinlock<Vmas> = IrqSpinlock::with_name("LAZY_AREAS", Vmas::new());
+
+/// Initializes physical memory management from the bootloader provided information.
+///
+/// Requires the bootloader to map the complete physical memory at `PHYSICAL_MEMORY_OFFSET`, i.e.
+/// the kernel to be built with `crate::BOOTLOADER_CONFIG`. Also sets up the kernel heap.
+pub fn init(boot_info: &'static BootInfo) {
+    assert_eq!(
+        boot_info.physical_memory_offset.into_option(),
+        Some(PHYSICAL_MEMORY_OFFSET),
+        "Physical memory not mapped at the configured offset"
+    );
+    // Make the kernel respect read-only pages too, which copy-on-write relies on
+    // SAFETY: the kernel never writes to read-only pages intentionally
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::new(&boot_info.memory_regions));
+    // SAFETY: no other mapper exists during initialization
+    let mut mapper = unsafe { active_mapper() };
+    crate::allocator::init(&mut mapper).expect("Kernel heap initialization failed");
+    init_lazy_region(&mut mapper);
+}
+
+/// Creates the level 3 page table of the lazily allocated region up front, so that address spaces
+/// created later share it with the kernel.
+fn init_lazy_region(mapper: &mut OffsetPageTable) {
+    let index = Page::<Size4KiB>::containing_address(VirtAddr::new(LAZY_START)).p4_index();
+    let entry = &mut mapper.level_4_table()[index];
+    assert!(entry.is_unused(), "Lazily allocated region in use");
+    let frame = allocate_zeroed_frame().expect("Out of memory");
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+/// Returns the virtual address through which the physical address `addr` can be accessed.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr.as_u64())
+}
+
+/// Returns a mapper for the page table hierarchy rooted at `level_4_frame`.
+///
+/// # Safety
+///
+/// `level_4_frame` must contain a valid level 4 page table, and the caller must make sure that no
+/// aliasing mutable references to the page tables are created, e.g. by calling this function
+/// multiple times for the same table at once.
+pub unsafe fn mapper_for(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let table: *mut PageTable = phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+    let physical_memory_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET);
+    // SAFETY: guaranteed by the caller
+    unsafe { OffsetPageTable::new(&mut *table, physical_memory_offset) }
+}
+
+/// Returns a mapper for the currently active page table hierarchy.
+///
+/// # Safety
+///
+/// See `mapper_for()`.
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    let (level_4_frame, _) = Cr3::read();
+    // SAFETY: guaranteed by the caller
+    unsafe { mapper_for(level_4_frame) }
+}
+
+/// Allocates a physical frame and fills it with zeros.
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    let page: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    // SAFETY: the frame was just allocated so nothing else refers to it
+    unsafe { core::ptr::write_bytes(page, 0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+/// Returns the number of frames currently handed out by the frame allocator.
+pub fn allocated_frames() -> usize {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.allocated)
+}
+
+/// Adds a reference to the allocated `frame`, which then needs to be released once more before it
+/// is deallocated.
+pub fn share_frame(frame: PhysFrame) {
+    *SHARED_FRAMES.lock().entry(frame).or_insert(0) += 1;
+}
+
+/// Returns whether `frame` has more than one reference.
+pub fn is_frame_shared(frame: PhysFrame) -> bool {
+    SHARED_FRAMES.lock().contains_key(&frame)
+}
+
+/// Drops a reference to `frame`, deallocating it when it was the last one.
+///
+/// # Safety
+///
+/// The reference being dropped must no longer be used.
+pub unsafe fn release_frame(frame: PhysFrame) {
+    {
+        let mut shared = SHARED_FRAMES.lock();
+        if let Some(references) = shared.get_mut(&frame) {
+            *references -= 1;
+            if *references == 0 {
+                shared.remove(&frame);
+            }
+            return;
+        }
+    }
+    // SAFETY: this was the last reference
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+/// Reserves a kernel buffer of `size` bytes, rounded up to whole pages, and returns its address.
+/// Returns `None` if there is no room left.
+///
+/// The pages of the buffer read as zero, and frames are only allocated for them when they are
+/// first accessed.
+pub fn reserve_lazy(size: u64) -> Option<VirtAddr> {
+    let size = size.max(1).checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let mut areas = LAZY_AREAS.lock();
+    let (lower, upper) = (
+        VirtAddr::new(LAZY_START),
+        VirtAddr::new(LAZY_START + LAZY_SIZE),
+    );
+    let start = areas.find_free(size, lower, upper)?;
+    areas
+        .insert(Vma {
+            start,
+            end: start + size,
+            protection: Protection::READ_WRITE,
+        })
+        .ok()?;
+    Some(start)
+}
+
+/// Releases the kernel buffer of `size` bytes at `start` returned by `reserve_lazy()`, and frees
+/// the frames allocated for it.
+///
+/// # Safety
+///
+/// The buffer must not be used afterwards.
+pub unsafe fn release_lazy(start: VirtAddr, size: u64) {
+    let end = (start + size.max(1)).align_up(PAGE_SIZE);
+    let removed = LAZY_AREAS.lock().remove(start, end);
+    // SAFETY: the lazily allocated region is only mapped through this mapper at a time
+    let mut mapper = unsafe { active_mapper() };
+    for area in removed {
+        for page in Page::<Size4KiB>::range(
+            Page::containing_address(area.start),
+            Page::containing_address(area.end),
+        ) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                // SAFETY: guaranteed by the caller
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            }
+        }
+    }
+}
+
+/// Maps a zeroed frame at the unmapped page containing `address` in a reserved lazily allocated
+/// kernel buffer. Returns whether the page was mapped.
+pub(crate) fn handle_lazy_fault(address: VirtAddr) -> bool {
+    if LAZY_AREAS.lock().find(address).is_none() {
+        return false;
+    }
+    let frame = match allocate_zeroed_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    // SAFETY: see `release_lazy()`
+    let mut mapper = unsafe { active_mapper() };
+    // SAFETY: the frame is unused and the page lies in a reserved buffer
+    match unsafe {
+        mapper.map_to(
+            Page::<Size4KiB>::containing_address(address),
+            frame,
+            flags,
+            &mut GlobalFrameAllocator,
+        )
+    } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            // SAFETY: the frame was never mapped
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Maps `size` bytes starting at `start` to freshly allocated, zeroed frames using `flags`.
+///
+/// The range is extended to page boundaries. On failure the pages mapped so far stay mapped.
+pub fn map_zeroed(
+    mapper: &mut impl Mapper<Size4KiB>,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        let frame = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        // SAFETY: the frame is unused and the caller is responsible for the address range
+        match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                // SAFETY: the frame was never mapped
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `[start, start + size)` lies completely within user space.
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    start.as_u64() >= USER_SPACE_START
+        && start
+            .as_u64()
+            .checked_add(size)
+            .is_some_and(|end| end <= USER_SPACE_END)
+}
+
+/// Returns whether `[start, start + size)` lies within user space and is mapped user accessible in
+/// the active address space.
+///
+/// NOTE: only the flags of the last level page table entries are checked; mapping a page user
+/// accessible makes its parent tables user accessible as well.
+pub fn is_user_accessible(start: VirtAddr, size: u64) -> bool {
+    user_range_all(start, size, |_| true)
+}
+
+/// Returns whether `[start, start + size)` lies within user space and is mapped user accessible and
+/// writable, possibly after copying on write, in the active address space.
+pub fn is_user_writable(start: VirtAddr, size: u64) -> bool {
+    user_range_all(start, size, |flags| {
+        flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE)
+    })
+}
+
+/// Returns whether `[start, start + size)` lies within user space and all of its pages are mapped
+/// user accessible with flags satisfying `predicate`.
+fn user_range_all(start: VirtAddr, size: u64, predicate: impl Fn(PageTableFlags) -> bool) -> bool {
+    if !is_user_range(start, size) {
+        return false;
+    }
+    if size == 0 {
+        return true;
+    }
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    // SAFETY: the mapper is only used to read the page tables while it exists
+    let mapper = unsafe { active_mapper() };
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        matches!(
+            mapper.translate(page.start_address()),
+            TranslateResult::Mapped { flags, .. } if flags.contains(required) && predicate(flags)
+        )
+    })
+}
+
+/// A handle to the global frame allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator
+            .as_mut()
+            .expect("Frame deallocated before memory::init()");
+        // SAFETY: guaranteed by the caller
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+/// A frame allocator handing out the usable frames of the bootloader's memory map.
+///
+/// Frames are handed out in address order; deallocated frames are kept in a free list threaded
+/// through the frames themselves and are reused first.
+pub struct BootInfoFrameAllocator {
+    memory_regions: &'static MemoryRegions,
+    /// The index of the memory region `next` lies in.
+    region: usize,
+    /// The next never allocated frame.
+    next: PhysAddr,
+    /// The most recently deallocated frame, which stores the address of the next free frame.
+    free_list: Option<PhysFrame>,
+    /// The number of frames currently allocated.
+    allocated: usize,
+}
+
+impl BootInfoFrameAllocator {
+    fn new(memory_regions: &'static MemoryRegions) -> BootInfoFrameAllocator {
+        BootInfoFrameAllocator {
+            memory_regions,
+            region: 0,
+            // NOTE: this also keeps frame 0, which terminates the free list, from being allocated
+            next: PhysAddr::new(LOW_MEMORY_END),
+            free_list: None,
+            allocated: 0,
+        }
+    }
+
+    fn allocate_fresh(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_regions.get(self.region) {
+            let start = PhysAddr::new(region.start);
+            let end = PhysAddr::new(region.end);
+            if region.kind == MemoryRegionKind::Usable {
+                // NOTE: firmware provided regions are not necessarily page aligned
+                self.next = self.next.max(start.align_up(PAGE_SIZE));
+                if self.next + PAGE_SIZE <= end {
+                    let frame = PhysFrame::containing_address(self.next);
+                    self.next += PAGE_SIZE;
+                    return Some(frame);
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            Some(frame) => {
+                let link: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+                // SAFETY: free frames contain the address of the next free frame or zero
+                let next = unsafe { link.read() };
+                self.free_list =
+                    (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+                frame
+            }
+            None => self.allocate_fresh()?,
+        };
+        self.allocated += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let link: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        let next = self
+            .free_list
+            .map_or(0, |next| next.start_address().as_u64());
+        // SAFETY: the caller guarantees the frame is unused
+        unsafe { link.write(next) };
+        self.free_list = Some(frame);
+        self.allocated -= 1;
+    }
+}
@@ -0,0 +1,56 @@
+//! The PC speaker, driven by channel 2 of the programmable interval timer.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::time;
+
+/// The bits of the keyboard controller port B gating channel 2 and connecting it to the speaker.
+const SPEAKER_ENABLE: u8 = 0x03;
+
+/// The uptime in milliseconds at which the current beep ends, or 0 if there is none.
+static BEEP_END_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts a tone of `frequency_hz`, which plays until `stop()`.
+pub fn play(frequency_hz: u32) {
+    let divisor =
+        (time::PIT_FREQUENCY / u64::from(frequency_hz.max(19))).min(u16::MAX.into()) as u16;
+    let mut port_b = Port::<u8>::new(0x61);
+    // SAFETY: programming channel 2 and the speaker gate only affects the speaker
+    unsafe {
+        // channel 2, low and high byte, square wave generator, binary
+        Port::new(0x43).write(0xB6u8);
+        Port::new(0x42).write(divisor as u8);
+        Port::new(0x42).write((divisor >> 8) as u8);
+        let value = port_b.read();
+        port_b.write(value | SPEAKER_ENABLE);
+    }
+}
+
+/// Silences the speaker.
+pub fn stop() {
+    let mut port_b = Port::<u8>::new(0x61);
+    // SAFETY: clearing the speaker gate only affects the speaker
+    unsafe {
+        let value = port_b.read();
+        port_b.write(value & !SPEAKER_ENABLE);
+    }
+    BEEP_END_MS.store(0, Ordering::Relaxed);
+}
+
+/// Plays a tone of `frequency_hz` for `duration_ms` milliseconds, without waiting for it to end.
+///
+/// The tone is stopped by the timer, so it lasts until interrupts are enabled if started before.
+pub fn beep(frequency_hz: u32, duration_ms: u64) {
+    play(frequency_hz);
+    BEEP_END_MS.store(time::uptime_ms() + duration_ms.max(1), Ordering::Relaxed);
+}
+
+/// Stops a beep that has ended. Called from the deferred timer work, with interrupts enabled.
+pub(crate) fn tick() {
+    let end = BEEP_END_MS.load(Ordering::Relaxed);
+    if end != 0 && time::uptime_ms() >= end {
+        stop();
+    }
+}
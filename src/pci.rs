@@ -0,0 +1,81 @@
+//! Access to the PCI configuration space through configuration mechanism #1.
+
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The ports selecting a configuration space register, and accessing it.
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// The configuration space registers of the vendor and device IDs, the header type, and the first
+/// base address register.
+const VENDOR_DEVICE_ID: u8 = 0x00;
+const HEADER_TYPE: u8 = 0x0C;
+pub const BAR0: u8 = 0x10;
+
+/// The vendor ID read for a missing device.
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// The location of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Function {
+    /// Reads the 32 bit configuration space register at `offset`, which is rounded down to a
+    /// multiple of 4.
+    pub fn config_read(self, offset: u8) -> u32 {
+        let address = 1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC);
+        // NOTE: a configuration access interrupted by another one would read the wrong register
+        interrupts::without_interrupts(|| {
+            // SAFETY: reading the configuration space has no side effects
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS_PORT).write(address);
+                Port::<u32>::new(CONFIG_DATA_PORT).read()
+            }
+        })
+    }
+
+    /// Returns the vendor and device IDs, or `None` if there is no such function.
+    fn ids(self) -> Option<(u16, u16)> {
+        let ids = self.config_read(VENDOR_DEVICE_ID);
+        let vendor_id = ids as u16;
+        (vendor_id != NO_VENDOR).then_some((vendor_id, (ids >> 16) as u16))
+    }
+}
+
+/// Returns the first function with `vendor_id` and `device_id`, scanning all buses.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Function> {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = Function {
+                bus,
+                device,
+                function: 0,
+            };
+            if first.ids().is_none() {
+                continue;
+            }
+            // NOTE: bit 7 of the header type marks a multi-function device
+            let multi_function = first.config_read(HEADER_TYPE) >> 16 & 0x80 != 0;
+            let functions = if multi_function { 8 } else { 1 };
+            for function in 0..functions {
+                let candidate = Function {
+                    bus,
+                    device,
+                    function,
+                };
+                if candidate.ids() == Some((vendor_id, device_id)) {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+    None
+}
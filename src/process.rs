@@ -0,0 +1,538 @@
+//! Processes, i.e. programs running in their own address spaces.
+//!
+//! Every process has a unique `Pid`, an address space, one or more threads running user code, and
+//! a table of open files. Processes form a tree rooted at the kernel (`Pid::KERNEL`), which is the
+//! parent of the processes it spawns directly, such as the init process (`Pid::INIT`).
+//!
+//! A process can `fork` a child which starts out as a copy of it, sharing its memory copy-on-write,
+//! and replace the program it runs with `exec`.
+//!
+//! When a process exits, its threads, address space and files are torn down right away. The
+//! process lingers on as a zombie holding just its exit status until its parent collects it with
+//! `wait`. The children of an exiting process are handed over to init, which reaps them, or to the
+//! kernel if there is no init. The parent of the exiting process is sent `SIGCHLD`.
+//!
+//! Signals sent to a process are delivered right before its thread resumes user code, see
+//! `signal`. Faults of user code that cannot be resolved raise the corresponding signal instead of
+//! bringing down the kernel.
+//!
+//! Threads are scheduled cooperatively in round robin order, with a chance to switch to another
+//! thread on every system call.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::VirtAddr;
+
+use crate::{
+    address_space::{self, AddressSpace},
+    file::FileTable,
+    loader::{self, LoadError, Program},
+    signal::{self, Delivery, Signal, SignalState},
+    sync::Spinlock,
+    syscall::{self, Action},
+    usermode::{self, Trap, UserContext},
+    vma::Protection,
+    workqueue,
+};
+
+/// A process identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// The kernel, parent of the processes it spawns.
+    pub const KERNEL: Pid = Pid(0);
+    /// The init process, which adopts orphaned processes.
+    pub const INIT: Pid = Pid(1);
+
+    /// Returns the `Pid` with the numeric value `pid`.
+    pub const fn new(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    /// Returns the numeric value of the `Pid`.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The reason waiting for a child process can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The waiting process has no (matching) children.
+    NoChildren,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    /// Ready to run user code.
+    Runnable,
+    /// In a system call that is to be retried before running user code again.
+    Blocked,
+}
+
+/// A thread of user code execution within a process.
+#[derive(Debug)]
+pub struct Thread {
+    context: UserContext,
+    state: ThreadState,
+}
+
+impl Thread {
+    fn new(context: UserContext) -> Thread {
+        Thread {
+            context,
+            state: ThreadState::Runnable,
+        }
+    }
+}
+
+/// A process.
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    parent: Pid,
+    /// `None` once the process has exited.
+    address_space: Option<AddressSpace>,
+    threads: Vec<Thread>,
+    files: FileTable,
+    signals: SignalState,
+    exit_status: Option<u64>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Pid {
+        self.parent
+    }
+
+    pub fn files(&self) -> &FileTable {
+        &self.files
+    }
+
+    pub fn signals(&self) -> &SignalState {
+        &self.signals
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns the exit status if the process has exited, i.e. is a zombie.
+    pub fn exit_status(&self) -> Option<u64> {
+        self.exit_status
+    }
+
+    /// Tears down everything but the exit status.
+    fn exit(&mut self, status: u64) {
+        self.threads.clear();
+        self.files.close_all();
+        // NOTE: dropping the address space returns all of its frames
+        self.address_space = None;
+        self.exit_status = Some(status);
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    next_pid: u64,
+    /// The thread run most recently, for round robin scheduling.
+    last_run: (Pid, usize),
+}
+
+impl ProcessTable {
+    const fn new() -> ProcessTable {
+        ProcessTable {
+            processes: BTreeMap::new(),
+            // `Pid::INIT` is reserved for init
+            next_pid: 2,
+            last_run: (Pid::KERNEL, 0),
+        }
+    }
+
+    fn allocate_pid(&mut self) -> Pid {
+        let pid = Pid(self.next_pid);
+        self.next_pid += 1;
+        pid
+    }
+
+    fn children(&self, parent: Pid) -> impl Iterator<Item = &Process> {
+        self.processes
+            .values()
+            .filter(move |process| process.parent == parent)
+    }
+
+    /// Returns all threads in round robin order, starting after the one run most recently.
+    fn threads_in_schedule_order(&self) -> Vec<(Pid, usize)> {
+        let threads = self
+            .processes
+            .values()
+            .flat_map(|process| (0..process.threads.len()).map(move |index| (process.pid, index)));
+        let (before, after): (Vec<_>, Vec<_>) =
+            threads.partition(|&thread| thread <= self.last_run);
+        after.into_iter().chain(before).collect()
+    }
+
+    fn exit(&mut self, pid: Pid, status: u64) {
+        let parent = match self.processes.get_mut(&pid) {
+            Some(process) => {
+                process.exit(status);
+                process.parent
+            }
+            None => return,
+        };
+        let init_running = pid != Pid::INIT
+            && self
+                .processes
+                .get(&Pid::INIT)
+                .is_some_and(|init| init.exit_status.is_none());
+        let adopter = if init_running { Pid::INIT } else { Pid::KERNEL };
+        for process in self.processes.values_mut() {
+            if process.parent == pid {
+                process.parent = adopter;
+            }
+        }
+        if let Some(parent) = self.processes.get_mut(&parent) {
+            if parent.exit_status.is_none() {
+                parent.signals.raise(Signal::SIGCHLD);
+            }
+        }
+    }
+}
+
+static PROCESSES: Spinlock<ProcessTable> = Spinlock::with_name("PROCESSES", ProcessTable::new());
+
+/// The process whose thread is currently running, or `Pid::KERNEL` if none.
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+/// Loads the ELF executable `image` into a new process with `parent` as its parent process.
+///
+/// The process inherits the open files of its parent, or gets the console as standard input,
+/// output and error if spawned by the kernel.
+pub fn spawn(
+    image: &[u8],
+    arguments: &[&str],
+    environment: &[&str],
+    parent: Pid,
+) -> Result<Pid, LoadError> {
+    let program = loader::load(image, arguments, environment)?;
+    let mut table = PROCESSES.lock();
+    let pid = table.allocate_pid();
+    insert_process(&mut table, pid, parent, program);
+    Ok(pid)
+}
+
+/// Loads the ELF executable `image` into the init process, a child of the kernel with the process
+/// ID `Pid::INIT`.
+///
+/// Panics if init is already running or has not been waited for.
+pub fn spawn_init(image: &[u8], arguments: &[&str]) -> Result<Pid, LoadError> {
+    let program = loader::load(image, arguments, &[])?;
+    let mut table = PROCESSES.lock();
+    assert!(
+        !table.processes.contains_key(&Pid::INIT),
+        "Init process already exists"
+    );
+    insert_process(&mut table, Pid::INIT, Pid::KERNEL, program);
+    Ok(Pid::INIT)
+}
+
+fn insert_process(table: &mut ProcessTable, pid: Pid, parent: Pid, program: Program) {
+    let files = match table.processes.get(&parent) {
+        Some(parent) => parent.files.clone(),
+        None => FileTable::with_console(),
+    };
+    let process = Process {
+        pid,
+        parent: if table.processes.contains_key(&parent) {
+            parent
+        } else {
+            Pid::KERNEL
+        },
+        address_space: Some(program.address_space),
+        threads: alloc::vec![Thread::new(program.context)],
+        files,
+        signals: SignalState::new(),
+        exit_status: None,
+    };
+    table.processes.insert(pid, process);
+}
+
+/// Creates a child of the process `parent` with a copy of its address space, open files and signal
+/// actions, and a single thread continuing from `context` with 0 in `rax`.
+///
+/// Returns `None` if `parent` does not exist or there is not enough memory.
+pub fn fork(parent: Pid, context: &UserContext) -> Option<Pid> {
+    let mut table = PROCESSES.lock();
+    let process = table.processes.get_mut(&parent)?;
+    let address_space = process.address_space.as_mut()?.fork()?;
+    let files = process.files.clone();
+    let signals = process.signals.fork();
+    let mut context = context.clone();
+    context.rax = 0;
+    let pid = table.allocate_pid();
+    let child = Process {
+        pid,
+        parent,
+        address_space: Some(address_space),
+        threads: alloc::vec![Thread::new(context)],
+        files,
+        signals,
+        exit_status: None,
+    };
+    table.processes.insert(pid, child);
+    Some(pid)
+}
+
+/// Replaces the address space of the process `pid` with the one of `program`, and returns the
+/// context to continue its thread with. Signal handlers are reset to the default action.
+///
+/// The new address space is activated if the old one was active.
+///
+/// NOTE: processes only have a single thread so far, which keeps running.
+pub fn exec(pid: Pid, program: Program) -> Option<UserContext> {
+    let mut table = PROCESSES.lock();
+    let process = table.processes.get_mut(&pid)?;
+    let old = process.address_space.as_ref()?;
+    if old.is_active() {
+        // SAFETY: the old address space is dropped right away, and the new one stays alive in its
+        // place
+        unsafe { program.address_space.activate() };
+    }
+    process.address_space = Some(program.address_space);
+    process.signals.exec();
+    Some(program.context)
+}
+
+/// Returns the process whose thread is currently running, if any.
+pub fn current() -> Option<Pid> {
+    match CURRENT.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// Calls `f` with the process `pid`, if it exists.
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&Process) -> R) -> Option<R> {
+    PROCESSES.lock().processes.get(&pid).map(f)
+}
+
+/// Calls `f` with the address space of the process `pid`, if it exists and has not exited.
+pub fn with_address_space<R>(pid: Pid, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    PROCESSES
+        .lock()
+        .processes
+        .get_mut(&pid)?
+        .address_space
+        .as_mut()
+        .map(f)
+}
+
+/// Calls `f` with the signal state of the process `pid`, if it exists and has not exited.
+pub fn with_signals<R>(pid: Pid, f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+    PROCESSES
+        .lock()
+        .processes
+        .get_mut(&pid)
+        .filter(|process| process.exit_status.is_none())
+        .map(|process| f(&mut process.signals))
+}
+
+/// Sends `signal` to the process `pid`. Returns `false` if the process does not exist or has
+/// exited.
+pub fn kill(pid: Pid, signal: Signal) -> bool {
+    with_signals(pid, |signals| signals.raise(signal)).is_some()
+}
+
+/// Handles a page fault at `address` in user space for an access needing `access`, by mapping a
+/// page of a memory area of the current process. Returns whether the fault was resolved.
+pub(crate) fn handle_page_fault(address: VirtAddr, access: Protection) -> bool {
+    let pid = match current() {
+        Some(pid) => pid,
+        None => return false,
+    };
+    with_address_space(pid, |address_space| {
+        address_space.is_active() && address_space.handle_page_fault(address, access)
+    })
+    .unwrap_or(false)
+}
+
+/// Returns the children of `parent`, including exited ones not yet waited for.
+pub fn children(parent: Pid) -> Vec<Pid> {
+    PROCESSES
+        .lock()
+        .children(parent)
+        .map(Process::pid)
+        .collect()
+}
+
+/// Returns the number of processes, including exited ones not yet waited for.
+pub fn count() -> usize {
+    PROCESSES.lock().processes.len()
+}
+
+/// Collects the exit status of an exited child of `parent`, which is `target` if given, and
+/// returns its `Pid` and exit status. Returns `None` if no matching child has exited yet.
+pub fn try_wait(parent: Pid, target: Option<Pid>) -> Result<Option<(Pid, u64)>, WaitError> {
+    let mut table = PROCESSES.lock();
+    let (matching, exited) = table
+        .children(parent)
+        .filter(|child| target.is_none() || target == Some(child.pid))
+        .fold((0, None), |(matching, exited), child| {
+            let status = child.exit_status.map(|status| (child.pid, status));
+            (matching + 1, exited.or(status))
+        });
+    if matching == 0 {
+        return Err(WaitError::NoChildren);
+    }
+    if let Some((pid, _)) = exited {
+        table.processes.remove(&pid);
+    }
+    Ok(exited)
+}
+
+/// Waits for a child of the kernel, which is `target` if given, to exit, and returns its `Pid`
+/// and exit status. Runs processes meanwhile.
+pub fn wait(target: Option<Pid>) -> Result<(Pid, u64), WaitError> {
+    loop {
+        if let Some(exited) = try_wait(Pid::KERNEL, target)? {
+            return Ok(exited);
+        }
+        if !run_next() {
+            crate::idle();
+        }
+    }
+}
+
+/// Runs processes until none of them can make progress, e.g. because all have exited.
+pub fn run() {
+    while run_next() {}
+}
+
+/// Gives the next thread in round robin order a chance to run until it enters the kernel.
+///
+/// Returns `false` if no thread made progress, i.e. there are no threads or all of them are
+/// blocked.
+pub fn run_next() -> bool {
+    workqueue::run_worker();
+    let threads = PROCESSES.lock().threads_in_schedule_order();
+    threads
+        .into_iter()
+        .any(|(pid, index)| run_thread(pid, index))
+}
+
+/// Runs a thread until it enters the kernel, or retries its blocked system call, and delivers the
+/// pending signals of its process afterwards. Returns whether the thread made progress.
+fn run_thread(pid: Pid, index: usize) -> bool {
+    let (mut context, state, level_4_frame) = {
+        let mut table = PROCESSES.lock();
+        table.last_run = (pid, index);
+        let process = match table.processes.get(&pid) {
+            Some(process) => process,
+            None => return false,
+        };
+        let (thread, address_space) = match (process.threads.get(index), &process.address_space) {
+            (Some(thread), Some(address_space)) => (thread, address_space),
+            _ => return false,
+        };
+        (
+            thread.context.clone(),
+            thread.state,
+            address_space.level_4_frame(),
+        )
+    };
+
+    CURRENT.store(pid.as_u64(), Ordering::Relaxed);
+    // SAFETY: the address space is only torn down by `exit()` below, after switching back
+    let previous = unsafe { address_space::switch_to(level_4_frame) };
+    let trap = match state {
+        // SAFETY: the loader mapped the code and stack of the context user accessible
+        ThreadState::Runnable => unsafe { usermode::enter(&mut context) },
+        ThreadState::Blocked => Trap::Syscall,
+    };
+    let action = match trap {
+        Trap::Syscall => syscall::dispatch(&mut context),
+        Trap::Fault(fault) => {
+            if !fault.resolve() {
+                let signal = Signal::for_exception(fault.exception);
+                with_signals(pid, |signals| signals.force(signal));
+            }
+            Action::Resume
+        }
+    };
+    let action = match action {
+        Action::Exit(_) => action,
+        _ => deliver_signals(pid, &mut context, action),
+    };
+    // SAFETY: switching back to the address space active on entry
+    unsafe { address_space::switch_to(previous) };
+    CURRENT.store(Pid::KERNEL.as_u64(), Ordering::Relaxed);
+
+    let mut table = PROCESSES.lock();
+    if let Action::Exit(status) = action {
+        table.exit(pid, status);
+        return true;
+    }
+    if let Some(thread) = table
+        .processes
+        .get_mut(&pid)
+        .and_then(|process| process.threads.get_mut(index))
+    {
+        thread.context = context;
+        thread.state = match action {
+            Action::Block => ThreadState::Blocked,
+            _ => ThreadState::Runnable,
+        };
+    }
+    !(state == ThreadState::Blocked && action == Action::Block)
+}
+
+/// Delivers the pending signals of the process `pid` to its thread, which is about to continue
+/// from `context` after `action`, and returns the action to take instead.
+///
+/// A blocked system call is interrupted if a signal is to be handled. The address space of the
+/// process must be active.
+fn deliver_signals(pid: Pid, context: &mut UserContext, action: Action) -> Action {
+    let mut action = action;
+    loop {
+        // NOTE: the process table must not be locked while writing to user memory, which may fault
+        let delivery = with_signals(pid, |signals| {
+            if action == Action::Block && !signals.has_deliverable() {
+                return None;
+            }
+            signals.deliver()
+        });
+        match delivery.flatten() {
+            None => return action,
+            Some(Delivery::Terminate(signal)) => return Action::Exit(signal.exit_status()),
+            Some(Delivery::Handler {
+                signal,
+                handler,
+                restorer,
+                blocked,
+            }) => {
+                if action == Action::Block {
+                    context.rax = syscall::Error::Interrupted.as_return_value();
+                    action = Action::Resume;
+                }
+                // Like a fault while delivering the signal
+                if !signal::push_frame(context, signal, handler, restorer, blocked) {
+                    return Action::Exit(Signal::SIGSEGV.exit_status());
+                }
+            }
+        }
+    }
+}
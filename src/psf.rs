@@ -0,0 +1,357 @@
+//! Parser of PC Screen Font[^psf] files, the bitmap font format of the Linux console, in both
+//! version 1 and version 2.
+//!
+//! A font is a header followed by the glyph bitmaps, one bit per pixel with each row padded to a
+//! whole byte, and an optional Unicode table telling which characters each glyph shows.
+//!
+//! [^psf]: https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+
+use core::convert::TryInto;
+
+/// The magic bytes of a version 1 font.
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// The mode flag of a version 1 font with 512 instead of 256 glyphs.
+const PSF1_MODE512: u8 = 0x01;
+/// The mode flags of a version 1 font with a Unicode table.
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODEHASSEQ: u8 = 0x04;
+const PSF1_HEADER_SIZE: usize = 4;
+/// The Unicode table entries of version 1 fonts ending the characters of a glyph, and starting its
+/// character sequences.
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+/// The magic bytes of a version 2 font.
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+/// The flag of a version 2 font with a Unicode table.
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_HEADER_SIZE: usize = 32;
+/// The Unicode table bytes of version 2 fonts ending the characters of a glyph, and starting its
+/// character sequences, neither of which occurs in UTF-8.
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+/// An error parsing a font.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The data starts with neither magic.
+    BadMagic,
+    /// A version 2 font of a later version.
+    UnsupportedVersion(u32),
+    /// The header describes no glyphs, or glyphs too small for their size.
+    BadGeometry,
+    /// The data ends before the last glyph.
+    Truncated,
+}
+
+/// The version of a font, which tells the encoding of its Unicode table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// UCS-2 in little endian 16 bit words.
+    Psf1,
+    /// UTF-8.
+    Psf2,
+}
+
+/// A parsed font, borrowing the glyphs from the font data.
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    version: Version,
+    width: usize,
+    height: usize,
+    length: usize,
+    bytes_per_glyph: usize,
+    glyphs: &'a [u8],
+    unicode_table: Option<&'a [u8]>,
+}
+
+impl<'a> Font<'a> {
+    /// Parses the font in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Font<'a>, Error> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(data)
+        } else {
+            Err(Error::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Font<'a>, Error> {
+        let header = data.get(..PSF1_HEADER_SIZE).ok_or(Error::Truncated)?;
+        let (mode, height) = (header[2], usize::from(header[3]));
+        let length = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+        let has_table = mode & (PSF1_MODEHASTAB | PSF1_MODEHASSEQ) != 0;
+        Font::with_geometry(
+            Version::Psf1,
+            data,
+            PSF1_HEADER_SIZE,
+            (8, height, length, height),
+            has_table,
+        )
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Font<'a>, Error> {
+        let header = data.get(..PSF2_HEADER_SIZE).ok_or(Error::Truncated)?;
+        let field = |index: usize| {
+            let bytes = &header[index * 4..index * 4 + 4];
+            u32::from_le_bytes(bytes.try_into().unwrap())
+        };
+        let (version, header_size, flags) = (field(1), field(2), field(3));
+        if version != 0 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let [length, bytes_per_glyph, height, width] =
+            [4, 5, 6, 7].map(|index| field(index) as usize);
+        Font::with_geometry(
+            Version::Psf2,
+            data,
+            header_size as usize,
+            (width, height, length, bytes_per_glyph),
+            flags & PSF2_HAS_UNICODE_TABLE != 0,
+        )
+    }
+
+    /// Returns the font of the glyphs after `header_size`, given the `width`, `height`, `length`
+    /// and `bytes_per_glyph` in `geometry`, with the Unicode table after them if `has_table`.
+    fn with_geometry(
+        version: Version,
+        data: &'a [u8],
+        header_size: usize,
+        geometry: (usize, usize, usize, usize),
+        has_table: bool,
+    ) -> Result<Font<'a>, Error> {
+        let (width, height, length, bytes_per_glyph) = geometry;
+        if width == 0 || height == 0 || length == 0 || bytes_per_glyph < width.div_ceil(8) * height
+        {
+            return Err(Error::BadGeometry);
+        }
+        let glyphs_end = length
+            .checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(Error::BadGeometry)?;
+        let glyphs = data.get(header_size..glyphs_end).ok_or(Error::Truncated)?;
+        Ok(Font {
+            version,
+            width,
+            height,
+            length,
+            bytes_per_glyph,
+            glyphs,
+            unicode_table: has_table.then(|| &data[glyphs_end..]),
+        })
+    }
+
+    /// Returns the version of the font.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the width of the glyphs in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the glyphs in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the number of glyphs.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns whether the font has no glyphs, which parsing never returns.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Returns the bitmap of glyph `index`: `height()` rows of `width()` bits, each padded to a
+    /// whole byte, the leftmost pixel in the most significant bit of the first byte of a row.
+    pub fn glyph(&self, index: usize) -> Option<&'a [u8]> {
+        let start = index.checked_mul(self.bytes_per_glyph)?;
+        let size = self.width.div_ceil(8) * self.height;
+        self.glyphs.get(start..start + size)
+    }
+
+    /// Returns whether the font has a Unicode table, without which the glyphs are expected to
+    /// follow code page 437.
+    pub fn has_unicode_table(&self) -> bool {
+        self.unicode_table.is_some()
+    }
+
+    /// Returns the entries of the Unicode table, pairs of a glyph index and a character it shows.
+    ///
+    /// A glyph may show several characters. Character sequences, e.g. a letter and a combining
+    /// accent, are left out.
+    pub fn unicode_entries(&self) -> UnicodeEntries<'a> {
+        UnicodeEntries {
+            version: self.version,
+            table: self.unicode_table.unwrap_or(&[]),
+            glyph: 0,
+            length: self.length,
+            in_sequence: false,
+        }
+    }
+}
+
+/// An iterator over the Unicode table of a font, see `Font::unicode_entries()`.
+#[derive(Debug, Clone)]
+pub struct UnicodeEntries<'a> {
+    version: Version,
+    table: &'a [u8],
+    glyph: usize,
+    length: usize,
+    /// Whether the rest of the entries of `glyph` are character sequences.
+    in_sequence: bool,
+}
+
+impl UnicodeEntries<'_> {
+    /// Takes the next value of the table, a UCS-2 code unit or a separator for version 1, and a
+    /// code point or a separator for version 2. Returns `None` for an invalid code point.
+    fn next_value(&mut self) -> Option<u32> {
+        match self.version {
+            Version::Psf1 => {
+                let (&[low, high], rest) = self.table.split_first_chunk::<2>()?;
+                self.table = rest;
+                Some(u16::from_le_bytes([low, high]).into())
+            }
+            Version::Psf2 => {
+                let first = *self.table.first()?;
+                if first == PSF2_SEPARATOR || first == PSF2_START_SEQUENCE {
+                    self.table = &self.table[1..];
+                    return Some(first.into());
+                }
+                let size = match first.leading_ones() {
+                    0 => 1,
+                    size @ 2..=4 => size as usize,
+                    _ => {
+                        self.table = &self.table[1..];
+                        return None;
+                    }
+                };
+                let bytes = self.table.get(..size).unwrap_or(self.table);
+                self.table = &self.table[bytes.len()..];
+                let c = core::str::from_utf8(bytes).ok()?.chars().next()?;
+                Some(c.into())
+            }
+        }
+    }
+}
+
+impl Iterator for UnicodeEntries<'_> {
+    type Item = (usize, char);
+
+    fn next(&mut self) -> Option<(usize, char)> {
+        let (separator, start_sequence) = match self.version {
+            Version::Psf1 => (PSF1_SEPARATOR.into(), PSF1_START_SEQUENCE.into()),
+            Version::Psf2 => (PSF2_SEPARATOR.into(), PSF2_START_SEQUENCE.into()),
+        };
+        while self.glyph < self.length && !self.table.is_empty() {
+            match self.next_value() {
+                Some(value) if value == separator => {
+                    self.glyph += 1;
+                    self.in_sequence = false;
+                }
+                Some(value) if value == start_sequence => self.in_sequence = true,
+                Some(value) if !self.in_sequence => {
+                    if let Some(c) = char::from_u32(value) {
+                        return Some((self.glyph, c));
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Returns a version 1 font of 256 glyphs, 8 by `height` pixels each, filled with their index,
+/// and the given Unicode table.
+#[cfg(test)]
+pub(crate) fn test_psf1(height: u8, table: &[u16]) -> alloc::vec::Vec<u8> {
+    let mode = if table.is_empty() { 0 } else { PSF1_MODEHASTAB };
+    let mut data = alloc::vec![PSF1_MAGIC[0], PSF1_MAGIC[1], mode, height];
+    for glyph in 0..=0xFFu8 {
+        data.extend(core::iter::repeat_n(glyph, height.into()));
+    }
+    for value in table {
+        data.extend(value.to_le_bytes());
+    }
+    data
+}
+
+/// Returns a version 2 font of `length` glyphs, `width` by `height` pixels each, filled with
+/// their index, and the given Unicode table.
+#[cfg(test)]
+fn test_psf2(width: u32, height: u32, length: u32, table: &[u8]) -> alloc::vec::Vec<u8> {
+    let bytes_per_glyph = width.div_ceil(8) * height;
+    let flags = if table.is_empty() { 0 } else { 1 };
+    let mut data = alloc::vec::Vec::from(PSF2_MAGIC);
+    for field in [0, 32, flags, length, bytes_per_glyph, height, width] {
+        data.extend(u32::to_le_bytes(field));
+    }
+    for glyph in 0..length {
+        data.extend(core::iter::repeat_n(glyph as u8, bytes_per_glyph as usize));
+    }
+    data.extend(table);
+    data
+}
+
+#[test_case]
+fn test_psf1_parse() {
+    let data = test_psf1(
+        8,
+        &[0x41, 0xFFFF, 0x42, 0x20AC, 0xFFFE, 0x43, 0x301, 0xFFFF],
+    );
+    let font = Font::parse(&data).unwrap();
+    assert_eq!(font.version(), Version::Psf1);
+    assert_eq!((font.width(), font.height(), font.len()), (8, 8, 256));
+    assert_eq!(font.glyph(0x41), Some(&[0x41; 8][..]));
+    assert_eq!(font.glyph(256), None);
+    assert!(font.has_unicode_table());
+    let entries: alloc::vec::Vec<_> = font.unicode_entries().collect();
+    assert_eq!(entries, [(0, 'A'), (1, 'B'), (1, '€')]);
+}
+
+#[test_case]
+fn test_psf2_parse() {
+    let table = [
+        "A".as_bytes(),
+        &[PSF2_SEPARATOR],
+        "€Б".as_bytes(),
+        &[PSF2_START_SEQUENCE],
+        "C\u{301}".as_bytes(),
+        &[PSF2_SEPARATOR],
+    ]
+    .concat();
+    let data = test_psf2(12, 24, 300, &table);
+    let font = Font::parse(&data).unwrap();
+    assert_eq!(font.version(), Version::Psf2);
+    assert_eq!((font.width(), font.height(), font.len()), (12, 24, 300));
+    assert_eq!(font.glyph(299), Some(&[43; 48][..]));
+    let entries: alloc::vec::Vec<_> = font.unicode_entries().collect();
+    assert_eq!(entries, [(0, 'A'), (1, '€'), (1, 'Б')]);
+}
+
+#[test_case]
+fn test_psf_errors() {
+    assert_eq!(Font::parse(b"font").unwrap_err(), Error::BadMagic);
+    let data = test_psf1(16, &[]);
+    assert_eq!(
+        Font::parse(&data[..data.len() - 1]).unwrap_err(),
+        Error::Truncated
+    );
+    assert!(!Font::parse(&data).unwrap().has_unicode_table());
+    let mut data = test_psf2(8, 16, 1, &[]);
+    data[4] = 1;
+    assert_eq!(
+        Font::parse(&data).unwrap_err(),
+        Error::UnsupportedVersion(1)
+    );
+    assert_eq!(
+        Font::parse(&test_psf2(8, 0, 1, &[])).unwrap_err(),
+        Error::BadGeometry
+    );
+}
@@ -1,12 +1,13 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::sync::IrqSpinlock;
+
 lazy_static! {
-    pub static ref SERIAL0: Mutex<SerialPort> = {
+    pub static ref SERIAL0: IrqSpinlock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinlock::with_name("SERIAL0", serial_port)
     };
 }
 
@@ -14,12 +15,10 @@ lazy_static! {
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL0
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL0
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 /// Prints to the `SERIAL0` serial interface.
@@ -0,0 +1,436 @@
+//! POSIX-like signals sent to processes.
+//!
+//! A signal sent to a process stays pending until it is delivered right before user code of the
+//! process runs again. Signals in the blocked mask of the process stay pending until unblocked,
+//! except for `SIGKILL`, which can be neither blocked, ignored nor handled.
+//!
+//! Delivering a signal carries out its action: the default action terminates the process or
+//! ignores the signal, depending on the signal. A user handler is invoked by pushing a
+//! `SignalFrame` on the user stack and continuing at the handler with the signal number as its
+//! argument, and the restorer of the handler as its return address. The restorer makes the
+//! `sigreturn` system call, which restores the state saved in the frame.
+//!
+//! Faults of user code, e.g. page faults that cannot be resolved, raise signals which cannot be
+//! blocked or ignored, such as `SIGSEGV` or `SIGFPE`. Unless handled, they terminate the process.
+
+use core::fmt;
+
+use x86_64::{registers::rflags::RFlags, VirtAddr};
+
+use crate::{
+    gdt, memory,
+    usermode::{Exception, UserContext},
+};
+
+/// A signal number, from 1 to `Signal::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGCHLD: Signal = Signal(17);
+
+    /// The highest signal number.
+    pub const MAX: u8 = 63;
+
+    /// Returns the signal with the number `number`, if it is valid.
+    pub fn new(number: u64) -> Option<Signal> {
+        (1..=u64::from(Signal::MAX))
+            .contains(&number)
+            .then_some(Signal(number as u8))
+    }
+
+    /// Returns the signal number.
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    /// Returns the signal raised by user code causing `exception`.
+    pub fn for_exception(exception: Exception) -> Signal {
+        match exception {
+            Exception::DivideError | Exception::X87FloatingPoint | Exception::SimdFloatingPoint => {
+                Signal::SIGFPE
+            }
+            Exception::InvalidOpcode => Signal::SIGILL,
+            Exception::GeneralProtection | Exception::PageFault => Signal::SIGSEGV,
+        }
+    }
+
+    /// Returns whether the default action of the signal is to ignore it rather than to terminate
+    /// the process.
+    fn ignored_by_default(self) -> bool {
+        self == Signal::SIGCHLD
+    }
+
+    /// Returns the exit status of a process terminated by the signal, which is `128 + signal` as
+    /// reported by shells.
+    pub fn exit_status(self) -> u64 {
+        128 + u64::from(self.0)
+    }
+
+    fn bit(self) -> u64 {
+        1 << (self.0 - 1)
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A set of signals, with signal `n` represented by bit `n - 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignalSet(pub u64);
+
+impl SignalSet {
+    /// The empty set.
+    pub const EMPTY: SignalSet = SignalSet(0);
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & signal.bit() != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= signal.bit();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !signal.bit();
+    }
+
+    /// Returns the lowest numbered signal in the set.
+    fn lowest(self) -> Option<Signal> {
+        (self.0 != 0).then(|| Signal(self.0.trailing_zeros() as u8 + 1))
+    }
+}
+
+/// What to do when a signal is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// The default action of the signal.
+    Default,
+    /// Discard the signal.
+    Ignore,
+    /// Invoke a user handler, which returns to `restorer`.
+    Handler {
+        handler: VirtAddr,
+        restorer: VirtAddr,
+    },
+}
+
+/// The `sigaction` value of `SignalAction::Default`.
+pub const SIG_DFL: u64 = 0;
+/// The `sigaction` value of `SignalAction::Ignore`.
+pub const SIG_IGN: u64 = 1;
+
+/// The reasons changing a signal action can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unchangeable;
+
+/// What delivering a signal amounts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Terminate the process.
+    Terminate(Signal),
+    /// Invoke a user handler with `blocked` as the signal mask to restore afterwards.
+    Handler {
+        signal: Signal,
+        handler: VirtAddr,
+        restorer: VirtAddr,
+        blocked: SignalSet,
+    },
+}
+
+/// The signal actions, pending signals and blocked mask of a process.
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    actions: [SignalAction; Signal::MAX as usize],
+    pending: SignalSet,
+    blocked: SignalSet,
+}
+
+impl Default for SignalState {
+    fn default() -> SignalState {
+        SignalState::new()
+    }
+}
+
+impl SignalState {
+    /// Creates a state with default actions, no pending signals and nothing blocked.
+    pub const fn new() -> SignalState {
+        SignalState {
+            actions: [SignalAction::Default; Signal::MAX as usize],
+            pending: SignalSet::EMPTY,
+            blocked: SignalSet::EMPTY,
+        }
+    }
+
+    /// Returns the state inherited by a forked child, which has no pending signals.
+    pub fn fork(&self) -> SignalState {
+        SignalState {
+            pending: SignalSet::EMPTY,
+            ..self.clone()
+        }
+    }
+
+    /// Resets the handlers to the default action when executing a new program, as they are not
+    /// part of it. Ignored signals stay ignored.
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+
+    pub fn action(&self, signal: Signal) -> SignalAction {
+        self.actions[usize::from(signal.0 - 1)]
+    }
+
+    /// Sets the action of `signal`, and returns the previous one.
+    pub fn set_action(
+        &mut self,
+        signal: Signal,
+        action: SignalAction,
+    ) -> Result<SignalAction, Unchangeable> {
+        if signal == Signal::SIGKILL {
+            return Err(Unchangeable);
+        }
+        let previous = core::mem::replace(&mut self.actions[usize::from(signal.0 - 1)], action);
+        if action == SignalAction::Ignore {
+            self.pending.remove(signal);
+        }
+        Ok(previous)
+    }
+
+    pub fn pending(&self) -> SignalSet {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> SignalSet {
+        self.blocked
+    }
+
+    /// Sets the blocked mask, and returns the previous one. `SIGKILL` cannot be blocked.
+    pub fn set_blocked(&mut self, blocked: SignalSet) -> SignalSet {
+        let mut blocked = blocked;
+        blocked.remove(Signal::SIGKILL);
+        core::mem::replace(&mut self.blocked, blocked)
+    }
+
+    /// Makes `signal` pending, unless it is ignored.
+    pub fn raise(&mut self, signal: Signal) {
+        let ignored = match self.action(signal) {
+            SignalAction::Ignore => true,
+            SignalAction::Default => signal.ignored_by_default(),
+            SignalAction::Handler { .. } => false,
+        };
+        if !ignored {
+            self.pending.insert(signal);
+        }
+    }
+
+    /// Makes `signal` pending even if it is blocked or ignored, which resets it to the default
+    /// action. Used for signals caused by faults, after which user code cannot continue.
+    pub fn force(&mut self, signal: Signal) {
+        self.blocked.remove(signal);
+        if self.action(signal) == SignalAction::Ignore {
+            self.actions[usize::from(signal.0 - 1)] = SignalAction::Default;
+        }
+        self.pending.insert(signal);
+    }
+
+    /// Returns whether a signal is pending and not blocked.
+    pub fn has_deliverable(&self) -> bool {
+        self.pending.0 & !self.blocked.0 != 0
+    }
+
+    /// Takes the lowest numbered pending signal that is not blocked and returns what delivering
+    /// it amounts to. Blocks the signal if it is to be handled.
+    pub fn deliver(&mut self) -> Option<Delivery> {
+        loop {
+            let signal = SignalSet(self.pending.0 & !self.blocked.0).lowest()?;
+            self.pending.remove(signal);
+            match self.action(signal) {
+                SignalAction::Ignore => continue,
+                SignalAction::Default if signal.ignored_by_default() => continue,
+                SignalAction::Default => return Some(Delivery::Terminate(signal)),
+                SignalAction::Handler { handler, restorer } => {
+                    let blocked = self.blocked;
+                    self.blocked.insert(signal);
+                    return Some(Delivery::Handler {
+                        signal,
+                        handler,
+                        restorer,
+                        blocked,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The frame pushed on the user stack when invoking a signal handler.
+///
+/// The stack pointer points at `restorer` when the handler starts, as if it had been called from
+/// there.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SignalFrame {
+    pub restorer: u64,
+    pub signal: u64,
+    /// The blocked mask to restore.
+    pub blocked: u64,
+    /// The interrupted user state to restore.
+    pub context: UserContext,
+}
+
+/// The size of the area below the stack pointer which user code may use without moving it, and
+/// the signal frame thus must leave alone.
+const RED_ZONE_SIZE: u64 = 128;
+
+/// The flags user code may change with `sigreturn`.
+const USER_FLAGS: RFlags = RFlags::CARRY_FLAG
+    .union(RFlags::PARITY_FLAG)
+    .union(RFlags::AUXILIARY_CARRY_FLAG)
+    .union(RFlags::ZERO_FLAG)
+    .union(RFlags::SIGN_FLAG)
+    .union(RFlags::TRAP_FLAG)
+    .union(RFlags::DIRECTION_FLAG)
+    .union(RFlags::OVERFLOW_FLAG);
+
+/// Pushes a `SignalFrame` saving `context` on the user stack of the active address space, and
+/// changes `context` to call `handler` with it. Returns `false` if the frame does not fit on the
+/// user stack.
+pub fn push_frame(
+    context: &mut UserContext,
+    signal: Signal,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+    blocked: SignalSet,
+) -> bool {
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    // The ABI expects `rsp + 8` to be 16 byte aligned at function entry
+    let frame = match context.rsp.checked_sub(RED_ZONE_SIZE + size) {
+        Some(frame) => ((frame + 8) & !0xF) - 8,
+        None => return false,
+    };
+    let frame = match VirtAddr::try_new(frame) {
+        Ok(frame) if memory::is_user_writable(frame, size) => frame,
+        _ => return false,
+    };
+    let saved = SignalFrame {
+        restorer: restorer.as_u64(),
+        signal: u64::from(signal.0),
+        blocked: blocked.0,
+        context: context.clone(),
+    };
+    // SAFETY: the frame was checked to be writable user memory
+    unsafe { frame.as_mut_ptr::<SignalFrame>().write_unaligned(saved) };
+    context.rip = handler.as_u64();
+    context.rsp = frame.as_u64();
+    context.rdi = u64::from(signal.0);
+    // NOTE: user code might have left the direction flag set
+    context.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    true
+}
+
+/// Pops the `SignalFrame` the stack pointer of `context` points right above after the handler has
+/// returned to the restorer, and returns the saved user state and blocked mask. Returns `None` if
+/// the frame is not accessible or was tampered with.
+///
+/// Only the flags user code may change are restored, and the segment selectors are always those
+/// of user mode.
+pub fn pop_frame(context: &UserContext) -> Option<(UserContext, SignalSet)> {
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    let frame = VirtAddr::try_new(context.rsp.checked_sub(8)?).ok()?;
+    if !memory::is_user_accessible(frame, size) {
+        return None;
+    }
+    // SAFETY: the frame was checked to be accessible user memory
+    let frame = unsafe { frame.as_ptr::<SignalFrame>().read_unaligned() };
+    let mut restored = frame.context;
+    if !memory::is_user_range(VirtAddr::try_new(restored.rip).ok()?, 1) {
+        return None;
+    }
+    let selectors = gdt::selectors();
+    restored.cs = u64::from(selectors.user_code.0);
+    restored.ss = u64::from(selectors.user_data.0);
+    restored.rflags = (restored.rflags & USER_FLAGS.bits()) | (context.rflags & !USER_FLAGS.bits());
+    Some((restored, SignalSet(frame.blocked)))
+}
+
+#[test_case]
+fn test_signal_delivery_order_and_blocking() {
+    let mut state = SignalState::new();
+    let handler = SignalAction::Handler {
+        handler: VirtAddr::new(0x1000),
+        restorer: VirtAddr::new(0x2000),
+    };
+    state.set_action(Signal::SIGUSR1, handler).unwrap();
+    assert_eq!(
+        state.set_action(Signal::SIGKILL, handler),
+        Err(Unchangeable)
+    );
+    state.set_blocked(SignalSet(Signal::SIGTERM.bit() | Signal::SIGKILL.bit()));
+    assert!(!state.blocked().contains(Signal::SIGKILL));
+
+    state.raise(Signal::SIGTERM);
+    state.raise(Signal::SIGCHLD);
+    assert!(!state.has_deliverable());
+    state.raise(Signal::SIGUSR1);
+    assert_eq!(
+        state.deliver(),
+        Some(Delivery::Handler {
+            signal: Signal::SIGUSR1,
+            handler: VirtAddr::new(0x1000),
+            restorer: VirtAddr::new(0x2000),
+            blocked: SignalSet(Signal::SIGTERM.bit()),
+        })
+    );
+    assert!(state.blocked().contains(Signal::SIGUSR1));
+    assert_eq!(state.deliver(), None);
+    state.set_blocked(SignalSet::EMPTY);
+    assert_eq!(state.deliver(), Some(Delivery::Terminate(Signal::SIGTERM)));
+    assert_eq!(state.pending(), SignalSet::EMPTY);
+}
+
+#[test_case]
+fn test_forced_signals_and_exec() {
+    let mut state = SignalState::new();
+    state
+        .set_action(Signal::SIGSEGV, SignalAction::Ignore)
+        .unwrap();
+    state.set_blocked(SignalSet(Signal::SIGSEGV.bit()));
+    state.raise(Signal::SIGSEGV);
+    assert!(!state.has_deliverable());
+    state.force(Signal::SIGSEGV);
+    assert_eq!(state.deliver(), Some(Delivery::Terminate(Signal::SIGSEGV)));
+
+    let handler = SignalAction::Handler {
+        handler: VirtAddr::new(0x1000),
+        restorer: VirtAddr::new(0x2000),
+    };
+    state.set_action(Signal::SIGUSR1, handler).unwrap();
+    state
+        .set_action(Signal::SIGUSR2, SignalAction::Ignore)
+        .unwrap();
+    state.exec();
+    assert_eq!(state.action(Signal::SIGUSR1), SignalAction::Default);
+    assert_eq!(state.action(Signal::SIGUSR2), SignalAction::Ignore);
+}
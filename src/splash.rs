@@ -0,0 +1,336 @@
+//! Full screen graphics on the framebuffer: the boot splash and the panic screen.
+//!
+//! Both are drawn over the framebuffer console. The boot splash redraws the console when it is
+//! done, whereas the panic screen stays until the machine is reset.
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    cmdline::Param,
+    fb_console::{self, CELL_HEIGHT, CELL_WIDTH},
+    framebuffer::{Rgb, FRAMEBUFFER},
+    graphics::{blend, BackBuffer, Canvas, Image, Painter, Rect},
+    time, vga_buffer,
+};
+
+/// Whether to show the boot splash.
+pub static SPLASH: Param<bool> =
+    Param::new("splash", "show the boot splash on the framebuffer", true);
+
+/// How long the boot splash is shown, and in how many steps its progress bar fills.
+const SPLASH_DURATION_MS: u64 = 1000;
+const SPLASH_STEPS: usize = 20;
+
+/// The size of the progress bar of the boot splash in pixels.
+const PROGRESS_WIDTH: usize = 240;
+const PROGRESS_HEIGHT: usize = 12;
+
+/// The colors of the boot splash, whose background fades from the top color to the bottom one.
+const SPLASH_TOP: Rgb = Rgb::new(0x10, 0x20, 0x48);
+const SPLASH_BOTTOM: Rgb = Rgb::BLACK;
+const SPLASH_ACCENT: Rgb = Rgb::new(0x40, 0x90, 0xE0);
+
+/// The colors of the panic screen.
+const PANIC_BACKGROUND: Rgb = Rgb::new(0x90, 0x10, 0x10);
+const PANIC_FOREGROUND: Rgb = Rgb::WHITE;
+
+/// How opaquely the panic screen darkens the screen behind its panel, out of 255.
+const PANIC_DIM_ALPHA: u8 = 192;
+
+/// The largest number of columns and rows of text on the panic screen.
+const PANIC_COLUMNS: usize = 72;
+const PANIC_ROWS: usize = 16;
+
+/// Whether the panic screen has been drawn, so that a panic while drawing it does not loop.
+static PANIC_SCREEN_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Draws `text` in `color`, centered horizontally on `center_x` with the top at `y`, blended with
+/// what is drawn there.
+fn draw_label<C: Canvas + ?Sized>(
+    painter: &mut Painter<C>,
+    center_x: isize,
+    y: isize,
+    text: &str,
+    color: Rgb,
+) {
+    // The glyphs are drawn white on black, whose brightness is then the alpha value of `color`
+    let mut mask = BackBuffer::new(text.chars().count() * CELL_WIDTH, CELL_HEIGHT);
+    fb_console::draw_text(&mut mask, 0, 0, text, Rgb::WHITE, Rgb::BLACK);
+    let (width, height) = (mask.width(), mask.height());
+    let alpha: Vec<u8> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| mask.pixel(x, y).map_or(0, |pixel| pixel.r))
+        .collect();
+    let pixels = alloc::vec![color; width * height];
+    let image = Image::new(width, height, &pixels).with_alpha(&alpha);
+    painter.blit(center_x - (width / 2) as isize, y, &image);
+}
+
+/// Draws the boot splash on `canvas`, returning the area of its progress bar.
+fn draw_splash(canvas: &mut impl Canvas) -> Rect {
+    let (width, height) = (canvas.width(), canvas.height());
+    for y in 0..height {
+        let color = blend(SPLASH_BOTTOM, SPLASH_TOP, (y * 255 / height) as u8);
+        canvas.fill_rect(0, y, width, 1, color);
+    }
+
+    let mut painter = Painter::new(canvas);
+    let radius = width.min(height) / 8;
+    let (center_x, center_y) = (
+        (width / 2) as isize,
+        (height / 2) as isize - radius as isize,
+    );
+    let offset = (radius / 3) as isize;
+    painter.fill_circle(center_x, center_y, radius, SPLASH_ACCENT);
+    painter.set_alpha(96);
+    painter.fill_circle(center_x - offset, center_y - offset, radius / 2, Rgb::WHITE);
+    painter.set_alpha(u8::MAX);
+    painter.circle(center_x, center_y, radius + 3, Rgb::WHITE);
+
+    let label_y = center_y + radius as isize + CELL_HEIGHT as isize;
+    draw_label(&mut painter, center_x, label_y, "yarhos", Rgb::WHITE);
+
+    let progress_width = PROGRESS_WIDTH.min(width);
+    Rect::new(
+        center_x - (progress_width / 2) as isize,
+        label_y + 2 * CELL_HEIGHT as isize,
+        progress_width,
+        PROGRESS_HEIGHT,
+    )
+}
+
+/// Shows the boot splash on the framebuffer for a moment, unless disabled with the `splash` boot
+/// option, then draws the framebuffer console again. Does nothing without a framebuffer.
+///
+/// Must be called with interrupts enabled.
+pub fn show_boot_splash() {
+    if !SPLASH.get() {
+        return;
+    }
+    let area = match FRAMEBUFFER.lock().as_mut() {
+        Some(framebuffer) => draw_splash(framebuffer).intersection(framebuffer.bounds()),
+        None => return,
+    };
+    if area.is_empty() {
+        vga_buffer::redraw_framebuffer_console();
+        return;
+    }
+    // NOTE: the progress bar is drawn on a back buffer, so that each step only copies the part of
+    // the bar that filled since the previous one
+    let mut bar = BackBuffer::new(area.width(), area.height());
+    let bounds = bar.bounds();
+    let inner_width = area.width().saturating_sub(4);
+    let inner_height = area.height().saturating_sub(4);
+    Painter::new(&mut bar).rect(bounds, Rgb::WHITE);
+    for step in 1..=SPLASH_STEPS {
+        let filled = inner_width * step / SPLASH_STEPS;
+        Painter::new(&mut bar).fill_rect(Rect::new(2, 2, filled, inner_height), SPLASH_ACCENT);
+        if let Some(framebuffer) = FRAMEBUFFER.lock().as_mut() {
+            bar.present(framebuffer, area.left as usize, area.top as usize);
+        }
+        time::sleep_ms(SPLASH_DURATION_MS / SPLASH_STEPS as u64);
+    }
+    vga_buffer::redraw_framebuffer_console();
+}
+
+/// Lays out text in a box of character cells, wrapping long lines and dropping the lines that do
+/// not fit.
+struct TextBox<'a, C: Canvas> {
+    canvas: &'a mut C,
+    /// The top left corner of the box in pixels.
+    x: usize,
+    y: usize,
+    columns: usize,
+    rows: usize,
+    row: usize,
+    column: usize,
+}
+
+impl<C: Canvas> Write for TextBox<'_, C> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' || self.column == self.columns {
+                self.row += 1;
+                self.column = 0;
+            }
+            if c == '\n' || self.row >= self.rows {
+                continue;
+            }
+            let (x, y) = (
+                self.x + self.column * CELL_WIDTH,
+                self.y + self.row * CELL_HEIGHT,
+            );
+            fb_console::draw_char(self.canvas, x, y, c, PANIC_FOREGROUND, PANIC_BACKGROUND);
+            self.column += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Draws the panic screen showing `message` over what is on `canvas`.
+fn draw_panic_screen(canvas: &mut impl Canvas, message: fmt::Arguments) {
+    let (width, height) = (canvas.width(), canvas.height());
+    let columns = (width / CELL_WIDTH).saturating_sub(4).min(PANIC_COLUMNS);
+    let rows = (height / CELL_HEIGHT).saturating_sub(4).min(PANIC_ROWS);
+
+    let mut painter = Painter::new(canvas);
+    // Darken the console output leading up to the panic, leaving it legible
+    painter.set_alpha(PANIC_DIM_ALPHA);
+    painter.fill_rect(painter.clip(), Rgb::BLACK);
+    painter.set_alpha(u8::MAX);
+    // The title and the closing line, with a blank row each, and at least one row of the message
+    if columns == 0 || rows < 5 {
+        return;
+    }
+
+    let (panel_width, panel_height) = ((columns + 2) * CELL_WIDTH, (rows + 2) * CELL_HEIGHT);
+    let panel = Rect::new(
+        ((width - panel_width) / 2) as isize,
+        ((height - panel_height) / 2) as isize,
+        panel_width,
+        panel_height,
+    );
+    painter.fill_rect(panel, PANIC_BACKGROUND);
+    let border = Rect {
+        left: panel.left + 4,
+        top: panel.top + 4,
+        right: panel.right - 4,
+        bottom: panel.bottom - 4,
+    };
+    painter.rect(border, PANIC_FOREGROUND);
+
+    // A warning sign in the first two cells of the title row
+    let (text_x, text_y) = (
+        panel.left as usize + CELL_WIDTH,
+        panel.top as usize + CELL_HEIGHT,
+    );
+    let (left, top) = (text_x as isize, text_y as isize);
+    let (size, middle) = (
+        CELL_HEIGHT as isize,
+        text_x as isize + CELL_HEIGHT as isize / 2,
+    );
+    painter.line(middle, top, left, top + size - 1, PANIC_FOREGROUND);
+    painter.line(
+        middle,
+        top,
+        left + size - 1,
+        top + size - 1,
+        PANIC_FOREGROUND,
+    );
+    painter.line(
+        left,
+        top + size - 1,
+        left + size - 1,
+        top + size - 1,
+        PANIC_FOREGROUND,
+    );
+    painter.fill_rect(Rect::new(middle, top + 5, 1, 6), PANIC_FOREGROUND);
+    painter.fill_rect(Rect::new(middle, top + size - 4, 1, 1), PANIC_FOREGROUND);
+
+    let title = "Kernel panic";
+    let closing = "The machine has been halted.";
+    fb_console::draw_text(
+        canvas,
+        text_x + 3 * CELL_WIDTH,
+        text_y,
+        title,
+        PANIC_FOREGROUND,
+        PANIC_BACKGROUND,
+    );
+    fb_console::draw_text(
+        canvas,
+        text_x,
+        text_y + (rows - 1) * CELL_HEIGHT,
+        &closing[..closing.len().min(columns)],
+        PANIC_FOREGROUND,
+        PANIC_BACKGROUND,
+    );
+    let mut message_box = TextBox {
+        canvas,
+        x: text_x,
+        y: text_y + 2 * CELL_HEIGHT,
+        columns,
+        rows: rows - 4,
+        row: 0,
+        column: 0,
+    };
+    let _ = message_box.write_fmt(message);
+}
+
+/// Draws the panic screen showing `info` over the framebuffer, if there is one.
+///
+/// Any holder of `FRAMEBUFFER` is assumed to never resume, which holds when the caller is about to
+/// halt the machine.
+pub fn show_panic_screen(info: &PanicInfo) {
+    if PANIC_SCREEN_SHOWN.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    // SAFETY: the caller guarantees the holder never resumes
+    let mut framebuffer = unsafe { FRAMEBUFFER.force_lock() };
+    if let Some(framebuffer) = framebuffer.as_mut() {
+        draw_panic_screen(framebuffer, format_args!("{}", info));
+    }
+}
+
+#[cfg(test)]
+use crate::framebuffer::{test_framebuffer, PixelFormat};
+
+#[test_case]
+fn test_draw_splash() {
+    let mut framebuffer = test_framebuffer(240, 180, PixelFormat::Bgr);
+    let area = draw_splash(&mut framebuffer);
+    assert!(framebuffer.bounds().contains(area));
+    assert_eq!(
+        (area.width(), area.height()),
+        (PROGRESS_WIDTH, PROGRESS_HEIGHT)
+    );
+    assert_eq!(framebuffer.pixel(0, 0), Some(SPLASH_TOP));
+    // Within the logo, clear of its highlight
+    let (radius, center_y) = (180 / 8, 90 - 180 / 8);
+    assert_eq!(
+        framebuffer.pixel(120 + radius * 2 / 3, center_y),
+        Some(SPLASH_ACCENT)
+    );
+    // The label, blended with the background
+    let label_y = center_y + radius + CELL_HEIGHT;
+    let label = (label_y..label_y + CELL_HEIGHT)
+        .flat_map(|y| (90..150).map(move |x| (x, y)))
+        .filter(|&(x, y)| framebuffer.pixel(x, y) == Some(Rgb::WHITE))
+        .count();
+    assert!(label > 0);
+}
+
+#[test_case]
+fn test_draw_panic_screen() {
+    let (width, height) = (20 * CELL_WIDTH, 10 * CELL_HEIGHT);
+    let mut framebuffer = test_framebuffer(width, height, PixelFormat::Rgb);
+    framebuffer.clear(Rgb::WHITE);
+    draw_panic_screen(
+        &mut framebuffer,
+        format_args!("{}\n{}", "x".repeat(20), "y"),
+    );
+    assert_eq!(framebuffer.pixel(0, 0), Some(Rgb::new(63, 63, 63)));
+    // The panel of 16 by 6 cells, with a border
+    let (left, top) = (CELL_WIDTH, CELL_HEIGHT);
+    assert_eq!(framebuffer.pixel(left + 1, top + 1), Some(PANIC_BACKGROUND));
+    assert_eq!(framebuffer.pixel(left + 4, top + 4), Some(PANIC_FOREGROUND));
+    // The message wraps after 16 columns into the 2 message rows, dropping the rest
+    let text_row = |row: usize| {
+        let (x, y) = (left + CELL_WIDTH, top + (3 + row) * CELL_HEIGHT);
+        (y..y + CELL_HEIGHT)
+            .flat_map(|y| (x..x + 16 * CELL_WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| framebuffer.pixel(x, y) != Some(PANIC_BACKGROUND))
+            .count()
+    };
+    assert!(text_row(0) > 0);
+    assert_eq!(text_row(1), text_row(0) / 16 * 4);
+    assert_eq!(text_row(2), 0);
+
+    let mut tiny = test_framebuffer(CELL_WIDTH, CELL_HEIGHT, PixelFormat::Gray);
+    draw_panic_screen(&mut tiny, format_args!("too small"));
+}
@@ -0,0 +1,344 @@
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+use crate::lockdep::{self, LockClass};
+
+/// How many `IrqGuard`s are currently alive.
+static IRQ_OFF_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// Whether interrupts were enabled when the outermost `IrqGuard` was created.
+static IRQ_WERE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A RAII guard that keeps interrupts disabled for as long as it lives.
+///
+/// Guards nest: the interrupt flag is saved by the outermost guard and restored only when the last
+/// live guard is dropped.
+pub struct IrqGuard {
+    // Prevent construction outside of `IrqGuard::new()`
+    _private: (),
+}
+
+impl IrqGuard {
+    /// Disables interrupts, remembering whether they were enabled if this is the outermost guard.
+    pub fn new() -> IrqGuard {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        // NOTE: interrupts are disabled from here on, so the two statics can't be raced by an
+        // interrupt handler on this (only) CPU.
+        if IRQ_OFF_DEPTH.fetch_add(1, Ordering::Acquire) == 0 {
+            IRQ_WERE_ENABLED.store(were_enabled, Ordering::Relaxed);
+        }
+        IrqGuard { _private: () }
+    }
+
+    /// Returns the number of currently live `IrqGuard`s.
+    pub fn depth() -> usize {
+        IRQ_OFF_DEPTH.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for IrqGuard {
+    fn default() -> Self {
+        IrqGuard::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        let depth = IRQ_OFF_DEPTH.fetch_sub(1, Ordering::Release);
+        assert!(depth > 0, "IrqGuard depth underflow");
+        if depth == 1 && IRQ_WERE_ENABLED.load(Ordering::Relaxed) {
+            interrupts::enable();
+        }
+    }
+}
+
+/// The lock class name used for locks created without a name.
+const UNNAMED: &str = "<unnamed>";
+
+/// A plain spinlock which leaves the interrupt flag alone.
+///
+/// Only suitable for data that is never accessed from interrupt handlers, or only from interrupt
+/// handlers. Use `IrqSpinlock` otherwise.
+pub struct Spinlock<T: ?Sized> {
+    class: LockClass,
+    inner: Mutex<T>,
+}
+
+/// A guard granting access to the data protected by a `Spinlock`.
+pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
+    class: &'a LockClass,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> Spinlock<T> {
+    /// Creates a new unlocked `Spinlock` wrapping `value`.
+    pub const fn new(value: T) -> Spinlock<T> {
+        Spinlock::with_name(UNNAMED, value)
+    }
+
+    /// Creates a new unlocked `Spinlock` wrapping `value`, named `name` for lock validation.
+    pub const fn with_name(name: &'static str, value: T) -> Spinlock<T> {
+        Spinlock {
+            class: LockClass::new(name),
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Consumes the lock and returns the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Spinlock<T> {
+    /// Spins until the lock is acquired.
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        lockdep::acquire(&self.class, interrupts::are_enabled());
+        SpinlockGuard {
+            class: &self.class,
+            guard: self.inner.lock(),
+        }
+    }
+
+    /// Tries to acquire the lock once, returning `None` if it is already held.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        lockdep::acquire_try(&self.class, interrupts::are_enabled());
+        Some(SpinlockGuard {
+            class: &self.class,
+            guard,
+        })
+    }
+
+    /// Returns whether the lock is currently held.
+    ///
+    /// The result may be stale by the time it is acted upon.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T: ?Sized> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Locking an `IrqSpinlock` saves the interrupt flag and disables interrupts before spinning on the
+/// lock, and the returned guard restores the flag after releasing it. This makes the lock safe to
+/// share between regular kernel code and interrupt handlers on a single CPU without wrapping every
+/// access in `without_interrupts`.
+pub struct IrqSpinlock<T: ?Sized> {
+    class: LockClass,
+    inner: Mutex<T>,
+}
+
+/// A guard granting access to the data protected by an `IrqSpinlock`.
+///
+/// Interrupts stay disabled until the guard is dropped.
+pub struct IrqSpinlockGuard<'a, T: ?Sized + 'a> {
+    class: &'a LockClass,
+    // NOTE: fields are dropped in declaration order, so the lock is released before interrupts are
+    // restored.
+    guard: MutexGuard<'a, T>,
+    _irq: IrqGuard,
+}
+
+impl<T> IrqSpinlock<T> {
+    /// Creates a new unlocked `IrqSpinlock` wrapping `value`.
+    pub const fn new(value: T) -> IrqSpinlock<T> {
+        IrqSpinlock::with_name(UNNAMED, value)
+    }
+
+    /// Creates a new unlocked `IrqSpinlock` wrapping `value`, named `name` for lock validation.
+    pub const fn with_name(name: &'static str, value: T) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            class: LockClass::new(name),
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Consumes the lock and returns the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// Disables interrupts and spins until the lock is acquired.
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let irq = IrqGuard::new();
+        lockdep::acquire(&self.class, false);
+        IrqSpinlockGuard {
+            class: &self.class,
+            guard: self.inner.lock(),
+            _irq: irq,
+        }
+    }
+
+    /// Tries to acquire the lock once, returning `None` if it is already held.
+    ///
+    /// Interrupts are left untouched if the lock could not be acquired.
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        let guard = self.inner.try_lock()?;
+        lockdep::acquire_try(&self.class, false);
+        Some(IrqSpinlockGuard {
+            class: &self.class,
+            guard,
+            _irq: irq,
+        })
+    }
+
+    /// Returns whether the lock is currently held.
+    ///
+    /// The result may be stale by the time it is acted upon.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Acquires the lock, forcibly releasing it first if it is already held.
+    ///
+    /// # Safety
+    ///
+    /// The current holder of the lock, if any, must never touch the data again. Only meant for
+    /// last-resort paths such as panic handlers, where the holder is known to never resume.
+    pub unsafe fn force_lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let irq = IrqGuard::new();
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                // SAFETY: guaranteed by the caller; with interrupts disabled on a single CPU the
+                // holder can't be running concurrently
+                unsafe { self.inner.force_unlock() };
+                self.inner.lock()
+            }
+        };
+        lockdep::acquire_try(&self.class, false);
+        IrqSpinlockGuard {
+            class: &self.class,
+            guard,
+            _irq: irq,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
+
+#[test_case]
+fn test_irq_spinlock_disables_interrupts() {
+    static LOCK: IrqSpinlock<u32> = IrqSpinlock::new(0);
+
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = LOCK.lock();
+        assert!(!interrupts::are_enabled());
+        *value += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*LOCK.lock(), 1);
+}
+
+#[test_case]
+fn test_irq_spinlock_temporary_guard() {
+    static LOCK: IrqSpinlock<u32> = IrqSpinlock::new(1);
+
+    // A guard only living for the statement re-enables interrupts at its end
+    assert_eq!(*LOCK.lock(), 1);
+    assert!(interrupts::are_enabled());
+    *LOCK.lock() += 1;
+    assert!(interrupts::are_enabled());
+    assert_eq!(*LOCK.lock(), 2);
+}
+
+#[test_case]
+fn test_irq_spinlock_nesting() {
+    static OUTER: IrqSpinlock<()> = IrqSpinlock::new(());
+    static INNER: IrqSpinlock<()> = IrqSpinlock::new(());
+
+    assert!(interrupts::are_enabled());
+    let outer = OUTER.lock();
+    let inner = INNER.lock();
+    assert_eq!(IrqGuard::depth(), 2);
+    drop(inner);
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert_eq!(IrqGuard::depth(), 0);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_irq_spinlock_keeps_interrupts_disabled() {
+    static LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+
+    interrupts::without_interrupts(|| {
+        drop(LOCK.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_irq_spinlock_try_lock() {
+    static LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+
+    let guard = LOCK.lock();
+    assert!(LOCK.is_locked());
+    assert!(LOCK.try_lock().is_none());
+    drop(guard);
+    assert!(LOCK.try_lock().is_some());
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_irq_spinlock_force_lock() {
+    static LOCK: IrqSpinlock<u32> = IrqSpinlock::new(0);
+
+    // Leave the lock held as if its holder never returned
+    core::mem::forget(LOCK.inner.lock());
+    assert!(LOCK.is_locked());
+    // SAFETY: the forgotten guard is never used again
+    *unsafe { LOCK.force_lock() } = 1;
+    assert!(!LOCK.is_locked());
+    assert_eq!(*LOCK.lock(), 1);
+    assert!(interrupts::are_enabled());
+}
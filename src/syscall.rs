@@ -0,0 +1,563 @@
+//! System calls, through which user code requests services from the kernel.
+//!
+//! User code makes a system call with the `SYSCALL` instruction, or alternatively by trapping
+//! through `usermode::TRAP_VECTOR` (`int 0x80`). Both use the same ABI:
+//!
+//! - `rax`: the system call number on entry, the return value on exit
+//! - `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`: the arguments, in order
+//!
+//! All other registers are preserved, except that `SYSCALL` itself clobbers `rcx` and `r11`.
+//! A return value in `-4095..0` (as `i64`) is an `Error`, whose discriminant is the value as it
+//! is, without negating it; anything else is a success.
+
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    address_space::AreaError,
+    gdt,
+    loader::{self, LoadError},
+    memory, print,
+    process::{self, Pid, Process, WaitError},
+    signal::{self, Signal, SignalAction, SignalSet},
+    time,
+    usermode::UserContext,
+    vma::Protection,
+};
+
+/// `exit(code)`: stops running the calling user code with `code` as its exit code.
+pub const EXIT: u64 = 0;
+/// `write(buffer, length) -> length`: writes a UTF-8 string to the console.
+pub const WRITE: u64 = 1;
+/// `yield() -> 0`: lets the kernel and other threads run before resuming.
+pub const YIELD: u64 = 2;
+/// `sleep(milliseconds) -> 0`: waits for at least the given time.
+pub const SLEEP: u64 = 3;
+/// `time() -> milliseconds`: returns the time since boot.
+pub const TIME: u64 = 4;
+/// `getpid() -> pid`: returns the process ID of the calling process.
+pub const GETPID: u64 = 5;
+/// `getppid() -> pid`: returns the process ID of the parent of the calling process.
+pub const GETPPID: u64 = 6;
+/// `wait(pid, status) -> pid`: waits for the child process `pid`, or any child if `pid` is
+/// `u64::MAX`, to exit. Stores its exit status at `status` unless it is null, and returns its
+/// process ID.
+pub const WAIT: u64 = 7;
+/// `fork() -> pid`: creates a child process as a copy of the calling one. Returns the process ID
+/// of the child in the parent, and 0 in the child.
+pub const FORK: u64 = 8;
+/// `exec(image, length, arguments) -> error`: replaces the program of the calling process with the
+/// ELF executable in the `length` bytes at `image`. `arguments` points to a null terminated array
+/// of pointers to NUL terminated strings, or is null for no arguments. Only returns on failure.
+pub const EXEC: u64 = 9;
+/// `mmap(address, length, protection) -> address`: reserves `length` bytes of zero-filled memory,
+/// at `address` unless it is null, with `protection` being a combination of `PROT_READ`,
+/// `PROT_WRITE` and `PROT_EXEC`. Pages are only allocated once they are accessed.
+pub const MMAP: u64 = 10;
+/// `munmap(address, length) -> 0`: releases memory reserved with `mmap`.
+pub const MUNMAP: u64 = 11;
+/// `brk(address) -> address`: moves the end of the memory area right after the program image to
+/// `address`, and returns the new end. Returns the unchanged end on failure, or if `address` is
+/// null.
+pub const BRK: u64 = 12;
+/// `sigaction(signal, handler, restorer) -> handler`: sets the action of `signal` to `SIG_DFL`,
+/// `SIG_IGN`, or calling `handler`, which returns to `restorer` making a `sigreturn` system call.
+/// Returns the previous handler, `SIG_DFL` or `SIG_IGN`.
+pub const SIGACTION: u64 = 13;
+/// `sigprocmask(how, set) -> set`: changes the blocked signals by `SIG_BLOCK`ing, `SIG_UNBLOCK`ing
+/// or `SIG_SETMASK`ing to `set`, with signal `n` in bit `n - 1`. Returns the previous mask.
+pub const SIGPROCMASK: u64 = 14;
+/// `kill(pid, signal) -> 0`: sends `signal` to the process `pid`, or just checks whether the
+/// process exists if `signal` is 0.
+pub const KILL: u64 = 15;
+/// `sigreturn()`: returns from a signal handler, restoring the state saved on the stack when the
+/// handler was invoked. Made by the restorer of the handler, with the stack pointer right above
+/// the return address of the handler.
+pub const SIGRETURN: u64 = 16;
+
+/// `mmap` protection bits.
+pub const PROT_READ: u64 = Protection::READ.bits();
+pub const PROT_WRITE: u64 = Protection::WRITE.bits();
+pub const PROT_EXEC: u64 = Protection::EXECUTE.bits();
+
+/// `sigaction` handlers for the default action and ignoring the signal.
+pub use signal::{SIG_DFL, SIG_IGN};
+
+/// `sigprocmask` operations.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// The maximum number of arguments passed to `exec`.
+const MAX_ARGUMENTS: usize = 256;
+/// The maximum length of a string argument, excluding the terminating NUL.
+const MAX_STRING_LENGTH: u64 = 4096;
+
+/// The reasons a system call can fail. The discriminants are negative, and returned as they are in
+/// `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Error {
+    /// The system call number is unknown.
+    NoSuchSyscall = -1,
+    /// An argument is invalid.
+    InvalidArgument = -2,
+    /// A pointer argument does not point to accessible user memory.
+    BadAddress = -3,
+    /// The system call can only be made by a process, or the target process does not exist.
+    NoSuchProcess = -4,
+    /// The calling process has no matching child processes.
+    NoChildren = -5,
+    /// There is not enough memory to complete the system call.
+    OutOfMemory = -6,
+    /// The file is not a valid executable.
+    NotExecutable = -7,
+    /// A blocked system call was interrupted by a signal handler.
+    Interrupted = -8,
+}
+
+impl Error {
+    /// Returns the value `rax` is set to when a system call fails with this error.
+    pub fn as_return_value(self) -> u64 {
+        self as i64 as u64
+    }
+}
+
+/// What the caller of `dispatch()` is to do with the user code that made a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Resume the user code, with the return value in `rax`.
+    Resume,
+    /// Resume the user code after giving others a chance to run.
+    Yield,
+    /// The system call cannot complete yet. Call `dispatch()` again later, without resuming the
+    /// user code in between.
+    Block,
+    /// Stop running the user code with an exit code.
+    Exit(u64),
+}
+
+/// The result of a system call handler.
+enum Outcome {
+    /// Resume the user code with the value in `rax`.
+    Return(u64),
+    /// Resume the user code with 0 in `rax` after others have had a chance to run.
+    Yield,
+    /// Retry the system call later.
+    Block,
+    /// Stop running the user code with an exit code.
+    Exit(u64),
+}
+
+/// The arguments of a system call, in ABI order.
+type Arguments = [u64; 6];
+
+/// A system call handler, which gets the context of the calling user code for system calls that
+/// need more than the arguments.
+type Handler = fn(&mut UserContext, &Arguments) -> Result<Outcome, Error>;
+
+/// The system call handlers, indexed by system call number.
+static SYSCALL_TABLE: [Handler; 17] = [
+    sys_exit,
+    sys_write,
+    sys_yield,
+    sys_sleep,
+    sys_time,
+    sys_getpid,
+    sys_getppid,
+    sys_wait,
+    sys_fork,
+    sys_exec,
+    sys_mmap,
+    sys_munmap,
+    sys_brk,
+    sys_sigaction,
+    sys_sigprocmask,
+    sys_kill,
+    sys_sigreturn,
+];
+
+/// Enables the `SYSCALL` instruction and points it at the user mode entry point.
+///
+/// Requires the `GDT` to be loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT segment order incompatible with SYSCALL/SYSRET");
+    LStar::write(crate::usermode::init_syscall_entry());
+    // Interrupts stay disabled until the entry point has switched to the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    // SAFETY: enabling SYSCALL/SYSRET has no effect on memory safety
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Serves the system call requested by the user code in `context`, which has just entered the
+/// kernel, and returns what to do next.
+///
+/// Stores the return value in `rax` unless the system call blocks or exits. The address space of
+/// the user code must be active.
+pub fn dispatch(context: &mut UserContext) -> Action {
+    let arguments = [
+        context.rdi,
+        context.rsi,
+        context.rdx,
+        context.r10,
+        context.r8,
+        context.r9,
+    ];
+    let result = usize::try_from(context.rax)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number))
+        .ok_or(Error::NoSuchSyscall)
+        .and_then(|handler| handler(context, &arguments));
+    let (action, value) = match result {
+        Ok(Outcome::Return(value)) => (Action::Resume, value),
+        Ok(Outcome::Yield) => (Action::Yield, 0),
+        Ok(Outcome::Block) => return Action::Block,
+        Ok(Outcome::Exit(code)) => return Action::Exit(code),
+        Err(error) => (Action::Resume, error.as_return_value()),
+    };
+    context.rax = value;
+    action
+}
+
+fn sys_exit(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    Ok(Outcome::Exit(arguments[0]))
+}
+
+/// Maps the pages of `[start, start + size)` in memory areas of the calling process that were not
+/// accessed yet, so that they pass the user memory checks.
+fn populate(start: VirtAddr, size: u64, access: Protection) {
+    if let Some(pid) = process::current() {
+        process::with_address_space(pid, |address_space| {
+            address_space.populate(start, size, access)
+        });
+    }
+}
+
+/// Returns the `length` bytes at `address` in user memory.
+///
+/// NOTE: the bytes are only valid while the address space stays active and unchanged.
+fn user_bytes<'a>(address: u64, length: u64) -> Result<&'a [u8], Error> {
+    let start = VirtAddr::try_new(address).map_err(|_| Error::BadAddress)?;
+    populate(start, length, Protection::READ);
+    if !memory::is_user_accessible(start, length) {
+        return Err(Error::BadAddress);
+    }
+    // SAFETY: the range was checked to be mapped user memory, which the kernel can read too
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), length as usize) })
+}
+
+/// Returns the NUL terminated UTF-8 string at `address` in user memory.
+///
+/// NOTE: the string is only valid while the address space stays active and unchanged.
+fn user_string<'a>(address: u64) -> Result<&'a str, Error> {
+    let mut length = 0;
+    // NOTE: checking byte by byte is slow but simple
+    while user_bytes(address.wrapping_add(length), 1)?[0] != 0 {
+        length += 1;
+        if length > MAX_STRING_LENGTH {
+            return Err(Error::InvalidArgument);
+        }
+    }
+    core::str::from_utf8(user_bytes(address, length)?).map_err(|_| Error::InvalidArgument)
+}
+
+fn sys_write(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [buffer, length, ..] = *arguments;
+    let bytes = user_bytes(buffer, length)?;
+    let string = core::str::from_utf8(bytes).map_err(|_| Error::InvalidArgument)?;
+    print!("{}", string);
+    Ok(Outcome::Return(length))
+}
+
+fn sys_yield(_: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    Ok(Outcome::Yield)
+}
+
+fn sys_sleep(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    time::sleep_ms(arguments[0]);
+    Ok(Outcome::Return(0))
+}
+
+fn sys_time(_: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    Ok(Outcome::Return(time::uptime_ms()))
+}
+
+fn sys_getpid(_: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    Ok(Outcome::Return(pid.as_u64()))
+}
+
+fn sys_getppid(_: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let parent = process::with_process(pid, Process::parent).ok_or(Error::NoSuchProcess)?;
+    Ok(Outcome::Return(parent.as_u64()))
+}
+
+fn sys_wait(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [target, status, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let target = (target != u64::MAX).then(|| Pid::new(target));
+    let status = VirtAddr::try_new(status).map_err(|_| Error::BadAddress)?;
+    populate(status, 8, Protection::WRITE);
+    if !status.is_null() && !memory::is_user_writable(status, 8) {
+        return Err(Error::BadAddress);
+    }
+    match process::try_wait(pid, target) {
+        Ok(Some((child, exit_status))) => {
+            if !status.is_null() {
+                // SAFETY: the address was checked to be writable user memory
+                unsafe { status.as_mut_ptr::<u64>().write_unaligned(exit_status) };
+            }
+            Ok(Outcome::Return(child.as_u64()))
+        }
+        Ok(None) => Ok(Outcome::Block),
+        Err(WaitError::NoChildren) => Err(Error::NoChildren),
+    }
+}
+
+fn sys_fork(context: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let child = process::fork(pid, context).ok_or(Error::OutOfMemory)?;
+    Ok(Outcome::Return(child.as_u64()))
+}
+
+fn sys_exec(context: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [image, length, argument_array, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let image = user_bytes(image, length)?;
+    let mut program_arguments = Vec::new();
+    if argument_array != 0 {
+        for index in 0.. {
+            let address = argument_array.wrapping_add(8 * index);
+            let pointer = user_bytes(address, 8)?;
+            let pointer = u64::from_le_bytes(pointer.try_into().unwrap());
+            if pointer == 0 {
+                break;
+            }
+            if program_arguments.len() == MAX_ARGUMENTS {
+                return Err(Error::InvalidArgument);
+            }
+            program_arguments.push(user_string(pointer)?);
+        }
+    }
+    // NOTE: the image and arguments are read from the old address space, which is still active
+    let program = loader::load(image, &program_arguments, &[]).map_err(|error| match error {
+        LoadError::OutOfMemory => Error::OutOfMemory,
+        LoadError::ArgumentsTooLarge => Error::InvalidArgument,
+        _ => Error::NotExecutable,
+    })?;
+    *context = process::exec(pid, program).ok_or(Error::NoSuchProcess)?;
+    // NOTE: the new program starts with 0 in `rax`, which is stored as the return value
+    Ok(Outcome::Return(0))
+}
+
+fn sys_mmap(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [address, length, protection, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let protection = Protection::from_bits(protection).ok_or(Error::InvalidArgument)?;
+    let address = match address {
+        0 => None,
+        address => Some(VirtAddr::try_new(address).map_err(|_| Error::InvalidArgument)?),
+    };
+    let start = process::with_address_space(pid, |address_space| {
+        address_space.map_anonymous(address, length, protection)
+    })
+    .ok_or(Error::NoSuchProcess)?
+    .map_err(area_error)?;
+    Ok(Outcome::Return(start.as_u64()))
+}
+
+fn sys_munmap(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [address, length, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let address = VirtAddr::try_new(address).map_err(|_| Error::InvalidArgument)?;
+    process::with_address_space(pid, |address_space| address_space.unmap(address, length))
+        .ok_or(Error::NoSuchProcess)?
+        .map_err(area_error)?;
+    Ok(Outcome::Return(0))
+}
+
+fn sys_brk(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let program_break = process::with_address_space(pid, |address_space| {
+        if let Ok(address) = VirtAddr::try_new(arguments[0]) {
+            if !address.is_null() {
+                // NOTE: failures are reported by returning the unchanged break
+                let _ = address_space.set_program_break(address);
+            }
+        }
+        address_space.program_break()
+    })
+    .ok_or(Error::NoSuchProcess)?;
+    Ok(Outcome::Return(program_break.as_u64()))
+}
+
+fn sys_sigaction(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [signal, handler, restorer, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let signal = Signal::new(signal).ok_or(Error::InvalidArgument)?;
+    let action = match handler {
+        SIG_DFL => SignalAction::Default,
+        SIG_IGN => SignalAction::Ignore,
+        handler => {
+            let handler = user_code_address(handler)?;
+            let restorer = user_code_address(restorer)?;
+            SignalAction::Handler { handler, restorer }
+        }
+    };
+    let previous = process::with_signals(pid, |signals| signals.set_action(signal, action))
+        .ok_or(Error::NoSuchProcess)?
+        .map_err(|_| Error::InvalidArgument)?;
+    Ok(Outcome::Return(match previous {
+        SignalAction::Default => SIG_DFL,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Handler { handler, .. } => handler.as_u64(),
+    }))
+}
+
+/// Returns `address` if it lies in user space.
+fn user_code_address(address: u64) -> Result<VirtAddr, Error> {
+    VirtAddr::try_new(address)
+        .ok()
+        .filter(|&address| memory::is_user_range(address, 1))
+        .ok_or(Error::BadAddress)
+}
+
+fn sys_sigprocmask(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [how, set, ..] = *arguments;
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let previous = process::with_signals(pid, |signals| {
+        let blocked = signals.blocked().0;
+        let blocked = match how {
+            SIG_BLOCK => blocked | set,
+            SIG_UNBLOCK => blocked & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Error::InvalidArgument),
+        };
+        Ok(signals.set_blocked(SignalSet(blocked)))
+    })
+    .ok_or(Error::NoSuchProcess)??;
+    Ok(Outcome::Return(previous.0))
+}
+
+fn sys_kill(_: &mut UserContext, arguments: &Arguments) -> Result<Outcome, Error> {
+    let [target, signal, ..] = *arguments;
+    process::current().ok_or(Error::NoSuchProcess)?;
+    let target = Pid::new(target);
+    let sent = match signal {
+        0 => process::with_signals(target, |_| ()).is_some(),
+        signal => process::kill(target, Signal::new(signal).ok_or(Error::InvalidArgument)?),
+    };
+    if !sent {
+        return Err(Error::NoSuchProcess);
+    }
+    Ok(Outcome::Return(0))
+}
+
+fn sys_sigreturn(context: &mut UserContext, _: &Arguments) -> Result<Outcome, Error> {
+    let pid = process::current().ok_or(Error::NoSuchProcess)?;
+    let (restored, blocked) = match signal::pop_frame(context) {
+        Some(frame) => frame,
+        None => {
+            // The stack was clobbered, there is nothing sensible to return to
+            process::with_signals(pid, |signals| signals.force(Signal::SIGSEGV));
+            return Err(Error::BadAddress);
+        }
+    };
+    process::with_signals(pid, |signals| signals.set_blocked(blocked));
+    *context = restored;
+    // NOTE: the return value is stored in `rax`, which thus has to be the restored one
+    Ok(Outcome::Return(context.rax))
+}
+
+fn area_error(error: AreaError) -> Error {
+    match error {
+        AreaError::BadRange | AreaError::Overlap => Error::InvalidArgument,
+        AreaError::NoSpace => Error::OutOfMemory,
+    }
+}
+
+#[cfg(test)]
+fn syscall_context(number: u64, arguments: &Arguments) -> UserContext {
+    UserContext {
+        rax: number,
+        rdi: arguments[0],
+        rsi: arguments[1],
+        rdx: arguments[2],
+        r10: arguments[3],
+        r8: arguments[4],
+        r9: arguments[5],
+        ..UserContext::default()
+    }
+}
+
+#[test_case]
+fn test_dispatch_unknown_syscall() {
+    let mut context = syscall_context(SYSCALL_TABLE.len() as u64, &[0; 6]);
+    assert_eq!(dispatch(&mut context), Action::Resume);
+    assert_eq!(context.rax, Error::NoSuchSyscall.as_return_value());
+    assert_eq!(context.rax, u64::MAX);
+}
+
+#[test_case]
+fn test_dispatch_exit() {
+    let mut context = syscall_context(EXIT, &[7, 0, 0, 0, 0, 0]);
+    assert_eq!(dispatch(&mut context), Action::Exit(7));
+}
+
+#[test_case]
+fn test_dispatch_write_rejects_kernel_memory() {
+    let message = "kernel";
+    let mut context = syscall_context(
+        WRITE,
+        &[message.as_ptr() as u64, message.len() as u64, 0, 0, 0, 0],
+    );
+    assert_eq!(dispatch(&mut context), Action::Resume);
+    assert_eq!(context.rax, Error::BadAddress.as_return_value());
+}
+
+#[test_case]
+fn test_dispatch_write_rejects_non_canonical_address() {
+    let mut context = syscall_context(WRITE, &[0x0000_8000_0000_0000, 1, 0, 0, 0, 0]);
+    assert_eq!(dispatch(&mut context), Action::Resume);
+    assert_eq!(context.rax, Error::BadAddress.as_return_value());
+}
+
+#[test_case]
+fn test_dispatch_process_syscalls_outside_process() {
+    for &number in [
+        GETPID,
+        GETPPID,
+        WAIT,
+        FORK,
+        EXEC,
+        MMAP,
+        MUNMAP,
+        BRK,
+        SIGACTION,
+        SIGPROCMASK,
+        KILL,
+        SIGRETURN,
+    ]
+    .iter()
+    {
+        let mut context = syscall_context(number, &[u64::MAX, 0, 0, 0, 0, 0]);
+        assert_eq!(dispatch(&mut context), Action::Resume);
+        assert_eq!(context.rax, Error::NoSuchProcess.as_return_value());
+    }
+}
@@ -0,0 +1,79 @@
+//! Timekeeping based on the timer interrupt.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::cmdline::Param;
+
+/// The input clock frequency of the programmable interval timer in Hz.
+pub(crate) const PIT_FREQUENCY: u64 = 1_193_182;
+/// The divisor the firmware programs the PIT with (a reload value of 0 means 65536).
+const FIRMWARE_DIVISOR: u64 = 65536;
+
+/// The timer interrupt frequency in Hz, or 0 to keep the firmware rate of about 18.2 Hz.
+pub static TIMER_HZ: Param<u64> = Param::with_check(
+    "timer_hz",
+    "timer interrupt frequency in Hz, 0 for the firmware rate",
+    0,
+    |hz| hz == 0 || (PIT_FREQUENCY / FIRMWARE_DIVISOR + 1..=PIT_FREQUENCY).contains(&hz),
+);
+
+/// The divisor the PIT is programmed with.
+static DIVISOR: AtomicU64 = AtomicU64::new(FIRMWARE_DIVISOR);
+
+/// The number of timer interrupts since interrupts were enabled.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT with the frequency set with the `timer_hz` boot option. Must be called before
+/// interrupts are enabled.
+pub(crate) fn init() {
+    let divisor = match TIMER_HZ.get() {
+        0 => FIRMWARE_DIVISOR,
+        hz => PIT_FREQUENCY / hz,
+    };
+    DIVISOR.store(divisor, Ordering::Relaxed);
+    // SAFETY: programming channel 0 only changes the rate of the timer interrupt
+    unsafe {
+        // channel 0, low and high byte, rate generator, binary
+        Port::new(0x43).write(0x34u8);
+        Port::new(0x40).write(divisor as u8);
+        Port::new(0x40).write((divisor >> 8) as u8);
+    }
+}
+
+/// Accounts for a timer interrupt. Called by the timer interrupt handler itself, in interrupt
+/// context, unlike the rest of the timer handling, which is deferred to `workqueue::IRQ_WORK`.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since interrupts were enabled in milliseconds.
+///
+/// The resolution is one timer period, about 55 ms unless set otherwise with `timer_hz`.
+pub fn uptime_ms() -> u64 {
+    ticks() * DIVISOR.load(Ordering::Relaxed) * 1000 / PIT_FREQUENCY
+}
+
+/// Waits for at least `ms` milliseconds, running the kernel worker and halting the CPU meanwhile.
+///
+/// Must be called with interrupts enabled.
+pub fn sleep_ms(ms: u64) {
+    let deadline = uptime_ms().saturating_add(ms);
+    while uptime_ms() < deadline {
+        crate::idle();
+    }
+}
+
+#[test_case]
+fn test_sleep_ms() {
+    let start = uptime_ms();
+    sleep_ms(100);
+    assert!(uptime_ms() >= start + 100);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
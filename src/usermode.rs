@@ -0,0 +1,381 @@
+//! Execution of code in user mode (ring 3).
+//!
+//! The kernel runs user code by loading a `UserContext` and returning to ring 3 with `IRETQ`, or
+//! with the faster `SYSRET` when the context allows it. User code gets back into the kernel with
+//! the `SYSCALL` instruction or by trapping through `TRAP_VECTOR` (`int 0x80`). Either way the user
+//! registers are saved back into the same `UserContext` and `enter()` returns as if it were an
+//! ordinary function call. The kernel can then inspect the context, e.g. to serve a system call,
+//! and resume user code by calling `enter()` again.
+//!
+//! CPU exceptions caused by user code, such as page faults, return from `enter()` the same way,
+//! reporting the `Fault` instead of a system call. See `interrupts` for their entry points.
+
+use core::arch::global_asm;
+
+use x86_64::{registers::rflags::RFlags, structures::idt::PageFaultErrorCode, VirtAddr};
+
+use crate::{
+    gdt, interrupts, memory,
+    signal::Signal,
+    syscall::{self, Action},
+    workqueue,
+};
+
+/// The interrupt vector through which user code traps into the kernel.
+pub const TRAP_VECTOR: u8 = 0x80;
+
+/// The register state of user code.
+///
+/// NOTE: the layout is relied upon by the assembly in this module. The general purpose registers
+/// come first in `POP` order, followed by an `IRETQ` frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<UserContext>(), 20 * 8);
+
+/// The CPU exceptions user code can cause, identified by their vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    InvalidOpcode = 6,
+    GeneralProtection = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    SimdFloatingPoint = 19,
+}
+
+impl Exception {
+    fn from_vector(vector: u64) -> Option<Exception> {
+        match vector {
+            0 => Some(Exception::DivideError),
+            6 => Some(Exception::InvalidOpcode),
+            13 => Some(Exception::GeneralProtection),
+            14 => Some(Exception::PageFault),
+            16 => Some(Exception::X87FloatingPoint),
+            19 => Some(Exception::SimdFloatingPoint),
+            _ => None,
+        }
+    }
+}
+
+/// A CPU exception caused by user code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub exception: Exception,
+    /// The error code pushed by the CPU, or 0 if there is none.
+    pub error_code: u64,
+    /// The accessed address for page faults.
+    pub address: VirtAddr,
+}
+
+impl Fault {
+    /// Tries to resolve a page fault, e.g. by copying a page on write, so that user code can retry
+    /// the access. Returns whether it was resolved.
+    pub fn resolve(&self) -> bool {
+        self.exception == Exception::PageFault
+            && interrupts::resolve_page_fault(
+                self.address,
+                PageFaultErrorCode::from_bits_truncate(self.error_code),
+            )
+    }
+}
+
+/// The reason user code entered the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// A system call, with the arguments in the context.
+    Syscall,
+    /// A CPU exception.
+    Fault(Fault),
+}
+
+impl UserContext {
+    /// Creates a context which starts executing at `entry` with the stack pointer at `stack_top`,
+    /// interrupts enabled and all other registers zeroed.
+    pub fn new(entry: VirtAddr, stack_top: VirtAddr) -> UserContext {
+        let selectors = gdt::selectors();
+        UserContext {
+            rip: entry.as_u64(),
+            cs: u64::from(selectors.user_code.0),
+            rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+            rsp: stack_top.as_u64(),
+            ss: u64::from(selectors.user_data.0),
+            ..UserContext::default()
+        }
+    }
+
+    /// Returns whether the context can be resumed with `SYSRET` instead of `IRETQ`.
+    ///
+    /// `SYSRET` takes the instruction pointer from `rcx` and the flags from `r11`, which hold
+    /// exactly these after a `SYSCALL`. It loads the standard user selectors and faults in ring 0
+    /// on a non-canonical instruction pointer, so anything else is left to `IRETQ`.
+    fn can_sysret(&self) -> bool {
+        let selectors = gdt::selectors();
+        self.rcx == self.rip
+            && self.r11 == self.rflags
+            && (memory::USER_SPACE_START..memory::USER_SPACE_END).contains(&self.rip)
+            && self.cs == u64::from(selectors.user_code.0)
+            && self.ss == u64::from(selectors.user_data.0)
+    }
+}
+
+extern "C" {
+    fn __yarhos_enter_user(context: *mut UserContext, sysret: bool);
+    fn __yarhos_user_trap();
+    fn __yarhos_syscall_entry();
+}
+
+/// The kernel stack pointer saved by `__yarhos_enter_user`, restored when user code traps.
+static mut KERNEL_RSP: u64 = 0;
+/// The context user registers are saved into when user code traps.
+static mut CURRENT_CONTEXT: *mut UserContext = core::ptr::null_mut();
+/// The stack `__yarhos_syscall_entry` switches to, i.e. the privilege stack of the TSS.
+static mut SYSCALL_STACK_TOP: u64 = 0;
+/// The user stack pointer, saved by `__yarhos_syscall_entry` while switching stacks.
+static mut SYSCALL_USER_RSP: u64 = 0;
+/// The user code selector, which `SYSCALL` does not save.
+static mut USER_CS: u64 = 0;
+/// The user stack selector, which `SYSCALL` does not save.
+static mut USER_SS: u64 = 0;
+/// The vector of the exception which ended user code execution, or `NO_FAULT`.
+static mut FAULT_VECTOR: u64 = NO_FAULT;
+/// The error code of the exception which ended user code execution.
+static mut FAULT_ERROR_CODE: u64 = 0;
+/// The value of `CR2` when user code execution ended with an exception.
+static mut FAULT_ADDRESS: u64 = 0;
+
+const NO_FAULT: u64 = u64::MAX;
+
+// Entering user mode saves the callee-saved registers and flags of the kernel on the kernel stack,
+// points the stack pointer at the context, pops the general purpose registers from it, and finally
+// returns to ring 3 with the remaining `IRETQ` frame. When returning with `SYSRET`, `rcx` and `r11`
+// already hold the instruction pointer and flags, so only the stack pointer is left to load.
+//
+// On a trap the CPU switches to the privilege stack from the TSS and pushes an interrupt frame,
+// after which the handler pushes the general purpose registers, forming a `UserContext` on the
+// stack. It is copied to the current context, and the kernel stack of `__yarhos_enter_user` is
+// restored so that it appears to return normally.
+//
+// `SYSCALL` switches neither stacks nor saves anything on a stack, so its entry point switches to
+// the privilege stack and builds the same interrupt frame by hand before joining the trap path.
+// `SFMASK` keeps interrupts disabled until the frame is complete.
+//
+// Exceptions from user mode enter through `__yarhos_user_fault` with the vector and the error code
+// pushed on top of the interrupt frame. They are stored away along with `CR2` before joining the
+// trap path, leaving all user registers untouched.
+global_asm!(
+    ".global __yarhos_enter_user",
+    "__yarhos_enter_user:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    // No interrupts while the stack pointer points into the context
+    "cli",
+    "mov [rip + {kernel_rsp}], rsp",
+    "mov [rip + {context}], rdi",
+    "mov rsp, rdi",
+    "test sil, sil",
+    "jnz 2f",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "iretq",
+    "2:",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    // Skip `rip`, `cs` and `rflags` of the frame
+    // NOTE: an NMI here would run on the user stack; there are no NMI handlers yet
+    "mov rsp, [rsp + 24]",
+    "sysretq",
+    "",
+    ".global __yarhos_syscall_entry",
+    "__yarhos_syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {syscall_stack}]",
+    "push qword ptr [rip + {user_ss}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push r11",
+    "push qword ptr [rip + {user_cs}]",
+    "push rcx",
+    "jmp __yarhos_user_trap",
+    "",
+    ".global __yarhos_user_fault",
+    "__yarhos_user_fault:",
+    "pop qword ptr [rip + {fault_vector}]",
+    "pop qword ptr [rip + {fault_error_code}]",
+    "push rax",
+    "mov rax, cr2",
+    "mov [rip + {fault_address}], rax",
+    "pop rax",
+    "jmp __yarhos_user_trap",
+    "",
+    ".global __yarhos_user_trap",
+    "__yarhos_user_trap:",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    // The direction flag is controlled by user code
+    "cld",
+    "mov rsi, rsp",
+    "mov rdi, [rip + {context}]",
+    "mov ecx, 20",
+    "rep movsq",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    kernel_rsp = sym KERNEL_RSP,
+    context = sym CURRENT_CONTEXT,
+    syscall_stack = sym SYSCALL_STACK_TOP,
+    user_rsp = sym SYSCALL_USER_RSP,
+    user_cs = sym USER_CS,
+    user_ss = sym USER_SS,
+    fault_vector = sym FAULT_VECTOR,
+    fault_error_code = sym FAULT_ERROR_CODE,
+    fault_address = sym FAULT_ADDRESS,
+);
+
+/// Returns the address of the trap entry point, to be installed at `TRAP_VECTOR` in the IDT.
+pub(crate) fn trap_entry_address() -> VirtAddr {
+    VirtAddr::from_ptr(__yarhos_user_trap as *const ())
+}
+
+/// Prepares the `SYSCALL` entry point and returns its address, to be written to `LSTAR`.
+pub(crate) fn init_syscall_entry() -> VirtAddr {
+    let selectors = gdt::selectors();
+    // SAFETY: the entry point is not in use before its address is returned
+    unsafe {
+        SYSCALL_STACK_TOP = gdt::privilege_stack_top().as_u64();
+        USER_CS = u64::from(selectors.user_code.0);
+        USER_SS = u64::from(selectors.user_data.0);
+    }
+    VirtAddr::from_ptr(__yarhos_syscall_entry as *const ())
+}
+
+/// Runs user code from the state in `context` until it enters the kernel, and then saves the user
+/// state back into `context` and returns the reason.
+///
+/// # Safety
+///
+/// The instruction and stack pointers of `context` must point to user accessible memory in the
+/// active address space, and the segment selectors must be valid user mode selectors.
+pub unsafe fn enter(context: &mut UserContext) -> Trap {
+    let sysret = context.can_sysret();
+    // SAFETY: the fault statics are only accessed with interrupts disabled on entry to and exit
+    // from user mode, and by this function
+    unsafe {
+        FAULT_VECTOR = NO_FAULT;
+        // SAFETY: guaranteed by the caller
+        __yarhos_enter_user(context, sysret);
+        match Exception::from_vector(FAULT_VECTOR) {
+            Some(exception) => Trap::Fault(Fault {
+                exception,
+                error_code: FAULT_ERROR_CODE,
+                address: VirtAddr::new_truncate(FAULT_ADDRESS),
+            }),
+            None => Trap::Syscall,
+        }
+    }
+}
+
+/// Runs user code from the state in `context` until it makes an exit system call, returning its
+/// exit code.
+///
+/// Other system calls are served by `syscall::dispatch()`, running the kernel worker while a
+/// system call blocks or yields. A fault that cannot be resolved ends execution with the exit
+/// status of the signal it would raise in a process.
+///
+/// # Safety
+///
+/// See `enter()`.
+pub unsafe fn run_until_exit(context: &mut UserContext) -> u64 {
+    loop {
+        // SAFETY: guaranteed by the caller
+        if let Trap::Fault(fault) = unsafe { enter(context) } {
+            if fault.resolve() {
+                continue;
+            }
+            return Signal::for_exception(fault.exception).exit_status();
+        }
+        loop {
+            match syscall::dispatch(context) {
+                Action::Resume => break,
+                Action::Yield => {
+                    workqueue::run_worker();
+                    break;
+                }
+                Action::Block => crate::idle(),
+                Action::Exit(exit_code) => return exit_code,
+            }
+        }
+    }
+}
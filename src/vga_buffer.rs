@@ -1,8 +1,29 @@
-use core::ops::{Deref, DerefMut};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    ansi::{Erase, Output, Parser, Sequence},
+    cmdline::Param,
+    console, cp437, fb_console,
+    framebuffer::{Rgb, FRAMEBUFFER},
+    graphics::Canvas,
+    memory, pc_speaker, psf,
+    sync::IrqSpinlock,
+    time,
+    vga_font::{self, FontError, UnicodeMap},
+    vga_mode::{
+        self, crtc_read, crtc_write, in_text_mode, TextMode, CRTC_CURSOR_END,
+        CRTC_CURSOR_LOCATION_HIGH, CRTC_CURSOR_LOCATION_LOW, CRTC_CURSOR_START,
+        CRTC_MAXIMUM_SCAN_LINE,
+    },
+};
 
 /// The 16 color standard palette in VGA text mode.
 #[allow(dead_code)]
@@ -33,7 +54,7 @@ pub enum Color {
 struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 
@@ -70,15 +91,293 @@ impl Deref for ScreenChar {
     }
 }
 
-/// The height of the VGA text buffer (commonly 25 lines).
-const BUFFER_HEIGHT: usize = 25;
-/// The width of the VGA text buffer (commonly 80 columns).
-const BUFFER_WIDTH: usize = 80;
+/// The text mode `Writer`s start in, the standard mode of the firmware.
+const DEFAULT_TEXT_MODE: TextMode = TextMode::Text80x25;
+
+/// The maximum width of the text modes, see `vga_mode::TextMode`.
+const MAX_WIDTH: usize = 90;
+/// The maximum height of the text modes.
+const MAX_HEIGHT: usize = 60;
+/// The maximum number of characters on the screen.
+const MAX_CELLS: usize = MAX_WIDTH * MAX_HEIGHT;
+
+/// The maximum number of rows kept in the scrollback history.
+const MAX_SCROLLBACK: usize = 1000;
 
-/// A structure representing the VGA text mode buffer.
+/// The number of rows kept in the scrollback history.
+pub static SCROLLBACK: Param<u64> = Param::with_check(
+    "scrollback",
+    "rows of VGA scrollback history, at most 1000",
+    200,
+    |rows| rows <= MAX_SCROLLBACK as u64,
+);
+
+/// The physical address of the VGA text buffer.
+const BUFFER_ADDRESS: u64 = 0xb8000;
+
+/// A structure representing the VGA text mode buffer, large enough for any text mode.
+///
+/// The rows follow each other without gaps, so the buffer of a `width` column mode holds the
+/// character at `row` and `column` at `row * width + column`.
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [Volatile<ScreenChar>; MAX_CELLS],
+}
+
+/// A blank character in the default colors.
+const BLANK_CHAR: ScreenChar = ScreenChar {
+    code_point: b' ',
+    color_code: DEFAULT_COLOR_CODE,
+};
+
+/// The rows scrolled off the top of the screen, and the live screen while viewing them.
+///
+/// Both live on the heap, and are only allocated once needed, for the width of the console then.
+struct Scrollback {
+    /// A ring buffer of `capacity` rows of `width` characters, the oldest of which is at `start`.
+    rows: Vec<ScreenChar>,
+    width: usize,
+    capacity: usize,
+    start: usize,
+    len: usize,
+    /// How many rows the view is scrolled back, 0 showing the live screen.
+    offset: usize,
+    /// The live screen, saved row by row while `offset` is not 0.
+    screen: Vec<ScreenChar>,
+}
+
+impl Scrollback {
+    /// Returns an empty history, allocating nothing.
+    const fn new() -> Scrollback {
+        Scrollback {
+            rows: Vec::new(),
+            width: 0,
+            capacity: 0,
+            start: 0,
+            len: 0,
+            offset: 0,
+            screen: Vec::new(),
+        }
+    }
+
+    /// Appends a row, discarding the oldest one if `SCROLLBACK` rows are kept already.
+    ///
+    /// The first row allocates the history, for rows as wide as it. The row is discarded if that
+    /// fails.
+    fn push(&mut self, row: &[ScreenChar]) {
+        if self.rows.is_empty() && !self.allocate(row.len()) {
+            return;
+        }
+        let end = (self.start + self.len) % self.capacity * self.width;
+        self.rows[end..end + self.width].copy_from_slice(row);
+        if self.len == self.capacity {
+            self.start = (self.start + 1) % self.capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Allocates room for `SCROLLBACK` rows of `width` characters, and returns whether it could.
+    fn allocate(&mut self, width: usize) -> bool {
+        let capacity = SCROLLBACK.get() as usize;
+        // NOTE: emergency output never allocates, as the heap may be locked for good
+        if capacity == 0 || width == 0 || console::in_emergency() {
+            return false;
+        }
+        let mut rows = Vec::new();
+        if rows.try_reserve_exact(capacity * width).is_err() {
+            return false;
+        }
+        rows.resize(capacity * width, BLANK_CHAR);
+        self.rows = rows;
+        self.width = width;
+        self.capacity = capacity;
+        true
+    }
+
+    /// Returns the row at `index`, counting from the oldest one.
+    fn row(&self, index: usize) -> &[ScreenChar] {
+        let start = (self.start + index) % self.capacity * self.width;
+        &self.rows[start..start + self.width]
+    }
+
+    /// Discards the history and frees its memory, as after changing the width of the rows.
+    fn clear(&mut self) {
+        *self = Scrollback::new();
+    }
+}
+
+/// The bit of the Cursor Start register hiding the cursor.
+const CURSOR_DISABLE: u8 = 0x20;
+
+/// The DAC palette ports, for the visual bell.
+const DAC_WRITE_INDEX_PORT: u16 = 0x3C8;
+const DAC_DATA_PORT: u16 = 0x3C9;
+
+/// The 6 bit per channel red, green and blue of DAC color 0, the black background, during a
+/// visual bell.
+const FLASH_COLOR: [u8; 3] = [0x2A, 0x2A, 0x2A];
+
+/// The uptime in milliseconds at which the current visual bell ends, or 0 if there is none.
+static FLASH_END_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets DAC color 0 to `rgb`.
+fn set_background_palette(rgb: [u8; 3]) {
+    let mut index_port = Port::<u8>::new(DAC_WRITE_INDEX_PORT);
+    let mut data_port = Port::<u8>::new(DAC_DATA_PORT);
+    // SAFETY: the palette only affects the colors on the screen
+    unsafe {
+        index_port.write(0);
+        for channel in rgb {
+            data_port.write(channel);
+        }
+    }
+}
+
+/// Restores the background after a visual bell has ended. Called from the deferred timer work,
+/// with interrupts enabled.
+pub(crate) fn tick() {
+    let end = FLASH_END_MS.load(Ordering::Relaxed);
+    if end != 0 && time::uptime_ms() >= end {
+        FLASH_END_MS.store(0, Ordering::Relaxed);
+        set_background_palette([0; 3]);
+    }
+}
+
+/// The character cells drawn on the framebuffer console, and the cursor over them.
+struct FramebufferScreen {
+    /// The character drawn in each cell, or `None` if unknown, in the layout of `Buffer`.
+    cells: [Option<ScreenChar>; MAX_CELLS],
+    /// Whether each row of the console may have changed since it was last drawn.
+    dirty_rows: [bool; MAX_HEIGHT],
+    /// The row, column and shape of the cursor drawn, if any.
+    cursor: Option<(usize, usize, CursorShape)>,
+}
+
+impl FramebufferScreen {
+    /// A screen on which nothing is known to be drawn.
+    const UNKNOWN: FramebufferScreen = FramebufferScreen {
+        cells: [None; MAX_CELLS],
+        dirty_rows: [true; MAX_HEIGHT],
+        cursor: None,
+    };
+}
+
+/// Returns the screen of the framebuffer console.
+///
+/// # Safety
+///
+/// There must be no other reference to the screen, which only the console shown on the
+/// framebuffer console refers to.
+unsafe fn framebuffer_screen() -> &'static mut FramebufferScreen {
+    static mut SCREEN: FramebufferScreen = FramebufferScreen::UNKNOWN;
+    // SAFETY: guaranteed by the caller
+    unsafe { &mut *core::ptr::addr_of_mut!(SCREEN) }
+}
+
+/// Returns the VGA text buffer.
+///
+/// # Safety
+///
+/// There must be no other reference to the text buffer, which only the active console refers to
+/// in text mode.
+unsafe fn text_buffer() -> &'static mut Buffer {
+    let address = memory::phys_to_virt(PhysAddr::new(BUFFER_ADDRESS));
+    // SAFETY: all physical memory is mapped by the bootloader, the text buffer is large enough for
+    // `MAX_CELLS`, and there is no other reference to it as guaranteed by the caller
+    unsafe { &mut *address.as_mut_ptr() }
+}
+
+/// Returns the initial buffer of virtual console `index`: the VGA text buffer for the first one in
+/// text mode, and otherwise an offscreen buffer, as the bootloader switches the display to a
+/// graphics mode.
+fn console_buffer(index: usize, text_mode: bool) -> &'static mut Buffer {
+    const BLANK: Volatile<ScreenChar> = Volatile::new(BLANK_CHAR);
+    const BLANK_BUFFER: Buffer = Buffer {
+        chars: [BLANK; MAX_CELLS],
+    };
+    static mut OFFSCREEN: [Buffer; VIRTUAL_CONSOLE_COUNT] = [BLANK_BUFFER; VIRTUAL_CONSOLE_COUNT];
+    /// A bit for each offscreen buffer handed out.
+    static TAKEN: AtomicU8 = AtomicU8::new(0);
+
+    if index == 0 && text_mode {
+        // SAFETY: only called once, when initializing `VIRTUAL_CONSOLES`
+        unsafe { text_buffer() }
+    } else {
+        let bit = 1 << index;
+        assert!(
+            TAKEN.fetch_or(bit, Ordering::Relaxed) & bit == 0,
+            "Offscreen buffer taken twice"
+        );
+        // SAFETY: each offscreen buffer is only taken once, as asserted above
+        unsafe { &mut *core::ptr::addr_of_mut!(OFFSCREEN[index]) }
+    }
+}
+
+/// The colors `Writer` starts with and ANSI escape sequences reset to.
+const DEFAULT_COLOR_CODE: ColorCode = ColorCode::new(Color::LightGray, Color::Black);
+
+/// The VGA colors of the ANSI colors 0 to 15, i.e. the normal and the bright variants of black,
+/// red, green, yellow, blue, magenta, cyan and white.
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// The glyph `Writer` shows for characters missing from code page 437 by default, a black square.
+const DEFAULT_REPLACEMENT: u8 = 0xFE;
+
+/// The shape of the hardware cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// The bottom two scan lines of the character cell.
+    Underline,
+    /// The whole character cell.
+    Block,
+}
+
+/// What `Writer` does on the bell control character (BEL).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BellMode {
+    Off,
+    /// Beeps the PC speaker.
+    Audible,
+    /// Briefly lights up the black background, in text mode.
+    Visible,
+}
+
+/// The tone and the length of the bell.
+const BELL_FREQUENCY_HZ: u32 = 750;
+const BELL_DURATION_MS: u64 = 125;
+
+/// The distance between the tab stops `Writer` starts with.
+const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// Returns tab stops every `width` columns, or none if `width` is 0.
+const fn tab_stops(width: usize) -> [bool; MAX_WIDTH] {
+    let mut stops = [false; MAX_WIDTH];
+    if width > 0 {
+        let mut column = width;
+        while column < MAX_WIDTH {
+            stops[column] = true;
+            column += width;
+        }
+    }
+    stops
 }
 
 /// Controls whether `Writer` interprets control character bytes as glyphs.
@@ -90,22 +389,58 @@ pub enum ControlCharMode {
 
 /// A writer type that allows writing code page 437[^cp437] bytes and strings to an underlying buffer.
 ///
-/// Wraps lines at `BUFFER_WIDTH`. Control character handling is controlled via `control_char_mode`.
+/// Wraps lines at `width`. Control character handling is controlled via `control_char_mode`.
+/// Strings are translated from Unicode, showing characters missing from code page 437, or from the
+/// loaded font, as the `replacement` glyph, and interpreted as ANSI escape sequences in control
+/// mode, see `ansi`.
 /// Implements `core::fmt::Write`.
 ///
 /// [^cp437]: https://en.wikipedia.org/wiki/Code_page_437
 pub struct Writer {
+    /// The number of columns of the text mode.
+    width: usize,
+    /// The number of rows of the text mode.
+    height: usize,
     row_position: usize,
     column_position: usize,
     color_code: ColorCode,
     control_char_mode: ControlCharMode,
+    replacement: u8,
+    /// The characters shown by the glyphs of the loaded font, or `None` for code page 437.
+    unicode_map: Option<Arc<UnicodeMap>>,
+    /// Parses the escape sequences in strings written in control mode.
+    parser: Parser,
+    /// Whether the foreground color is bright, as set with an escape sequence.
+    bold: bool,
+    /// The cursor position and colors saved with an escape sequence.
+    saved_cursor: (usize, usize, ColorCode),
+    /// The first row scrolled by `new_line()`.
+    scroll_top: usize,
+    /// The last row scrolled by `new_line()`.
+    scroll_bottom: usize,
+    /// The columns a tab advances the cursor to.
+    tab_stops: [bool; MAX_WIDTH],
+    /// Whether a backspace erases the character it moves the cursor back onto.
+    destructive_backspace: bool,
+    bell_mode: BellMode,
+    /// Whether `buffer` is the VGA text buffer, and the hardware cursor is thus to be moved.
+    hardware_cursor: bool,
+    /// The cells drawn on the framebuffer console if this console is shown on it, see `render()`.
+    framebuffer: Option<&'static mut FramebufferScreen>,
+    cursor_visible: bool,
+    cursor_shape: CursorShape,
+    /// The rows scrolled off the top of the screen, which can be viewed with `scroll_back()`.
+    scrollback: Scrollback,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
     /// Sets whether control character bytes are interpreted as glyphs.
+    ///
+    /// Also abandons a partially written escape sequence.
     pub fn set_control_mode(&mut self, control_char_mode: ControlCharMode) {
         self.control_char_mode = control_char_mode;
+        self.parser.reset();
     }
 
     /// Sets the active `Color`s.
@@ -113,6 +448,124 @@ impl Writer {
         self.color_code = ColorCode::new(foreground, background);
     }
 
+    /// Sets tab stops every `width` columns, replacing the current ones. No tab stops remain if
+    /// `width` is 0.
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_stops = tab_stops(width);
+    }
+
+    /// Sets a tab stop at `column`.
+    pub fn set_tab_stop(&mut self, column: usize) {
+        if let Some(stop) = self.tab_stops.get_mut(column) {
+            *stop = true;
+        }
+    }
+
+    /// Clears the tab stop at `column`, if any.
+    pub fn clear_tab_stop(&mut self, column: usize) {
+        if let Some(stop) = self.tab_stops.get_mut(column) {
+            *stop = false;
+        }
+    }
+
+    /// Clears all tab stops, so that a tab advances the cursor to the last column.
+    pub fn clear_tab_stops(&mut self) {
+        self.tab_stops = [false; MAX_WIDTH];
+    }
+
+    /// Sets whether a backspace erases the character it moves the cursor back onto, instead of only
+    /// moving the cursor.
+    pub fn set_destructive_backspace(&mut self, destructive: bool) {
+        self.destructive_backspace = destructive;
+    }
+
+    /// Sets what the bell control character does.
+    pub fn set_bell_mode(&mut self, bell_mode: BellMode) {
+        self.bell_mode = bell_mode;
+    }
+
+    /// Sets the glyph shown for characters missing from code page 437, or from the loaded font.
+    pub fn set_replacement(&mut self, byte: u8) {
+        self.replacement = byte;
+    }
+
+    /// Returns the number of columns.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the number of rows.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the glyph byte at `row` and `column` of the buffer.
+    pub fn byte_at(&self, row: usize, column: usize) -> u8 {
+        self.cell(row, column).code_point
+    }
+
+    /// Returns the character at `row` and `column` of the buffer.
+    fn cell(&self, row: usize, column: usize) -> ScreenChar {
+        assert!(row < self.height && column < self.width);
+        self.buffer.chars[row * self.width + column].read()
+    }
+
+    /// Sets the character at `row` and `column` of the buffer.
+    fn set_cell(&mut self, row: usize, column: usize, character: ScreenChar) {
+        assert!(row < self.height && column < self.width);
+        self.buffer.chars[row * self.width + column].write(character);
+        if let Some(screen) = self.framebuffer.as_deref_mut() {
+            screen.dirty_rows[row] = true;
+        }
+    }
+
+    /// Marks all rows to be compared with the framebuffer console on the next `render()`.
+    fn mark_all_dirty(&mut self) {
+        if let Some(screen) = self.framebuffer.as_deref_mut() {
+            screen.dirty_rows = [true; MAX_HEIGHT];
+        }
+    }
+
+    /// Changes the number of columns and rows, as after a text mode change, clearing the screen
+    /// and the scrollback history. Also resets the scroll region and the saved cursor.
+    fn resize(&mut self, width: usize, height: usize) {
+        assert!(width <= MAX_WIDTH && height <= MAX_HEIGHT);
+        self.scrollback.clear();
+        self.width = width;
+        self.height = height;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+        self.saved_cursor = (0, 0, self.color_code);
+        self.update_cursor_shape();
+        self.reset_framebuffer();
+        self.clear();
+    }
+
+    /// Returns the character shown at `row` and `column` of the buffer.
+    pub fn char_at(&self, row: usize, column: usize) -> char {
+        self.char_of(self.byte_at(row, column))
+    }
+
+    /// Returns the character shown by glyph `byte`.
+    fn char_of(&self, byte: u8) -> char {
+        match &self.unicode_map {
+            Some(map) => map.char(byte),
+            None => cp437::to_char(byte),
+        }
+    }
+
+    /// Returns the glyph byte showing `c`, or the replacement glyph if there is none.
+    ///
+    /// ASCII characters missing from the Unicode table of the loaded font fall back to their code
+    /// page 437 glyphs.
+    fn glyph(&self, c: char) -> u8 {
+        let glyph = match &self.unicode_map {
+            Some(map) => map.glyph(c).or_else(|| c.is_ascii().then_some(c as u8)),
+            None => cp437::from_char(c),
+        };
+        glyph.unwrap_or(self.replacement)
+    }
+
     /// Sets the active foreground `Color`.
     pub fn set_fg_color(&mut self, color: Color) {
         self.color_code.set_foreground(color);
@@ -123,18 +576,188 @@ impl Writer {
         self.color_code.set_background(color);
     }
 
+    /// Shows or hides the hardware cursor.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        self.update_cursor_shape();
+        self.render();
+    }
+
+    /// Sets the shape of the hardware cursor, for the character height of the text mode.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = shape;
+        self.update_cursor_shape();
+        self.render();
+    }
+
+    /// Programs the visibility and the shape of the hardware cursor.
+    fn update_cursor_shape(&self) {
+        if !self.hardware_cursor {
+            return;
+        }
+        let last_scan_line = crtc_read(CRTC_MAXIMUM_SCAN_LINE) & 0x1F;
+        let first_scan_line = match self.cursor_shape {
+            CursorShape::Underline => last_scan_line.saturating_sub(1),
+            CursorShape::Block => 0,
+        };
+        let disable = if self.cursor_visible {
+            0
+        } else {
+            CURSOR_DISABLE
+        };
+        crtc_write(CRTC_CURSOR_START, disable | first_scan_line);
+        // NOTE: keep the skew bits of the Cursor End register
+        let end = crtc_read(CRTC_CURSOR_END) & !0x1F;
+        crtc_write(CRTC_CURSOR_END, end | last_scan_line);
+    }
+
+    /// Exchanges the screen contents and the buffers of two consoles, moving the VGA text buffer
+    /// and the hardware cursor, or the framebuffer console, from one to the other.
+    ///
+    /// Both consoles are in the same text mode.
+    fn swap_screen(&mut self, other: &mut Writer) {
+        for index in 0..self.width * self.height {
+            let character = self.buffer.chars[index].read();
+            let other_character = other.buffer.chars[index].read();
+            self.buffer.chars[index].write(other_character);
+            other.buffer.chars[index].write(character);
+        }
+        core::mem::swap(&mut self.buffer, &mut other.buffer);
+        core::mem::swap(&mut self.hardware_cursor, &mut other.hardware_cursor);
+        core::mem::swap(&mut self.framebuffer, &mut other.framebuffer);
+        for writer in [self, other] {
+            writer.mark_all_dirty();
+            writer.update_cursor_shape();
+            writer.update_cursor();
+        }
+    }
+
+    /// Returns the offset of the character cell the hardware cursor is to be at, i.e. the next
+    /// one written, or the last column before wrapping the line.
+    ///
+    /// NOTE: the offset is past the end of the screen, hiding the cursor, if viewing scrollback
+    /// pushes it off the bottom
+    fn cursor_offset(&self) -> u16 {
+        let row = self.row_position + self.scrollback.offset;
+        let column = self.column_position.min(self.width - 1);
+        (row * self.width + column) as u16
+    }
+
+    /// Moves the hardware cursor to `row_position` and `column_position`, and brings the
+    /// framebuffer console up to date.
+    fn update_cursor(&mut self) {
+        self.render();
+        if !self.hardware_cursor {
+            return;
+        }
+        let [low, high] = self.cursor_offset().to_le_bytes();
+        crtc_write(CRTC_CURSOR_LOCATION_HIGH, high);
+        crtc_write(CRTC_CURSOR_LOCATION_LOW, low);
+    }
+
+    /// Draws the cells changed since the last time, and the cursor, on the framebuffer console if
+    /// this console is shown on it. Only the rows written to since are compared with what was
+    /// drawn, see `FramebufferScreen::dirty_rows`.
+    ///
+    /// NOTE: skips drawing while the framebuffer is locked, e.g. by a panicking holder, leaving the
+    /// changes to the next call
+    fn render(&mut self) {
+        let screen = match self.framebuffer.take() {
+            Some(screen) => screen,
+            None => return,
+        };
+        if let Some(mut framebuffer) = FRAMEBUFFER.try_lock() {
+            if let Some(framebuffer) = framebuffer.as_mut() {
+                self.draw_changes(screen, framebuffer);
+            }
+        }
+        self.framebuffer = Some(screen);
+    }
+
+    /// Draws the cells of the dirty rows that differ from `screen`, and the cursor, on
+    /// `framebuffer`.
+    fn draw_changes(
+        &self,
+        screen: &mut FramebufferScreen,
+        framebuffer: &mut crate::framebuffer::FrameBuffer,
+    ) {
+        let offset = usize::from(self.cursor_offset());
+        let cursor = (self.cursor_visible && offset < self.width * self.height)
+            .then(|| (offset / self.width, offset % self.width, self.cursor_shape));
+        // The rows the cursor leaves and enters change even if their cells do not
+        for &(row, _, _) in [cursor, screen.cursor].iter().flatten() {
+            if row < self.height {
+                screen.dirty_rows[row] = true;
+            }
+        }
+        for row in 0..self.height {
+            if !core::mem::take(&mut screen.dirty_rows[row]) {
+                continue;
+            }
+            for column in 0..self.width {
+                let index = row * self.width + column;
+                let character = self.buffer.chars[index].read();
+                let cursor_here = |cursor: Option<(usize, usize, CursorShape)>| {
+                    cursor.filter(|&(r, c, _)| (r, c) == (row, column))
+                };
+                let (shape, drawn_shape) = (cursor_here(cursor), cursor_here(screen.cursor));
+                if screen.cells[index] == Some(character) && shape == drawn_shape {
+                    continue;
+                }
+                let ColorCode(color_code) = character.color_code;
+                let foreground = fb_console::PALETTE[usize::from(color_code & 0x0F)];
+                let background = fb_console::PALETTE[usize::from(color_code >> 4)];
+                let c = self.char_of(character.code_point);
+                if let Some((_, _, CursorShape::Block)) = shape {
+                    fb_console::draw_cell(framebuffer, row, column, c, background, foreground);
+                } else {
+                    fb_console::draw_cell(framebuffer, row, column, c, foreground, background);
+                }
+                if let Some((_, _, CursorShape::Underline)) = shape {
+                    fb_console::draw_underline(framebuffer, row, column, foreground);
+                }
+                screen.cells[index] = Some(character);
+            }
+        }
+        screen.cursor = cursor;
+    }
+
+    /// Clears the framebuffer console if this console is shown on it, and forgets what was drawn,
+    /// as after a change of the number of columns.
+    fn reset_framebuffer(&mut self) {
+        if let Some(screen) = self.framebuffer.as_deref_mut() {
+            *screen = FramebufferScreen::UNKNOWN;
+            if let Some(mut framebuffer) = FRAMEBUFFER.try_lock() {
+                if let Some(framebuffer) = framebuffer.as_mut() {
+                    framebuffer.clear(Rgb::BLACK);
+                }
+            }
+        }
+    }
+
     /// Writes a byte to the buffer.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. The behavior regarding `\t`, `\n`, and `\r` is controlled
-    /// through `set_control_mode()`.
+    /// Wraps lines at `width`. The interpretation of control characters is controlled
+    /// through `set_control_mode()`, see `handle_control_char()`.
     pub fn write_byte(&mut self, byte: u8) {
+        self.snap_back();
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    /// Writes a byte to the buffer without moving the hardware cursor, see `write_byte()`.
+    fn put_byte(&mut self, byte: u8) {
         if matches!(self.control_char_mode, ControlCharMode::Control)
             && self.handle_control_char(byte)
         {
             return;
         }
+        self.put_glyph(byte);
+    }
 
-        if self.column_position >= BUFFER_WIDTH {
+    /// Writes a byte to the buffer as a glyph, regardless of the control character mode.
+    fn put_glyph(&mut self, byte: u8) {
+        if self.column_position >= self.width {
             self.new_line();
         }
 
@@ -142,23 +765,155 @@ impl Writer {
         let col = self.column_position;
 
         let color_code = self.color_code;
-        self.buffer.chars[row][col].write(ScreenChar {
-            code_point: byte,
-            color_code,
-        });
+        self.set_cell(
+            row,
+            col,
+            ScreenChar {
+                code_point: byte,
+                color_code,
+            },
+        );
         self.column_position += 1;
     }
 
     /// Writes a string to the buffer.
     ///
-    /// Interprets `s` as bytes and assumes the IBM PC character set (code page 437).
+    /// Translates `s` to the IBM PC character set (code page 437), or to the glyphs of the loaded
+    /// font, writing the replacement glyph for characters missing from it, see `set_replacement()`.
+    /// Only ASCII control characters are interpreted, not the characters sharing their glyphs.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. The behavior regarding `\t`, `\n`, and `\r` is controlled
-    /// through `set_control_mode()`.
+    /// Wraps lines at `width`. The interpretation of control characters and ANSI escape
+    /// sequences is controlled through `set_control_mode()`.
     pub fn write_string(&mut self, s: &str) {
-        // TODO: Support conversion from Unicode via the codepage_437 crate?
-        for byte in s.bytes() {
-            self.write_byte(byte);
+        self.snap_back();
+        for c in s.chars() {
+            let output = match self.control_char_mode {
+                ControlCharMode::Control => self.parser.advance(c),
+                ControlCharMode::Glyph => Some(Output::Char(c)),
+            };
+            match output {
+                Some(Output::Char(c)) if c.is_ascii_control() => self.put_byte(c as u8),
+                Some(Output::Char(c)) => self.put_glyph(self.glyph(c)),
+                Some(Output::Sequence(sequence)) => self.apply(sequence),
+                None => {}
+            }
+        }
+        self.update_cursor();
+    }
+
+    /// Carries out an ANSI escape sequence.
+    fn apply(&mut self, sequence: Sequence) {
+        // The rows the cursor moves within: the scroll region if the cursor is in it
+        let (top, bottom) = if (self.scroll_top..=self.scroll_bottom).contains(&self.row_position) {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.height - 1)
+        };
+        let column = self.column_position.min(self.width - 1);
+        match sequence {
+            Sequence::SelectGraphicRendition(params) => {
+                for param in params.iter() {
+                    self.select_graphic_rendition(param);
+                }
+            }
+            Sequence::CursorUp(count) => {
+                self.row_position = self.row_position.saturating_sub(count).max(top);
+            }
+            Sequence::CursorDown(count) => {
+                self.row_position = (self.row_position + count).min(bottom);
+            }
+            Sequence::CursorForward(count) => {
+                self.column_position = (column + count).min(self.width - 1);
+            }
+            Sequence::CursorBack(count) => self.column_position = column.saturating_sub(count),
+            Sequence::CursorNextLine(count) => {
+                self.row_position = (self.row_position + count).min(bottom);
+                self.column_position = 0;
+            }
+            Sequence::CursorPreviousLine(count) => {
+                self.row_position = self.row_position.saturating_sub(count).max(top);
+                self.column_position = 0;
+            }
+            Sequence::CursorColumn(column) => self.column_position = column.min(self.width - 1),
+            Sequence::CursorPosition { row, column } => {
+                self.row_position = row.min(self.height - 1);
+                self.column_position = column.min(self.width - 1);
+            }
+            Sequence::EraseInDisplay(erase) => {
+                let rows = match erase {
+                    Erase::ToEnd => self.row_position + 1..self.height,
+                    Erase::ToStart => 0..self.row_position,
+                    Erase::All => 0..self.height,
+                };
+                let blank = self.blank();
+                for row in rows {
+                    self.clear_row(row, blank);
+                }
+                if erase != Erase::All {
+                    self.erase_in_line(erase);
+                }
+            }
+            Sequence::EraseInLine(erase) => self.erase_in_line(erase),
+            Sequence::SaveCursor => {
+                self.saved_cursor = (self.row_position, self.column_position, self.color_code);
+            }
+            Sequence::RestoreCursor => {
+                let (row, column, color_code) = self.saved_cursor;
+                self.row_position = row;
+                self.column_position = column;
+                self.color_code = color_code;
+            }
+            Sequence::SetScrollRegion { top, bottom } => {
+                let bottom = bottom.unwrap_or(self.height - 1).min(self.height - 1);
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.row_position = 0;
+                    self.column_position = 0;
+                }
+            }
+        }
+    }
+
+    /// Applies a Select Graphic Rendition parameter. Bold shows as the bright variant of the
+    /// foreground color.
+    ///
+    /// NOTE: bright background colors blink instead in the default VGA configuration
+    fn select_graphic_rendition(&mut self, param: u16) {
+        let bright = if self.bold { 8 } else { 0 };
+        match param {
+            0 => {
+                self.color_code = DEFAULT_COLOR_CODE;
+                self.bold = false;
+            }
+            1 => {
+                self.bold = true;
+                self.color_code.0 |= 0x08;
+            }
+            22 => {
+                self.bold = false;
+                self.color_code.0 &= !0x08;
+            }
+            30..=37 => self.set_fg_color(ANSI_COLORS[(param - 30) as usize | bright]),
+            39 => self.set_fg_color(ANSI_COLORS[7 | bright]),
+            40..=47 => self.set_bg_color(ANSI_COLORS[(param - 40) as usize]),
+            49 => self.set_bg_color(Color::Black),
+            90..=97 => self.set_fg_color(ANSI_COLORS[(param - 90) as usize + 8]),
+            100..=107 => self.set_bg_color(ANSI_COLORS[(param - 100) as usize + 8]),
+            _ => {}
+        }
+    }
+
+    /// Erases part of the cursor row.
+    fn erase_in_line(&mut self, erase: Erase) {
+        let columns = match erase {
+            Erase::ToEnd => self.column_position.min(self.width)..self.width,
+            Erase::ToStart => 0..(self.column_position + 1).min(self.width),
+            Erase::All => 0..self.width,
+        };
+        let blank = self.blank();
+        for col in columns {
+            self.set_cell(self.row_position, col, blank);
         }
     }
 
@@ -166,6 +921,7 @@ impl Writer {
     ///
     /// Disregards but preserves current `ControlCharMode`.
     pub fn print_character_set(&mut self) {
+        self.snap_back();
         let ccmode_save = self.control_char_mode;
         self.control_char_mode = ControlCharMode::Glyph;
         if self.column_position > 0 {
@@ -173,82 +929,182 @@ impl Writer {
         }
         for row in 0..0x10u8 {
             for col in 0..0x10u8 {
-                self.write_byte((row << 4) | col);
+                self.put_byte((row << 4) | col);
             }
             self.new_line();
         }
         self.control_char_mode = ccmode_save;
+        self.update_cursor();
     }
 
     /// Clears the underlying buffer with current backroung color and places the cursor at the
     /// upper left corner.
     pub fn clear(&mut self) {
-        let blank = ScreenChar {
-            code_point: b' ',
-            color_code: self.color_code,
-        };
+        self.snap_back();
+        let blank = self.blank();
         self.fill(blank);
         self.row_position = 0;
         self.column_position = 0;
+        self.update_cursor();
     }
 
     fn fill(&mut self, character: ScreenChar) {
-        for row in 0..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                self.buffer.chars[row][col].write(character);
-            }
+        for index in 0..self.width * self.height {
+            self.buffer.chars[index].write(character);
         }
+        self.mark_all_dirty();
     }
 
-    /// Advances one line (row) and returns to the first column *unless* already on the last row in
-    /// which case shifts all lines up by one (discarding the contents of the first row) and clears
-    /// the last row.
+    /// Advances one line (row) and returns to the first column *unless* already on the last row of
+    /// the scroll region, in which case shifts the lines of the region up by one (discarding the
+    /// contents of its first row) and clears its last row.
+    ///
+    /// The scroll region spans the whole buffer unless set with an ANSI escape sequence.
     fn new_line(&mut self) {
-        if self.row_position >= BUFFER_HEIGHT - 1 {
-            for row in 1..BUFFER_HEIGHT {
-                for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(character);
+        if self.row_position == self.scroll_bottom {
+            // Only rows scrolling off the top of the screen go to the scrollback history
+            if self.scroll_top == 0 {
+                let row = self.read_row(0);
+                self.scrollback.push(&row[..self.width]);
+            }
+            for row in self.scroll_top + 1..=self.scroll_bottom {
+                for col in 0..self.width {
+                    let character = self.cell(row, col);
+                    self.set_cell(row - 1, col, character);
                 }
             }
-            let blank = ScreenChar {
-                code_point: b' ',
-                color_code: self.color_code,
-            };
-            self.clear_row(BUFFER_HEIGHT - 1, blank);
-        } else {
+            let blank = self.blank();
+            self.clear_row(self.scroll_bottom, blank);
+        } else if self.row_position < self.height - 1 {
             self.row_position += 1;
         }
         self.column_position = 0;
     }
 
+    /// Returns the contents of a row of the buffer, padded with blanks to `MAX_WIDTH`.
+    fn read_row(&self, row: usize) -> [ScreenChar; MAX_WIDTH] {
+        let mut contents = [BLANK_CHAR; MAX_WIDTH];
+        for (col, character) in contents[..self.width].iter_mut().enumerate() {
+            *character = self.cell(row, col);
+        }
+        contents
+    }
+
+    /// Scrolls the view `rows` rows back into the scrollback history, as far as it goes.
+    ///
+    /// Writing to the buffer returns the view to the live screen.
+    pub fn scroll_back(&mut self, rows: usize) {
+        let offset = (self.scrollback.offset + rows).min(self.scrollback.len);
+        self.scroll_view(offset);
+    }
+
+    /// Scrolls the view `rows` rows forward towards the live screen.
+    pub fn scroll_forward(&mut self, rows: usize) {
+        let offset = self.scrollback.offset.saturating_sub(rows);
+        self.scroll_view(offset);
+    }
+
+    /// Returns the view to the live screen, if scrolled back.
+    fn snap_back(&mut self) {
+        if self.scrollback.offset != 0 {
+            self.scroll_view(0);
+        }
+    }
+
+    /// Shows the screen scrolled `offset` rows back, saving the live screen when leaving it.
+    ///
+    /// Stays on the live screen if there is no memory to save it in.
+    fn scroll_view(&mut self, offset: usize) {
+        if offset == self.scrollback.offset {
+            return;
+        }
+        if self.scrollback.offset == 0 {
+            let mut screen = core::mem::take(&mut self.scrollback.screen);
+            screen.clear();
+            if screen.try_reserve_exact(self.width * self.height).is_err() {
+                return;
+            }
+            for row in 0..self.height {
+                screen.extend((0..self.width).map(|col| self.cell(row, col)));
+            }
+            self.scrollback.screen = screen;
+        }
+        self.scrollback.offset = offset;
+        let first_history_row = self.scrollback.len - offset;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let character = if row < offset {
+                    self.scrollback.row(first_history_row + row)[col]
+                } else {
+                    self.scrollback.screen[(row - offset) * self.width + col]
+                };
+                self.set_cell(row, col, character);
+            }
+        }
+        self.update_cursor();
+    }
+
+    /// Returns a blank character in the active colors.
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
+            code_point: b' ',
+            color_code: self.color_code,
+        }
+    }
+
     /// Clears a row by overwriting it.
     fn clear_row(&mut self, row: usize, blank: ScreenChar) {
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in 0..self.width {
+            self.set_cell(row, col, blank);
         }
     }
 
+    /// Carries out a control character in control mode: bell, backspace, tab, line feed, vertical
+    /// tab (as line feed), form feed (clearing the screen), and carriage return. Returns whether
+    /// `byte` is one of them, the others being written as glyphs.
     fn handle_control_char(&mut self, byte: u8) -> bool {
         assert!(
             matches!(self.control_char_mode, ControlCharMode::Control),
             "Writer::handle_control_char() called in glyph mode."
         );
         match byte {
-            b'\t' => {
-                // TODO: variable tab width
-                self.write_byte(b' ');
-                true
+            // BEL
+            0x07 => self.bell(),
+            // BS
+            0x08 => {
+                self.column_position = self.column_position.min(self.width - 1).saturating_sub(1);
+                if self.destructive_backspace {
+                    let blank = self.blank();
+                    self.set_cell(self.row_position, self.column_position, blank);
+                }
             }
-            b'\n' => {
-                self.new_line();
-                true
+            b'\t' => {
+                self.column_position = (self.column_position + 1..self.width)
+                    .find(|&column| self.tab_stops[column])
+                    .unwrap_or(self.width - 1)
+                    .max(self.column_position);
             }
-            b'\r' => {
-                self.column_position = 0;
-                true
+            // LF and VT
+            b'\n' | 0x0B => self.new_line(),
+            // FF
+            0x0C => self.clear(),
+            b'\r' => self.column_position = 0,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Rings the bell, see `set_bell_mode()`.
+    fn bell(&self) {
+        match self.bell_mode {
+            BellMode::Off => {}
+            BellMode::Audible => pc_speaker::beep(BELL_FREQUENCY_HZ, BELL_DURATION_MS),
+            // NOTE: only the console on the screen flashes it
+            BellMode::Visible if self.hardware_cursor => {
+                set_background_palette(FLASH_COLOR);
+                FLASH_END_MS.store(time::uptime_ms() + BELL_DURATION_MS, Ordering::Relaxed);
             }
-            _ => false,
+            BellMode::Visible => {}
         }
     }
 }
@@ -260,84 +1116,276 @@ impl core::fmt::Write for Writer {
     }
 }
 
+/// The number of virtual consoles, switched between with Alt+F1 to Alt+F6.
+pub const VIRTUAL_CONSOLE_COUNT: usize = 6;
+
+/// The lock class names of `VIRTUAL_CONSOLES`.
+const VIRTUAL_CONSOLE_NAMES: [&str; VIRTUAL_CONSOLE_COUNT] = [
+    "VIRTUAL_CONSOLES[0]",
+    "VIRTUAL_CONSOLES[1]",
+    "VIRTUAL_CONSOLES[2]",
+    "VIRTUAL_CONSOLES[3]",
+    "VIRTUAL_CONSOLES[4]",
+    "VIRTUAL_CONSOLES[5]",
+];
+
 lazy_static! {
-    /// A global `Writer` instance for printing to the VGA text mode buffer.
+    /// Independent `Writer`s, of which only the active one writes to the VGA text mode buffer,
+    /// see `console_buffer()` and `switch_console()`. The others write to offscreen buffers.
+    pub static ref VIRTUAL_CONSOLES: [IrqSpinlock<Writer>; VIRTUAL_CONSOLE_COUNT] = {
+        let text_mode = in_text_mode();
+        core::array::from_fn(|index| {
+            IrqSpinlock::with_name(VIRTUAL_CONSOLE_NAMES[index], Writer {
+                width: DEFAULT_TEXT_MODE.width(),
+                height: DEFAULT_TEXT_MODE.height(),
+                row_position: 0,
+                column_position: 0,
+                control_char_mode: ControlCharMode::Control,
+                color_code: DEFAULT_COLOR_CODE,
+                replacement: DEFAULT_REPLACEMENT,
+                unicode_map: None,
+                parser: Parser::new(),
+                bold: false,
+                saved_cursor: (0, 0, DEFAULT_COLOR_CODE),
+                tab_stops: tab_stops(DEFAULT_TAB_WIDTH),
+                destructive_backspace: false,
+                bell_mode: BellMode::Audible,
+                scroll_top: 0,
+                scroll_bottom: DEFAULT_TEXT_MODE.height() - 1,
+                hardware_cursor: index == 0 && text_mode,
+                framebuffer: None,
+                cursor_visible: true,
+                cursor_shape: CursorShape::Underline,
+                scrollback: Scrollback::new(),
+                buffer: console_buffer(index, text_mode),
+            })
+        })
+    };
+
+    /// The first virtual console, which the kernel prints to.
     ///
     /// Used by the `print!` and `println!` macros.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        row_position: 0,
-        column_position: 0,
-        control_char_mode: ControlCharMode::Control,
-        color_code: ColorCode::new(Color::LightGray, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: &'static IrqSpinlock<Writer> = &VIRTUAL_CONSOLES[0];
+}
+
+/// The index of the virtual console shown on the screen.
+static ACTIVE_CONSOLE: IrqSpinlock<usize> = IrqSpinlock::with_name("ACTIVE_CONSOLE", 0);
+
+/// Returns the index of the virtual console shown on the screen.
+pub fn active_console() -> usize {
+    *ACTIVE_CONSOLE.lock()
+}
+
+/// Shows virtual console `index`, moving the VGA text buffer over to it from the active one.
+/// Does nothing if there is no such console.
+pub fn switch_console(index: usize) {
+    if index >= VIRTUAL_CONSOLE_COUNT {
+        return;
+    }
+    let mut active = ACTIVE_CONSOLE.lock();
+    if index == *active {
+        return;
+    }
+    // NOTE: the consoles are always locked in index order, to keep a consistent lock order
+    let mut first = VIRTUAL_CONSOLES[index.min(*active)].lock();
+    let mut second = VIRTUAL_CONSOLES[index.max(*active)].lock();
+    first.swap_screen(&mut second);
+    *active = index;
+}
+
+/// Switches the display to text `mode`, resizing and clearing all virtual consoles.
+///
+/// Leaves the font as it is, so the display may need a font for the character height of the mode,
+/// see `load_font()`.
+pub fn set_text_mode(mode: TextMode) {
+    let active = ACTIVE_CONSOLE.lock();
+    // NOTE: the consoles are always locked in index order, to keep a consistent lock order
+    let mut consoles: [_; VIRTUAL_CONSOLE_COUNT] =
+        core::array::from_fn(|index| VIRTUAL_CONSOLES[index].lock());
+    vga_mode::set_text_mode(mode);
+    let console = &mut consoles[*active];
+    if !console.hardware_cursor {
+        // SAFETY: coming from a graphics mode, no console refers to the text buffer. The offscreen
+        // buffer of the active console is left unused, as the one of the first console in text mode
+        console.buffer = unsafe { text_buffer() };
+        console.hardware_cursor = true;
+    }
+    for console in consoles.iter_mut() {
+        console.framebuffer = None;
+        console.resize(mode.width(), mode.height());
+    }
+}
+
+/// Shows the active virtual console on the framebuffer console, see `fb_console`, resizing and
+/// clearing all virtual consoles to fit the framebuffer. Does nothing without a framebuffer.
+pub fn enable_framebuffer_console() {
+    let active = ACTIVE_CONSOLE.lock();
+    // NOTE: the consoles are always locked in index order, to keep a consistent lock order
+    let mut consoles: [_; VIRTUAL_CONSOLE_COUNT] =
+        core::array::from_fn(|index| VIRTUAL_CONSOLES[index].lock());
+    let (columns, rows) = match FRAMEBUFFER.lock().as_ref().map(fb_console::size) {
+        Some((columns, rows)) if columns > 0 && rows > 0 => (columns, rows),
+        _ => return,
+    };
+    if consoles.iter().all(|console| console.framebuffer.is_none()) {
+        // SAFETY: no console refers to the screen
+        consoles[*active].framebuffer = Some(unsafe { framebuffer_screen() });
+    }
+    for console in consoles.iter_mut() {
+        console.resize(columns.min(MAX_WIDTH), rows.min(MAX_HEIGHT));
+    }
+}
+
+/// Draws the active virtual console anew on the framebuffer console, e.g. after something else was
+/// drawn over it.
+pub fn redraw_framebuffer_console() {
+    let active = ACTIVE_CONSOLE.lock();
+    let mut console = VIRTUAL_CONSOLES[*active].lock();
+    console.reset_framebuffer();
+    console.render();
+}
+
+/// Loads `font` into the VGA, for the character height of the text mode, and translates Unicode
+/// for all virtual consoles by its Unicode table, or as code page 437 if it has none.
+///
+/// Loading a font with the character height of the text mode replaces the glyphs of
+/// `load_extra_glyphs()`.
+pub fn load_font(font: &psf::Font) -> Result<(), FontError> {
+    // NOTE: the consoles are always locked in index order, to keep a consistent lock order
+    let mut consoles: [_; VIRTUAL_CONSOLE_COUNT] =
+        core::array::from_fn(|index| VIRTUAL_CONSOLES[index].lock());
+    let map = vga_font::upload(font)?.map(Arc::new);
+    for console in consoles.iter_mut() {
+        console.unicode_map = map.clone();
+    }
+    Ok(())
 }
 
-/// Like the `print!` macro in `std`, but prints to the VGA text buffer.
+/// Loads the bundled glyphs for characters missing from code page 437 over the current font, and
+/// translates Unicode for all virtual consoles accordingly, see `vga_font::EXTRA_GLYPHS`.
+///
+/// The current font must follow code page 437, like the one of the firmware.
+pub fn load_extra_glyphs() -> Result<(), FontError> {
+    // NOTE: the consoles are always locked in index order, to keep a consistent lock order
+    let mut consoles: [_; VIRTUAL_CONSOLE_COUNT] =
+        core::array::from_fn(|index| VIRTUAL_CONSOLES[index].lock());
+    let map = Arc::new(vga_font::upload_extra_glyphs()?);
+    for console in consoles.iter_mut() {
+        console.unicode_map = Some(map.clone());
+    }
+    Ok(())
+}
+
+/// Shows the first virtual console, which the kernel prints to, even if the consoles are locked.
+///
+/// # Safety
+///
+/// Any holder of `ACTIVE_CONSOLE` or a virtual console must never resume, as in `force_lock()`.
+pub unsafe fn force_switch_to_writer() {
+    let mut active = ACTIVE_CONSOLE.force_lock();
+    if *active != 0 {
+        let mut writer = WRITER.force_lock();
+        writer.swap_screen(&mut VIRTUAL_CONSOLES[*active].force_lock());
+        *active = 0;
+    }
+}
+
+/// Like the `print!` macro in `std`, but prints to the console, see `console::CONSOLE`.
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
 }
 
-/// Like the `println!` macro in `std`, but prints to the VGA text buffer.
+/// Like the `println!` macro in `std`, but prints to the console, see `console::CONSOLE`.
 #[macro_export]
 macro_rules! println {
-    ($($arg:tt)*) => ($crate::vga_buffer::_println(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_println(format_args!($($arg)*)));
 }
 
 /// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[doc(hidden)]
 pub fn _println(args: core::fmt::Arguments) {
     use core::fmt::Write;
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        let mut w = WRITER.lock();
-        w.write_fmt(args).unwrap();
-        w.new_line();
-    });
+    let mut w = WRITER.lock();
+    w.write_fmt(args).unwrap();
+    w.new_line();
+    w.update_cursor();
 }
 
 /// Sets whether `WRITER` interprets control character bytes as glyphs.
 pub fn set_control_mode(control_char_mode: ControlCharMode) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().set_control_mode(control_char_mode);
-    });
+    WRITER.lock().set_control_mode(control_char_mode);
+}
+
+/// Sets tab stops every `width` columns for `WRITER`, see `Writer::set_tab_width()`.
+pub fn set_tab_width(width: usize) {
+    WRITER.lock().set_tab_width(width);
+}
+
+/// Sets what the bell control character does for `WRITER`.
+pub fn set_bell_mode(bell_mode: BellMode) {
+    WRITER.lock().set_bell_mode(bell_mode);
 }
 
 /// Sets the active `Color`s (foreground and background) for `WRITER`.
 pub fn set_color(foreground: Color, background: Color) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().set_color(foreground, background);
-    });
+    WRITER.lock().set_color(foreground, background);
 }
 
 /// Sets the active foreground `Color` for `WRITER`.
 pub fn set_fg_color(color: Color) {
-    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().set_fg_color(color));
+    WRITER.lock().set_fg_color(color);
 }
 
 /// Sets the active background `Color` for `WRITER`.
 pub fn set_bg_color(color: Color) {
-    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().set_bg_color(color));
+    WRITER.lock().set_bg_color(color);
+}
+
+/// Shows or hides the hardware cursor of `WRITER`.
+pub fn set_cursor_visible(visible: bool) {
+    WRITER.lock().set_cursor_visible(visible);
+}
+
+/// Sets the shape of the hardware cursor of `WRITER`.
+pub fn set_cursor_shape(shape: CursorShape) {
+    WRITER.lock().set_cursor_shape(shape);
+}
+
+/// Returns the number of rows of the active virtual console.
+pub fn active_height() -> usize {
+    VIRTUAL_CONSOLES[active_console()].lock().height()
+}
+
+/// Scrolls the view of the active virtual console `rows` rows back into the scrollback history.
+pub fn scroll_back(rows: usize) {
+    VIRTUAL_CONSOLES[active_console()].lock().scroll_back(rows);
+}
+
+/// Scrolls the view of the active virtual console `rows` rows forward towards the live screen.
+pub fn scroll_forward(rows: usize) {
+    VIRTUAL_CONSOLES[active_console()]
+        .lock()
+        .scroll_forward(rows);
 }
 
 /// Using `WRITER`, outputs the full code page 437 character set as a 16 by 16
 /// block.
 pub fn print_character_set() {
-    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().print_character_set());
+    WRITER.lock().print_character_set();
 }
 
 /// Clears the `WRITER` buffer with currently active background color and resets the cursor.
 pub fn clear() {
-    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().clear());
+    WRITER.lock().clear();
 }
 
 #[test_case]
@@ -357,15 +1405,366 @@ fn test_println_output() {
     use core::fmt::Write;
 
     let s = "The quick brown foz jumps over the lazy dog.";
-    assert!(s.len() <= BUFFER_WIDTH);
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        let mut w = WRITER.lock();
-        w.clear();
-        writeln!(w, "{}", s).expect("writeln!() failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = w.buffer.chars[0][i].read();
-            assert_eq!(char::from(screen_char.code_point), c);
+    let mut w = WRITER.lock();
+    assert!(s.len() <= w.width);
+    w.clear();
+    writeln!(w, "{}", s).expect("writeln!() failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = w.cell(0, i);
+        assert_eq!(char::from(screen_char.code_point), c);
+    }
+}
+
+#[test_case]
+fn test_println_unicode() {
+    use core::fmt::Write;
+
+    let s = "Grüße, ±½° ╔═╗ ☺ αβπ";
+    let mut w = WRITER.lock();
+    w.clear();
+    writeln!(w, "{}", s).expect("writeln!() failed");
+    for (i, c) in s.chars().enumerate() {
+        assert_eq!(w.char_at(0, i), if c == 'β' { 'ß' } else { c });
+    }
+    assert_eq!(w.byte_at(0, 2), 0x81);
+    assert_eq!(w.byte_at(0, 11), 0xC9);
+}
+
+#[test_case]
+fn test_println_replacement() {
+    use core::fmt::Write;
+
+    let mut w = WRITER.lock();
+    w.clear();
+    writeln!(w, "€ Д").expect("writeln!() failed");
+    assert_eq!(w.byte_at(0, 0), DEFAULT_REPLACEMENT);
+    assert_eq!(w.char_at(0, 2), '■');
+    w.set_replacement(b'?');
+    w.clear();
+    writeln!(w, "€").expect("writeln!() failed");
+    w.set_replacement(DEFAULT_REPLACEMENT);
+    assert_eq!(w.char_at(0, 0), '?');
+}
+
+#[test_case]
+fn test_glyph_translation() {
+    let mut w = WRITER.lock();
+    w.clear();
+    // Characters sharing the glyphs of control characters are not interpreted
+    w.write_string("•◙€");
+    assert_eq!(w.byte_at(0, 0), 0x07);
+    assert_eq!(w.byte_at(0, 1), 0x0A);
+    assert_eq!(w.byte_at(0, 2), DEFAULT_REPLACEMENT);
+    w.unicode_map = Some(Arc::new(UnicodeMap::with_extra_glyphs()));
+    w.write_string("\x0C€₧é\x01");
+    w.unicode_map = None;
+    assert_eq!(w.byte_at(0, 0), 0x9E);
+    assert_eq!(w.byte_at(0, 1), DEFAULT_REPLACEMENT);
+    assert_eq!(w.byte_at(0, 2), 0x82);
+    assert_eq!(w.byte_at(0, 3), 0x01);
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    let mut w = WRITER.lock();
+    w.clear();
+    w.write_string("\x1B[31;44ma\x1B[1mb\x1B[22;39;49mc\x1B[92;103md\x1B[0me");
+    let color = |w: &Writer, column: usize| w.cell(0, column).color_code;
+    assert_eq!(color(&w, 0), ColorCode::new(Color::Red, Color::Blue));
+    assert_eq!(color(&w, 1), ColorCode::new(Color::LightRed, Color::Blue));
+    assert_eq!(color(&w, 2), DEFAULT_COLOR_CODE);
+    assert_eq!(
+        color(&w, 3),
+        ColorCode::new(Color::LightGreen, Color::Yellow)
+    );
+    assert_eq!(color(&w, 4), DEFAULT_COLOR_CODE);
+    assert_eq!(w.char_at(0, 4), 'e');
+}
+
+#[test_case]
+fn test_ansi_cursor() {
+    let mut w = WRITER.lock();
+    w.clear();
+    w.write_string("\x1B[3;5Ha\x1B[2Db\x1B[Ac\x1B[99;99Hd\x1B[1;1H\x1B[Ae");
+    assert_eq!(w.char_at(2, 4), 'a');
+    assert_eq!(w.char_at(2, 3), 'b');
+    assert_eq!(w.char_at(1, 4), 'c');
+    assert_eq!(w.char_at(w.height - 1, w.width - 1), 'd');
+    assert_eq!(w.char_at(0, 0), 'e');
+    w.write_string("\x1B[s\x1B[5;5Hf\x1B[ug");
+    assert_eq!(w.char_at(4, 4), 'f');
+    assert_eq!(w.char_at(0, 1), 'g');
+}
+
+#[test_case]
+fn test_ansi_erase() {
+    let mut w = WRITER.lock();
+    w.clear();
+    w.write_string("abcdef\nghijkl\nmnopqr");
+    w.write_string("\x1B[1;3H\x1B[K\x1B[2;3H\x1B[1K\x1B[3;3H\x1B[2K");
+    assert_eq!(w.char_at(0, 1), 'b');
+    assert_eq!(w.char_at(0, 2), ' ');
+    assert_eq!(w.char_at(1, 2), ' ');
+    assert_eq!(w.char_at(1, 3), 'j');
+    assert_eq!(w.char_at(2, 0), ' ');
+    w.write_string("\x1B[2J");
+    assert_eq!(w.char_at(0, 0), ' ');
+}
+
+#[test_case]
+fn test_ansi_scroll_region() {
+    use core::fmt::Write;
+
+    let mut w = WRITER.lock();
+    w.clear();
+    w.write_string("top\x1B[2;4r");
+    for i in 0..5 {
+        write!(w, "\x1B[4;1H\n{}", i).unwrap();
+    }
+    w.write_string("\x1B[r");
+    assert_eq!(w.char_at(0, 0), 't');
+    assert_eq!(w.char_at(1, 0), '2');
+    assert_eq!(w.char_at(2, 0), '3');
+    assert_eq!(w.char_at(3, 0), '4');
+    assert_eq!(w.char_at(4, 0), ' ');
+    assert_eq!((w.scroll_top, w.scroll_bottom), (0, w.height - 1));
+}
+
+#[test_case]
+fn test_cursor_offset() {
+    let mut w = WRITER.lock();
+    w.clear();
+    assert_eq!(w.cursor_offset(), 0);
+    w.write_string("ab\nc");
+    assert_eq!(w.cursor_offset(), (w.width + 1) as u16);
+    w.write_string("\x1B[3;80Hx");
+    assert_eq!(w.cursor_offset(), (3 * w.width - 1) as u16);
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+
+    let mut w = WRITER.lock();
+    w.clear();
+    for i in 0..30 {
+        writeln!(w, "line {}", i).expect("writeln!() failed");
+    }
+    assert_eq!(w.char_at(0, 5), '6');
+    w.scroll_back(2);
+    assert_eq!(w.char_at(0, 5), '4');
+    assert_eq!(w.char_at(2, 5), '6');
+    w.scroll_forward(1);
+    assert_eq!(w.char_at(0, 5), '5');
+    w.scroll_back(MAX_SCROLLBACK * 2);
+    assert!(w.scrollback.offset <= w.scrollback.len);
+    w.write_string("x");
+    assert_eq!(w.scrollback.offset, 0);
+    assert_eq!(w.char_at(0, 5), '6');
+    assert_eq!(w.char_at(w.height - 1, 0), 'x');
+}
+
+#[test_case]
+fn test_scrollback_is_allocated_on_demand() {
+    let mut w = WRITER.lock();
+    w.resize(DEFAULT_TEXT_MODE.width(), DEFAULT_TEXT_MODE.height());
+    assert!(w.scrollback.rows.is_empty());
+    for _ in 0..w.height {
+        w.write_string("\n");
+    }
+    let capacity = SCROLLBACK.get() as usize;
+    assert_eq!(w.scrollback.rows.len(), capacity * w.width);
+    assert_eq!(w.scrollback.len, 1);
+}
+
+#[test_case]
+fn test_switch_console() {
+    WRITER.lock().clear();
+    WRITER.lock().write_string("one");
+    switch_console(1);
+    assert_eq!(active_console(), 1);
+    {
+        let mut console = VIRTUAL_CONSOLES[1].lock();
+        console.clear();
+        console.write_string("two");
+        assert_eq!(console.hardware_cursor, in_text_mode());
+    }
+    assert_eq!(WRITER.lock().char_at(0, 1), 'n');
+    assert!(!WRITER.lock().hardware_cursor);
+    switch_console(0);
+    assert_eq!(active_console(), 0);
+    assert_eq!(WRITER.lock().char_at(0, 1), 'n');
+    assert_eq!(VIRTUAL_CONSOLES[1].lock().char_at(0, 1), 'w');
+}
+
+#[test_case]
+fn test_tab_stops() {
+    let mut w = WRITER.lock();
+    w.clear();
+    w.write_string("a\tb\tc");
+    assert_eq!(w.char_at(0, 8), 'b');
+    assert_eq!(w.char_at(0, 16), 'c');
+    w.set_tab_width(4);
+    w.set_tab_stop(6);
+    w.clear_tab_stop(8);
+    w.write_string("\rd\te\tf\tg");
+    assert_eq!(w.char_at(0, 4), 'e');
+    assert_eq!(w.char_at(0, 6), 'f');
+    assert_eq!(w.char_at(0, 12), 'g');
+    w.clear_tab_stops();
+    w.write_string("\r\th");
+    assert_eq!(w.char_at(0, w.width - 1), 'h');
+    w.set_tab_width(DEFAULT_TAB_WIDTH);
+}
+
+#[test_case]
+fn test_control_characters() {
+    let mut w = WRITER.lock();
+    w.set_bell_mode(BellMode::Off);
+    w.clear();
+    w.write_string("ab\x08c\x07d\x0Be");
+    assert_eq!(w.char_at(0, 0), 'a');
+    assert_eq!(w.char_at(0, 1), 'c');
+    assert_eq!(w.char_at(0, 2), 'd');
+    assert_eq!(w.char_at(1, 0), 'e');
+    w.set_destructive_backspace(true);
+    w.write_string("fg\x08\x08");
+    w.set_destructive_backspace(false);
+    assert_eq!(w.char_at(1, 1), ' ');
+    assert_eq!(w.char_at(1, 2), ' ');
+    assert_eq!(w.column_position, 1);
+    w.write_string("\x0Ci");
+    assert_eq!(w.char_at(0, 0), 'i');
+    assert_eq!(w.char_at(1, 0), ' ');
+    w.set_bell_mode(BellMode::Audible);
+}
+
+#[test_case]
+fn test_framebuffer_console() {
+    use fb_console::{count_pixels, CELL_HEIGHT, CELL_WIDTH, PALETTE};
+
+    use crate::framebuffer::{test_framebuffer, PixelFormat};
+
+    // An inactive console, shown on a framebuffer of its own
+    let mut w = VIRTUAL_CONSOLES[VIRTUAL_CONSOLE_COUNT - 1].lock();
+    let framebuffer = test_framebuffer(4 * CELL_WIDTH, 2 * CELL_HEIGHT, PixelFormat::Bgr);
+    let previous = FRAMEBUFFER.lock().replace(framebuffer);
+    w.framebuffer = Some(alloc::boxed::Box::leak(alloc::boxed::Box::new(
+        FramebufferScreen::UNKNOWN,
+    )));
+    w.resize(4, 2);
+    w.set_color(Color::Yellow, Color::Blue);
+    w.write_string("a═\r\n");
+    w.set_color(Color::LightGray, Color::Black);
+    {
+        let framebuffer = FRAMEBUFFER.lock();
+        let framebuffer = framebuffer.as_ref().unwrap();
+        let (yellow, blue) = (
+            PALETTE[Color::Yellow as usize],
+            PALETTE[Color::Blue as usize],
+        );
+        assert!(count_pixels(framebuffer, 0, 0, yellow) > 0);
+        assert!(count_pixels(framebuffer, 0, 0, blue) > 0);
+        assert_eq!(
+            framebuffer.pixel(CELL_WIDTH, CELL_HEIGHT / 2 - 2),
+            Some(yellow)
+        );
+        assert_eq!(
+            count_pixels(framebuffer, 0, 2, Rgb::BLACK),
+            CELL_WIDTH * CELL_HEIGHT
+        );
+        // The underline cursor at the start of the second row
+        let cursor = (CELL_WIDTH / 2, 2 * CELL_HEIGHT - 1);
+        assert_eq!(framebuffer.pixel(cursor.0, cursor.1), Some(PALETTE[7]));
+    }
+    w.write_string("b");
+    assert_eq!(
+        FRAMEBUFFER
+            .lock()
+            .as_ref()
+            .unwrap()
+            .pixel(CELL_WIDTH / 2, 2 * CELL_HEIGHT - 1),
+        Some(Rgb::BLACK)
+    );
+    w.framebuffer = None;
+    w.resize(DEFAULT_TEXT_MODE.width(), DEFAULT_TEXT_MODE.height());
+    *FRAMEBUFFER.lock() = previous;
+}
+
+#[test_case]
+fn test_framebuffer_console_draws_dirty_rows() {
+    use fb_console::{count_pixels, CELL_HEIGHT, CELL_WIDTH};
+
+    use crate::framebuffer::{test_framebuffer, PixelFormat};
+
+    const CELL_PIXELS: usize = CELL_WIDTH * CELL_HEIGHT;
+    let black_pixels = |row, column| {
+        let framebuffer = FRAMEBUFFER.lock();
+        count_pixels(framebuffer.as_ref().unwrap(), row, column, Rgb::BLACK)
+    };
+
+    // An inactive console, shown on a framebuffer of its own
+    let mut w = VIRTUAL_CONSOLES[VIRTUAL_CONSOLE_COUNT - 1].lock();
+    let framebuffer = test_framebuffer(4 * CELL_WIDTH, 2 * CELL_HEIGHT, PixelFormat::Bgr);
+    let previous = FRAMEBUFFER.lock().replace(framebuffer);
+    w.framebuffer = Some(alloc::boxed::Box::leak(alloc::boxed::Box::new(
+        FramebufferScreen::UNKNOWN,
+    )));
+    w.resize(4, 2);
+    w.write_string("\r\n");
+    let dirty_rows = |w: &Writer| w.framebuffer.as_ref().unwrap().dirty_rows;
+    assert!(dirty_rows(&w).iter().all(|&dirty| !dirty));
+
+    // A cell changed behind the back of the console is not drawn, as its row is not dirty
+    let hidden = ScreenChar {
+        code_point: b'#',
+        color_code: DEFAULT_COLOR_CODE,
+    };
+    w.buffer.chars[0].write(hidden);
+    w.write_string("a");
+    assert!(black_pixels(1, 0) < CELL_PIXELS);
+    assert_eq!(black_pixels(0, 0), CELL_PIXELS);
+
+    w.set_cell(0, 1, hidden);
+    assert_eq!(dirty_rows(&w)[..2], [true, false]);
+    w.render();
+    assert!(black_pixels(0, 0) < CELL_PIXELS);
+    assert!(black_pixels(0, 1) < CELL_PIXELS);
+    w.framebuffer = None;
+    w.resize(DEFAULT_TEXT_MODE.width(), DEFAULT_TEXT_MODE.height());
+    *FRAMEBUFFER.lock() = previous;
+}
+
+#[test_case]
+fn test_text_mode_geometries() {
+    use core::fmt::Write;
+
+    // An inactive console, so that only its offscreen buffer is resized
+    let mut w = VIRTUAL_CONSOLES[VIRTUAL_CONSOLE_COUNT - 1].lock();
+    assert!(!w.hardware_cursor);
+    for mode in TextMode::ALL {
+        let (width, height) = (mode.width(), mode.height());
+        w.resize(width, height);
+        assert_eq!((w.width(), w.height()), (width, height));
+
+        for _ in 0..width {
+            w.write_byte(b'a');
         }
-    });
+        w.write_byte(b'b');
+        assert_eq!(w.char_at(0, width - 1), 'a');
+        assert_eq!(w.char_at(1, 0), 'b');
+        assert_eq!(w.cursor_offset(), (width + 1) as u16);
+
+        w.write_string("\rfirst");
+        for _ in 0..height {
+            write!(w, "\nx").expect("write!() failed");
+        }
+        assert_eq!(w.char_at(0, 0), 'x');
+        assert_eq!(w.char_at(height - 1, 0), 'x');
+        let len = w.scrollback.len;
+        assert_eq!(w.scrollback.row(len - 1)[0].code_point, b'f');
+        assert_eq!(w.scrollback.row(len - 2)[0].code_point, b'a');
+    }
+    w.resize(DEFAULT_TEXT_MODE.width(), DEFAULT_TEXT_MODE.height());
 }
@@ -0,0 +1,259 @@
+//! Fonts of the VGA text modes: loading 8 pixel wide bitmap fonts, e.g. PC Screen Fonts (see
+//! `psf`), and a bundled set of glyphs for characters missing from code page 437.
+//!
+//! The text mode shows the glyphs of plane 2 of the display memory, which the firmware fills with
+//! its code page 437 font. A loaded font replaces them, and tells which characters its glyphs
+//! show with a `UnicodeMap`.
+
+use alloc::vec::Vec;
+
+use crate::{
+    cp437,
+    psf::Font,
+    vga_mode::{self, in_text_mode, FONT_GLYPH_COUNT, FONT_GLYPH_SIZE},
+};
+
+/// An error loading a font.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The display shows a graphics mode, in which the font plane holds pixels.
+    NotTextMode,
+    /// The glyphs are not 8 pixels wide.
+    UnsupportedWidth(usize),
+    /// The glyphs are taller than `FONT_GLYPH_SIZE` scan lines.
+    UnsupportedHeight(usize),
+}
+
+/// The characters shown by the glyphs of a font not following code page 437.
+#[derive(Debug)]
+pub struct UnicodeMap {
+    /// The glyph showing each character, sorted by character.
+    glyphs: Vec<(char, u8)>,
+    /// The first character of each glyph.
+    chars: [char; FONT_GLYPH_COUNT],
+}
+
+impl UnicodeMap {
+    /// Returns the map of `entries`, pairs of a character and the glyph showing it. The first
+    /// entry of a character or a glyph takes precedence.
+    pub fn from_entries(entries: impl IntoIterator<Item = (char, u8)>) -> UnicodeMap {
+        let mut glyphs: Vec<(char, u8)> = entries.into_iter().collect();
+        let mut chars = [char::REPLACEMENT_CHARACTER; FONT_GLYPH_COUNT];
+        let mut has_char = [false; FONT_GLYPH_COUNT];
+        for &(c, glyph) in glyphs.iter() {
+            if !has_char[usize::from(glyph)] {
+                chars[usize::from(glyph)] = c;
+                has_char[usize::from(glyph)] = true;
+            }
+        }
+        // NOTE: the sort is stable, so deduplication keeps the first entry of a character
+        glyphs.sort_by_key(|&(c, _)| c);
+        glyphs.dedup_by_key(|&mut (c, _)| c);
+        UnicodeMap { glyphs, chars }
+    }
+
+    /// Returns the map of the Unicode table of `font`, or `None` if it has none.
+    pub fn of_font(font: &Font) -> Option<UnicodeMap> {
+        font.has_unicode_table().then(|| {
+            UnicodeMap::from_entries(
+                font.unicode_entries()
+                    .filter(|&(glyph, _)| glyph < FONT_GLYPH_COUNT)
+                    .map(|(glyph, c)| (c, glyph as u8)),
+            )
+        })
+    }
+
+    /// Returns the map of code page 437 with `EXTRA_GLYPHS` in place of the glyphs they replace.
+    pub fn with_extra_glyphs() -> UnicodeMap {
+        let replaced = |byte: u8| EXTRA_GLYPHS.iter().any(|extra| extra.byte == byte);
+        let extra = EXTRA_GLYPHS.iter().map(|extra| (extra.c, extra.byte));
+        let code_page = (0..=0xFF)
+            .map(|byte| (cp437::to_char(byte), byte))
+            .chain(cp437::ALIASES);
+        UnicodeMap::from_entries(extra.chain(code_page.filter(|&(_, byte)| !replaced(byte))))
+    }
+
+    /// Returns the glyph showing `c`, or `None` if the font lacks it.
+    pub fn glyph(&self, c: char) -> Option<u8> {
+        self.glyphs
+            .binary_search_by_key(&c, |&(c, _)| c)
+            .ok()
+            .map(|index| self.glyphs[index].1)
+    }
+
+    /// Returns the character shown by `glyph`, or the replacement character if none is.
+    pub fn char(&self, glyph: u8) -> char {
+        self.chars[usize::from(glyph)]
+    }
+}
+
+/// A bundled glyph for a character missing from code page 437.
+#[derive(Debug)]
+pub struct ExtraGlyph {
+    /// The character shown.
+    pub c: char,
+    /// The code page 437 byte whose glyph is replaced.
+    pub byte: u8,
+    /// The 8 by 16 pixel bitmap, the leftmost pixel of a row in the most significant bit.
+    pub rows: [u8; 16],
+}
+
+/// The bundled glyphs, replacing glyphs of rarely used characters, most of which have a similar
+/// looking one elsewhere in code page 437.
+pub const EXTRA_GLYPHS: [ExtraGlyph; 7] = [
+    ExtraGlyph {
+        c: '€',
+        // Peseta sign
+        byte: 0x9E,
+        rows: [
+            0x00, 0x00, 0x1E, 0x33, 0x60, 0xFC, 0x60, 0xF8, //
+            0x60, 0x60, 0x33, 0x1E, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '“',
+        // Reversed not sign
+        byte: 0xA9,
+        rows: [
+            0x00, 0x00, 0x24, 0x48, 0x6C, 0x6C, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '”',
+        // House, the glyph of DEL
+        byte: 0x7F,
+        rows: [
+            0x00, 0x00, 0x6C, 0x6C, 0x24, 0x48, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '‘',
+        // Top half integral
+        byte: 0xF4,
+        rows: [
+            0x00, 0x00, 0x08, 0x10, 0x18, 0x18, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '’',
+        // Bottom half integral
+        byte: 0xF5,
+        rows: [
+            0x00, 0x00, 0x18, 0x18, 0x08, 0x10, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '–',
+        // Bullet operator, like the middle dot
+        byte: 0xF9,
+        rows: [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    ExtraGlyph {
+        c: '…',
+        // Superscript n
+        byte: 0xFC,
+        rows: [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0xDB, 0xDB, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+];
+
+/// Returns the 8 by 16 pixel glyph `rows` scaled to `height` scan lines, as the first `height`
+/// bytes. Shrinking merges rows, so that thin lines remain, and growing pads at the bottom.
+fn scale_glyph(rows: &[u8; 16], height: usize) -> [u8; FONT_GLYPH_SIZE] {
+    let mut scaled = [0; FONT_GLYPH_SIZE];
+    if height >= rows.len() {
+        scaled[..rows.len()].copy_from_slice(rows);
+    } else {
+        for (row, byte) in scaled.iter_mut().take(height).enumerate() {
+            let source = row * rows.len() / height..(row + 1) * rows.len() / height;
+            *byte = rows[source].iter().fold(0, |merged, &row| merged | row);
+        }
+    }
+    scaled
+}
+
+/// Uploads the first 256 glyphs of `font`, returning the map of its Unicode table.
+///
+/// Nothing may access the text buffer meanwhile, see `vga_mode::with_font_plane()`.
+pub(crate) fn upload(font: &Font) -> Result<Option<UnicodeMap>, FontError> {
+    if font.width() != 8 {
+        return Err(FontError::UnsupportedWidth(font.width()));
+    }
+    if font.height() > FONT_GLYPH_SIZE {
+        return Err(FontError::UnsupportedHeight(font.height()));
+    }
+    if !in_text_mode() {
+        return Err(FontError::NotTextMode);
+    }
+    let map = UnicodeMap::of_font(font);
+    vga_mode::with_font_plane(|plane| {
+        for index in 0..font.len().min(FONT_GLYPH_COUNT) {
+            plane.write_glyph(index, font.glyph(index).unwrap());
+        }
+    });
+    Ok(map)
+}
+
+/// Draws `EXTRA_GLYPHS` over the glyphs they replace in the current font, scaled to the
+/// character height of the text mode, returning the map of the result.
+///
+/// Nothing may access the text buffer meanwhile, see `vga_mode::with_font_plane()`.
+pub(crate) fn upload_extra_glyphs() -> Result<UnicodeMap, FontError> {
+    if !in_text_mode() {
+        return Err(FontError::NotTextMode);
+    }
+    let height = vga_mode::char_height();
+    let map = UnicodeMap::with_extra_glyphs();
+    vga_mode::with_font_plane(|plane| {
+        for extra in EXTRA_GLYPHS.iter() {
+            let rows = scale_glyph(&extra.rows, height);
+            plane.write_glyph(usize::from(extra.byte), &rows[..height]);
+        }
+    });
+    Ok(map)
+}
+
+#[test_case]
+fn test_unicode_map_extra_glyphs() {
+    let map = UnicodeMap::with_extra_glyphs();
+    assert_eq!(map.glyph('A'), Some(b'A'));
+    assert_eq!(map.glyph('€'), Some(0x9E));
+    assert_eq!(map.glyph('₧'), None);
+    assert_eq!(map.glyph('β'), Some(0xE1));
+    assert_eq!(map.glyph('Д'), None);
+    assert_eq!(map.char(0x9E), '€');
+    assert_eq!(map.char(0x94), 'ö');
+    for extra in EXTRA_GLYPHS.iter() {
+        assert_eq!(cp437::from_char(extra.c), None);
+    }
+}
+
+#[test_case]
+fn test_unicode_map_of_font() {
+    let data = crate::psf::test_psf1(16, &[0x41, 0xFFFF, 0x20AC, 0x41, 0xFFFF]);
+    let map = UnicodeMap::of_font(&Font::parse(&data).unwrap()).unwrap();
+    assert_eq!(map.glyph('A'), Some(0));
+    assert_eq!(map.glyph('€'), Some(1));
+    assert_eq!(map.char(1), '€');
+    assert_eq!(map.char(2), char::REPLACEMENT_CHARACTER);
+    let data = crate::psf::test_psf1(16, &[]);
+    assert!(UnicodeMap::of_font(&Font::parse(&data).unwrap()).is_none());
+}
+
+#[test_case]
+fn test_scale_glyph() {
+    let rows = EXTRA_GLYPHS[6].rows;
+    assert_eq!(scale_glyph(&rows, 16)[..16], rows);
+    let halved = scale_glyph(&rows, 8);
+    assert_eq!(halved[..8], [0, 0, 0, 0, 0, 0xDB, 0, 0]);
+    assert_eq!(scale_glyph(&rows, 32)[16..], [0; 16]);
+}
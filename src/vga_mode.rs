@@ -0,0 +1,373 @@
+//! Programming of the VGA registers: the text modes, and the CRT Controller registers of the
+//! hardware cursor.
+//!
+//! The register values of the text modes are those of the standard BIOS modes, with the 90 column
+//! modes using 8 pixel wide characters and the 28 MHz dot clock for 720 by 480 pixels.
+//!
+//! NOTE: the 8 by 8 pixel character modes show the top halves of the 8 by 16 pixel glyphs of the
+//! firmware font, until an 8 by 8 pixel font is loaded, see `vga_buffer::load_font()`
+
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{framebuffer, memory};
+
+/// The ports of the VGA registers, with the color mode I/O addresses of the CRT Controller.
+const MISC_OUTPUT_WRITE_PORT: u16 = 0x3C2;
+const SEQUENCER_INDEX_PORT: u16 = 0x3C4;
+const SEQUENCER_DATA_PORT: u16 = 0x3C5;
+const GRAPHICS_INDEX_PORT: u16 = 0x3CE;
+const GRAPHICS_DATA_PORT: u16 = 0x3CF;
+const ATTRIBUTE_PORT: u16 = 0x3C0;
+/// Reading Input Status #1 resets the attribute port to expect an index.
+const INPUT_STATUS_PORT: u16 = 0x3DA;
+const CRTC_INDEX_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+
+/// The CRT Controller registers of the hardware cursor.
+pub(crate) const CRTC_MAXIMUM_SCAN_LINE: u8 = 0x09;
+pub(crate) const CRTC_CURSOR_START: u8 = 0x0A;
+pub(crate) const CRTC_CURSOR_END: u8 = 0x0B;
+pub(crate) const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+pub(crate) const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+/// The CRT Controller registers write protected while bit 7 of Vertical Retrace End is set.
+const CRTC_END_HORIZONTAL_BLANKING: u8 = 0x03;
+const CRTC_VERTICAL_RETRACE_END: u8 = 0x11;
+
+/// The Sequencer registers selecting the planes written to, and the addressing of the planes.
+const SEQUENCER_MAP_MASK: u8 = 0x02;
+const SEQUENCER_MEMORY_MODE: u8 = 0x04;
+
+/// The Graphics Controller registers selecting the plane read from, the addressing of the planes,
+/// and the memory range they are mapped at.
+const GRAPHICS_READ_MAP_SELECT: u8 = 0x04;
+const GRAPHICS_MODE: u8 = 0x05;
+const GRAPHICS_MISCELLANEOUS: u8 = 0x06;
+
+/// The physical address plane 2 of the display memory is mapped at by `with_font_plane()`.
+const FONT_PLANE_ADDRESS: u64 = 0xA0000;
+
+/// The bytes reserved for each glyph in the font plane, of which the character height many top
+/// ones are shown.
+pub const FONT_GLYPH_SIZE: usize = 32;
+
+/// The number of glyphs of the font shown in text mode.
+pub const FONT_GLYPH_COUNT: usize = 256;
+
+/// A VGA text mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// The standard mode of the firmware, with 9 by 16 pixel characters.
+    Text80x25,
+    /// 8 by 8 pixel characters on the 80 by 25 timing.
+    Text80x50,
+    /// 8 by 16 pixel characters on 720 by 480 pixels.
+    Text90x30,
+    /// 8 by 8 pixel characters on 720 by 480 pixels.
+    Text90x60,
+    /// 9 by 16 pixel characters at half the dot clock.
+    Text40x25,
+}
+
+impl TextMode {
+    /// All text modes.
+    pub const ALL: [TextMode; 5] = [
+        TextMode::Text80x25,
+        TextMode::Text80x50,
+        TextMode::Text90x30,
+        TextMode::Text90x60,
+        TextMode::Text40x25,
+    ];
+
+    /// Returns the number of columns.
+    pub const fn width(self) -> usize {
+        match self {
+            TextMode::Text80x25 | TextMode::Text80x50 => 80,
+            TextMode::Text90x30 | TextMode::Text90x60 => 90,
+            TextMode::Text40x25 => 40,
+        }
+    }
+
+    /// Returns the number of rows.
+    pub const fn height(self) -> usize {
+        match self {
+            TextMode::Text80x25 | TextMode::Text40x25 => 25,
+            TextMode::Text90x30 => 30,
+            TextMode::Text80x50 => 50,
+            TextMode::Text90x60 => 60,
+        }
+    }
+
+    /// Returns the height of the characters in scan lines.
+    pub const fn char_height(self) -> usize {
+        match self {
+            TextMode::Text80x25 | TextMode::Text90x30 | TextMode::Text40x25 => 16,
+            TextMode::Text80x50 | TextMode::Text90x60 => 8,
+        }
+    }
+
+    fn registers(self) -> &'static ModeRegisters {
+        match self {
+            TextMode::Text80x25 => &TEXT_80X25,
+            TextMode::Text80x50 => &TEXT_80X50,
+            TextMode::Text90x30 => &TEXT_90X30,
+            TextMode::Text90x60 => &TEXT_90X60,
+            TextMode::Text40x25 => &TEXT_40X25,
+        }
+    }
+}
+
+/// The register values of a mode.
+struct ModeRegisters {
+    miscellaneous: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+    graphics: [u8; 9],
+    attribute: [u8; 21],
+}
+
+/// The Graphics Controller registers of the text modes: odd/even addressing of the text buffer at
+/// 0xB8000.
+const TEXT_GRAPHICS: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x00, 0xFF];
+
+/// The Attribute Controller registers of the text modes: the standard 16 color palette, blinking
+/// and 9 pixel wide line graphics characters.
+const TEXT_ATTRIBUTE: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+    0x0C, 0x00, 0x0F, 0x08, 0x00,
+];
+
+const TEXT_80X25: ModeRegisters = ModeRegisters {
+    miscellaneous: 0x67,
+    sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00,
+        0x50, 0x9C, 0x0E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: TEXT_GRAPHICS,
+    attribute: TEXT_ATTRIBUTE,
+};
+
+const TEXT_80X50: ModeRegisters = ModeRegisters {
+    miscellaneous: 0x67,
+    sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x47, 0x06, 0x07, 0x00, 0x00, 0x01,
+        0x40, 0x9C, 0x8E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: TEXT_GRAPHICS,
+    attribute: TEXT_ATTRIBUTE,
+};
+
+const TEXT_90X30: ModeRegisters = ModeRegisters {
+    miscellaneous: 0xE7,
+    sequencer: [0x03, 0x01, 0x03, 0x00, 0x02],
+    crtc: [
+        0x6B, 0x59, 0x5A, 0x82, 0x60, 0x8D, 0x0B, 0x3E, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00,
+        0x00, 0xEA, 0x0C, 0xDF, 0x2D, 0x10, 0xE8, 0x05, 0xA3, 0xFF,
+    ],
+    graphics: TEXT_GRAPHICS,
+    attribute: TEXT_ATTRIBUTE,
+};
+
+const TEXT_90X60: ModeRegisters = ModeRegisters {
+    miscellaneous: 0xE7,
+    sequencer: [0x03, 0x01, 0x03, 0x00, 0x02],
+    crtc: [
+        0x6B, 0x59, 0x5A, 0x82, 0x60, 0x8D, 0x0B, 0x3E, 0x00, 0x47, 0x06, 0x07, 0x00, 0x00, 0x00,
+        0x00, 0xEA, 0x0C, 0xDF, 0x2D, 0x08, 0xE8, 0x05, 0xA3, 0xFF,
+    ],
+    graphics: TEXT_GRAPHICS,
+    attribute: TEXT_ATTRIBUTE,
+};
+
+const TEXT_40X25: ModeRegisters = ModeRegisters {
+    miscellaneous: 0x67,
+    sequencer: [0x03, 0x08, 0x03, 0x00, 0x02],
+    crtc: [
+        0x2D, 0x27, 0x28, 0x90, 0x2B, 0xA0, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00,
+        0xA0, 0x9C, 0x8E, 0x8F, 0x14, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: TEXT_GRAPHICS,
+    attribute: TEXT_ATTRIBUTE,
+};
+
+/// Writes `value` to the register at `index` of an indexed register set.
+fn write_indexed(index_port: u16, data_port: u16, index: u8, value: u8) {
+    // SAFETY: the VGA registers only affect the display, see `set_text_mode()`
+    unsafe {
+        Port::<u8>::new(index_port).write(index);
+        Port::<u8>::new(data_port).write(value);
+    }
+}
+
+/// Reads the register at `index` of an indexed register set.
+fn read_indexed(index_port: u16, data_port: u16, index: u8) -> u8 {
+    // SAFETY: reading the VGA registers has no side effects, and without a VGA the read returns
+    // all ones
+    unsafe {
+        Port::<u8>::new(index_port).write(index);
+        Port::<u8>::new(data_port).read()
+    }
+}
+
+/// Reads the CRT Controller register at `index`.
+pub(crate) fn crtc_read(index: u8) -> u8 {
+    read_indexed(CRTC_INDEX_PORT, CRTC_DATA_PORT, index)
+}
+
+/// Writes `value` to the CRT Controller register at `index`.
+pub(crate) fn crtc_write(index: u8, value: u8) {
+    write_indexed(CRTC_INDEX_PORT, CRTC_DATA_PORT, index, value);
+}
+
+/// Returns whether the display is in VGA text mode, i.e. shows the contents of the text buffer.
+pub fn in_text_mode() -> bool {
+    let miscellaneous = read_indexed(
+        GRAPHICS_INDEX_PORT,
+        GRAPHICS_DATA_PORT,
+        GRAPHICS_MISCELLANEOUS,
+    );
+    // NOTE: bit 0 selects graphics mode
+    miscellaneous & 0x01 == 0
+}
+
+/// Returns the character height of the current text mode in scan lines.
+pub fn char_height() -> usize {
+    usize::from(crtc_read(CRTC_MAXIMUM_SCAN_LINE) & 0x1F) + 1
+}
+
+/// Plane 2 of the display memory, holding the glyphs of the text mode font, see
+/// `with_font_plane()`.
+pub(crate) struct FontPlane {
+    base: *mut u8,
+}
+
+impl FontPlane {
+    /// Writes `rows` as glyph `index`, clearing the rest of its rows.
+    pub(crate) fn write_glyph(&mut self, index: usize, rows: &[u8]) {
+        assert!(index < FONT_GLYPH_COUNT && rows.len() <= FONT_GLYPH_SIZE);
+        for row in 0..FONT_GLYPH_SIZE {
+            let byte = rows.get(row).copied().unwrap_or(0);
+            // SAFETY: the glyph lies within the mapped plane, see `with_font_plane()`
+            unsafe {
+                self.base
+                    .add(index * FONT_GLYPH_SIZE + row)
+                    .write_volatile(byte)
+            };
+        }
+    }
+}
+
+/// Calls `f` with the font plane mapped at 0xA0000 in place of the text buffer, which neither
+/// shows nor stores characters meanwhile.
+///
+/// Must only be called in text mode, with the text buffer left alone until `f` returns.
+pub(crate) fn with_font_plane<R>(f: impl FnOnce(&mut FontPlane) -> R) -> R {
+    let saved = [
+        (SEQUENCER_INDEX_PORT, SEQUENCER_MAP_MASK),
+        (SEQUENCER_INDEX_PORT, SEQUENCER_MEMORY_MODE),
+        (GRAPHICS_INDEX_PORT, GRAPHICS_READ_MAP_SELECT),
+        (GRAPHICS_INDEX_PORT, GRAPHICS_MODE),
+        (GRAPHICS_INDEX_PORT, GRAPHICS_MISCELLANEOUS),
+    ]
+    .map(|(index_port, index)| {
+        (
+            index_port,
+            index,
+            read_indexed(index_port, index_port + 1, index),
+        )
+    });
+    let access = [
+        // Write plane 2 only, with sequential addressing
+        (SEQUENCER_INDEX_PORT, SEQUENCER_MAP_MASK, 0x04),
+        (SEQUENCER_INDEX_PORT, SEQUENCER_MEMORY_MODE, 0x06),
+        // Read plane 2, with sequential addressing, mapped at 0xA0000
+        (GRAPHICS_INDEX_PORT, GRAPHICS_READ_MAP_SELECT, 0x02),
+        (GRAPHICS_INDEX_PORT, GRAPHICS_MODE, 0x00),
+        (GRAPHICS_INDEX_PORT, GRAPHICS_MISCELLANEOUS, 0x04),
+    ];
+    for &(index_port, index, value) in access.iter() {
+        write_indexed(index_port, index_port + 1, index, value);
+    }
+
+    let mut plane = FontPlane {
+        base: memory::phys_to_virt(PhysAddr::new(FONT_PLANE_ADDRESS)).as_mut_ptr(),
+    };
+    let result = f(&mut plane);
+
+    for &(index_port, index, value) in saved.iter().rev() {
+        write_indexed(index_port, index_port + 1, index, value);
+    }
+    result
+}
+
+/// Programs the VGA registers for text `mode`.
+///
+/// Only reprograms the display, leaving the text buffer and the font as they are. Use
+/// `vga_buffer::set_text_mode()` to also resize the consoles.
+pub fn set_text_mode(mode: TextMode) {
+    let registers = mode.registers();
+    framebuffer::disable_bochs_vbe();
+    // SAFETY: the VGA registers only affect the display
+    unsafe { Port::<u8>::new(MISC_OUTPUT_WRITE_PORT).write(registers.miscellaneous) };
+    for (index, &value) in registers.sequencer.iter().enumerate() {
+        write_indexed(
+            SEQUENCER_INDEX_PORT,
+            SEQUENCER_DATA_PORT,
+            index as u8,
+            value,
+        );
+    }
+
+    // Unlock the CRT Controller registers, and keep them unlocked
+    let horizontal = crtc_read(CRTC_END_HORIZONTAL_BLANKING);
+    crtc_write(CRTC_END_HORIZONTAL_BLANKING, horizontal | 0x80);
+    let vertical = crtc_read(CRTC_VERTICAL_RETRACE_END);
+    crtc_write(CRTC_VERTICAL_RETRACE_END, vertical & !0x80);
+    for (index, &value) in registers.crtc.iter().enumerate() {
+        let value = match index as u8 {
+            CRTC_END_HORIZONTAL_BLANKING => value | 0x80,
+            CRTC_VERTICAL_RETRACE_END => value & !0x80,
+            _ => value,
+        };
+        crtc_write(index as u8, value);
+    }
+
+    for (index, &value) in registers.graphics.iter().enumerate() {
+        write_indexed(GRAPHICS_INDEX_PORT, GRAPHICS_DATA_PORT, index as u8, value);
+    }
+
+    let mut input_status = Port::<u8>::new(INPUT_STATUS_PORT);
+    let mut attribute = Port::<u8>::new(ATTRIBUTE_PORT);
+    // SAFETY: the VGA registers only affect the display
+    unsafe {
+        for (index, &value) in registers.attribute.iter().enumerate() {
+            input_status.read();
+            attribute.write(index as u8);
+            attribute.write(value);
+        }
+        // Lock the palette and enable the display
+        input_status.read();
+        attribute.write(0x20);
+    }
+}
+
+#[test_case]
+fn test_text_mode_registers() {
+    for mode in TextMode::ALL {
+        let crtc = &mode.registers().crtc;
+        // Offset: words per row
+        assert_eq!(usize::from(crtc[0x13]) * 2, mode.width());
+        // Horizontal Display End: the last character clock of a row
+        assert_eq!(usize::from(crtc[0x01]) + 1, mode.width());
+        // Maximum Scan Line: the last scan line of a character
+        assert_eq!(usize::from(crtc[0x09] & 0x1F) + 1, mode.char_height());
+        // Vertical Display End, with bits 8 and 9 in Overflow: the last scan line shown
+        let overflow = crtc[0x07];
+        let display_end = usize::from(crtc[0x12])
+            | usize::from(overflow >> 1 & 1) << 8
+            | usize::from(overflow >> 6 & 1) << 9;
+        assert_eq!(display_end + 1, mode.height() * mode.char_height());
+    }
+}
@@ -0,0 +1,314 @@
+//! Virtual memory areas, i.e. reserved ranges of virtual memory whose pages are only backed by
+//! frames once they are first accessed.
+//!
+//! The areas of an address space are kept in a `Vmas` set. A page fault on an unmapped page of an
+//! area maps a zeroed frame with the permissions of the area, while faults outside of all areas
+//! are genuine errors.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::memory::PAGE_SIZE;
+
+/// The access permissions of a memory area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u64);
+
+impl Protection {
+    /// No access at all, e.g. for guard areas.
+    pub const NONE: Protection = Protection(0);
+    /// Readable.
+    pub const READ: Protection = Protection(1);
+    /// Writable, which implies readable.
+    pub const WRITE: Protection = Protection(2);
+    /// Executable, which implies readable.
+    pub const EXECUTE: Protection = Protection(4);
+    /// Readable and writable.
+    pub const READ_WRITE: Protection = Protection(1 | 2);
+
+    /// Returns the protection with the bits in `bits`, or `None` if some are unknown. Readable is
+    /// added if the bits are writable or executable.
+    pub fn from_bits(bits: u64) -> Option<Protection> {
+        (bits & !0b111 == 0).then(|| Protection(bits).with_implied())
+    }
+
+    /// Returns the protection with the permissions implied by it added, i.e. readable if it is
+    /// writable or executable.
+    pub fn with_implied(self) -> Protection {
+        if self == Protection::NONE {
+            self
+        } else {
+            self | Protection::READ
+        }
+    }
+
+    /// Returns the bits of the protection.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether all permissions of `other` are included in this protection.
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the page table flags of a page with this protection, or `None` if the page is to
+    /// stay unmapped.
+    pub fn page_flags(self) -> Option<PageTableFlags> {
+        if self == Protection::NONE {
+            return None;
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        Some(flags)
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+
+/// A page aligned range of virtual memory with uniform permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// The address right after the area.
+    pub end: VirtAddr,
+    pub protection: Protection,
+}
+
+impl Vma {
+    /// Returns whether `address` lies within the area.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// Returns the size of the area in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// An error adding a memory area that overlaps an existing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap;
+
+/// A set of non-overlapping memory areas.
+///
+/// Adjacent areas with the same protection are merged.
+#[derive(Debug, Clone, Default)]
+pub struct Vmas {
+    /// The areas keyed by their start address.
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl Vmas {
+    /// Creates an empty set.
+    pub const fn new() -> Vmas {
+        Vmas {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the area containing `address`.
+    pub fn find(&self, address: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=address.as_u64())
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(address))
+    }
+
+    /// Returns whether any area overlaps `[start, end)`.
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        // NOTE: areas do not overlap, so only the last one starting before `end` can reach `start`
+        self.areas
+            .range(..end.as_u64())
+            .next_back()
+            .is_some_and(|(_, area)| area.end > start)
+    }
+
+    /// Adds `area`, which must not overlap existing areas.
+    pub fn insert(&mut self, area: Vma) -> Result<(), Overlap> {
+        assert!(
+            area.start.is_aligned(PAGE_SIZE) && area.end.is_aligned(PAGE_SIZE),
+            "Memory area not page aligned"
+        );
+        if area.start >= area.end {
+            return Ok(());
+        }
+        if self.overlaps(area.start, area.end) {
+            return Err(Overlap);
+        }
+        let mut area = area;
+        let before = self
+            .areas
+            .range(..area.start.as_u64())
+            .next_back()
+            .map(|(_, before)| *before)
+            .filter(|before| before.end == area.start && before.protection == area.protection);
+        if let Some(before) = before {
+            self.areas.remove(&before.start.as_u64());
+            area.start = before.start;
+        }
+        if let Some(after) = self.areas.get(&area.end.as_u64()).copied() {
+            if after.protection == area.protection {
+                self.areas.remove(&after.start.as_u64());
+                area.end = after.end;
+            }
+        }
+        self.areas.insert(area.start.as_u64(), area);
+        Ok(())
+    }
+
+    /// Removes `[start, end)` from all areas, splitting those which extend beyond it, and returns
+    /// the removed parts.
+    pub fn remove(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        let overlapping: Vec<Vma> = self
+            .areas
+            .range(..end.as_u64())
+            .rev()
+            .map(|(_, area)| *area)
+            .take_while(|area| area.end > start)
+            .collect();
+        let mut removed = Vec::new();
+        for area in overlapping {
+            self.areas.remove(&area.start.as_u64());
+            if area.start < start {
+                let below = Vma { end: start, ..area };
+                self.areas.insert(below.start.as_u64(), below);
+            }
+            if area.end > end {
+                let above = Vma { start: end, ..area };
+                self.areas.insert(above.start.as_u64(), above);
+            }
+            removed.push(Vma {
+                start: area.start.max(start),
+                end: area.end.min(end),
+                ..area
+            });
+        }
+        removed
+    }
+
+    /// Returns the highest start address of a free range of `size` bytes within `[lower, upper)`.
+    pub fn find_free(&self, size: u64, lower: VirtAddr, upper: VirtAddr) -> Option<VirtAddr> {
+        let mut end = upper;
+        for area in self
+            .areas
+            .range(..upper.as_u64())
+            .rev()
+            .map(|(_, area)| area)
+        {
+            if area.end <= lower {
+                break;
+            }
+            if area.end < end && end - area.end >= size {
+                return Some(end - size);
+            }
+            end = end.min(area.start);
+        }
+        (end > lower && end - lower >= size).then(|| end - size)
+    }
+
+    /// Returns an iterator over the areas in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Returns the number of areas.
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    /// Returns whether there are no areas.
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+}
+
+#[cfg(test)]
+fn test_area(start: u64, end: u64, protection: Protection) -> Vma {
+    Vma {
+        start: VirtAddr::new(start * PAGE_SIZE),
+        end: VirtAddr::new(end * PAGE_SIZE),
+        protection,
+    }
+}
+
+#[test_case]
+fn test_vmas_find_and_merge() {
+    let mut areas = Vmas::new();
+    areas.insert(test_area(1, 3, Protection::READ)).unwrap();
+    areas.insert(test_area(5, 6, Protection::READ)).unwrap();
+    areas.insert(test_area(3, 5, Protection::READ)).unwrap();
+    assert_eq!(areas.len(), 1);
+    areas
+        .insert(test_area(6, 7, Protection::READ_WRITE))
+        .unwrap();
+    assert_eq!(areas.len(), 2);
+    assert_eq!(
+        areas.insert(test_area(0, 2, Protection::READ)),
+        Err(Overlap)
+    );
+    assert_eq!(
+        areas.find(VirtAddr::new(4 * PAGE_SIZE + 8)),
+        Some(&test_area(1, 6, Protection::READ))
+    );
+    assert_eq!(areas.find(VirtAddr::new(7 * PAGE_SIZE)), None);
+    assert_eq!(areas.find(VirtAddr::new(0)), None);
+}
+
+#[test_case]
+fn test_vmas_remove_splits_areas() {
+    let mut areas = Vmas::new();
+    areas.insert(test_area(1, 9, Protection::READ)).unwrap();
+    areas.insert(test_area(10, 12, Protection::WRITE)).unwrap();
+    let removed = areas.remove(VirtAddr::new(3 * PAGE_SIZE), VirtAddr::new(11 * PAGE_SIZE));
+    assert_eq!(
+        removed,
+        [
+            test_area(10, 11, Protection::WRITE),
+            test_area(3, 9, Protection::READ)
+        ]
+    );
+    let remaining: Vec<Vma> = areas.iter().copied().collect();
+    assert_eq!(
+        remaining,
+        [
+            test_area(1, 3, Protection::READ),
+            test_area(11, 12, Protection::WRITE)
+        ]
+    );
+}
+
+#[test_case]
+fn test_vmas_find_free() {
+    let mut areas = Vmas::new();
+    let (lower, upper) = (VirtAddr::new(0), VirtAddr::new(16 * PAGE_SIZE));
+    assert_eq!(
+        areas.find_free(4 * PAGE_SIZE, lower, upper),
+        Some(VirtAddr::new(12 * PAGE_SIZE))
+    );
+    areas.insert(test_area(10, 16, Protection::READ)).unwrap();
+    areas.insert(test_area(2, 8, Protection::WRITE)).unwrap();
+    assert_eq!(
+        areas.find_free(2 * PAGE_SIZE, lower, upper),
+        Some(VirtAddr::new(8 * PAGE_SIZE))
+    );
+    assert_eq!(areas.find_free(2 * PAGE_SIZE + 1, lower, upper), None);
+    assert_eq!(
+        areas.find_free(PAGE_SIZE, lower, VirtAddr::new(9 * PAGE_SIZE)),
+        Some(VirtAddr::new(8 * PAGE_SIZE))
+    );
+}
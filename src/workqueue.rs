@@ -0,0 +1,279 @@
+//! Work queues for deferring work out of interrupt context.
+//!
+//! Interrupt handlers should do as little as possible with interrupts disabled. Instead of e.g.
+//! formatting and writing to the screen directly, a handler enqueues a `Work` item which the kernel
+//! worker later executes with interrupts enabled. Each queue executes its items in FIFO order.
+
+use crate::sync::IrqSpinlock;
+
+/// The maximum number of pending items per queue.
+const QUEUE_CAPACITY: usize = 64;
+
+/// The queue for deferred processing of hardware interrupts.
+pub static IRQ_WORK: WorkQueue = WorkQueue::new("irq");
+/// The queue for general kernel housekeeping.
+pub static SYSTEM_WORK: WorkQueue = WorkQueue::new("system");
+
+/// The queues serviced by `run_worker()`, in order of priority.
+static QUEUES: [&WorkQueue; 2] = [&IRQ_WORK, &SYSTEM_WORK];
+
+/// A deferred function call.
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    data: usize,
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), data: usize) -> Work {
+        Work { func, data }
+    }
+
+    fn run(self) {
+        (self.func)(self.data);
+    }
+}
+
+/// Returned when enqueuing to a full `WorkQueue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Statistics of a `WorkQueue` since its creation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkQueueStats {
+    /// The number of items successfully enqueued.
+    pub enqueued: u64,
+    /// The number of items executed.
+    pub executed: u64,
+    /// The number of items rejected because the queue was full.
+    pub dropped: u64,
+    /// The highest number of simultaneously pending items.
+    pub max_pending: usize,
+}
+
+/// A fixed capacity ring buffer of pending `Work`.
+struct Pending {
+    items: [Option<Work>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+    stats: WorkQueueStats,
+}
+
+impl Pending {
+    fn push(&mut self, work: Work) -> Result<(), QueueFull> {
+        if self.len == QUEUE_CAPACITY {
+            self.stats.dropped += 1;
+            return Err(QueueFull);
+        }
+        self.items[(self.head + self.len) % QUEUE_CAPACITY] = Some(work);
+        self.len += 1;
+        self.stats.enqueued += 1;
+        self.stats.max_pending = self.stats.max_pending.max(self.len);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        work
+    }
+}
+
+/// A FIFO queue of deferred `Work`, safe to enqueue to from interrupt handlers.
+pub struct WorkQueue {
+    name: &'static str,
+    pending: IrqSpinlock<Pending>,
+}
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> WorkQueue {
+        WorkQueue {
+            name,
+            pending: IrqSpinlock::with_name(
+                name,
+                Pending {
+                    items: [None; QUEUE_CAPACITY],
+                    head: 0,
+                    len: 0,
+                    stats: WorkQueueStats {
+                        enqueued: 0,
+                        executed: 0,
+                        dropped: 0,
+                        max_pending: 0,
+                    },
+                },
+            ),
+        }
+    }
+
+    /// Returns the name of the queue.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Schedules `func(data)` to be called later by the worker.
+    ///
+    /// Fails if the queue is full, in which case the work is dropped.
+    pub fn enqueue(&self, func: fn(usize), data: usize) -> Result<(), QueueFull> {
+        self.pending.lock().push(Work::new(func, data))
+    }
+
+    /// Returns the number of pending items.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len
+    }
+
+    /// Returns a snapshot of the queue statistics.
+    pub fn stats(&self) -> WorkQueueStats {
+        self.pending.lock().stats
+    }
+
+    /// Executes the items pending at the time of the call in FIFO order, returning how many were
+    /// executed.
+    ///
+    /// Items enqueued while running, e.g. by the work itself, are left for the next call. The queue
+    /// is not locked while an item executes, so the interrupt flag of the caller is in effect.
+    pub fn run_pending(&self) -> usize {
+        let budget = self.pending();
+        let mut executed = 0;
+        while executed < budget {
+            let work = {
+                let mut pending = self.pending.lock();
+                match pending.pop() {
+                    Some(work) => {
+                        pending.stats.executed += 1;
+                        work
+                    }
+                    None => break,
+                }
+            };
+            work.run();
+            executed += 1;
+        }
+        executed
+    }
+}
+
+/// Returns whether any of the kernel work queues has pending items.
+pub fn has_pending() -> bool {
+    QUEUES.iter().any(|queue| queue.pending() > 0)
+}
+
+/// Runs the kernel worker once, executing the pending items of all kernel work queues.
+///
+/// Must be called with interrupts enabled, outside of interrupt context. Returns the number of
+/// executed items.
+pub fn run_worker() -> usize {
+    debug_assert!(
+        !crate::interrupts::in_interrupt(),
+        "Kernel worker run in interrupt context"
+    );
+    QUEUES.iter().map(|queue| queue.run_pending()).sum()
+}
+
+#[cfg(test)]
+static LOG: IrqSpinlock<([usize; 8], usize)> = IrqSpinlock::new(([0; 8], 0));
+
+#[cfg(test)]
+fn record(data: usize) {
+    let mut log = LOG.lock();
+    let (entries, len) = &mut *log;
+    entries[*len] = data;
+    *len += 1;
+}
+
+#[cfg(test)]
+fn clear_log() {
+    *LOG.lock() = ([0; 8], 0);
+}
+
+#[test_case]
+fn test_work_queue_fifo_order() {
+    static QUEUE: WorkQueue = WorkQueue::new("test_fifo");
+
+    clear_log();
+    for data in [3, 1, 4, 1, 5] {
+        QUEUE.enqueue(record, data).unwrap();
+    }
+    assert_eq!(QUEUE.pending(), 5);
+    assert_eq!(QUEUE.run_pending(), 5);
+    assert_eq!(QUEUE.pending(), 0);
+
+    let (entries, len) = *LOG.lock();
+    assert_eq!(&entries[..len], &[3, 1, 4, 1, 5]);
+}
+
+#[test_case]
+fn test_work_queue_stats() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static QUEUE: WorkQueue = WorkQueue::new("test_stats");
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_: usize) {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    for _ in 0..QUEUE_CAPACITY {
+        QUEUE.enqueue(count, 0).unwrap();
+    }
+    assert_eq!(QUEUE.enqueue(count, 0), Err(QueueFull));
+    assert_eq!(QUEUE.run_pending(), QUEUE_CAPACITY);
+    assert_eq!(CALLS.load(Ordering::Relaxed), QUEUE_CAPACITY);
+    assert_eq!(
+        QUEUE.stats(),
+        WorkQueueStats {
+            enqueued: QUEUE_CAPACITY as u64,
+            executed: QUEUE_CAPACITY as u64,
+            dropped: 1,
+            max_pending: QUEUE_CAPACITY,
+        }
+    );
+}
+
+#[test_case]
+fn test_work_queue_defers_requeued_work() {
+    static QUEUE: WorkQueue = WorkQueue::new("test_requeue");
+
+    fn requeue(data: usize) {
+        record(data);
+        if data > 0 {
+            QUEUE.enqueue(requeue, data - 1).unwrap();
+        }
+    }
+
+    clear_log();
+    QUEUE.enqueue(requeue, 2).unwrap();
+    QUEUE.enqueue(record, 7).unwrap();
+    assert_eq!(QUEUE.run_pending(), 2);
+    assert_eq!(QUEUE.run_pending(), 1);
+    assert_eq!(QUEUE.run_pending(), 1);
+    assert_eq!(QUEUE.run_pending(), 0);
+
+    let (entries, len) = *LOG.lock();
+    assert_eq!(&entries[..len], &[2, 7, 1, 0]);
+}
+
+#[test_case]
+fn test_work_runs_with_interrupts_enabled() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static QUEUE: WorkQueue = WorkQueue::new("test_interrupts");
+    static ENABLED: AtomicUsize = AtomicUsize::new(0);
+
+    fn check(_: usize) {
+        if x86_64::instructions::interrupts::are_enabled() {
+            ENABLED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        QUEUE.enqueue(check, 0).unwrap();
+    });
+    QUEUE.run_pending();
+    assert_eq!(ENABLED.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,41 @@
+//! The fixture shared by the integration tests running hand-written programs in ring 3: a code page
+//! and a stack mapped into user space, and a loader copying a program into the code page.
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+use yarhos::{memory, usermode::UserContext};
+
+/// Where test programs are copied to.
+pub const CODE_ADDR: u64 = memory::USER_SPACE_START;
+/// The size reserved for the code of a test program.
+pub const CODE_SIZE: u64 = memory::PAGE_SIZE;
+/// The initial user stack pointer.
+pub const STACK_TOP: u64 = memory::USER_SPACE_START + 0x10_0000;
+/// The size of the user stack.
+pub const STACK_SIZE: u64 = 4 * memory::PAGE_SIZE;
+
+/// Maps the user code page and the user stack. Must be called once, after `memory::init()`.
+pub fn map_user_memory() {
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    // SAFETY: no other mapper exists at this point
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::map_zeroed(&mut mapper, VirtAddr::new(CODE_ADDR), CODE_SIZE, flags)
+        .expect("Mapping user code failed");
+    memory::map_zeroed(
+        &mut mapper,
+        VirtAddr::new(STACK_TOP - STACK_SIZE),
+        STACK_SIZE,
+        flags,
+    )
+    .expect("Mapping user stack failed");
+}
+
+/// Copies the code between `start` and `end` to user space and returns a context to run it.
+pub fn load_program(start: &u8, end: &u8) -> UserContext {
+    let start = start as *const u8;
+    let len = end as *const u8 as usize - start as usize;
+    assert!(len as u64 <= CODE_SIZE);
+    // SAFETY: the user code page is mapped and writable, and `start..end` is a valid range
+    unsafe { core::ptr::copy_nonoverlapping(start, CODE_ADDR as *mut u8, len) };
+    UserContext::new(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_TOP))
+}
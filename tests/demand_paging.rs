@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+use yarhos::{
+    address_space::{AreaError, MMAP_START},
+    loader, memory,
+    process::{self, Pid},
+    vma::Protection,
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static EXIT_PROGRAM: &[u8] = include_bytes!("programs/exit.elf");
+static MMAP_PROGRAM: &[u8] = include_bytes!("programs/mmap.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_mmap_munmap_and_brk() {
+    let frames = memory::allocated_frames();
+    let pid = process::spawn(MMAP_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 42)));
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn test_areas_are_mapped_on_demand() {
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).unwrap();
+    let address_space = &mut program.address_space;
+    let frames = memory::allocated_frames();
+    let size = 1 << 30;
+    let start = address_space
+        .map_anonymous(None, size, Protection::READ_WRITE)
+        .expect("Reserving failed");
+    assert_eq!(memory::allocated_frames(), frames);
+
+    let address = start + size / 2;
+    assert!(address_space.handle_page_fault(address, Protection::WRITE));
+    let mapped = memory::allocated_frames();
+    // The frame and possibly page tables
+    assert!((frames + 1..=frames + 4).contains(&mapped));
+    assert!(!address_space.handle_page_fault(address, Protection::WRITE));
+    assert!(!address_space.handle_page_fault(start + size, Protection::READ));
+    assert!(!address_space.handle_page_fault(start, Protection::EXECUTE));
+
+    address_space.unmap(start, size).unwrap();
+    assert_eq!(memory::allocated_frames(), mapped - 1);
+    assert!(!address_space.handle_page_fault(address, Protection::READ));
+}
+
+#[test_case]
+fn test_area_placement() {
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).unwrap();
+    let address_space = &mut program.address_space;
+    let fixed = VirtAddr::new(MMAP_START);
+    assert_eq!(
+        address_space.map_anonymous(Some(fixed), 4096, Protection::READ),
+        Ok(fixed)
+    );
+    assert_eq!(
+        address_space.map_anonymous(Some(fixed), 1, Protection::READ),
+        Err(AreaError::Overlap)
+    );
+    assert_eq!(
+        address_space.map_anonymous(Some(fixed + 1u64), 4096, Protection::READ),
+        Err(AreaError::BadRange)
+    );
+    assert_eq!(
+        address_space.map_anonymous(
+            Some(VirtAddr::new(loader::USER_STACK_TOP)),
+            1,
+            Protection::READ
+        ),
+        Err(AreaError::BadRange)
+    );
+    let first = address_space
+        .map_anonymous(None, 4096, Protection::READ)
+        .unwrap();
+    let second = address_space
+        .map_anonymous(None, 4096, Protection::READ)
+        .unwrap();
+    assert!(first.as_u64() >= MMAP_START && second.as_u64() >= MMAP_START);
+    assert_ne!(first, second);
+    assert_eq!(program.run(), 42);
+}
+
+#[test_case]
+fn test_writable_areas_are_readable() {
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).unwrap();
+    let address_space = &mut program.address_space;
+    let start = address_space
+        .map_anonymous(None, 4096, Protection::WRITE)
+        .expect("Reserving failed");
+    assert!(address_space.handle_page_fault(start, Protection::READ));
+    assert!(!address_space.handle_page_fault(start, Protection::EXECUTE));
+    assert_eq!(
+        Protection::from_bits(Protection::EXECUTE.bits()),
+        Some(Protection::EXECUTE | Protection::READ)
+    );
+}
+
+#[test_case]
+fn test_program_break() {
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).unwrap();
+    let address_space = &mut program.address_space;
+    let start = address_space.program_break();
+    assert!(start.is_aligned(4096u64));
+    assert_eq!(
+        address_space.set_program_break(start - 1u64),
+        Err(AreaError::BadRange)
+    );
+    address_space.set_program_break(start + 10_000u64).unwrap();
+    assert_eq!(address_space.program_break(), start + 10_000u64);
+    assert!(address_space.handle_page_fault(start + 8192u64, Protection::WRITE));
+    assert!(!address_space.handle_page_fault(start + 12_288u64, Protection::WRITE));
+    let frames = memory::allocated_frames();
+    address_space.set_program_break(start).unwrap();
+    assert_eq!(memory::allocated_frames(), frames - 1);
+    assert!(address_space.areas().is_empty());
+}
+
+#[test_case]
+fn test_lazy_kernel_buffer() {
+    let frames = memory::allocated_frames();
+    let size = 16 * 1024 * 1024;
+    let start = memory::reserve_lazy(size).expect("Reserving failed");
+    assert_eq!(memory::allocated_frames(), frames);
+
+    let buffer: *mut u64 = (start + size / 2).as_mut_ptr();
+    // SAFETY: the buffer was reserved above
+    unsafe {
+        assert_eq!(buffer.read_volatile(), 0);
+        buffer.write_volatile(42);
+        assert_eq!(buffer.read_volatile(), 42);
+    }
+    let touched = memory::allocated_frames();
+    assert!(touched > frames);
+
+    // SAFETY: the buffer is not used anymore
+    unsafe { memory::release_lazy(start, size) };
+    assert_eq!(memory::allocated_frames(), touched - 1);
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+use yarhos::{
+    elf::{ElfError, ElfFile},
+    loader::{self, LoadError},
+    memory,
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static EXIT_PROGRAM: &[u8] = include_bytes!("programs/exit.elf");
+static ARGS_PROGRAM: &[u8] = include_bytes!("programs/args.elf");
+static DATA_PROGRAM: &[u8] = include_bytes!("programs/data.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_run_program() {
+    let mut program = loader::load(EXIT_PROGRAM, &["exit"], &[]).expect("Loading failed");
+    assert_eq!(program.run(), 42);
+}
+
+#[test_case]
+fn test_initial_stack() {
+    let mut program = loader::load(ARGS_PROGRAM, &["args", "one ", "two "], &["A=1", "B=2"])
+        .expect("Loading failed");
+    let argc = 3;
+    let envc = 2 << 8;
+    let page_size_ok = 1 << 16;
+    let aligned = 1 << 17;
+    assert_eq!(program.run(), argc | envc | page_size_ok | aligned);
+}
+
+#[test_case]
+fn test_data_segments() {
+    let mut program = loader::load(DATA_PROGRAM, &[], &[]).expect("Loading failed");
+    assert_eq!(program.run(), 42);
+}
+
+#[test_case]
+fn test_segment_permissions() {
+    let file = ElfFile::parse(DATA_PROGRAM).unwrap();
+    let mut program = loader::load(DATA_PROGRAM, &[], &[]).expect("Loading failed");
+    let mapper = program.address_space.mapper();
+    let flags_at = |address: u64| match mapper.translate(VirtAddr::new(address)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} not mapped", address),
+    };
+    for header in file.program_headers().filter(|header| header.is_load()) {
+        let flags = flags_at(header.virtual_address);
+        assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        let writable = header.flags & yarhos::elf::PF_W != 0;
+        let executable = header.flags & yarhos::elf::PF_X != 0;
+        assert_eq!(flags.contains(PageTableFlags::WRITABLE), writable);
+        assert_eq!(!flags.contains(PageTableFlags::NO_EXECUTE), executable);
+    }
+    let stack_flags = flags_at(loader::USER_STACK_TOP - 8);
+    assert!(stack_flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn test_address_spaces_are_isolated() {
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).expect("Loading failed");
+    let entry = VirtAddr::new(program.context.rip);
+    assert!(!memory::is_user_accessible(entry, 1));
+    assert_eq!(program.run(), 42);
+    assert!(!memory::is_user_accessible(entry, 1));
+    assert!(!program.address_space.is_active());
+}
+
+#[test_case]
+fn test_rejects_invalid_files() {
+    assert_eq!(
+        loader::load(&EXIT_PROGRAM[1..], &[], &[]).unwrap_err(),
+        LoadError::Elf(ElfError::BadMagic)
+    );
+    assert_eq!(
+        loader::load(&EXIT_PROGRAM[..32], &[], &[]).unwrap_err(),
+        LoadError::Elf(ElfError::Truncated)
+    );
+}
+
+#[test_case]
+fn test_rejects_kernel_segments() {
+    let file = ElfFile::parse(EXIT_PROGRAM).unwrap();
+    let mut image = [0; 8192];
+    let image = &mut image[..EXIT_PROGRAM.len()];
+    image.copy_from_slice(EXIT_PROGRAM);
+    // Move the first loadable segment to the kernel image
+    let index = file
+        .program_headers()
+        .position(|header| header.is_load())
+        .unwrap();
+    let address_field =
+        file.program_header_offset() + index * yarhos::elf::PROGRAM_HEADER_SIZE + 16;
+    image[address_field..address_field + 8].copy_from_slice(&0x20_0000u64.to_le_bytes());
+    assert_eq!(
+        loader::load(image, &[], &[]).unwrap_err(),
+        LoadError::BadSegment
+    );
+}
+
+#[test_case]
+fn test_rejects_oversized_arguments() {
+    let argument = core::str::from_utf8(&[b'x'; 1024]).unwrap();
+    let arguments = [argument; 32];
+    assert_eq!(
+        loader::load(EXIT_PROGRAM, &arguments, &[]).unwrap_err(),
+        LoadError::ArgumentsTooLarge
+    );
+}
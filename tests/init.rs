@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use yarhos::{
+    memory,
+    process::{self, Pid},
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static EXIT_PROGRAM: &[u8] = include_bytes!("programs/exit.elf");
+static INIT_PROGRAM: &[u8] = include_bytes!("programs/init.elf");
+static PIDS_PROGRAM: &[u8] = include_bytes!("programs/pids.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_orphans_without_init_go_to_kernel() {
+    let parent = process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    assert_ne!(parent, Pid::INIT);
+    let child = process::spawn(EXIT_PROGRAM, &[], &[], parent).unwrap();
+    assert_eq!(process::wait(Some(parent)), Ok((parent, 42)));
+    assert_eq!(process::wait(Some(child)), Ok((child, 42)));
+}
+
+#[test_case]
+fn test_init_reaps_orphans() {
+    let frames = memory::allocated_frames();
+    assert_eq!(process::spawn_init(INIT_PROGRAM, &["init"]), Ok(Pid::INIT));
+    let parent = process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let orphan = process::spawn(PIDS_PROGRAM, &[], &[], parent).unwrap();
+    // The parent exits on its first system call, before the orphan can exit
+    assert_eq!(process::wait(Some(parent)), Ok((parent, 42)));
+    assert_eq!(
+        process::with_process(orphan, |process| process.parent()),
+        Some(Pid::INIT)
+    );
+    assert_eq!(process::wait(Some(Pid::INIT)), Ok((Pid::INIT, 0)));
+    assert_eq!(process::count(), 0);
+    assert_eq!(memory::allocated_frames(), frames);
+}
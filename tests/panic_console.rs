@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use x86_64::PhysAddr;
+use yarhos::{
+    memory,
+    serial::SERIAL0,
+    vga_buffer::{self, WRITER},
+    vga_mode::TextMode,
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+/// A message unlikely to appear on screen by accident.
+const MESSAGE: &str = "panic_console: deliberate panic";
+
+/// The physical address of the VGA text buffer.
+const VGA_BUFFER: u64 = 0xb8000;
+
+#[no_mangle]
+pub fn _test_start(_boot_info: &'static mut BootInfo) -> ! {
+    yarhos::serial_print!("panic_console::panic_while_holding_locks...\t");
+
+    // Show the first console in the VGA text buffer, in a mode other than the one of the firmware
+    vga_buffer::set_text_mode(TextMode::Text90x30);
+
+    // Simulate a panic in code holding both console locks
+    core::mem::forget(WRITER.lock());
+    core::mem::forget(SERIAL0.lock());
+
+    panic!("{}", MESSAGE);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::emergency_println!("{}", info);
+
+    if !screen_contains(MESSAGE) {
+        yarhos::emergency_println!("[failed]\n");
+        yarhos::emergency_println!("Error: panic message not found in the VGA text buffer\n");
+        yarhos::exit_qemu(yarhos::QemuExitCode::Failure);
+        yarhos::hang();
+    }
+
+    yarhos::emergency_println!("[ok]");
+    yarhos::exit_qemu(yarhos::QemuExitCode::Success);
+    yarhos::hang();
+}
+
+/// Checks whether any single row of the VGA text buffer contains `needle`.
+fn screen_contains(needle: &str) -> bool {
+    let needle = needle.as_bytes();
+    let (width, height) = {
+        // SAFETY: the test itself holds the lock and never resumes
+        let writer = unsafe { WRITER.force_lock() };
+        (writer.width(), writer.height())
+    };
+    let cells: *const u16 = memory::phys_to_virt(PhysAddr::new(VGA_BUFFER)).as_ptr();
+    (0..height).any(|row| {
+        (0..=width - needle.len()).any(|start| {
+            needle.iter().enumerate().all(|(i, &byte)| {
+                // SAFETY: all physical memory is mapped by the bootloader, and the index is within
+                // the text mode
+                let cell = unsafe { cells.add(row * width + start + i).read_volatile() };
+                cell as u8 == byte
+            })
+        })
+    })
+}
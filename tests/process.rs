@@ -0,0 +1,193 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+use yarhos::{
+    loader, memory,
+    process::{self, Pid, WaitError},
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static EXIT_PROGRAM: &[u8] = include_bytes!("programs/exit.elf");
+static WAIT_PROGRAM: &[u8] = include_bytes!("programs/wait.elf");
+static PIDS_PROGRAM: &[u8] = include_bytes!("programs/pids.elf");
+static FORK_PROGRAM: &[u8] = include_bytes!("programs/fork.elf");
+static RUN_PROGRAM: &[u8] = include_bytes!("programs/run.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_spawn_and_wait() {
+    let frames = memory::allocated_frames();
+    let pid = process::spawn(EXIT_PROGRAM, &["exit"], &[], Pid::KERNEL).expect("Spawning failed");
+    assert_eq!(
+        process::with_process(pid, |process| process.parent()),
+        Some(Pid::KERNEL)
+    );
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 42)));
+    assert!(process::with_process(pid, |_| ()).is_none());
+    assert_eq!(process::count(), 0);
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn test_exited_process_is_torn_down() {
+    let frames = memory::allocated_frames();
+    let pid = process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    process::run();
+    let (status, threads, files) = process::with_process(pid, |process| {
+        (
+            process.exit_status(),
+            process.thread_count(),
+            process.files().len(),
+        )
+    })
+    .expect("Zombie collected without waiting");
+    assert_eq!((status, threads, files), (Some(42), 0, 0));
+    assert_eq!(memory::allocated_frames(), frames);
+    assert_eq!(process::wait(None), Ok((pid, 42)));
+}
+
+#[test_case]
+fn test_unique_pids() {
+    let pids = [
+        process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap(),
+        process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap(),
+        process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap(),
+    ];
+    assert!(pids[0] != pids[1] && pids[1] != pids[2] && pids[0] != pids[2]);
+    assert_eq!(process::children(Pid::KERNEL), pids);
+    for _ in pids.iter() {
+        let (pid, status) = process::wait(None).unwrap();
+        assert!(pids.contains(&pid));
+        assert_eq!(status, 42);
+    }
+    assert_eq!(process::wait(None), Err(WaitError::NoChildren));
+}
+
+#[test_case]
+fn test_process_waits_for_children() {
+    let parent = process::spawn(WAIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    for _ in 0..3 {
+        process::spawn(EXIT_PROGRAM, &[], &[], parent).unwrap();
+    }
+    assert_eq!(process::children(parent).len(), 3);
+    assert_eq!(process::wait(Some(parent)), Ok((parent, 3 * 42)));
+    assert_eq!(process::count(), 0);
+}
+
+#[test_case]
+fn test_orphans_are_reparented() {
+    let parent = process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let child = process::spawn(EXIT_PROGRAM, &[], &[], parent).unwrap();
+    assert_eq!(process::wait(Some(parent)), Ok((parent, 42)));
+    assert_eq!(
+        process::with_process(child, |process| process.parent()),
+        Some(Pid::KERNEL)
+    );
+    assert_eq!(process::wait(Some(child)), Ok((child, 42)));
+}
+
+#[test_case]
+fn test_wait_for_non_child() {
+    let parent = process::spawn(WAIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let other = process::spawn(EXIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    assert_eq!(
+        process::try_wait(parent, Some(other)),
+        Err(WaitError::NoChildren)
+    );
+    // Without any children, the waiting process exits with the error of its first wait
+    let no_children = -5i64 as u64;
+    assert_eq!(process::wait(Some(parent)), Ok((parent, no_children)));
+    assert_eq!(process::wait(Some(other)), Ok((other, 42)));
+}
+
+#[test_case]
+fn test_getpid_and_getppid() {
+    let parent = process::spawn(WAIT_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let child = process::spawn(PIDS_PROGRAM, &[], &[], parent).unwrap();
+    let expected = child.as_u64() | parent.as_u64() << 16;
+    assert_eq!(process::wait(Some(parent)), Ok((parent, expected)));
+}
+
+#[test_case]
+fn test_fork_isolates_writes() {
+    let frames = memory::allocated_frames();
+    let parent = process::spawn(FORK_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let child_status = 2;
+    let parent_value = 3;
+    assert_eq!(
+        process::wait(Some(parent)),
+        Ok((parent, 16 * child_status + parent_value))
+    );
+    assert_eq!(process::count(), 0);
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn test_fork_and_exec() {
+    let frames = memory::allocated_frames();
+    let parent = process::spawn(RUN_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    let argc = 2;
+    let page_size_ok = 1 << 16;
+    let aligned = 1 << 17;
+    assert_eq!(
+        process::wait(Some(parent)),
+        Ok((parent, argc | page_size_ok | aligned))
+    );
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn test_fork_shares_frames_copy_on_write() {
+    let frames = memory::allocated_frames();
+    let mut program = loader::load(EXIT_PROGRAM, &[], &[]).unwrap();
+    let stack = VirtAddr::new(loader::USER_STACK_TOP - 8);
+    let mut child = program.address_space.fork().expect("Fork failed");
+    let translate = |mapper: &dyn Translate| match mapper.translate(stack) {
+        TranslateResult::Mapped { frame, flags, .. } => (frame.start_address(), flags),
+        _ => panic!("Stack not mapped"),
+    };
+    let (parent_frame, parent_flags) = translate(&program.address_space.mapper());
+    let (child_frame, child_flags) = translate(&child.mapper());
+    assert_eq!(parent_frame, child_frame);
+    for &flags in [parent_flags, child_flags].iter() {
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(flags.contains(memory::COPY_ON_WRITE));
+    }
+
+    child.write(stack, &[1]).unwrap();
+    let (copy_frame, copy_flags) = translate(&child.mapper());
+    assert_ne!(copy_frame, parent_frame);
+    assert!(copy_flags.contains(PageTableFlags::WRITABLE));
+    drop(child);
+    // The last reference to the frame is still copy-on-write, but needs no copy
+    program.address_space.write(stack, &[2]).unwrap();
+    assert_eq!(translate(&program.address_space.mapper()).0, parent_frame);
+
+    assert_eq!(program.run(), 42);
+    drop(program);
+    assert_eq!(memory::allocated_frames(), frames);
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use yarhos::{
+    memory,
+    process::{self, Pid},
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static RUNTIME_PROGRAM: &[u8] = include_bytes!("programs/runtime.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_rust_program() {
+    let frames = memory::allocated_frames();
+    let pid = process::spawn(RUNTIME_PROGRAM, &["runtime", "a", "b"], &[], Pid::KERNEL).unwrap();
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 42)));
+    assert_eq!(memory::allocated_frames(), frames);
+}
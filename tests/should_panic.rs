@@ -6,12 +6,12 @@
 
 use core::panic::PanicInfo;
 
-use bootloader::{entry_point, BootInfo};
+use bootloader_api::{entry_point, BootInfo};
 
-entry_point!(_test_start);
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
 
 #[no_mangle]
-pub fn _test_start(_boot_info: &'static BootInfo) -> ! {
+pub fn _test_start(_boot_info: &'static mut BootInfo) -> ! {
     test_main();
     yarhos::halt_loop();
 }
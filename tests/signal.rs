@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use yarhos::{
+    loader, memory,
+    process::{self, Pid},
+    signal::Signal,
+};
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+// Test programs, see `scripts/build_test_programs.sh`
+static FAULT_PROGRAM: &[u8] = include_bytes!("programs/fault.elf");
+static PIDS_PROGRAM: &[u8] = include_bytes!("programs/pids.elf");
+static SIGNAL_PROGRAM: &[u8] = include_bytes!("programs/signal.elf");
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_faults_terminate_processes() {
+    let frames = memory::allocated_frames();
+    let arguments = ["fault"; 5];
+    let expected = [
+        Signal::SIGFPE,
+        Signal::SIGSEGV,
+        Signal::SIGILL,
+        Signal::SIGSEGV,
+        Signal::SIGSEGV,
+    ];
+    for (count, signal) in (1..).zip(expected.iter()) {
+        let pid = process::spawn(FAULT_PROGRAM, &arguments[..count], &[], Pid::KERNEL).unwrap();
+        assert_eq!(process::wait(Some(pid)), Ok((pid, signal.exit_status())));
+    }
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn test_signal_handlers_and_sigreturn() {
+    let pid = process::spawn(SIGNAL_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 42)));
+}
+
+#[test_case]
+fn test_kill() {
+    let pid = process::spawn(PIDS_PROGRAM, &[], &[], Pid::KERNEL).unwrap();
+    assert!(process::kill(pid, Signal::SIGTERM));
+    assert_eq!(
+        process::wait(Some(pid)),
+        Ok((pid, Signal::SIGTERM.exit_status()))
+    );
+    assert_eq!(Signal::SIGTERM.exit_status(), 143);
+    assert!(!process::kill(pid, Signal::SIGTERM));
+}
+
+#[test_case]
+fn test_fault_outside_process() {
+    let mut program = loader::load(FAULT_PROGRAM, &["fault"], &[]).unwrap();
+    assert_eq!(program.run(), Signal::SIGFPE.exit_status());
+}
@@ -4,14 +4,14 @@
 
 use core::{ops::Deref, panic::PanicInfo};
 
-use bootloader::{entry_point, BootInfo};
+use bootloader_api::{entry_point, BootInfo};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-entry_point!(_test_start);
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
 
 #[no_mangle]
-pub fn _test_start(_boot_info: &'static BootInfo) -> ! {
+pub fn _test_start(_boot_info: &'static mut BootInfo) -> ! {
     yarhos::serial_print!("stack_overflow::stack_overflow...\t");
 
     yarhos::gdt::init();
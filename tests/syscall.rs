@@ -0,0 +1,246 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+use bootloader_api::{entry_point, BootInfo};
+use common::{load_program, STACK_TOP};
+use yarhos::{
+    gdt, memory,
+    syscall::{self, Error},
+    usermode,
+};
+
+mod common;
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    common::map_user_memory();
+
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+// Test programs, placed in read-only data as they are only ever copied to user space.
+// NOTE: the exit system call is number 0 and the others are numbered as in `yarhos::syscall`
+global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+    // Writes a message, and exits with the result
+    "write_message_start:",
+    "lea rdi, [rip + 3f]",
+    "mov esi, 19",
+    "mov eax, 1",
+    "syscall",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "3:",
+    ".ascii \"Hello from ring 3! \"",
+    "write_message_end:",
+    // Writes from the address in `rdi` and the length in `rsi`, and exits with the result
+    "write_buffer_start:",
+    "mov eax, 1",
+    "syscall",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "write_buffer_end:",
+    // Writes bytes that are not UTF-8, and exits with the result
+    "write_invalid_utf8_start:",
+    "lea rdi, [rip + 3f]",
+    "mov esi, 2",
+    "mov eax, 1",
+    "syscall",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "3:",
+    ".byte 0xC3, 0x28",
+    "write_invalid_utf8_end:",
+    // Sleeps for the milliseconds in `rdi`, and exits with the time passed in between
+    "sleep_start:",
+    "mov r12, rdi",
+    "mov eax, 4",
+    "syscall",
+    "mov rbx, rax",
+    "mov rdi, r12",
+    "mov eax, 3",
+    "syscall",
+    "mov eax, 4",
+    "syscall",
+    "sub rax, rbx",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "sleep_end:",
+    // Yields, spins with interrupts enabled for a while, and exits with the yield result
+    "yield_and_spin_start:",
+    "mov eax, 2",
+    "syscall",
+    "mov rdi, rax",
+    "mov ecx, 0x1000000",
+    "2:",
+    "pause",
+    "loop 2b",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "yield_and_spin_end:",
+    // Makes the system call numbered `rdi`, and exits with the result
+    "call_number_start:",
+    "mov rax, rdi",
+    "syscall",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "syscall",
+    "ud2",
+    "call_number_end:",
+    // Writes a message through the interrupt gate, and exits with the result and `rcx` added
+    "gate_write_start:",
+    "lea rdi, [rip + 3f]",
+    "mov esi, 24",
+    "mov ecx, 1000",
+    "mov eax, 1",
+    "int 0x80",
+    "lea rdi, [rax + rcx]",
+    "xor eax, eax",
+    "int 0x80",
+    "ud2",
+    "3:",
+    ".ascii \"Hello through the gate! \"",
+    "gate_write_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static write_message_start: u8;
+    static write_message_end: u8;
+    static write_buffer_start: u8;
+    static write_buffer_end: u8;
+    static write_invalid_utf8_start: u8;
+    static write_invalid_utf8_end: u8;
+    static sleep_start: u8;
+    static sleep_end: u8;
+    static yield_and_spin_start: u8;
+    static yield_and_spin_end: u8;
+    static call_number_start: u8;
+    static call_number_end: u8;
+    static gate_write_start: u8;
+    static gate_write_end: u8;
+}
+
+#[test_case]
+fn test_write() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &write_message_start }, unsafe {
+        &write_message_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 19);
+    // The exit system call was entered with `SYSCALL`
+    assert_eq!(context.rcx, context.rip);
+    assert_eq!(context.cs, u64::from(gdt::selectors().user_code.0));
+}
+
+#[test_case]
+fn test_write_rejects_kernel_memory() {
+    let message = "kernel memory";
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &write_buffer_start }, unsafe { &write_buffer_end });
+    context.rdi = message.as_ptr() as u64;
+    context.rsi = message.len() as u64;
+    // SAFETY: the program and stack are mapped user accessible
+    let result = unsafe { usermode::run_until_exit(&mut context) };
+    assert_eq!(result, Error::BadAddress.as_return_value());
+}
+
+#[test_case]
+fn test_write_rejects_unmapped_memory() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &write_buffer_start }, unsafe { &write_buffer_end });
+    // The buffer starts on the stack and runs past its top
+    context.rdi = STACK_TOP - 8;
+    context.rsi = 16;
+    // SAFETY: the program and stack are mapped user accessible
+    let result = unsafe { usermode::run_until_exit(&mut context) };
+    assert_eq!(result, Error::BadAddress.as_return_value());
+}
+
+#[test_case]
+fn test_write_rejects_invalid_utf8() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &write_invalid_utf8_start }, unsafe {
+        &write_invalid_utf8_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    let result = unsafe { usermode::run_until_exit(&mut context) };
+    assert_eq!(result, Error::InvalidArgument.as_return_value());
+}
+
+#[test_case]
+fn test_sleep_and_time() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &sleep_start }, unsafe { &sleep_end });
+    context.rdi = 200;
+    // SAFETY: the program and stack are mapped user accessible
+    let elapsed = unsafe { usermode::run_until_exit(&mut context) };
+    assert!(elapsed >= 200, "slept for only {} ms", elapsed);
+}
+
+#[test_case]
+fn test_yield_and_survive_interrupts() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &yield_and_spin_start }, unsafe {
+        &yield_and_spin_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 0);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_unknown_syscall() {
+    for number in [5, 0x1000, u64::MAX] {
+        // SAFETY: the symbols delimit a test program
+        let mut context = load_program(unsafe { &call_number_start }, unsafe { &call_number_end });
+        context.rdi = number;
+        // SAFETY: the program and stack are mapped user accessible
+        let result = unsafe { usermode::run_until_exit(&mut context) };
+        assert_eq!(result, Error::NoSuchSyscall.as_return_value());
+    }
+}
+
+#[test_case]
+fn test_time_is_monotonic() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &call_number_start }, unsafe { &call_number_end });
+    context.rdi = syscall::TIME;
+    let before = yarhos::time::uptime_ms();
+    // SAFETY: the program and stack are mapped user accessible
+    let time = unsafe { usermode::run_until_exit(&mut context) };
+    assert!(time >= before && time <= yarhos::time::uptime_ms());
+}
+
+#[test_case]
+fn test_interrupt_gate() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &gate_write_start }, unsafe { &gate_write_end });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 24 + 1000);
+}
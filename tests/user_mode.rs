@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(yarhos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+use bootloader_api::{entry_point, BootInfo};
+use common::{load_program, CODE_ADDR};
+use yarhos::{memory, usermode};
+
+mod common;
+
+entry_point!(_test_start, config = &yarhos::BOOTLOADER_CONFIG);
+
+#[no_mangle]
+pub fn _test_start(boot_info: &'static mut BootInfo) -> ! {
+    yarhos::init();
+    memory::init(boot_info);
+    common::map_user_memory();
+
+    test_main();
+    yarhos::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    yarhos::test_panic_handler(info)
+}
+
+// Test programs, placed in read-only data as they are only ever copied to user space.
+// NOTE: an exit request is made with `rax` = 0 and the exit code in `rdi`
+global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+    // Exits with the current privilege level
+    "exit_with_cpl_start:",
+    "mov rdi, cs",
+    "and edi, 3",
+    "xor eax, eax",
+    "int 0x80",
+    "ud2",
+    "exit_with_cpl_end:",
+    // Exits with the sum of `rdi` and `rsi`
+    "exit_with_sum_start:",
+    "add rdi, rsi",
+    "xor eax, eax",
+    "int 0x80",
+    "ud2",
+    "exit_with_sum_end:",
+    // Makes an unknown request, and exits with the result
+    "exit_with_result_start:",
+    "mov eax, 0xFFFF",
+    "int 0x80",
+    "mov rdi, rax",
+    "xor eax, eax",
+    "int 0x80",
+    "ud2",
+    "exit_with_result_end:",
+    // Spins with interrupts enabled for a while before exiting with the loop count
+    "exit_after_spinning_start:",
+    "mov ecx, 0x1000000",
+    "2:",
+    "pause",
+    "loop 2b",
+    "mov rdi, rcx",
+    "xor eax, eax",
+    "int 0x80",
+    "ud2",
+    "exit_after_spinning_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static exit_with_cpl_start: u8;
+    static exit_with_cpl_end: u8;
+    static exit_with_sum_start: u8;
+    static exit_with_sum_end: u8;
+    static exit_with_result_start: u8;
+    static exit_with_result_end: u8;
+    static exit_after_spinning_start: u8;
+    static exit_after_spinning_end: u8;
+}
+
+#[test_case]
+fn test_runs_in_ring_3() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &exit_with_cpl_start }, unsafe {
+        &exit_with_cpl_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 3);
+}
+
+#[test_case]
+fn test_passes_registers() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &exit_with_sum_start }, unsafe {
+        &exit_with_sum_end
+    });
+    context.rdi = 40;
+    context.rsi = 2;
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 42);
+    assert_eq!(context.rip, CODE_ADDR + 7);
+}
+
+#[test_case]
+fn test_answers_unknown_requests() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &exit_with_result_start }, unsafe {
+        &exit_with_result_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, u64::MAX);
+}
+
+#[test_case]
+fn test_survives_interrupts() {
+    // SAFETY: the symbols delimit a test program
+    let mut context = load_program(unsafe { &exit_after_spinning_start }, unsafe {
+        &exit_after_spinning_end
+    });
+    // SAFETY: the program and stack are mapped user accessible
+    assert_eq!(unsafe { usermode::run_until_exit(&mut context) }, 0);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}